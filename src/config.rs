@@ -0,0 +1,612 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crossterm::style::Color;
+use log::info;
+use mime::Mime;
+use thiserror::Error;
+
+use crate::dirs;
+use crate::gemini::tls::{Verification, VerificationPolicy};
+use crate::terminal::colors;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("unknown setting: {0}")]
+    UnknownKey(String),
+    #[error("invalid value for {0}: {1}")]
+    InvalidValue(String, String),
+    #[error("expected key = value: {0}")]
+    InvalidLine(String),
+    #[error("unable to read config: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentKind {
+    Mode,
+    StatusCode,
+    Url,
+    Spacer,
+    Position,
+    AutoReload,
+    /// Only shown while offline
+    Offline,
+    /// The match moved to and how many there are, while there's a search
+    Search,
+    /// The languages the page says it's in, when it says
+    Lang,
+    /// The charset the page was decoded with, when it had to be auto-detected
+    Encoding,
+    /// The first column in view, while lines aren't wrapped
+    Column,
+    /// How the certificate compared to the one pinned for the host
+    Trust,
+    /// The time of day
+    Clock,
+}
+
+impl SegmentKind {
+    fn parse(name: &str) -> Option<Self> {
+        use SegmentKind::*;
+
+        match name {
+            "mode" => Some(Mode),
+            "status_code" => Some(StatusCode),
+            "url" => Some(Url),
+            "spacer" => Some(Spacer),
+            "position" => Some(Position),
+            "auto_reload" => Some(AutoReload),
+            "offline" => Some(Offline),
+            "search" => Some(Search),
+            "lang" => Some(Lang),
+            "encoding" => Some(Encoding),
+            "column" => Some(Column),
+            "trust" => Some(Trust),
+            "clock" => Some(Clock),
+            _ => None,
+        }
+    }
+}
+
+/// A piece of the status line, optionally with its own colors
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub colors: Option<(Color, Color)>,
+}
+
+impl Segment {
+    // <name>[:<FG>/<BG>]
+    fn parse(input: &str) -> Option<Self> {
+        let mut parts = input.trim().splitn(2, ':');
+        let kind = SegmentKind::parse(parts.next()?.trim())?;
+
+        let colors = match parts.next() {
+            Some(colors) => {
+                let mut colors = colors.splitn(2, '/');
+                let fg = colors::by_name(colors.next()?.trim())?;
+                let bg = colors::by_name(colors.next()?.trim())?;
+                Some((fg, bg))
+            }
+            None => None,
+        };
+
+        Some(Self { kind, colors })
+    }
+}
+
+/// What to do when a download would overwrite an existing file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DownloadConflict {
+    /// Save as name(1).ext
+    Rename,
+    /// Ask before overwriting
+    Prompt,
+}
+
+/// Whether a download is opened once it has been saved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenDownloads {
+    Never,
+    Always,
+    Ask,
+}
+
+/// Which URLs in plain text lines are shown and followed like links
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DetectUrls {
+    Off,
+    Gemini,
+    /// Gemini and HTTP(S)
+    All,
+}
+
+impl DetectUrls {
+    pub fn schemes(self) -> &'static [&'static str] {
+        match self {
+            DetectUrls::Off => &[],
+            DetectUrls::Gemini => &["gemini://"],
+            DetectUrls::All => &["gemini://", "http://", "https://"],
+        }
+    }
+}
+
+/// The program downloads are opened with when no handler matches their MIME type
+#[cfg(not(windows))]
+const DEFAULT_HANDLER: &str = "xdg-open";
+#[cfg(windows)]
+const DEFAULT_HANDLER: &str = "explorer";
+
+#[derive(Debug)]
+pub struct Config {
+    /// The status line segments in order, the default layout is used when this is empty
+    pub status_line: Vec<Segment>,
+    /// Cache DNS lookups for the session
+    pub dns_cache: bool,
+    /// Refuse to load from a server whose certificate is for another host, rather than warn
+    pub strict_hostnames: bool,
+    /// How certificates are checked, `verify.<host>` keys override it for a host
+    pub verify: VerificationPolicy,
+    /// The number of same host links to fetch in the background after a page loads
+    pub prefetch: usize,
+    /// Fetch the emoji capsules serve at /favicon.txt and show it before the URL
+    pub favicons: bool,
+    /// Limits of the in-memory page cache
+    pub cache_max_entries: usize,
+    pub cache_max_bytes: usize,
+    /// Seconds after which a page in memory is fetched again
+    pub cache_max_age: u64,
+    /// Keep pages on disk between sessions, in the cache directory
+    pub disk_cache: bool,
+    /// Seconds after which a page on disk is fetched again
+    pub disk_cache_max_age: u64,
+    pub disk_cache_max_bytes: u64,
+    /// How many more times to try a request when connecting fails in a way that might not last
+    pub retries: usize,
+    /// Seconds a page can take to load, redirects included, 0 never gives up
+    pub timeout: u64,
+    /// Seconds a download can take, 0 never gives up
+    pub download_timeout: u64,
+    /// Where downloads are saved, the platform's download directory when not set
+    pub download_dir: Option<PathBuf>,
+    pub download_conflict: DownloadConflict,
+    /// Ask before continuing a download once this many bytes have been received, 0 never asks
+    pub download_prompt_bytes: usize,
+    /// Offer to show images inline when the terminal supports it, needs the image_preview feature
+    pub image_preview: bool,
+    pub open_downloads: OpenDownloads,
+    /// Commands downloads are opened with keyed by MIME type, `type/*` matches any subtype
+    pub handlers: HashMap<String, String>,
+    /// Draw in rows below the shell's prompt rather than taking over the screen, the last page is
+    /// left in the scrollback on quit
+    pub inline: bool,
+    /// How many rows to draw in inline, at most the height of the terminal
+    pub inline_rows: u16,
+    /// Ring the bell when a page finishes loading while something else is being looked at
+    pub bell: bool,
+    /// Open URLs given to another invocation of diosk in this one rather than starting another
+    pub single_instance: bool,
+    pub detect_urls: DetectUrls,
+    /// Show URLs with percent-escapes decoded, off shows them as they're sent
+    pub decode_urls: bool,
+    /// Show the URLs of links that have names beside them, off shows the current link's in the
+    /// status line
+    pub show_urls: bool,
+    /// Color preformatted blocks whose alt text names a language, with the syntax_highlighting
+    /// feature
+    pub highlight_syntax: bool,
+    /// Columns between tab stops, tabs in pages are expanded to spaces up to the next one
+    pub tab_width: usize,
+    /// Open a page visited earlier in the session where it was left rather than at the top
+    pub remember_positions: bool,
+    /// Rows kept on screen above and below the current line when moving starts scrolling
+    pub scrolloff: usize,
+    /// Wrap text at the edge of the screen, off cuts lines off there and h and l pan across
+    pub wrap: bool,
+    /// Show preformatted blocks collapsed to their alt text until they're expanded
+    pub collapse_preformatted: bool,
+    /// Treat searches as regular expressions, a search ending in /r always is one
+    pub search_regex: bool,
+    /// Search ignoring case, unless with smartcase the pattern has capitals in it
+    pub ignorecase: bool,
+    pub smartcase: bool,
+    /// Schemes of links that are handed to their handler without asking first
+    pub allowed_schemes: Vec<String>,
+    /// Run to read the clipboard when the terminal won't share it, e.g. wl-paste or xclip -o
+    pub paste_command: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            status_line: Vec::new(),
+            dns_cache: true,
+            strict_hostnames: false,
+            verify: VerificationPolicy::default(),
+            prefetch: 0,
+            favicons: false,
+            cache_max_entries: 100,
+            cache_max_bytes: 16 * 1024 * 1024,
+            cache_max_age: 10 * 60,
+            disk_cache: false,
+            disk_cache_max_age: 60 * 60,
+            disk_cache_max_bytes: 64 * 1024 * 1024,
+            retries: 2,
+            timeout: 60,
+            download_timeout: 0,
+            download_dir: None,
+            download_conflict: DownloadConflict::Rename,
+            download_prompt_bytes: 1024 * 1024,
+            image_preview: true,
+            open_downloads: OpenDownloads::Never,
+            handlers: HashMap::new(),
+            inline: false,
+            inline_rows: 20,
+            bell: false,
+            single_instance: true,
+            detect_urls: DetectUrls::Off,
+            decode_urls: true,
+            show_urls: false,
+            highlight_syntax: true,
+            tab_width: 8,
+            remember_positions: true,
+            scrolloff: 0,
+            wrap: true,
+            collapse_preformatted: false,
+            search_regex: false,
+            ignorecase: true,
+            smartcase: true,
+            allowed_schemes: Vec::new(),
+            paste_command: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config from a file of `key = value` lines, a missing file is the default config
+    ///
+    /// Invalid lines are skipped and returned alongside the config so they can be reported.
+    pub fn load(path: &Path) -> (Self, Vec<ConfigError>) {
+        let mut config = Self::default();
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return (config, Vec::new()),
+            Err(e) => return (config, vec![e.into()]),
+        };
+
+        let errors = config.apply(&content);
+        info!("loaded config: {:?}", config);
+
+        (config, errors)
+    }
+
+    /// Change a setting in the config file, replacing the line that sets it or adding one
+    pub fn save(path: &Path, key: &str, value: &str) -> io::Result<()> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let setting = format!("{} = {}", key, value);
+        let mut replaced = false;
+        let mut lines: Vec<&str> = content
+            .lines()
+            .map(|line| match line.split_once('=') {
+                Some((k, _)) if k.trim() == key && !line.trim_start().starts_with('#') => {
+                    replaced = true;
+                    setting.as_str()
+                }
+                _ => line,
+            })
+            .collect();
+        if !replaced {
+            lines.push(&setting);
+        }
+
+        // The config directory isn't there until something is first saved to it
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, lines.join("\n") + "\n")
+    }
+
+    fn apply(&mut self, content: &str) -> Vec<ConfigError> {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| match line.split_once('=') {
+                Some((key, value)) => self.set(key.trim(), value.trim()).err(),
+                None => Some(ConfigError::InvalidLine(line.to_string())),
+            })
+            .collect()
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue(key.to_string(), value.to_string());
+
+        match key {
+            "status_line" => {
+                self.status_line = value
+                    .split(',')
+                    .filter(|s| !s.trim().is_empty())
+                    .map(|s| Segment::parse(s).ok_or_else(invalid))
+                    .collect::<Result<_, _>>()?;
+            }
+            "dns_cache" => self.dns_cache = parse_bool(value).ok_or_else(invalid)?,
+            "strict_hostnames" => self.strict_hostnames = parse_bool(value).ok_or_else(invalid)?,
+            "verify" => self.verify.default = Verification::parse(value).ok_or_else(invalid)?,
+            "prefetch" => self.prefetch = value.parse().map_err(|_| invalid())?,
+            "favicons" => self.favicons = parse_bool(value).ok_or_else(invalid)?,
+            "cache_max_entries" => self.cache_max_entries = value.parse().map_err(|_| invalid())?,
+            "cache_max_bytes" => self.cache_max_bytes = value.parse().map_err(|_| invalid())?,
+            "cache_max_age" => self.cache_max_age = value.parse().map_err(|_| invalid())?,
+            "disk_cache" => self.disk_cache = parse_bool(value).ok_or_else(invalid)?,
+            "disk_cache_max_age" => {
+                self.disk_cache_max_age = value.parse().map_err(|_| invalid())?
+            }
+            "disk_cache_max_bytes" => {
+                self.disk_cache_max_bytes = value.parse().map_err(|_| invalid())?
+            }
+            "retries" => self.retries = value.parse().map_err(|_| invalid())?,
+            "timeout" => self.timeout = value.parse().map_err(|_| invalid())?,
+            "download_timeout" => self.download_timeout = value.parse().map_err(|_| invalid())?,
+            "download_dir" => self.download_dir = Some(expand_home(value)),
+            "download_prompt_bytes" => {
+                self.download_prompt_bytes = value.parse().map_err(|_| invalid())?
+            }
+            "image_preview" => self.image_preview = parse_bool(value).ok_or_else(invalid)?,
+            "inline" => self.inline = parse_bool(value).ok_or_else(invalid)?,
+            // The status line and prompt take two
+            "inline_rows" => {
+                self.inline_rows = value
+                    .parse()
+                    .ok()
+                    .filter(|rows| *rows > 2)
+                    .ok_or_else(invalid)?
+            }
+            "bell" => self.bell = parse_bool(value).ok_or_else(invalid)?,
+            "allowed_schemes" => {
+                self.allowed_schemes = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|scheme| !scheme.is_empty())
+                    .map(str::to_lowercase)
+                    .collect();
+            }
+            "paste_command" => {
+                self.paste_command = Some(value.to_string()).filter(|command| !command.is_empty())
+            }
+            "decode_urls" => self.decode_urls = parse_bool(value).ok_or_else(invalid)?,
+            "show_urls" => self.show_urls = parse_bool(value).ok_or_else(invalid)?,
+            "highlight_syntax" => self.highlight_syntax = parse_bool(value).ok_or_else(invalid)?,
+            "collapse_preformatted" => {
+                self.collapse_preformatted = parse_bool(value).ok_or_else(invalid)?
+            }
+            "search_regex" => self.search_regex = parse_bool(value).ok_or_else(invalid)?,
+            "ignorecase" => self.ignorecase = parse_bool(value).ok_or_else(invalid)?,
+            "smartcase" => self.smartcase = parse_bool(value).ok_or_else(invalid)?,
+            "remember_positions" => {
+                self.remember_positions = parse_bool(value).ok_or_else(invalid)?
+            }
+            "scrolloff" => self.scrolloff = value.parse().map_err(|_| invalid())?,
+            "wrap" => self.wrap = parse_bool(value).ok_or_else(invalid)?,
+            "tab_width" => {
+                self.tab_width = value
+                    .parse()
+                    .ok()
+                    .filter(|width| *width > 0)
+                    .ok_or_else(invalid)?
+            }
+            "single_instance" => self.single_instance = parse_bool(value).ok_or_else(invalid)?,
+            "download_conflict" => {
+                self.download_conflict = match value {
+                    "rename" => DownloadConflict::Rename,
+                    "prompt" => DownloadConflict::Prompt,
+                    _ => return Err(invalid()),
+                }
+            }
+            "detect_urls" => {
+                self.detect_urls = match value {
+                    "off" => DetectUrls::Off,
+                    "gemini" => DetectUrls::Gemini,
+                    "all" => DetectUrls::All,
+                    _ => return Err(invalid()),
+                }
+            }
+            // Turning it on keeps HTTP(S) URLs if they were already detected
+            "linkify" => {
+                self.detect_urls = match parse_bool(value).ok_or_else(invalid)? {
+                    false => DetectUrls::Off,
+                    true if self.detect_urls == DetectUrls::Off => DetectUrls::Gemini,
+                    true => self.detect_urls,
+                }
+            }
+            "open_downloads" => {
+                self.open_downloads = match value {
+                    "never" => OpenDownloads::Never,
+                    "always" => OpenDownloads::Always,
+                    "ask" => OpenDownloads::Ask,
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => match (key.strip_prefix("handler."), key.strip_prefix("verify.")) {
+                (Some(mime_type), _) if !mime_type.is_empty() && !value.is_empty() => {
+                    self.handlers
+                        .insert(mime_type.to_string(), value.to_string());
+                }
+                (_, Some(host)) if !host.is_empty() => {
+                    let verification = Verification::parse(value).ok_or_else(invalid)?;
+                    self.verify
+                        .hosts
+                        .insert(host.to_ascii_lowercase(), verification);
+                }
+                (None, None) => return Err(ConfigError::UnknownKey(key.to_string())),
+                _ => return Err(invalid()),
+            },
+        }
+
+        Ok(())
+    }
+
+    /// The command used to open a link diosk can't load itself, set with the
+    /// `x-scheme-handler/<scheme>` MIME type like desktop environments do
+    pub fn scheme_handler(&self, scheme: &str) -> &str {
+        match format!("x-scheme-handler/{}", scheme).parse() {
+            Ok(mime_type) => self.handler(&mime_type),
+            Err(_) => DEFAULT_HANDLER,
+        }
+    }
+
+    /// The command used to open a file of the given MIME type
+    pub fn handler(&self, mime_type: &Mime) -> &str {
+        self.handlers
+            .get(mime_type.essence_str())
+            .or_else(|| self.handlers.get(&format!("{}/*", mime_type.type_())))
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_HANDLER)
+    }
+}
+
+/// Expand a leading ~ to the home directory
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "on" | "yes" => Some(true),
+        "false" | "off" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let mut config = Config::default();
+        let errors = config.apply(
+            "# comment\n\nstatus_line = mode, url:FOREGROUND/old_brick, spacer\nfoo = bar\nbaz",
+        );
+
+        assert_eq!(
+            config.status_line,
+            vec![
+                Segment {
+                    kind: SegmentKind::Mode,
+                    colors: None
+                },
+                Segment {
+                    kind: SegmentKind::Url,
+                    colors: Some((colors::FOREGROUND, colors::OLD_BRICK))
+                },
+                Segment {
+                    kind: SegmentKind::Spacer,
+                    colors: None
+                },
+            ]
+        );
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn invalid_status_line_keeps_previous_value() {
+        let mut config = Config::default();
+        config.set("status_line", "url").unwrap();
+
+        assert!(config.set("status_line", "url, nope").is_err());
+        assert_eq!(config.status_line.len(), 1);
+    }
+
+    #[test]
+    fn handler() {
+        let mut config = Config::default();
+        config.set("handler.image/*", "feh").unwrap();
+        config.set("handler.image/gif", "mpv --loop").unwrap();
+
+        let handler = |mime: &str| config.handler(&mime.parse().unwrap()).to_string();
+
+        assert_eq!(handler("image/png"), "feh");
+        assert_eq!(handler("image/gif"), "mpv --loop");
+        assert_eq!(handler("application/pdf"), DEFAULT_HANDLER);
+        assert!(config.set("handler.", "feh").is_err());
+
+        config
+            .set("handler.x-scheme-handler/https", "firefox")
+            .unwrap();
+        assert_eq!(config.scheme_handler("https"), "firefox");
+        assert_eq!(config.scheme_handler("mailto"), DEFAULT_HANDLER);
+    }
+
+    #[test]
+    fn verify() {
+        let mut config = Config::default();
+        assert_eq!(config.verify.for_host("example.org"), Verification::Tofu);
+
+        config.set("verify", "tofu+webpki").unwrap();
+        config.set("verify.Bank.example", "webpki").unwrap();
+        config.set("verify.self.example", "tofu").unwrap();
+        assert_eq!(config.verify.for_host("bank.example"), Verification::Webpki);
+        assert_eq!(config.verify.for_host("self.example"), Verification::Tofu);
+        assert_eq!(
+            config.verify.for_host("example.org"),
+            Verification::TofuWebpki
+        );
+
+        assert!(config.set("verify", "ca").is_err());
+        assert!(config.set("verify.", "tofu").is_err());
+        assert!(config.set("verify.example.org", "").is_err());
+    }
+
+    #[test]
+    fn linkify() {
+        let mut config = Config::default();
+        config.set("linkify", "on").unwrap();
+        assert_eq!(config.detect_urls, DetectUrls::Gemini);
+
+        config.set("detect_urls", "all").unwrap();
+        config.set("linkify", "on").unwrap();
+        assert_eq!(config.detect_urls, DetectUrls::All);
+
+        config.set("linkify", "off").unwrap();
+        assert_eq!(config.detect_urls, DetectUrls::Off);
+        assert!(config.set("linkify", "gemini").is_err());
+    }
+
+    #[test]
+    fn save() {
+        let path = env::temp_dir().join(format!("diosk-config-{}.txt", std::process::id()));
+        let path = path.as_path();
+        fs::write(
+            path,
+            "# allowed_schemes = ftp\nbell = on\nallowed_schemes = http\n",
+        )
+        .unwrap();
+
+        Config::save(path, "allowed_schemes", "http,mailto").unwrap();
+        Config::save(path, "prefetch", "2").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            "# allowed_schemes = ftp\nbell = on\nallowed_schemes = http,mailto\nprefetch = 2\n"
+        );
+        let (config, errors) = Config::load(path);
+        assert!(errors.is_empty());
+        assert_eq!(config.allowed_schemes, ["http", "mailto"]);
+
+        fs::remove_file(path).unwrap();
+    }
+}
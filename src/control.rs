@@ -0,0 +1,137 @@
+//! A socket a running diosk listens on so URLs given to another invocation open in it instead
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use log::{info, warn};
+use url::Url;
+
+use crate::state::Event;
+
+/// Where the socket goes, there's nowhere private enough for it without XDG_RUNTIME_DIR
+pub fn socket_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("diosk.sock"))
+}
+
+/// Hand a URL to the instance listening on the socket, false if there isn't one
+pub fn send(path: &Path, url: &Url) -> io::Result<bool> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        // A socket nobody is listening on was left behind by a crash, the next instance to
+        // listen cleans it up
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            return Ok(false)
+        }
+        Err(e) => return Err(e),
+    };
+
+    writeln!(stream, "{}", url)?;
+    Ok(true)
+}
+
+/// Removes the socket when diosk exits
+pub struct Socket {
+    path: PathBuf,
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Listen on the socket, URLs sent to it are passed to the worker as `Event::Open`
+///
+/// Fails with `AddrInUse` if another instance is already listening.
+pub fn listen(path: &Path, tx: mpsc::Sender<Event>) -> io::Result<Socket> {
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) if e.kind() == ErrorKind::AddrInUse && is_stale(path) => {
+            info!("removing stale control socket");
+            fs::remove_file(path)?;
+            UnixListener::bind(path)?
+        }
+        Err(e) => return Err(e),
+    };
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("unable to accept control connection: {}", e);
+                    continue;
+                }
+            };
+
+            for line in BufReader::new(stream).lines() {
+                let url = match line.map(|line| Url::parse(line.trim())) {
+                    Ok(Ok(url)) => url,
+                    Ok(Err(e)) => {
+                        warn!("invalid URL sent to the control socket: {}", e);
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("unable to read from control connection: {}", e);
+                        break;
+                    }
+                };
+
+                // diosk is shutting down
+                if tx.send(Event::Open(url)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(Socket {
+        path: path.to_path_buf(),
+    })
+}
+
+/// Whether nobody is listening on an existing socket
+fn is_stale(path: &Path) -> bool {
+    matches!(
+        UnixStream::connect(path),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_sent_to_the_listener() {
+        let path = env::temp_dir().join(format!("diosk-test-{}.sock", std::process::id()));
+        let url = Url::parse("gemini://example.org/").unwrap();
+
+        assert!(!send(&path, &url).unwrap());
+
+        // Left behind by a crash
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(!send(&path, &url).unwrap());
+
+        let (tx, rx) = mpsc::channel();
+        let socket = listen(&path, tx).unwrap();
+        assert_eq!(
+            listen(&path, mpsc::channel().0).err().map(|e| e.kind()),
+            Some(ErrorKind::AddrInUse)
+        );
+
+        assert!(send(&path, &url).unwrap());
+        match rx.recv().unwrap() {
+            Event::Open(received) => assert_eq!(received, url),
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,49 @@
+//! Where diosk keeps its files, following each platform's conventions
+//!
+//! The directories crate works out the platform's directories: the XDG base directories on Linux,
+//! Application Support and Caches on macOS, and APPDATA and LOCALAPPDATA on Windows. Tests keep
+//! everything in target so they never touch the files of a real session.
+
+use std::path::{Path, PathBuf};
+
+use directories::{BaseDirs, ProjectDirs, UserDirs};
+
+/// The config file and startup script
+pub fn config_dir() -> PathBuf {
+    dir(ProjectDirs::config_dir)
+}
+
+/// Bookmarks, histories, the download log and known hosts
+pub fn data_dir() -> PathBuf {
+    dir(ProjectDirs::data_dir)
+}
+
+pub fn cache_dir() -> PathBuf {
+    dir(ProjectDirs::cache_dir)
+}
+
+/// The log, kept with the local data on platforms without a state directory
+pub fn state_dir() -> PathBuf {
+    dir(|dirs| dirs.state_dir().unwrap_or_else(|| dirs.data_local_dir()))
+}
+
+/// The user's home directory
+pub fn home_dir() -> Option<PathBuf> {
+    BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf())
+}
+
+/// The user's download directory, if the platform has one
+pub fn download_dir() -> Option<PathBuf> {
+    UserDirs::new().and_then(|dirs| dirs.download_dir().map(Path::to_path_buf))
+}
+
+/// One of diosk's directories, the working directory when there's no home to put it in
+fn dir(kind: impl Fn(&ProjectDirs) -> &Path) -> PathBuf {
+    if cfg!(test) {
+        return PathBuf::from("target");
+    }
+
+    ProjectDirs::from("", "", "diosk")
+        .map(|dirs| kind(&dirs).to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("."))
+}
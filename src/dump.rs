@@ -0,0 +1,273 @@
+//! Fetching a page without the interface, for `--dump` and `--dump-links`
+
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use url::Url;
+
+use crate::gemini::cache::{self, DiskCache};
+use crate::gemini::fetcher::{Fetcher, Network, Offline, Replay};
+use crate::gemini::{Client, Document, Response, Timeouts, TransactionError};
+
+pub const SUCCESS: i32 = 0;
+pub const ERROR: i32 = 1;
+pub const USAGE: i32 = 2;
+pub const DNS: i32 = 3;
+pub const CONNECTION: i32 = 4;
+pub const TIMEOUT: i32 = 5;
+pub const TEMPORARY_FAILURE: i32 = 6;
+pub const PERMANENT_FAILURE: i32 = 7;
+pub const REDIRECTS: i32 = 8;
+
+/// Exit codes with the name used for them on stderr and a description for the usage
+pub const EXIT_CODES: &[(i32, &str, &str)] = &[
+    (SUCCESS, "success", "success"),
+    (ERROR, "error", "any other error"),
+    (USAGE, "usage", "invalid arguments"),
+    (DNS, "dns", "the host could not be resolved"),
+    (
+        CONNECTION,
+        "connection",
+        "the connection or TLS handshake failed",
+    ),
+    (TIMEOUT, "timeout", "--timeout elapsed"),
+    (
+        TEMPORARY_FAILURE,
+        "temporary-failure",
+        "the server responded with 4x",
+    ),
+    (
+        PERMANENT_FAILURE,
+        "permanent-failure",
+        "the server responded with 5x",
+    ),
+    (REDIRECTS, "redirects", "too many redirects"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    /// The response body as it was received
+    Page,
+    /// Every link on the page as an absolute URL, one per line
+    Links,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub url: String,
+    pub output: Output,
+    /// Bounds the whole operation, including redirects
+    pub timeout: Option<Duration>,
+    /// Serve the page from the disk cache rather than the network
+    pub offline: bool,
+    /// Serve the page from a recording made with --record
+    pub replay: Option<PathBuf>,
+}
+
+/// Fetch the page and write it to stdout, returning the exit code
+///
+/// Errors are written to stderr as a single `diosk: <name>: <message>` line where the name is
+/// one of those in `EXIT_CODES`.
+pub fn run(options: &Options) -> i32 {
+    let url = match Url::parse(&options.url) {
+        Ok(url) => url,
+        Err(e) => return fail(USAGE, &format!("invalid URL {}: {}", options.url, e)),
+    };
+
+    let fetcher: Arc<dyn Fetcher> = match (&options.replay, options.offline) {
+        (Some(dir), _) => Arc::new(Replay::new(dir.clone())),
+        // Only read from, so the limits don't matter
+        (None, true) => Arc::new(Offline::new(DiskCache::new(
+            cache::default_dir(),
+            Duration::default(),
+            0,
+        ))),
+        (None, false) => Arc::new(Network),
+    };
+    let client = Client::new().fetcher(fetcher).timeouts(Timeouts {
+        page: options.timeout,
+        download: options.timeout,
+    });
+
+    let (tx, rx) = mpsc::channel();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let fetch = {
+        let cancelled = cancelled.clone();
+        thread::spawn(move || {
+            let mut progress = |_: &_, _| !cancelled.load(Ordering::SeqCst);
+            let _ = tx.send(client.fetch_with_progress(&url, &mut progress));
+        })
+    };
+
+    let result = match options.timeout {
+        Some(timeout) => match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(_) => {
+                // Waited for so a partial download is removed before the process exits, the
+                // fetch has the same deadline so it isn't long
+                cancelled.store(true, Ordering::SeqCst);
+                let _ = fetch.join();
+                let message = format!("no response within {}s", timeout.as_secs());
+                return fail(TIMEOUT, &message);
+            }
+        },
+        None => rx.recv().unwrap_or(Err(TransactionError::Crashed)),
+    };
+
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => return fail(exit_code(&e), &message(&e)),
+    };
+
+    match write(options.output, response) {
+        Ok(()) => SUCCESS,
+        // Nothing left to report to, e.g. piped into head
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => SUCCESS,
+        Err(e) => fail(ERROR, &format!("unable to write output: {}", e)),
+    }
+}
+
+fn write(output: Output, response: Response) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    match (output, response) {
+        (Output::Page, Response::Body { content, .. }) => {
+            stdout.write_all(content.unwrap_or_default().as_bytes())?;
+        }
+        (Output::Links, Response::Body { content, meta, .. }) => {
+            for link in links(&meta.url, content.as_deref().unwrap_or_default()) {
+                writeln!(stdout, "{}", link)?;
+            }
+        }
+        (Output::Page, Response::Download { file, .. }) => {
+            let copied = File::open(&file).and_then(|mut f| io::copy(&mut f, &mut stdout));
+            let _ = fs::remove_file(&file);
+            copied?;
+        }
+        // Not gemtext, there are no links
+        (Output::Links, Response::Download { file, .. }) => {
+            let _ = fs::remove_file(&file);
+        }
+    }
+
+    stdout.flush()
+}
+
+/// Every link on the page, relative links are resolved against the URL the page was served from
+fn links(base: &Url, content: &str) -> Vec<Url> {
+    Document::parse(base, content)
+        .links()
+        .map(|link| link.url)
+        .collect()
+}
+
+fn exit_code(e: &TransactionError) -> i32 {
+    match e {
+        TransactionError::InvalidDnsName(_)
+        | TransactionError::Resolve(..)
+        | TransactionError::NoAddress(_) => DNS,
+        TransactionError::IoError(e) | TransactionError::Connection(e)
+            if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) =>
+        {
+            TIMEOUT
+        }
+        TransactionError::IoError(_)
+        | TransactionError::Connection(_)
+        | TransactionError::HostMismatch { .. }
+        | TransactionError::CertificateRejected { .. }
+        | TransactionError::CertificateChanged(_)
+        | TransactionError::CertificateInvalid { .. } => CONNECTION,
+        TransactionError::Timeout { .. } => TIMEOUT,
+        TransactionError::GaveUp { error, .. } => exit_code(error),
+        TransactionError::TemporaryFailure(..) => TEMPORARY_FAILURE,
+        TransactionError::PermanentFailure(..) => PERMANENT_FAILURE,
+        TransactionError::RedirectLoop | TransactionError::InvalidRedirect(_) => REDIRECTS,
+        TransactionError::StatusCodeParseError(_)
+        | TransactionError::NoHost
+        | TransactionError::Cancelled
+        | TransactionError::NotCached(_)
+        | TransactionError::NotRecorded(_)
+        | TransactionError::Crashed => ERROR,
+    }
+}
+
+fn message(e: &TransactionError) -> String {
+    match e {
+        TransactionError::IoError(io) => format!("{}: {}", e, io),
+        e => e.to_string(),
+    }
+}
+
+/// Report an error on a single line of stderr
+fn fail(code: i32, message: &str) -> i32 {
+    let name = EXIT_CODES
+        .iter()
+        .find(|(c, _, _)| *c == code)
+        .map_or("error", |(_, name, _)| name);
+    let message = message.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let _ = writeln!(io::stderr(), "diosk: {}: {}", name, message);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        let io_error = |kind: ErrorKind| TransactionError::IoError(kind.into());
+
+        assert_eq!(exit_code(&TransactionError::NoAddress("a".into())), DNS);
+        assert_eq!(
+            exit_code(&io_error(ErrorKind::ConnectionRefused)),
+            CONNECTION
+        );
+        assert_eq!(exit_code(&io_error(ErrorKind::TimedOut)), TIMEOUT);
+        assert_eq!(
+            exit_code(&TransactionError::GaveUp {
+                attempts: 3,
+                error: Box::new(TransactionError::Connection(
+                    ErrorKind::ConnectionRefused.into()
+                )),
+            }),
+            CONNECTION
+        );
+        assert_eq!(
+            exit_code(&TransactionError::TemporaryFailure("44".into(), "".into())),
+            TEMPORARY_FAILURE
+        );
+        assert_eq!(
+            exit_code(&TransactionError::PermanentFailure("51".into(), "".into())),
+            PERMANENT_FAILURE
+        );
+        assert_eq!(exit_code(&TransactionError::RedirectLoop), REDIRECTS);
+        assert_eq!(exit_code(&TransactionError::NoHost), ERROR);
+    }
+
+    #[test]
+    fn links() {
+        let base = Url::parse("gemini://example.org/a/b").unwrap();
+        let content = "# Title\n=> c\n=> /d Name\n=> gemini://other.org/\n=>\n";
+
+        let links: Vec<String> = super::links(&base, content)
+            .iter()
+            .map(Url::to_string)
+            .collect();
+
+        assert_eq!(
+            links,
+            [
+                "gemini://example.org/a/c",
+                "gemini://example.org/d",
+                "gemini://other.org/"
+            ]
+        );
+    }
+}
@@ -1,27 +1,111 @@
 use log::info;
 use mime::Mime;
+use rustls::Session;
 use thiserror::Error;
 use url::Url;
 
+use crate::logging::redact;
+
 use std::io::prelude::*;
 use std::io::{self, BufReader, ErrorKind};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::time::Duration;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 
+pub mod cache;
+mod client;
+pub mod dns;
+mod document;
+pub mod fetcher;
 pub mod gemtext;
+pub mod gopher;
+pub mod recording;
 pub mod status_code;
-mod tls;
+pub(crate) mod temp_file;
+pub mod tls;
+
+pub use client::Client;
+pub use document::{Document, Link};
+pub use tls::{Trust, Verification};
 
 use status_code::StatusCode;
+use temp_file::TempFile;
 
 const PORT: u16 = 1965;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Response {
     Body {
         content: Option<String>,
+        /// The body as it was received, kept so it can be decoded again with another charset,
+        /// None when it wasn't kept e.g. for a page from the disk cache
+        raw: Option<Vec<u8>>,
         status_code: StatusCode,
+        meta: PageMeta,
     },
+    /// A response diosk can't display, streamed to a hidden file in the download directory that
+    /// should be renamed into place or removed
+    Download { file: PathBuf, meta: PageMeta },
+}
+
+impl Response {
+    pub fn meta(&self) -> &PageMeta {
+        match self {
+            Response::Body { meta, .. } | Response::Download { meta, .. } => meta,
+        }
+    }
+
+    fn meta_mut(&mut self) -> &mut PageMeta {
+        match self {
+            Response::Body { meta, .. } | Response::Download { meta, .. } => meta,
+        }
+    }
+}
+
+/// Details about a response that aren't needed to render it
+#[derive(Debug, Clone)]
+pub struct PageMeta {
+    /// The URL the content was served from, after following redirects
+    pub url: Url,
+    /// The response header without the trailing CRLF
+    pub header: String,
+    pub mime_type: Mime,
+    /// The size of the body in bytes, before decoding
+    pub size: usize,
+    pub duration: Duration,
+    pub tls_version: Option<String>,
+    /// SHA-256 fingerprint of the server's end-entity certificate
+    pub certificate_fingerprint: Option<String>,
+    /// The TLS session resumed an earlier one rather than making a full handshake
+    pub tls_resumed: bool,
+    /// When the response was fetched, if it was served from the disk cache
+    pub cached: Option<SystemTime>,
+    /// The charset the body was decoded with when the declared one turned it into garbage
+    pub detected_charset: Option<&'static str>,
+    /// How the certificate compared to the one pinned for the host when the page was fetched
+    pub trust: Option<Trust>,
+    /// The certificate isn't for the host that was requested
+    pub host_mismatch: bool,
+}
+
+impl PageMeta {
+    /// The lang parameter as sent, language tags separated by commas
+    pub fn lang(&self) -> Option<&str> {
+        self.mime_type
+            .get_param("lang")
+            .map(|lang| lang.as_str())
+            .filter(|lang| !lang.trim().is_empty())
+    }
+
+    /// The languages the content is in, in the order they're given
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.lang().into_iter().flat_map(languages)
+    }
+}
+
+/// The language tags in a lang parameter
+pub fn languages(lang: &str) -> impl Iterator<Item = &str> {
+    lang.split(',').map(str::trim).filter(|tag| !tag.is_empty())
 }
 
 #[derive(Error, Debug)]
@@ -30,6 +114,9 @@ pub enum TransactionError {
     InvalidDnsName(#[from] webpki::InvalidDNSNameError),
     #[error("IO error")]
     IoError(#[from] io::Error),
+    /// Connecting or sending the request failed, before any response was received
+    #[error("connection failed: {0}")]
+    Connection(io::Error),
     #[error("{0}")]
     StatusCodeParseError(#[from] status_code::ParseError),
     #[error("permanent failure: {0} {1}")]
@@ -38,49 +125,275 @@ pub enum TransactionError {
     TemporaryFailure(String, String),
     #[error("no host")]
     NoHost,
+    #[error("unable to resolve {0}: {1}")]
+    Resolve(String, io::Error),
+    #[error("no addresses found for {0}")]
+    NoAddress(String),
     #[error("redirect loop")]
     RedirectLoop,
+    #[error("invalid redirect: {0:?}")]
+    InvalidRedirect(String),
+    /// Only an error with strict_hostnames set, otherwise the page is loaded with a warning
+    #[error("the certificate isn't for {host}")]
+    HostMismatch { host: String },
+    /// A check the verify setting asks for failed, the request wasn't sent
+    #[error("{check} check failed: {reason}")]
+    CertificateRejected { check: &'static str, reason: String },
+    /// The certificate differs from the one pinned for the host and the user hasn't accepted it,
+    /// the request wasn't sent
+    #[error("the certificate of {} has changed", .0.host)]
+    CertificateChanged(Box<ChangedCertificate>),
+    /// The certificate is expired or not valid yet and the user hasn't accepted it, the request
+    /// wasn't sent
+    #[error("the certificate of {} isn't valid: {reason}", .certificate.host)]
+    CertificateInvalid {
+        certificate: Box<ChangedCertificate>,
+        reason: String,
+    },
+    #[error("timed out after {seconds}s, {received} bytes received")]
+    Timeout { seconds: u64, received: usize },
+    #[error("cancelled")]
+    Cancelled,
+    #[error("offline — {0} is not in the cache")]
+    NotCached(Url),
+    #[error("{0} is not in the recording")]
+    NotRecorded(Url),
+    #[error("{error}, gave up after {attempts} attempts")]
+    GaveUp {
+        attempts: usize,
+        error: Box<TransactionError>,
+    },
+    #[error("the request crashed, details are in the log")]
+    Crashed,
 }
 
-#[cfg(feature = "debug_content")]
-pub fn transaction(_url: &Url) -> Result<Response, TransactionError> {
-    Ok(Response::Body {
-        content: Some("Foo.\nBar.\nBaz.".to_string()),
-        status_code: StatusCode::parse(&"20 text/gemini\r\n").unwrap(),
-    })
+/// A certificate the user has to accept before a URL is loaded, it differs from the one pinned for
+/// its host or isn't valid
+#[derive(Debug, Clone)]
+pub struct ChangedCertificate {
+    pub url: Url,
+    pub host: String,
+    pub port: u16,
+    pub fingerprint: String,
 }
 
-#[cfg(not(feature = "debug_content"))]
-pub fn transaction(url: &Url) -> Result<Response, TransactionError> {
-    transaction_inner(url, 0)
+impl TransactionError {
+    /// Whether trying again might work, e.g. the server is restarting or the network dropped out
+    ///
+    /// Only failures before a response was received are worth it, a request is only safe to
+    /// make again when nothing has come of it.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TransactionError::Connection(e) => matches!(
+                e.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::WouldBlock
+            ),
+            _ => false,
+        }
+    }
 }
 
-fn transaction_inner(url: &Url, redirect_count: usize) -> Result<Response, TransactionError> {
+/// Called with the MIME type and number of bytes received so far before each chunk of a response
+/// body is read, returning false cancels the transaction
+pub type Progress<'a> = dyn FnMut(&Mime, usize) -> bool + 'a;
+
+/// The size of each read from the response body
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// How long a whole transaction, redirects included, can take before it's given up on
+///
+/// Without these a server sending a byte every so often could keep a load going forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+    /// For pages, None never gives up
+    pub page: Option<Duration>,
+    /// For responses saved as downloads, which can take far longer
+    pub download: Option<Duration>,
+}
+
+/// The point a transaction is given up on
+struct Deadline {
+    at: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    fn new(started: Instant, timeout: Option<Duration>) -> Option<Self> {
+        timeout.map(|timeout| Self {
+            at: started + timeout,
+            timeout,
+        })
+    }
+
+    fn passed(&self) -> bool {
+        Instant::now() >= self.at
+    }
+
+    fn expired(&self, received: usize) -> TransactionError {
+        info!("timed out after {} bytes", received);
+        TransactionError::Timeout {
+            seconds: self.timeout.as_secs(),
+            received,
+        }
+    }
+
+    /// Fail once the deadline has passed, otherwise have the socket wait no longer than the time
+    /// left for its next read
+    fn check(&self, socket: &TcpStream, received: usize) -> Result<(), TransactionError> {
+        let remaining = self.at.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(self.expired(received));
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        Ok(())
+    }
+}
+
+/// Request a URL, responses that can't be displayed are streamed into the download directory
+pub fn transaction(
+    url: &Url,
+    download_dir: &Path,
+    timeouts: &Timeouts,
+    progress: &mut Progress,
+) -> Result<Response, TransactionError> {
+    let start = Instant::now();
+
+    let mut response = match url.scheme() {
+        "gopher" => gopher::transaction(url, download_dir, timeouts, start, progress)?,
+        _ => transaction_inner(url, download_dir, timeouts, start, 0, progress)?,
+    };
+    response.meta_mut().duration = start.elapsed();
+
+    Ok(response)
+}
+
+fn transaction_inner(
+    url: &Url,
+    download_dir: &Path,
+    timeouts: &Timeouts,
+    start: Instant,
+    redirect_count: usize,
+    progress: &mut Progress,
+) -> Result<Response, TransactionError> {
     let host = url.host_str().ok_or(TransactionError::NoHost)?;
 
-    let mut tls_client = tls::client(&host)?;
+    // Redirects count towards the same deadline
+    let deadline = Deadline::new(start, timeouts.page);
+    if let Some(deadline) = deadline.as_ref().filter(|d| d.passed()) {
+        return Err(deadline.expired(0));
+    }
+
+    let (mut tls_client, handshake) = tls::client(host)?;
 
-    info!("resolving domain");
-    let addrs: Vec<_> = format!("{}:{}", &host, &PORT)
-        .to_socket_addrs()
-        .expect("unable to resolve domain")
-        .collect();
-    let addr = addrs.first().expect("no domain");
+    let port = url.port().unwrap_or(PORT);
 
     // C: Opens connection
     // S: Accepts connection
     // C/S: Complete TLS handshake (see section 4)
     // C: Validates server certificate (see 4.2)
-    info!("opening socket: {}:{}", &host, &PORT);
-    let mut socket = TcpStream::connect_timeout(&addr, Duration::from_secs(4))?;
+    let mut socket = connect(host, port)?;
+
+    // Reads through the TLS stream borrow the socket, a handle to it is kept for the timeouts
+    let watched = socket.try_clone()?;
+    let watch = |deadline: &Option<Deadline>| match deadline {
+        Some(deadline) => deadline.check(&watched, 0),
+        None => Ok(watched.set_read_timeout(None)?),
+    };
+    // Failing before the response is down to the deadline if it has passed
+    let failed = |e| match &deadline {
+        Some(deadline) if deadline.passed() => deadline.expired(0),
+        _ => TransactionError::Connection(e),
+    };
+    watch(&deadline)?;
+
+    // Done up front rather than with the request so it can be timed
+    let handshake_start = Instant::now();
+    tls_client.complete_io(&mut socket).map_err(failed)?;
+    let tls_resumed = handshake.resumed();
+    info!(
+        "TLS handshake with {} took {} ms ({})",
+        host,
+        handshake_start.elapsed().as_millis(),
+        if tls_resumed { "resumed" } else { "full" }
+    );
+
+    // Checked before the request is sent so a strict check doesn't send it to the wrong host
+    let certificates = tls_client.get_peer_certificates().unwrap_or_default();
+    let host_mismatch = certificates
+        .first()
+        .is_some_and(|certificate| !tls::covers(certificate, host));
+    if host_mismatch && tls::strict_hostnames() {
+        return Err(TransactionError::HostMismatch {
+            host: host.to_string(),
+        });
+    }
+    let ca_signed = match tls::verification(host) {
+        Verification::Tofu => false,
+        Verification::Webpki => {
+            tls::verify_webpki(&certificates, host).map_err(|reason| {
+                TransactionError::CertificateRejected {
+                    check: "web PKI",
+                    reason,
+                }
+            })?;
+            true
+        }
+        // Self-signed certificates fall back to being pinned
+        Verification::TofuWebpki => tls::verify_webpki(&certificates, host).is_ok(),
+    };
+    let invalid = certificates
+        .first()
+        .and_then(|certificate| tls::verify_validity(certificate, SystemTime::now()).err());
+    if let Some(reason) = &invalid {
+        if tls::strict_hostnames() || tls::verification(host) == Verification::Webpki {
+            return Err(TransactionError::CertificateRejected {
+                check: "validity",
+                reason: reason.clone(),
+            });
+        }
+    }
+
+    // A certificate that differs from its pin or isn't valid stops the load until the user
+    // accepts it
+    let certificate_fingerprint = certificates.first().map(tls::fingerprint);
+    let trust = match (ca_signed, &certificate_fingerprint) {
+        (true, _) => Some(Trust::Verified),
+        (false, Some(fingerprint)) => {
+            let changed = || {
+                Box::new(ChangedCertificate {
+                    url: request_url(url),
+                    host: host.to_string(),
+                    port,
+                    fingerprint: fingerprint.clone(),
+                })
+            };
+            let trust = match invalid {
+                Some(reason) => tls::trust_invalid(host, port, fingerprint).ok_or_else(|| {
+                    TransactionError::CertificateInvalid {
+                        certificate: changed(),
+                        reason,
+                    }
+                })?,
+                None => tls::trust(host, port, fingerprint)
+                    .ok_or_else(|| TransactionError::CertificateChanged(changed()))?,
+            };
+            Some(trust)
+        }
+        (false, None) => None,
+    };
 
     info!("opening stream");
     let mut stream = rustls::Stream::new(&mut tls_client, &mut socket);
 
     // C: Sends request (one CRLF terminated line) (see section 2)
-    let request = format!("{}\r\n", url);
-    info!("sending request: {}", url);
-    stream.write_all(request.as_bytes())?;
+    info!("sending request: {}", redact(url));
+    watch(&deadline)?;
+    write_request(&mut stream, url).map_err(failed)?;
 
     // S: Sends response header (one CRLF terminated line), closes connection under non-success
     //      conditions (see 3.1 and 3.2)
@@ -88,47 +401,71 @@ fn transaction_inner(url: &Url, redirect_count: usize) -> Result<Response, Trans
 
     // Read the header
     let mut header = String::new();
-    reader.read_line(&mut header)?;
+    reader.read_line(&mut header).map_err(failed)?;
     let status_code = StatusCode::parse(&header)?;
+    // Anything but a success has no body
+    if !matches!(status_code, StatusCode::Success { .. }) {
+        recording::record(url, &header, &[]);
+    }
+
+    let session = &reader.get_ref().sess;
+    let tls_version = session.get_protocol_version().map(|v| format!("{:?}", v));
 
     // S: Sends response body (text or binary data) (see 3.3)
     // S: Closes connection
     match status_code.clone() {
         StatusCode::Success { code: _, mime_type } => {
-            let mut body = Vec::new();
-            match reader.read_to_end(&mut body) {
-                Ok(_len) => {}
-                Err(e) => {
-                    match e.kind() {
-                        ErrorKind::ConnectionAborted => {
-                            // This is expected and should be treated as EOF
-                        }
-                        _ => panic!("{:?}", e),
-                    }
-                }
-            }
-
             let mime_type =
                 mime_type.unwrap_or_else(|| "text/gemini".parse::<Mime>().expect("infallible"));
             let charset = mime_type.get_param("charset").unwrap_or(mime::UTF_8);
 
+            let mut meta = PageMeta {
+                url: request_url(url),
+                header: header.trim_end().to_string(),
+                mime_type: mime_type.clone(),
+                size: 0,
+                duration: Duration::default(),
+                tls_version,
+                certificate_fingerprint,
+                tls_resumed,
+                cached: None,
+                detected_charset: None,
+                trust,
+                host_mismatch,
+            };
+
             // C: Handles response (see 3.4)
             match (mime_type.type_(), mime_type.subtype()) {
-                (mime::TEXT, name) => match name.as_str() {
-                    "gemini" => {
-                        let body = encoding::label::encoding_from_whatwg_label(charset.as_str())
-                            .expect("unable to find decoder")
-                            .decode(&body, encoding::types::DecoderTrap::Replace)
-                            .expect("unable to decode");
-
-                        Ok(Response::Body {
-                            content: Some(body),
-                            status_code,
-                        })
-                    }
-                    _ => todo!("unsupported mime type: {}", mime_type),
-                },
-                _ => todo!("unsupported mime type: {}", mime_type),
+                (mime::TEXT, name) if name == "gemini" => {
+                    let mut body = Vec::new();
+                    let deadline = deadline.as_ref().map(|d| (d, &watched));
+                    meta.size = read_body(&mut reader, &mut body, &mime_type, progress, deadline)?;
+                    recording::record(url, &header, &body);
+
+                    let (decoded, detected) = decode_detecting(url, &body, charset.as_str());
+                    meta.detected_charset = detected;
+
+                    Ok(Response::Body {
+                        meta,
+                        content: Some(decoded),
+                        raw: Some(body),
+                        status_code,
+                    })
+                }
+                _ => {
+                    // Stream straight to disk so memory use doesn't grow with the file size
+                    let mut file = TempFile::create(download_dir)?;
+                    // Downloads have a deadline of their own, if any
+                    let deadline = Deadline::new(start, timeouts.download);
+                    watch(&deadline)?;
+                    let deadline = deadline.as_ref().map(|d| (d, &watched));
+                    meta.size = read_body(&mut reader, &mut file, &mime_type, progress, deadline)?;
+
+                    let file = file.keep()?;
+                    recording::record_file(url, &header, &file);
+
+                    Ok(Response::Download { meta, file })
+                }
             }
         }
         StatusCode::TemporaryFailure { code, meta } => {
@@ -148,23 +485,601 @@ fn transaction_inner(url: &Url, redirect_count: usize) -> Result<Response, Trans
                 return Err(TransactionError::RedirectLoop);
             }
 
-            let url = qualify_url(Some(url), &redirect_url.unwrap());
-            transaction_inner(&url, redirect_count + 1)
+            let redirect_url = redirect_url.unwrap_or_default();
+            let url = qualify_url(Some(url), &redirect_url)
+                .map_err(|_| TransactionError::InvalidRedirect(redirect_url))?;
+            transaction_inner(
+                &url,
+                download_dir,
+                timeouts,
+                start,
+                redirect_count + 1,
+                progress,
+            )
+        }
+    }
+}
+
+/// The URL as it's sent to the server, fragments are only for the client so the requester keeps
+/// hold of it
+fn request_url(url: &Url) -> Url {
+    let mut url = with_path(url.clone());
+    url.set_fragment(None);
+    url
+}
+
+/// An empty path, as in gemini://example.org, becomes "/" as some servers reject the request
+/// otherwise and relative links resolve against it oddly
+fn with_path(mut url: Url) -> Url {
+    if url.path().is_empty() && url.has_host() {
+        url.set_path("/");
+    }
+    url
+}
+
+/// Decode a body from the URL in the charset, ready to be parsed as gemtext, gopher menus and
+/// text files are turned into it
+pub fn decode_body(url: &Url, body: &[u8], charset: &str) -> String {
+    as_gemtext(url, &decode(body, charset))
+}
+
+/// Decode a body like decode_body, unless the charset turns much of it into replacement
+/// characters and a likelier one can be found, returning the charset that was detected if so
+pub fn decode_detecting(url: &Url, body: &[u8], charset: &str) -> (String, Option<&'static str>) {
+    let decoded = decode(body, charset);
+    let replaced = decoded
+        .chars()
+        .filter(|&c| c == REPLACEMENT_CHARACTER)
+        .count();
+    if replaced * 100 <= decoded.chars().count() * MAX_REPLACED_PERCENT {
+        return (as_gemtext(url, &decoded), None);
+    }
+
+    let declared = implausibility(&decoded);
+    let detected = LIKELY_CHARSETS
+        .iter()
+        .map(|&charset| {
+            let text = decode(body, charset);
+            (implausibility(&text), charset, text)
+        })
+        .min_by_key(|(score, _, _)| *score)
+        .filter(|(score, _, _)| *score < declared);
+
+    match detected {
+        Some((_, charset, text)) => {
+            info!(
+                "decoding as {}, it looks likelier than the declared charset",
+                charset
+            );
+            (as_gemtext(url, &text), Some(charset))
+        }
+        None => (as_gemtext(url, &decoded), None),
+    }
+}
+
+/// Decoded text ready to be parsed as gemtext, gopher menus and text files are turned into it
+fn as_gemtext(url: &Url, text: &str) -> String {
+    let text = normalize(text);
+    match url.scheme() {
+        "gopher" => gopher::gemtext(url, &text),
+        _ => text,
+    }
+}
+
+const REPLACEMENT_CHARACTER: char = '\u{fffd}';
+
+/// Up to this percentage of characters can fail to decode before another charset is tried, a
+/// stray bad byte isn't worth second guessing the server over
+const MAX_REPLACED_PERCENT: usize = 1;
+
+/// The charsets tried when the declared one gives garbage, iso-8859-1 is read as windows-1252
+/// like browsers do so it's covered by it
+const LIKELY_CHARSETS: &[&str] = &["windows-1252", "shift_jis"];
+
+/// How unlikely it is that text was decoded with the right charset, the lower the better
+///
+/// Replacement and control characters count the most, then the signs of reading one charset as
+/// another: half-width katakana, which Latin text read as Shift_JIS is full of, and runs of
+/// accented letters and symbols, which Japanese read as windows-1252 is.
+fn implausibility(text: &str) -> usize {
+    let latin =
+        |c| matches!(c, '\u{80}'..='\u{2ff}' | '\u{2000}'..='\u{20cf}' | '\u{2100}'..='\u{214f}');
+
+    let mut score = 0;
+    let mut previous = ' ';
+    for c in text.chars() {
+        score += match c {
+            REPLACEMENT_CHARACTER => 100,
+            '\t' | '\n' | '\r' => 0,
+            c if c.is_control() => 20,
+            '\u{ff61}'..='\u{ff9f}' => 5,
+            c if latin(c) && latin(previous) => 2,
+            _ => 0,
+        };
+        previous = c;
+    }
+    score
+}
+
+/// The name of the charset a WHATWG label refers to, e.g. windows-1252 for latin1
+pub fn charset_name(label: &str) -> Option<&'static str> {
+    let encoding = encoding::label::encoding_from_whatwg_label(label)?;
+    Some(encoding.whatwg_name().unwrap_or_else(|| encoding.name()))
+}
+
+/// Decode a body in the charset, anything that can't be decoded is replaced
+///
+/// An unknown charset is read as UTF-8 rather than failing the whole page.
+fn decode(body: &[u8], charset: &str) -> String {
+    let encoding = encoding::label::encoding_from_whatwg_label(charset).unwrap_or_else(|| {
+        info!("unknown charset {}, decoding as UTF-8", charset);
+        encoding::all::UTF_8
+    });
+
+    encoding
+        .decode(body, encoding::types::DecoderTrap::Replace)
+        .unwrap_or_else(|_| String::from_utf8_lossy(body).into_owned())
+}
+
+/// Strip a leading byte order mark and end every line with a plain LF
+///
+/// Carriage returns ending a line are dropped, any others on their own end one.
+fn normalize(text: &str) -> String {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+
+    let mut normalized = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            normalized.push('\n');
+        }
+        normalized.push_str(&line.trim_end_matches('\r').replace('\r', "\n"));
+    }
+    normalized
+}
+
+fn write_request<W: Write>(writer: &mut W, url: &Url) -> io::Result<()> {
+    write!(writer, "{}\r\n", request_url(url))
+}
+
+/// Copy the response body to the writer in chunks, reporting progress between each one
+///
+/// Reads from the socket stop once the deadline passes, however slowly the body arrives. Returns
+/// the number of bytes read.
+fn read_body<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    mime_type: &Mime,
+    progress: &mut Progress,
+    deadline: Option<(&Deadline, &TcpStream)>,
+) -> Result<usize, TransactionError> {
+    let mut received = 0;
+    let mut chunk = vec![0; CHUNK_SIZE];
+
+    loop {
+        if !progress(mime_type, received) {
+            info!("cancelled after {} bytes", received);
+            return Err(TransactionError::Cancelled);
+        }
+
+        if let Some((deadline, socket)) = deadline {
+            deadline.check(socket, received)?;
+        }
+
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => {
+                writer.write_all(&chunk[..len])?;
+                received += len;
+            }
+            // A signal arrived before anything was read
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // The socket stopped waiting, the deadline is checked before trying again
+            Err(e)
+                if deadline.is_some()
+                    && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                continue
+            }
+            // This is expected and should be treated as EOF
+            Err(e) if e.kind() == ErrorKind::ConnectionAborted => break,
+            // Gemini has no content length so a body can't be told apart from a truncated one,
+            // plenty of servers close without a TLS close_notify so it's kept like any other
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                info!("connection closed uncleanly after {} bytes", received);
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(received)
+}
+
+fn connect(host: &str, port: u16) -> Result<TcpStream, TransactionError> {
+    let resolve = || -> Result<_, TransactionError> {
+        let (addrs, cached) =
+            dns::resolve(host, port).map_err(|e| TransactionError::Resolve(host.to_string(), e))?;
+        let addr = *addrs
+            .first()
+            .ok_or_else(|| TransactionError::NoAddress(host.to_string()))?;
+        Ok((addr, cached))
+    };
+
+    let (addr, cached) = resolve()?;
+    info!("opening socket: {} ({})", addr, host);
+    match TcpStream::connect_timeout(&addr, Duration::from_secs(4)) {
+        Ok(socket) => Ok(socket),
+        Err(e) if cached => {
+            // The cached address might be stale, resolve the host again and have one more go
+            info!("unable to connect to cached address: {}", e);
+            dns::invalidate(host, port);
+            let (addr, _) = resolve()?;
+            TcpStream::connect_timeout(&addr, Duration::from_secs(4))
+                .map_err(TransactionError::Connection)
+        }
+        Err(e) => Err(TransactionError::Connection(e)),
+    }
+}
+
+/// A canonical form of the URL used for comparisons, the fragment and default port are dropped
+/// and an empty path becomes "/"
+pub fn normalize_url(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    if let Some(host) = url.host_str().map(str::to_lowercase) {
+        let _ = url.set_host(Some(&host));
+    }
+    match (url.scheme(), url.port()) {
+        ("gemini", Some(PORT)) | ("gopher", Some(gopher::PORT)) => {
+            let _ = url.set_port(None);
         }
+        _ => {}
+    }
+    if url.path().is_empty() {
+        url.set_path("/");
+    }
+    url.to_string()
+}
+
+/// The URL of the parent directory, e.g. /a/b/c.gmi → /a/b/ → /a/ → /
+///
+/// Returns None when already at the root.
+pub fn parent_url(url: &Url) -> Option<Url> {
+    let path = url.path().trim_end_matches('/');
+    if path.is_empty() {
+        return None;
+    }
+
+    let parent = &path[..=path.rfind('/').expect("path starts with /")];
+
+    let mut url = url.clone();
+    url.set_path(parent);
+    url.set_query(None);
+    url.set_fragment(None);
+    Some(url)
+}
+
+/// The URL of the root of the capsule, None when already there
+pub fn root_url(url: &Url) -> Option<Url> {
+    if matches!(url.path(), "" | "/") && url.query().is_none() {
+        return None;
     }
+
+    let mut url = url.clone();
+    url.set_path("/");
+    url.set_query(None);
+    url.set_fragment(None);
+    Some(url)
 }
 
-pub fn qualify_url(current_url: Option<&Url>, url_or_path: &str) -> Url {
-    match Url::parse(&url_or_path) {
-        Ok(url) => url,
-        Err(url::ParseError::RelativeUrlWithoutBase) => {
-            let mut url = current_url.unwrap().clone();
+/// Resolve a URL or a path relative to the current URL, a path is an error without one
+pub fn qualify_url(current_url: Option<&Url>, url_or_path: &str) -> Result<Url, url::ParseError> {
+    match (Url::parse(url_or_path), current_url) {
+        (Ok(url), _) => Ok(with_path(url)),
+        (Err(url::ParseError::RelativeUrlWithoutBase), Some(current_url)) => {
+            let mut url = current_url.clone();
             // If we don't have a URL base, we clear the query/fragment and join
-            // on the requested path.
-            url.set_query(None);
+            // on the requested path. A link to a fragment stays on the same page, query and all.
+            if !url_or_path.starts_with('#') {
+                url.set_query(None);
+            }
             url.set_fragment(None);
-            url.join(&url_or_path).unwrap()
+            url.join(url_or_path)
+        }
+        (Err(e), _) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parent_url() {
+        let parent =
+            |url: &str| super::parent_url(&Url::parse(url).unwrap()).map(|u| u.to_string());
+
+        assert_eq!(
+            parent("gemini://example.org/a/b/c.gmi?q#f").as_deref(),
+            Some("gemini://example.org/a/b/")
+        );
+        assert_eq!(
+            parent("gemini://example.org/a/b/").as_deref(),
+            Some("gemini://example.org/a/")
+        );
+        assert_eq!(
+            parent("gemini://example.org/a/").as_deref(),
+            Some("gemini://example.org/")
+        );
+        assert_eq!(parent("gemini://example.org/"), None);
+        assert_eq!(parent("gemini://example.org"), None);
+    }
+
+    #[test]
+    fn root_url() {
+        let root = |url: &str| super::root_url(&Url::parse(url).unwrap()).map(|u| u.to_string());
+
+        assert_eq!(
+            root("gemini://example.org/a/b/c.gmi").as_deref(),
+            Some("gemini://example.org/")
+        );
+        assert_eq!(
+            root("gemini://example.org/?q").as_deref(),
+            Some("gemini://example.org/")
+        );
+        assert_eq!(root("gemini://example.org/"), None);
+    }
+
+    /// Reads a body in chunks, failing with each error once before the chunk it's given for
+    struct FlakyReader {
+        chunks: Vec<(Option<ErrorKind>, &'static [u8])>,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let (error, chunk) = match self.chunks.first_mut() {
+                Some(next) => next,
+                None => return Ok(0),
+            };
+            if let Some(kind) = error.take() {
+                return Err(kind.into());
+            }
+
+            let len = chunk.len();
+            buf[..len].copy_from_slice(chunk);
+            self.chunks.remove(0);
+            Ok(len)
         }
-        e => panic!("{:?}", e),
+    }
+
+    #[test]
+    fn read_body_errors() {
+        let read = |chunks| {
+            let mut body = Vec::new();
+            let mime_type = mime::TEXT_PLAIN;
+            super::read_body(
+                &mut FlakyReader { chunks },
+                &mut body,
+                &mime_type,
+                &mut |_, _| true,
+                None,
+            )
+            .map(|_| body)
+        };
+
+        // Retried
+        let interrupted = read(vec![(None, b"ab"), (Some(ErrorKind::Interrupted), b"cd")]);
+        assert_eq!(interrupted.unwrap(), b"abcd");
+
+        // Both end the body wherever they happen
+        for kind in &[ErrorKind::ConnectionAborted, ErrorKind::UnexpectedEof] {
+            assert_eq!(read(vec![(Some(*kind), b"ab")]).unwrap(), b"");
+            assert_eq!(
+                read(vec![(None, b"ab"), (Some(*kind), b"cd")]).unwrap(),
+                b"ab"
+            );
+        }
+
+        for kind in &[
+            ErrorKind::ConnectionReset,
+            ErrorKind::TimedOut,
+            ErrorKind::WouldBlock,
+            ErrorKind::Other,
+        ] {
+            let failed =
+                |result| matches!(result, Err(TransactionError::IoError(e)) if e.kind() == *kind);
+            assert!(failed(read(vec![(Some(*kind), b"ab")])));
+            assert!(failed(read(vec![(None, b"ab"), (Some(*kind), b"cd")])));
+        }
+    }
+
+    #[test]
+    fn read_body_deadline() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        // A server that sends a little then goes quiet without closing the connection
+        server.write_all(b"abc").unwrap();
+        let deadline = Deadline::new(Instant::now(), Some(Duration::from_millis(200))).unwrap();
+        let socket = client.try_clone().unwrap();
+
+        let mut body = Vec::new();
+        let result = super::read_body(
+            &mut client,
+            &mut body,
+            &mime::TEXT_PLAIN,
+            &mut |_, _| true,
+            Some((&deadline, &socket)),
+        );
+        assert!(matches!(
+            result,
+            Err(TransactionError::Timeout {
+                seconds: 0,
+                received: 3
+            })
+        ));
+        assert_eq!(body, b"abc");
+        assert!(deadline.passed());
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(super::decode(b"caf\xe9", "iso-8859-1"), "café");
+        assert_eq!(super::decode("café".as_bytes(), "no-such-charset"), "café");
+        assert_eq!(super::decode(b"caf\xe9", "utf-8"), "caf\u{fffd}");
+    }
+
+    #[test]
+    fn decode_detecting() {
+        use encoding::Encoding;
+
+        let url = Url::parse("gemini://example.org/").unwrap();
+        let decode = |body: &[u8]| super::decode_detecting(&url, body, "utf-8");
+
+        assert_eq!(
+            decode(b"Cr\xe8me br\xfbl\xe9e\n"),
+            ("Crème brûlée\n".to_string(), Some("windows-1252"))
+        );
+
+        let japanese = "日本語のテキストです。\n";
+        let shift_jis = encoding::all::WINDOWS_31J
+            .encode(japanese, encoding::types::EncoderTrap::Strict)
+            .unwrap();
+        assert_eq!(
+            decode(&shift_jis),
+            (japanese.to_string(), Some("shift_jis"))
+        );
+
+        assert_eq!(decode("Crème brûlée\n".as_bytes()).1, None);
+
+        // A stray bad byte in a long page is left alone
+        let mut body = "a".repeat(200).into_bytes();
+        body.push(0xe9);
+        assert_eq!(decode(&body).1, None);
+    }
+
+    #[test]
+    fn charset_name() {
+        assert_eq!(super::charset_name("latin1"), Some("windows-1252"));
+        assert_eq!(super::charset_name("UTF8"), Some("utf-8"));
+        assert_eq!(super::charset_name("no-such-charset"), None);
+    }
+
+    #[test]
+    fn languages() {
+        let languages = |mime_type: &str| {
+            let meta = PageMeta {
+                url: Url::parse("gemini://example.org/").unwrap(),
+                header: format!("20 {}", mime_type),
+                mime_type: mime_type.parse().unwrap(),
+                size: 0,
+                duration: Duration::default(),
+                tls_version: None,
+                certificate_fingerprint: None,
+                tls_resumed: false,
+                cached: None,
+                detected_charset: None,
+                trust: None,
+                host_mismatch: false,
+            };
+            meta.languages().map(String::from).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            languages("text/gemini; lang=\"de, fr,en-GB\""),
+            ["de", "fr", "en-GB"]
+        );
+        assert_eq!(languages("text/gemini; charset=utf-8; lang=ar"), ["ar"]);
+        assert!(languages("text/gemini; lang=\", \"").is_empty());
+        assert!(languages("text/gemini").is_empty());
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(
+            super::normalize("\u{feff}# Hi\r\n\r\nText\r\r\n"),
+            "# Hi\n\nText\n"
+        );
+        assert_eq!(super::normalize("a\rb\n\u{feff}c"), "a\nb\n\u{feff}c");
+
+        let base = Url::parse("gemini://example.org/dir/").unwrap();
+        let body = super::normalize("=> page.gmi A page\r\n=> /other.gmi\r\n");
+        let links: Vec<Url> = body
+            .lines()
+            .map(|line| match gemtext::Line::parse(line) {
+                gemtext::Line::Link { url, .. } => base.join(&url).unwrap(),
+                line => panic!("not a link: {:?}", line),
+            })
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                Url::parse("gemini://example.org/dir/page.gmi").unwrap(),
+                Url::parse("gemini://example.org/other.gmi").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn write_request() {
+        let request = |url: &str| {
+            let mut bytes = Vec::new();
+            super::write_request(&mut bytes, &Url::parse(url).unwrap()).unwrap();
+            bytes
+        };
+
+        assert!(request("gemini://example.org").ends_with(b"gemini://example.org/\r\n"));
+        assert!(request("gemini://example.org/#top").ends_with(b"gemini://example.org/\r\n"));
+        assert_eq!(
+            request("gemini://example.org/a?q"),
+            b"gemini://example.org/a?q\r\n"
+        );
+    }
+
+    #[test]
+    fn qualify_url() {
+        let qualify = |url: &str| super::qualify_url(None, url).unwrap().to_string();
+
+        assert_eq!(qualify("gemini://example.org"), "gemini://example.org/");
+        assert_eq!(
+            super::qualify_url(Some(&Url::parse("gemini://example.org").unwrap()), "a.gmi")
+                .unwrap()
+                .to_string(),
+            "gemini://example.org/a.gmi"
+        );
+        assert_eq!(qualify("about:cache"), "about:cache");
+
+        // Errors rather than panics
+        assert!(super::qualify_url(None, "a.gmi").is_err());
+        assert!(super::qualify_url(None, "gemini://[example.org").is_err());
+    }
+
+    #[test]
+    fn read_body() {
+        let mime_type: Mime = "application/zip".parse().unwrap();
+        let data = vec![7; CHUNK_SIZE * 2 + 10];
+
+        let mut body = Vec::new();
+        let mut reported = Vec::new();
+        let mut progress = |_: &Mime, n| {
+            reported.push(n);
+            true
+        };
+        let received =
+            super::read_body(&mut &data[..], &mut body, &mime_type, &mut progress, None).unwrap();
+        assert_eq!(received, data.len());
+        assert_eq!(body, data);
+        assert_eq!(reported, vec![0, CHUNK_SIZE, CHUNK_SIZE * 2, data.len()]);
+
+        let mut progress = |_: &Mime, n| n == 0;
+        let cancelled = super::read_body(
+            &mut &data[..],
+            &mut Vec::new(),
+            &mime_type,
+            &mut progress,
+            None,
+        );
+        assert!(matches!(cancelled, Err(TransactionError::Cancelled)));
     }
 }
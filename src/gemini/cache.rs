@@ -0,0 +1,301 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use mime::Mime;
+use url::Url;
+
+use super::status_code::StatusCode;
+use super::temp_file::TempFile;
+use super::{normalize_url, PageMeta, Response};
+use crate::dirs;
+
+const EXTENSION: &str = "entry";
+
+/// Successful gemtext responses kept on disk between sessions, one file per normalized URL
+///
+/// Entries older than the max age aren't served but are kept until pruned, the least recently
+/// used are removed once the cache grows past its size limit.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_age: Duration,
+    max_bytes: u64,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, max_age: Duration, max_bytes: u64) -> Self {
+        Self {
+            dir,
+            max_age,
+            max_bytes,
+        }
+    }
+
+    /// A page cached within the max age, along with when it was fetched
+    pub fn get(&self, url: &Url) -> Option<(Response, SystemTime)> {
+        self.lookup(url)
+            .filter(|(_, fetched)| fetched.elapsed().is_ok_and(|age| age <= self.max_age))
+    }
+
+    /// A page cached at any age, along with when it was fetched
+    pub fn lookup(&self, url: &Url) -> Option<(Response, SystemTime)> {
+        let key = normalize_url(url);
+        let path = self.path(&key);
+        let entry = fs::read_to_string(&path).ok()?;
+
+        let cached = parse_entry(&key, &entry);
+        match &cached {
+            // Reading an entry makes it the most recently used
+            Some(_) => {
+                let _ = File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|f| f.set_modified(SystemTime::now()));
+            }
+            None => warn!("ignoring invalid cache entry {}", path.display()),
+        }
+        cached
+    }
+
+    /// Cache a response to a request for the URL, only successful gemtext responses are kept
+    ///
+    /// A response that was itself served from the cache is left as it is.
+    pub fn insert(&self, url: &Url, response: &Response) -> io::Result<()> {
+        if response.meta().cached.is_some() {
+            return Ok(());
+        }
+
+        let (meta, content) = match response {
+            Response::Body {
+                meta,
+                content: Some(content),
+                status_code: StatusCode::Success { .. },
+                ..
+            } => (meta, content),
+            _ => return Ok(()),
+        };
+
+        let key = normalize_url(url);
+        let fetched = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        // Written next to the entry and renamed into place so a reader never sees half of it
+        let mut file = TempFile::create(&self.dir)?;
+        write!(
+            file,
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            fetched,
+            key,
+            meta.url,
+            meta.header,
+            meta.mime_type,
+            meta.tls_version.as_deref().unwrap_or_default(),
+            meta.certificate_fingerprint.as_deref().unwrap_or_default(),
+            content
+        )?;
+        fs::rename(file.keep()?, self.path(&key))?;
+
+        self.prune()
+    }
+
+    /// Remove every entry, returning how many there were
+    pub fn clear(&self) -> io::Result<usize> {
+        let entries = self.entries()?;
+        for (path, _, _) in &entries {
+            fs::remove_file(path)?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Remove the least recently used entries until the cache fits in its size limit
+    fn prune(&self) -> io::Result<()> {
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|(_, _, used)| *used);
+
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        for (path, size, _) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(path)?;
+            total -= size;
+        }
+        Ok(())
+    }
+
+    /// Every entry with its size and when it was last used
+    fn entries(&self) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = Vec::new();
+        for entry in dir {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let metadata = fs::metadata(&path)?;
+            entries.push((path, metadata.len(), metadata.modified()?));
+        }
+        Ok(entries)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.{}", hash(key), EXTENSION))
+    }
+}
+
+/// Where the cache lives, the platform's cache directory
+pub fn default_dir() -> PathBuf {
+    dirs::cache_dir()
+}
+
+fn parse_entry(key: &str, entry: &str) -> Option<(Response, SystemTime)> {
+    let mut fields = entry.splitn(8, '\n');
+    let fetched = UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+    // Another URL with the same hash
+    if fields.next()? != key {
+        return None;
+    }
+    let url = Url::parse(fields.next()?).ok()?;
+    let header = fields.next()?.to_string();
+    let mime_type: Mime = fields.next()?.parse().ok()?;
+    let optional = |field: &str| Some(field.to_string()).filter(|f| !f.is_empty());
+    let tls_version = optional(fields.next()?);
+    let certificate_fingerprint = optional(fields.next()?);
+    let content = fields.next()?.to_string();
+    // Only successes are kept, but the header isn't always a Gemini one e.g. for gopher
+    let status_code = match StatusCode::parse(&format!("{}\r\n", header)) {
+        Ok(status_code @ StatusCode::Success { .. }) => status_code,
+        _ => StatusCode::Success {
+            code: "20".to_string(),
+            mime_type: Some(mime_type.clone()),
+        },
+    };
+
+    let meta = PageMeta {
+        url,
+        header,
+        mime_type,
+        size: content.len(),
+        duration: Duration::default(),
+        tls_version,
+        certificate_fingerprint,
+        tls_resumed: false,
+        cached: Some(fetched),
+        detected_charset: None,
+        trust: None,
+        host_mismatch: false,
+    };
+    let response = Response::Body {
+        content: Some(content),
+        raw: None,
+        status_code,
+        meta,
+    };
+    Some((response, fetched))
+}
+
+/// FNV-1a, entry names have to stay the same from one build to the next
+pub(super) fn hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn response(url: &Url, content: &str) -> Response {
+        Response::Body {
+            content: Some(content.to_string()),
+            raw: None,
+            status_code: StatusCode::parse("20 text/gemini\r\n").unwrap(),
+            meta: PageMeta {
+                url: url.clone(),
+                header: "20 text/gemini".to_string(),
+                mime_type: "text/gemini".parse().unwrap(),
+                size: content.len(),
+                duration: Duration::default(),
+                tls_version: Some("TLSv1_3".to_string()),
+                certificate_fingerprint: None,
+                tls_resumed: false,
+                cached: None,
+                detected_charset: None,
+                trust: None,
+                host_mismatch: false,
+            },
+        }
+    }
+
+    fn content(response: &Response) -> &str {
+        match response {
+            Response::Body { content, .. } => content.as_deref().unwrap(),
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn disk_cache() {
+        let dir = env::temp_dir().join(format!("diosk-cache-{}", process::id()));
+        let a = Url::parse("gemini://example.org/a").unwrap();
+        let b = Url::parse("gemini://example.org/b").unwrap();
+
+        let cache = DiskCache::new(dir.clone(), Duration::from_secs(60), 1024);
+        assert!(cache.get(&a).is_none());
+
+        cache.insert(&a, &response(&a, "# A\n\nText.\n")).unwrap();
+        let (cached, _) = cache
+            .get(&Url::parse("gemini://EXAMPLE.org:1965/a#x").unwrap())
+            .unwrap();
+        assert_eq!(content(&cached), "# A\n\nText.\n");
+        assert_eq!(cached.meta().url, a);
+        assert_eq!(cached.meta().tls_version.as_deref(), Some("TLSv1_3"));
+        assert_eq!(cached.meta().certificate_fingerprint, None);
+        assert!(cached.meta().cached.is_some());
+
+        // Expired entries are only there for when they're asked for at any age
+        let expired = DiskCache::new(dir.clone(), Duration::from_secs(0), 1024);
+        assert!(expired.get(&a).is_none());
+        assert!(expired.lookup(&a).is_some());
+
+        // There's only room for b, a is the least recently used
+        let small = DiskCache::new(dir.clone(), Duration::from_secs(60), 1100);
+        small.insert(&b, &response(&b, &"b".repeat(1000))).unwrap();
+        assert!(small.get(&a).is_none());
+        assert!(small.get(&b).is_some());
+
+        assert_eq!(small.clear().unwrap(), 1);
+        assert!(small.get(&b).is_none());
+
+        // Gopher pages have no Gemini header to go by
+        let menu = Url::parse("gopher://example.org/1/").unwrap();
+        let mut gopher = response(&menu, "Menu");
+        if let Response::Body { meta, .. } = &mut gopher {
+            meta.header = "gopher item type 1".to_string();
+        }
+        cache.insert(&menu, &gopher).unwrap();
+        let (cached, _) = cache.get(&menu).unwrap();
+        assert_eq!(cached.meta().header, "gopher item type 1");
+        assert!(matches!(
+            cached,
+            Response::Body {
+                status_code: StatusCode::Success { .. },
+                ..
+            }
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
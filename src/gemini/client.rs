@@ -0,0 +1,153 @@
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use url::Url;
+
+use super::fetcher::{self, Fetcher, Network, Retry};
+use super::{Progress, Response, Timeouts, TransactionError};
+
+/// How long a page can take when no timeouts are given, the same as the browser's default
+const PAGE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Fetches pages without anything else diosk does, for building on its protocol code
+///
+/// ```no_run
+/// use diosk::gemini::{Client, Document};
+/// use url::Url;
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let client = Client::new();
+/// let response = client.fetch(&Url::parse("gemini://geminiprotocol.net/")?)?;
+///
+/// if let Some(document) = Document::from_response(&response) {
+///     for link in document.links() {
+///         println!("{} {}", link.url, link.name.unwrap_or_default());
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Certificates are checked as set with `tls::set_verification` and `tls::set_strict_hostnames`,
+/// which apply to every client in the process.
+pub struct Client {
+    fetcher: Arc<dyn Fetcher>,
+    timeouts: Timeouts,
+    download_dir: PathBuf,
+}
+
+impl Client {
+    /// A client for the network that gives up on a page after a minute and saves anything that
+    /// isn't text to the temporary directory
+    pub fn new() -> Self {
+        Self {
+            fetcher: Arc::new(Network),
+            timeouts: Timeouts {
+                page: Some(PAGE_TIMEOUT),
+                download: None,
+            },
+            download_dir: env::temp_dir(),
+        }
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Where responses that aren't text are saved, as hidden files that are the caller's to
+    /// rename or remove
+    pub fn download_dir(mut self, dir: &Path) -> Self {
+        self.download_dir = dir.to_path_buf();
+        self
+    }
+
+    /// Get responses some other way, e.g. from the disk cache
+    pub fn fetcher(mut self, fetcher: Arc<dyn Fetcher>) -> Self {
+        self.fetcher = fetcher;
+        self
+    }
+
+    /// Request a URL, following redirects
+    pub fn fetch(&self, url: &Url) -> Result<Response, TransactionError> {
+        self.fetch_with_progress(url, &mut |_, _| true)
+    }
+
+    /// Request a URL, the progress callback is called as the body is read and returning false
+    /// cancels the request
+    ///
+    /// A bug that would have panicked is returned as `TransactionError::Crashed`.
+    pub fn fetch_with_progress(
+        &self,
+        url: &Url,
+        progress: &mut Progress,
+    ) -> Result<Response, TransactionError> {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            self.fetcher
+                .fetch(url, &self.download_dir, &self.timeouts, progress)
+        }))
+        .unwrap_or(Err(TransactionError::Crashed))
+    }
+
+    /// Like `fetch_with_progress`, trying again after transient errors as `retry` says
+    pub fn fetch_with_retry(
+        &self,
+        url: &Url,
+        progress: &mut Progress,
+        retry: Retry,
+    ) -> Result<Response, TransactionError> {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            fetcher::fetch_with_retry(
+                self.fetcher.as_ref(),
+                url,
+                &self.download_dir,
+                &self.timeouts,
+                progress,
+                retry,
+            )
+        }))
+        .unwrap_or(Err(TransactionError::Crashed))
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gemini::fetcher::Fixture;
+    use crate::gemini::Document;
+
+    struct Panics;
+
+    impl Fetcher for Panics {
+        fn fetch(
+            &self,
+            _url: &Url,
+            _download_dir: &Path,
+            _timeouts: &Timeouts,
+            _progress: &mut Progress,
+        ) -> Result<Response, TransactionError> {
+            panic!("a bug")
+        }
+    }
+
+    #[test]
+    fn fetch() {
+        let url = Url::parse("gemini://example.org/").unwrap();
+
+        let client = Client::new().fetcher(Arc::new(Fixture::new("=> /a A")));
+        let document = Document::from_response(&client.fetch(&url).unwrap()).unwrap();
+        assert_eq!(document.links().count(), 1);
+
+        let client = Client::new().fetcher(Arc::new(Panics));
+        assert!(matches!(client.fetch(&url), Err(TransactionError::Crashed)));
+    }
+}
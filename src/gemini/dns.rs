@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::info;
+use once_cell::sync::Lazy;
+
+const TTL: Duration = Duration::from_secs(5 * 60);
+
+static CACHE: Lazy<Mutex<DnsCache>> = Lazy::new(|| Mutex::new(DnsCache::new(TTL)));
+
+/// Resolved addresses per host for the session, so browsing a capsule doesn't repeat the same
+/// blocking lookup for every request
+struct DnsCache {
+    enabled: bool,
+    ttl: Duration,
+    entries: HashMap<(String, u16), (Vec<SocketAddr>, Instant)>,
+}
+
+impl DnsCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            enabled: true,
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the addresses and whether they came from the cache
+    fn resolve_with<F>(
+        &mut self,
+        host: &str,
+        port: u16,
+        now: Instant,
+        lookup: F,
+    ) -> io::Result<(Vec<SocketAddr>, bool)>
+    where
+        F: FnOnce() -> io::Result<Vec<SocketAddr>>,
+    {
+        let key = (host.to_string(), port);
+
+        if self.enabled {
+            if let Some((addrs, resolved_at)) = self.entries.get(&key) {
+                if now.duration_since(*resolved_at) < self.ttl {
+                    return Ok((addrs.clone(), true));
+                }
+            }
+        }
+
+        let addrs = lookup()?;
+        if self.enabled {
+            self.entries.insert(key, (addrs.clone(), now));
+        }
+
+        Ok((addrs, false))
+    }
+
+    fn invalidate(&mut self, host: &str, port: u16) {
+        self.entries.remove(&(host.to_string(), port));
+    }
+}
+
+/// Resolve a host, using the cache when enabled
+///
+/// Returns the addresses and whether they came from the cache.
+pub fn resolve(host: &str, port: u16) -> io::Result<(Vec<SocketAddr>, bool)> {
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache.resolve_with(host, port, Instant::now(), || {
+        info!("resolving domain: {}", host);
+        Ok((host, port).to_socket_addrs()?.collect())
+    })
+}
+
+/// Forget the cached addresses for a host, e.g. when connecting to them failed
+pub fn invalidate(host: &str, port: u16) {
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache.invalidate(host, port);
+}
+
+pub fn set_enabled(enabled: bool) {
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache.enabled = enabled;
+    if !enabled {
+        cache.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(s: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![s.parse().unwrap()])
+    }
+
+    #[test]
+    fn resolve_with() {
+        let mut cache = DnsCache::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        let (a, cached) = cache
+            .resolve_with("example.org", 1965, now, || addrs("10.0.0.1:1965"))
+            .unwrap();
+        assert!(!cached);
+
+        let (b, cached) = cache
+            .resolve_with("example.org", 1965, now, || addrs("10.0.0.2:1965"))
+            .unwrap();
+        assert!(cached);
+        assert_eq!(a, b);
+
+        // Expired entries are resolved again
+        let later = now + Duration::from_secs(61);
+        let (c, cached) = cache
+            .resolve_with("example.org", 1965, later, || addrs("10.0.0.3:1965"))
+            .unwrap();
+        assert!(!cached);
+        assert_eq!(c, addrs("10.0.0.3:1965").unwrap());
+
+        // As are invalidated entries
+        cache.invalidate("example.org", 1965);
+        let (_, cached) = cache
+            .resolve_with("example.org", 1965, later, || addrs("10.0.0.4:1965"))
+            .unwrap();
+        assert!(!cached);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut cache = DnsCache::new(Duration::from_secs(60));
+        cache.enabled = false;
+        let now = Instant::now();
+
+        cache
+            .resolve_with("example.org", 1965, now, || addrs("10.0.0.1:1965"))
+            .unwrap();
+        let (_, cached) = cache
+            .resolve_with("example.org", 1965, now, || addrs("10.0.0.2:1965"))
+            .unwrap();
+        assert!(!cached);
+    }
+}
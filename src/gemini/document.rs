@@ -0,0 +1,105 @@
+use url::Url;
+
+use super::gemtext::{self, Line};
+use super::Response;
+
+/// A gemtext page and the URL it was served from, which its links are relative to
+///
+/// ```
+/// use diosk::gemini::Document;
+/// use url::Url;
+///
+/// let url = Url::parse("gemini://example.org/notes/").unwrap();
+/// let document = Document::parse(&url, "# Notes\n=> first.gmi The first\n=> /\n");
+///
+/// let links: Vec<String> = document.links().map(|link| link.url.to_string()).collect();
+/// assert_eq!(links, ["gemini://example.org/notes/first.gmi", "gemini://example.org/"]);
+/// ```
+#[derive(Debug)]
+pub struct Document {
+    url: Url,
+    lines: Vec<Line>,
+}
+
+/// A link on a page, with its URL resolved
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link<'a> {
+    pub url: Url,
+    pub name: Option<&'a str>,
+}
+
+impl Document {
+    /// Parse a page, its links are resolved against the URL
+    pub fn parse(url: &Url, content: &str) -> Self {
+        let mut lines = gemtext::parse(content);
+        gemtext::resolve_links(&mut lines, url);
+        Self {
+            url: url.clone(),
+            lines,
+        }
+    }
+
+    /// The page a response is for, None when the response was saved as a download
+    ///
+    /// Text that isn't gemtext has already been turned into gemtext by the time it's a response.
+    pub fn from_response(response: &Response) -> Option<Self> {
+        match response {
+            Response::Body {
+                content: Some(content),
+                meta,
+                ..
+            } => Some(Self::parse(&meta.url, content)),
+            _ => None,
+        }
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    /// The links on the page in order, those with URLs that can't be resolved are invalid and
+    /// skipped
+    pub fn links(&self) -> impl Iterator<Item = Link<'_>> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Link {
+                name,
+                target: Some(target),
+                ..
+            } => Some(Link {
+                url: target.clone(),
+                name: name.as_deref(),
+            }),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links() {
+        let url = Url::parse("gemini://example.org/a/b").unwrap();
+        let document = Document::parse(&url, "=> c\n=> /d Name\n=>\n=> http://[ Broken\ntext");
+
+        assert_eq!(document.lines().len(), 5);
+        assert_eq!(
+            document.links().collect::<Vec<_>>(),
+            [
+                Link {
+                    url: Url::parse("gemini://example.org/a/c").unwrap(),
+                    name: None,
+                },
+                Link {
+                    url: Url::parse("gemini://example.org/d").unwrap(),
+                    name: Some("Name"),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+use url::Url;
+
+use super::cache::DiskCache;
+use super::recording;
+use super::status_code::StatusCode;
+use super::{transaction, PageMeta, Progress, Response, Timeouts, TransactionError};
+
+/// Something that can turn a URL into a response
+///
+/// Responses that can't be displayed are written to a file in the download directory, the
+/// timeouts bound how long it can take, and the progress callback is called as the body is read
+/// so the load can be cancelled.
+pub trait Fetcher: Send + Sync {
+    fn fetch(
+        &self,
+        url: &Url,
+        download_dir: &Path,
+        timeouts: &Timeouts,
+        progress: &mut Progress,
+    ) -> Result<Response, TransactionError>;
+}
+
+/// How often a wait between attempts checks whether the load was cancelled
+const RETRY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Trying a fetch again when it fails in a way that might not last
+pub struct Retry<'a> {
+    /// Attempts after the first, 0 never retries
+    pub retries: usize,
+    /// The wait before the first retry, doubled for each one after
+    pub backoff: Duration,
+    /// Called with the number of the attempt about to be made and how many there can be
+    pub retrying: &'a mut dyn FnMut(usize, usize),
+    pub cancelled: &'a dyn Fn() -> bool,
+}
+
+/// Fetch a URL, trying again while it fails before any response is received
+///
+/// An error after more than one attempt says how many were made.
+pub fn fetch_with_retry(
+    fetcher: &dyn Fetcher,
+    url: &Url,
+    download_dir: &Path,
+    timeouts: &Timeouts,
+    progress: &mut Progress,
+    retry: Retry,
+) -> Result<Response, TransactionError> {
+    let attempts = retry.retries + 1;
+    let mut backoff = retry.backoff;
+
+    for attempt in 1.. {
+        let error = match fetcher.fetch(url, download_dir, timeouts, progress) {
+            Err(e) if e.is_transient() && attempt < attempts => e,
+            Err(e) if attempt > 1 => {
+                return Err(TransactionError::GaveUp {
+                    attempts: attempt,
+                    error: Box::new(e),
+                })
+            }
+            result => return result,
+        };
+        info!("attempt {} of {} failed: {}", attempt, attempts, error);
+
+        let until = Instant::now() + backoff;
+        while Instant::now() < until {
+            if (retry.cancelled)() {
+                return Err(TransactionError::Cancelled);
+            }
+            thread::sleep(RETRY_POLL_INTERVAL.min(until - Instant::now()));
+        }
+        backoff *= 2;
+
+        (retry.retrying)(attempt + 1, attempts);
+    }
+
+    unreachable!("the last attempt returns")
+}
+
+/// Fetches over the network
+#[derive(Debug, Default)]
+pub struct Network;
+
+impl Fetcher for Network {
+    fn fetch(
+        &self,
+        url: &Url,
+        download_dir: &Path,
+        timeouts: &Timeouts,
+        progress: &mut Progress,
+    ) -> Result<Response, TransactionError> {
+        transaction(url, download_dir, timeouts, progress)
+    }
+}
+
+/// Serves pages from the disk cache whatever their age, without ever touching the network
+#[derive(Debug)]
+pub struct Offline {
+    cache: DiskCache,
+}
+
+impl Offline {
+    pub fn new(cache: DiskCache) -> Self {
+        Self { cache }
+    }
+}
+
+impl Fetcher for Offline {
+    fn fetch(
+        &self,
+        url: &Url,
+        _download_dir: &Path,
+        _timeouts: &Timeouts,
+        progress: &mut Progress,
+    ) -> Result<Response, TransactionError> {
+        let (response, _) = self
+            .cache
+            .lookup(url)
+            .ok_or_else(|| TransactionError::NotCached(url.clone()))?;
+        if !progress(&response.meta().mime_type, 0) {
+            return Err(TransactionError::Cancelled);
+        }
+
+        Ok(response)
+    }
+}
+
+/// Serves the responses recorded with --record, never touching the network
+#[derive(Debug)]
+pub struct Replay {
+    dir: PathBuf,
+}
+
+impl Replay {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl Fetcher for Replay {
+    fn fetch(
+        &self,
+        url: &Url,
+        download_dir: &Path,
+        _timeouts: &Timeouts,
+        progress: &mut Progress,
+    ) -> Result<Response, TransactionError> {
+        recording::replay(&self.dir, url, download_dir, progress)
+    }
+}
+
+/// Responds to every URL with the same gemtext, useful for working on the UI offline
+#[derive(Debug)]
+pub struct Fixture {
+    content: String,
+}
+
+impl Fixture {
+    pub fn new(content: &str) -> Self {
+        Self {
+            content: content.to_string(),
+        }
+    }
+}
+
+impl Fetcher for Fixture {
+    fn fetch(
+        &self,
+        url: &Url,
+        _download_dir: &Path,
+        _timeouts: &Timeouts,
+        progress: &mut Progress,
+    ) -> Result<Response, TransactionError> {
+        let mime_type = "text/gemini".parse().expect("infallible");
+        if !progress(&mime_type, 0) {
+            return Err(TransactionError::Cancelled);
+        }
+
+        Ok(Response::Body {
+            meta: PageMeta {
+                url: url.clone(),
+                header: "20 text/gemini".to_string(),
+                mime_type,
+                size: self.content.len(),
+                duration: Duration::default(),
+                tls_version: None,
+                certificate_fingerprint: None,
+                tls_resumed: false,
+                cached: None,
+                detected_charset: None,
+                trust: None,
+                host_mismatch: false,
+            },
+            content: Some(self.content.clone()),
+            raw: Some(self.content.clone().into_bytes()),
+            status_code: StatusCode::parse("20 text/gemini\r\n").unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind::{self, ConnectionRefused, NotFound};
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn fixture() {
+        let fixture = Fixture::new("Foo.\nBar.");
+        let url = Url::parse("gemini://example.org/a").unwrap();
+
+        match fixture.fetch(&url, Path::new("."), &Timeouts::default(), &mut |_, _| true) {
+            Ok(Response::Body { content, meta, .. }) => {
+                assert_eq!(content.as_deref(), Some("Foo.\nBar."));
+                assert_eq!(meta.url, url);
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+
+        let cancelled = fixture.fetch(&url, Path::new("."), &Timeouts::default(), &mut |_, _| {
+            false
+        });
+        assert!(matches!(cancelled, Err(TransactionError::Cancelled)));
+    }
+
+    /// Fails to connect a number of times before responding
+    struct Flaky {
+        failures: Mutex<usize>,
+        kind: ErrorKind,
+    }
+
+    impl Fetcher for Flaky {
+        fn fetch(
+            &self,
+            url: &Url,
+            download_dir: &Path,
+            timeouts: &Timeouts,
+            progress: &mut Progress,
+        ) -> Result<Response, TransactionError> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(TransactionError::Connection(self.kind.into()));
+            }
+            Fixture::new("Foo.").fetch(url, download_dir, timeouts, progress)
+        }
+    }
+
+    #[test]
+    fn retries() {
+        let url = Url::parse("gemini://example.org/").unwrap();
+        let fetch = |failures, kind, retries, cancelled: bool| {
+            let flaky = Flaky {
+                failures: Mutex::new(failures),
+                kind,
+            };
+            let mut attempts = Vec::new();
+            let result = fetch_with_retry(
+                &flaky,
+                &url,
+                Path::new("."),
+                &Timeouts::default(),
+                &mut |_, _| true,
+                Retry {
+                    retries,
+                    backoff: Duration::from_millis(1),
+                    retrying: &mut |attempt, of| attempts.push((attempt, of)),
+                    cancelled: &|| cancelled,
+                },
+            );
+            (result, attempts)
+        };
+        let (result, attempts) = fetch(2, ConnectionRefused, 2, false);
+        assert!(result.is_ok());
+        assert_eq!(attempts, [(2, 3), (3, 3)]);
+
+        let (result, _) = fetch(3, ConnectionRefused, 2, false);
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error,
+            TransactionError::GaveUp { attempts: 3, .. }
+        ));
+        assert!(error.to_string().ends_with("gave up after 3 attempts"));
+
+        // Not worth trying again, or not allowed to
+        let (result, attempts) = fetch(1, NotFound, 2, false);
+        assert!(matches!(result, Err(TransactionError::Connection(_))));
+        assert!(attempts.is_empty());
+        let (result, _) = fetch(1, ConnectionRefused, 0, false);
+        assert!(matches!(result, Err(TransactionError::Connection(_))));
+        let (result, _) = fetch(1, ConnectionRefused, 2, true);
+        assert!(matches!(result, Err(TransactionError::Cancelled)));
+    }
+
+    #[test]
+    fn offline() {
+        let dir = std::env::temp_dir().join(format!("diosk-offline-{}", std::process::id()));
+        let cache = DiskCache::new(dir.clone(), Duration::from_secs(0), 1024);
+        let offline = Offline::new(cache.clone());
+        let url = Url::parse("gemini://example.org/a").unwrap();
+
+        match offline.fetch(&url, &dir, &Timeouts::default(), &mut |_, _| true) {
+            Err(TransactionError::NotCached(missing)) => assert_eq!(missing, url),
+            r => panic!("unexpected response: {:?}", r),
+        }
+
+        // Served however old it is
+        let response = Fixture::new("Foo.")
+            .fetch(&url, &dir, &Timeouts::default(), &mut |_, _| true)
+            .unwrap();
+        cache.insert(&url, &response).unwrap();
+        match offline.fetch(&url, &dir, &Timeouts::default(), &mut |_, _| true) {
+            Ok(Response::Body { content, meta, .. }) => {
+                assert_eq!(content.as_deref(), Some("Foo."));
+                assert!(meta.cached.is_some());
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,51 @@
 // https://gemini.circumlunar.space/docs/gemtext.gmi
 
-#[derive(Debug, PartialEq)]
+use std::ops::Range;
+
+use url::Url;
+
+use super::qualify_url;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Line {
     Normal(String),
-    Link { url: String, name: Option<String> },
+    /// A line starting with one to three #s, level is how many
+    Heading {
+        level: u8,
+        text: String,
+    },
+    /// A line starting with "* ", without it
+    ListItem(String),
+    /// A line starting with >, without it and the space after it if there is one
+    Quote(String),
+    /// url is as it's written on the page, target is where it leads once it's been resolved
+    /// against the page's URL, which absolute URLs don't need
+    Link {
+        url: String,
+        name: Option<String>,
+        target: Option<Url>,
+    },
     InvalidLink,
+    /// The ``` line opening a preformatted block and the alt text after it, collapsed is the
+    /// number of lines hidden when the block is collapsed
+    PreformatStart {
+        alt: Option<String>,
+        collapsed: Option<usize>,
+    },
+    Preformatted {
+        text: String,
+        hidden: bool,
+    },
+    /// The ``` line closing a preformatted block
+    PreformatEnd {
+        hidden: bool,
+    },
 }
 
 impl Line {
+    /// Parse a line on its own, without knowing whether it's in a preformatted block
     pub fn parse(line: &str) -> Line {
-        if line.starts_with("=>") {
+        if let Some(rest) = line.strip_prefix("=>") {
             // Lines beginning with the two characters "=>" are link lines, which have the following syntax:
             //
             // =>[<whitespace>]<URL>[<whitespace><USER-FRIENDLY LINK NAME>]
@@ -19,73 +55,415 @@ impl Line {
             //     <whitespace> is any non-zero number of consecutive spaces or tabs
             //     Square brackets indicate that the enclosed content is optional.
             //     <URL> is a URL, which may be absolute or relative.
+            let is_whitespace = |c: char| c == ' ' || c == '\t';
 
-            let mut last_whitespace = false;
-            let split_on_whitespace = {
-                |c: char| {
-                    if c.is_whitespace() {
-                        if last_whitespace {
-                            false
-                        } else {
-                            last_whitespace = true;
-                            true
-                        }
-                    } else {
-                        last_whitespace = false;
-                        false
-                    }
-                }
+            let rest = rest.trim_start_matches(is_whitespace);
+            let (url, name) = match rest.find(is_whitespace) {
+                Some(end) => (&rest[..end], rest[end..].trim_matches(is_whitespace)),
+                None => (rest, ""),
             };
-            let mut parts = line.splitn(3, split_on_whitespace).map(str::trim);
+            if url.is_empty() {
+                return Line::InvalidLink;
+            }
 
-            let _ = parts.next(); // =>
+            // The name is optional, and kept as it is apart from the whitespace around it
+            Line::Link {
+                url: url.to_string(),
+                name: Some(name.to_string()).filter(|name| !name.is_empty()),
+                target: qualify_url(None, url).ok(),
+            }
+        } else if let Some(text) = line.strip_prefix("* ") {
+            Line::ListItem(text.to_string())
+        } else if let Some(text) = line.strip_prefix('>') {
+            Line::Quote(text.strip_prefix(' ').unwrap_or(text).to_string())
+        } else if let Some(text) = heading(line) {
+            Line::Heading {
+                level: (line.len() - line.trim_start_matches('#').len()) as u8,
+                text: text.to_string(),
+            }
+        } else {
+            Line::Normal(line.to_string())
+        }
+    }
 
-            if let Some(url) = parts.next() {
-                if url.is_empty() {
-                    return Line::InvalidLink;
+    /// Whether the line is in a collapsed preformatted block, and takes up no rows
+    pub fn is_hidden(&self) -> bool {
+        matches!(
+            self,
+            Line::Preformatted { hidden: true, .. } | Line::PreformatEnd { hidden: true }
+        )
+    }
+}
+
+/// Parse the lines of a page, ``` lines toggle between gemtext and preformatted text
+pub fn parse(content: &str) -> Vec<Line> {
+    let mut preformatted = false;
+
+    content
+        .lines()
+        .map(|line| match line.strip_prefix("```") {
+            Some(alt) => {
+                preformatted = !preformatted;
+                match preformatted {
+                    true => Line::PreformatStart {
+                        alt: Some(alt.trim())
+                            .filter(|alt| !alt.is_empty())
+                            .map(str::to_string),
+                        collapsed: None,
+                    },
+                    false => Line::PreformatEnd { hidden: false },
                 }
+            }
+            None if preformatted => Line::Preformatted {
+                text: line.to_string(),
+                hidden: false,
+            },
+            None => Line::parse(line),
+        })
+        .collect()
+}
 
-                // Name is optional
-                let name: String = parts.collect();
-                let name = if name.is_empty() { None } else { Some(name) };
+/// Resolve the links on a page against its URL, those that can't be are invalid
+pub fn resolve_links(lines: &mut [Line], base: &Url) {
+    for line in lines.iter_mut() {
+        if let Line::Link {
+            url,
+            target: target @ None,
+            ..
+        } = line
+        {
+            match qualify_url(Some(base), url) {
+                Ok(url) => *target = Some(url),
+                Err(_) => *line = Line::InvalidLink,
+            }
+        }
+    }
+}
 
-                Line::Link {
-                    url: url.to_string(),
-                    name,
+/// The line opening the preformatted block a line is part of
+pub fn block_start(lines: &[Line], line: usize) -> Option<usize> {
+    match lines.get(line)? {
+        Line::PreformatStart { .. } => Some(line),
+        Line::Preformatted { .. } | Line::PreformatEnd { .. } => lines[..line]
+            .iter()
+            .rposition(|line| matches!(line, Line::PreformatStart { .. })),
+        _ => None,
+    }
+}
+
+/// Hide the lines of the preformatted block opened at start, along with the line closing it
+pub fn collapse(lines: &mut [Line], start: usize) {
+    let mut count = 0;
+    for line in lines.iter_mut().skip(start + 1) {
+        match line {
+            Line::Preformatted { hidden, .. } => {
+                *hidden = true;
+                count += 1;
+            }
+            Line::PreformatEnd { hidden } => {
+                *hidden = true;
+                break;
+            }
+            _ => break,
+        }
+    }
+
+    if let Some(Line::PreformatStart { collapsed, .. }) = lines.get_mut(start) {
+        *collapsed = Some(count);
+    }
+}
+
+/// The text of a heading line, any level
+pub fn heading(line: &str) -> Option<&str> {
+    let text = line.trim_start_matches('#');
+    match line.len() - text.len() {
+        1..=3 => Some(text.trim()),
+        _ => None,
+    }
+}
+
+/// The fragment that links to a heading: lowercased, punctuation stripped, and spaces replaced
+/// with hyphens
+pub fn slug(heading: &str) -> String {
+    heading
+        .split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Where URLs starting with one of the schemes, e.g. "gemini://", appear in a line of text
+///
+/// A URL has to start at the beginning of a word and runs to the next whitespace, less any
+/// trailing punctuation. Closing brackets are only kept when they match one in the URL.
+pub fn find_urls(text: &str, schemes: &[&str]) -> Vec<Range<usize>> {
+    let mut urls = Vec::new();
+    let mut position = 0;
+
+    while position < text.len() {
+        let rest = &text[position..];
+        let scheme = schemes.iter().find(|scheme| {
+            rest.get(..scheme.len())
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+        });
+        let at_word_start = text[..position]
+            .chars()
+            .next_back()
+            .is_none_or(|c| !c.is_alphanumeric());
+
+        match scheme {
+            Some(scheme) if at_word_start => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let url = trim_url(&rest[..end]);
+                // Nothing but the scheme isn't worth following
+                if url.len() > scheme.len() {
+                    urls.push(position..position + url.len());
                 }
-            } else {
-                Line::InvalidLink
+                position += end;
             }
-        } else {
-            Line::Normal(line.to_string())
+            _ => position += rest.chars().next().map_or(1, char::len_utf8),
         }
     }
+
+    urls
+}
+
+/// Drop punctuation from the end of a URL that's more likely part of the sentence around it
+fn trim_url(mut url: &str) -> &str {
+    loop {
+        let trimmed = url.trim_end_matches(&['.', ',', ';', ':', '!', '?', '\'', '"'][..]);
+        let trimmed = match trimmed.chars().next_back() {
+            Some(close @ (')' | ']' | '}' | '>')) => {
+                let open = match close {
+                    ')' => '(',
+                    ']' => '[',
+                    '}' => '{',
+                    _ => '<',
+                };
+                let opened = trimmed.matches(open).count();
+                let closed = trimmed.matches(close).count();
+                if closed > opened {
+                    &trimmed[..trimmed.len() - 1]
+                } else {
+                    trimmed
+                }
+            }
+            _ => trimmed,
+        };
+
+        if trimmed.len() == url.len() {
+            return url;
+        }
+        url = trimmed;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn preformatted_blocks() {
+        let mut lines = parse("Text\n``` python source\n=> not a link\n\n```\n=> /a A");
+        assert_eq!(
+            lines[1],
+            Line::PreformatStart {
+                alt: Some("python source".to_string()),
+                collapsed: None
+            }
+        );
+        assert_eq!(
+            lines[2],
+            Line::Preformatted {
+                text: "=> not a link".to_string(),
+                hidden: false
+            }
+        );
+        assert_eq!(lines[4], Line::PreformatEnd { hidden: false });
+        assert!(matches!(lines[5], Line::Link { .. }));
+
+        assert_eq!(block_start(&lines, 4), Some(1));
+        assert_eq!(block_start(&lines, 1), Some(1));
+        assert_eq!(block_start(&lines, 5), None);
+
+        collapse(&mut lines, 1);
+        assert!(matches!(
+            lines[1],
+            Line::PreformatStart {
+                collapsed: Some(2),
+                ..
+            }
+        ));
+        let hidden: Vec<bool> = lines.iter().map(Line::is_hidden).collect();
+        assert_eq!(hidden, [false, false, true, true, true, false]);
+
+        // A block left open runs to the end of the page
+        assert_eq!(
+            parse("```\n# Not a heading")[1],
+            Line::Preformatted {
+                text: "# Not a heading".to_string(),
+                hidden: false
+            }
+        );
+
+        // Only ``` at the start of a line toggles, backticks anywhere else are text
+        let lines = parse("```\n ```indented\n``two\nx ``` y\n```\nAfter");
+        let texts: Vec<&str> = lines[1..4]
+            .iter()
+            .filter_map(|line| match line {
+                Line::Preformatted { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts, [" ```indented", "``two", "x ``` y"]);
+        assert_eq!(lines[4], Line::PreformatEnd { hidden: false });
+        assert_eq!(lines[5], Line::Normal("After".to_string()));
+    }
+
     #[test]
     fn line_parse() {
         let assert_normal = |i: &str, o: &str| {
             assert_eq!(Line::parse(i), Line::Normal(o.to_string()));
         };
-        let assert_link = |i: &str, u: &str, n: Option<&str>| {
+        let assert_link = |i: &str, u: &str, n: Option<&str>| match Line::parse(i) {
+            Line::Link { url, name, .. } => {
+                assert_eq!(url, u);
+                assert_eq!(name.as_deref(), n);
+            }
+            line => panic!("not a link: {:?}", line),
+        };
+
+        assert_normal("", "");
+        assert_normal("Hello, World", "Hello, World");
+        assert_normal(" => Hello, World", " => Hello, World");
+
+        assert_link("=> Hello, World", "Hello,", Some("World"));
+        assert_link("=>   Hello,   World   ", "Hello,", Some("World"));
+
+        // The whitespace after => is optional, and can be tabs as well as spaces
+        assert_link("=>gemini://example.org", "gemini://example.org", None);
+        assert_link("=>/a\tA link", "/a", Some("A link"));
+        assert_link("=>\t/a \t A\tlink\t", "/a", Some("A\tlink"));
+        assert_link(
+            "=> gemini://example.org/  Two  spaces  inside",
+            "gemini://example.org/",
+            Some("Two  spaces  inside"),
+        );
+        assert_link("=> /a ", "/a", None);
+
+        assert_eq!(Line::parse("=>"), Line::InvalidLink);
+        assert_eq!(Line::parse("=> \t "), Line::InvalidLink);
+
+        let assert_heading = |i: &str, level: u8, text: &str| {
             assert_eq!(
                 Line::parse(i),
-                Line::Link {
-                    url: u.to_string(),
-                    name: n.map(|s| s.to_string()),
+                Line::Heading {
+                    level,
+                    text: text.to_string(),
                 }
             );
         };
+        assert_heading("# Title", 1, "Title");
+        assert_heading("### Deepest", 3, "Deepest");
+        // The space after the #s is optional and any extra is trimmed
+        assert_heading("#NoSpace", 1, "NoSpace");
+        assert_heading("##  extra spaces  ", 2, "extra spaces");
+        assert_heading("#", 1, "");
+        assert_heading("## \t", 2, "");
+        assert_normal("#### Too deep", "#### Too deep");
+
+        assert_eq!(Line::parse("* Item"), Line::ListItem("Item".to_string()));
+        assert_eq!(Line::parse("* "), Line::ListItem(String::new()));
+        assert_eq!(
+            Line::parse("*  Spaced"),
+            Line::ListItem(" Spaced".to_string())
+        );
+        assert_normal("*", "*");
+        assert_normal("*Emphasis*", "*Emphasis*");
+
+        let assert_quote = |i: &str, o: &str| {
+            assert_eq!(Line::parse(i), Line::Quote(o.to_string()));
+        };
+        assert_quote("> Quoted", "Quoted");
+        assert_quote(">Quoted", "Quoted");
+        assert_quote(">  Indented", " Indented");
+        assert_quote(">", "");
+        assert_quote("> ", "");
+        assert_normal(" # Indented", " # Indented");
+    }
 
-        assert_normal(&"", "");
-        assert_normal(&"Hello, World", "Hello, World");
-        assert_normal(&" => Hello, World", " => Hello, World");
+    #[test]
+    fn resolve_links() {
+        let base = Url::parse("gemini://example.org/dir/page.gmi?q").unwrap();
+        let mut lines = parse(
+            "=> other.gmi\n=> #section\n=> //other.org/path\n=> gemini://a.org\n=> http://[ Broken",
+        );
+        super::resolve_links(&mut lines, &base);
+
+        let targets: Vec<Option<String>> = lines
+            .iter()
+            .map(|line| match line {
+                Line::Link { target, .. } => target.as_ref().map(Url::to_string),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                Some("gemini://example.org/dir/other.gmi".to_string()),
+                Some("gemini://example.org/dir/page.gmi?q#section".to_string()),
+                Some("gemini://other.org/path".to_string()),
+                Some("gemini://a.org/".to_string()),
+                None,
+            ]
+        );
+        assert_eq!(lines[4], Line::InvalidLink);
+    }
+
+    #[test]
+    fn heading_slugs() {
+        assert_eq!(
+            heading("## Section 3: The End!"),
+            Some("Section 3: The End!")
+        );
+        assert_eq!(heading("#### Too deep"), None);
+        assert_eq!(heading("Not a heading"), None);
+
+        assert_eq!(slug("Section 3: The End!"), "section-3-the-end");
+        assert_eq!(
+            slug("Über  dark-mode_v2 — notes"),
+            "über-dark-mode_v2-notes"
+        );
+    }
+
+    #[test]
+    fn find_urls() {
+        let urls = |text: &'static str, schemes: &[&str]| -> Vec<&'static str> {
+            super::find_urls(text, schemes)
+                .into_iter()
+                .map(|range| &text[range])
+                .collect()
+        };
+        let gemini = &["gemini://"];
 
-        assert_link(&"=> Hello, World", "Hello,", Some("World"));
-        assert_link(&"=>   Hello,   World   ", "Hello,", Some("World"));
+        assert_eq!(
+            urls("See gemini://a.org/x, or (gemini://b.org/y).", gemini),
+            ["gemini://a.org/x", "gemini://b.org/y"]
+        );
+        assert_eq!(
+            urls("gemini://a.org/Foo_(bar) and http://c.org/", gemini),
+            ["gemini://a.org/Foo_(bar)"]
+        );
+        assert_eq!(
+            urls("http://c.org/", &["gemini://", "http://"]),
+            ["http://c.org/"]
+        );
+        assert!(urls("notgemini://a.org/ gemini:// gemini://.", gemini).is_empty());
     }
 }
@@ -0,0 +1,440 @@
+// https://www.rfc-editor.org/rfc/rfc1436
+// https://www.rfc-editor.org/rfc/rfc4266
+
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use log::info;
+use mime::Mime;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use url::Url;
+
+use crate::logging::redact;
+
+use super::gemtext::Line;
+use super::status_code::StatusCode;
+use super::temp_file::TempFile;
+use super::{
+    connect, read_body, request_url, Deadline, PageMeta, Progress, Response, Timeouts,
+    TransactionError,
+};
+
+pub const PORT: u16 = 70;
+
+/// Characters in a selector that would change the meaning of the URL it's put in
+const SELECTOR: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`');
+
+/// Request a gopher URL, anything other than a menu or text file is streamed into the download
+/// directory
+pub(super) fn transaction(
+    url: &Url,
+    download_dir: &Path,
+    timeouts: &Timeouts,
+    start: Instant,
+    progress: &mut Progress,
+) -> Result<Response, TransactionError> {
+    let host = url.host_str().ok_or(TransactionError::NoHost)?;
+    let port = url.port().unwrap_or(PORT);
+    let (item_type, selector) = item(url);
+    let mime_type = mime_type(item_type, &selector);
+    let is_page = mime_type.essence_str() == "text/gemini";
+
+    let timeout = match is_page {
+        true => timeouts.page,
+        false => timeouts.download,
+    };
+    let deadline = Deadline::new(start, timeout);
+    if let Some(deadline) = deadline.as_ref().filter(|d| d.passed()) {
+        return Err(deadline.expired(0));
+    }
+
+    let mut socket = connect(host, port)?;
+    let failed = |e| match &deadline {
+        Some(deadline) if deadline.passed() => deadline.expired(0),
+        _ => TransactionError::Connection(e),
+    };
+    if let Some(deadline) = &deadline {
+        deadline.check(&socket, 0)?;
+    }
+
+    // The whole request is the selector, the server answers with the item and closes
+    info!("sending selector: {}", redact(url));
+    write!(socket, "{}\r\n", selector).map_err(failed)?;
+
+    let mut meta = PageMeta {
+        url: request_url(url),
+        // Gopher has no header, what was asked for is shown in its place
+        header: format!("gopher item type {}", item_type),
+        mime_type: mime_type.clone(),
+        size: 0,
+        duration: Duration::default(),
+        tls_version: None,
+        certificate_fingerprint: None,
+        tls_resumed: false,
+        cached: None,
+        detected_charset: None,
+        trust: None,
+        host_mismatch: false,
+    };
+    let deadline = deadline.as_ref().map(|d| (d, &socket));
+    let mut reader = &socket;
+
+    if is_page {
+        let mut body = Vec::new();
+        meta.size = read_body(&mut reader, &mut body, &mime_type, progress, deadline)?;
+        // Gopher says nothing about charsets, plenty of it predates UTF-8
+        let (content, detected) = super::decode_detecting(url, &body, mime::UTF_8.as_str());
+        meta.detected_charset = detected;
+
+        Ok(Response::Body {
+            meta,
+            content: Some(content),
+            raw: Some(body),
+            // Menus and text files are turned into gemtext, so they're as good as a gemtext page
+            status_code: StatusCode::Success {
+                code: "20".to_string(),
+                mime_type: Some(mime_type),
+            },
+        })
+    } else {
+        let mut file = TempFile::create(download_dir)?;
+        meta.size = read_body(&mut reader, &mut file, &mime_type, progress, deadline)?;
+
+        Ok(Response::Download {
+            meta,
+            file: file.keep()?,
+        })
+    }
+}
+
+/// The item type and selector a gopher URL asks for, the root of a server is its menu
+fn item(url: &Url) -> (char, String) {
+    let path = percent_decode_str(url.path()).decode_utf8_lossy();
+    let mut chars = path.trim_start_matches('/').chars();
+
+    match chars.next() {
+        Some(item_type) => {
+            let mut selector = chars.as_str().to_string();
+            if let Some(query) = url.query() {
+                selector.push('?');
+                selector.push_str(&percent_decode_str(query).decode_utf8_lossy());
+            }
+            (item_type, selector)
+        }
+        None => ('1', String::new()),
+    }
+}
+
+/// What an item is served as, menus and text files are shown as gemtext and anything else is a
+/// download
+fn mime_type(item_type: char, selector: &str) -> Mime {
+    let extension = selector.rsplit('.').next().unwrap_or_default();
+    let mime_type = match item_type {
+        '0' | '1' => "text/gemini",
+        '4' => "application/mac-binhex40",
+        '6' => "text/x-uuencode",
+        'd' => "application/pdf",
+        'g' => "image/gif",
+        'h' => "text/html",
+        'p' => "image/png",
+        's' => "audio/x-wav",
+        'c' => "text/calendar",
+        'I' => match extension.to_ascii_lowercase().as_str() {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            _ => "application/octet-stream",
+        },
+        _ => "application/octet-stream",
+    };
+    mime_type.parse().expect("infallible")
+}
+
+/// Gopher item types diosk can't follow, with what they're labelled as
+fn unsupported(item_type: char) -> Option<&'static str> {
+    match item_type {
+        '2' => Some("CSO"),
+        '3' => Some("error"),
+        '7' => Some("search"),
+        '8' | 'T' => Some("telnet"),
+        '+' => Some("mirror"),
+        '0' | '1' | '4' | '5' | '6' | '9' | 'c' | 'd' | 'g' | 'h' | 'I' | 'p' | 's' | ';' => None,
+        _ => Some("unknown"),
+    }
+}
+
+/// Turn a decoded gopher body into gemtext, the URL says whether it's a menu or a text file
+pub(super) fn gemtext(url: &Url, text: &str) -> String {
+    let lines = match item(url).0 {
+        '1' => menu(url, text),
+        _ => text_file(text),
+    };
+
+    let mut gemtext = String::new();
+    for line in lines {
+        let text = match line {
+            Line::Link {
+                url, name: None, ..
+            } => format!("=> {}", url),
+            Line::Link {
+                url,
+                name: Some(name),
+                ..
+            } => format!("=> {} {}", url, name),
+            Line::PreformatStart { .. } | Line::PreformatEnd { .. } => "```".to_string(),
+            // Text that looks like gemtext markup is moved along so it stays as it is
+            Line::Normal(text) | Line::Preformatted { text, .. }
+                if text.starts_with("=>") || text.starts_with("```") =>
+            {
+                format!(" {}", text)
+            }
+            Line::Normal(text)
+                if text.starts_with('#') || text.starts_with("* ") || text.starts_with('>') =>
+            {
+                format!(" {}", text)
+            }
+            Line::Normal(text) | Line::Preformatted { text, .. } => text,
+            Line::Heading { level, text } => format!("{} {}", "#".repeat(level as usize), text),
+            Line::ListItem(text) => format!("* {}", text),
+            Line::Quote(text) => format!("> {}", text),
+            Line::InvalidLink => continue,
+        };
+        gemtext.push_str(&text);
+        gemtext.push('\n');
+    }
+    gemtext
+}
+
+/// Parse a gophermap, items are links to the URLs they're at and anything that can't be followed
+/// is labelled with its type
+fn menu(base: &Url, text: &str) -> Vec<Line> {
+    text.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .take_while(|line| *line != ".")
+        .map(|line| {
+            let mut fields = line.split('\t');
+            let mut display = fields.next().unwrap_or_default().chars();
+            let item_type = match display.next() {
+                Some(item_type) => item_type,
+                None => return Line::Normal(String::new()),
+            };
+            let display = display.as_str().to_string();
+            let selector = fields.next().unwrap_or_default();
+
+            if item_type == 'i' {
+                return Line::Normal(display);
+            }
+            if let Some(label) = unsupported(item_type) {
+                return Line::Normal(format!("[{}] {}", label, display));
+            }
+
+            // By convention an h item with a URL: selector links to somewhere off gopher
+            if let Some(url) = selector.strip_prefix("URL:").filter(|_| item_type == 'h') {
+                return link(url.to_string(), display);
+            }
+
+            // Items missing where they are, as in hand written gophermaps, are on the same server
+            let host = fields
+                .next()
+                .filter(|host| !host.is_empty())
+                .or_else(|| base.host_str())
+                .unwrap_or_default();
+            let port = fields
+                .next()
+                .and_then(|port| port.trim().parse().ok())
+                .unwrap_or_else(|| base.port().unwrap_or(PORT));
+            let port = match port {
+                PORT => String::new(),
+                port => format!(":{}", port),
+            };
+            let url = format!(
+                "gopher://{}{}/{}{}",
+                host,
+                port,
+                item_type,
+                utf8_percent_encode(selector, SELECTOR)
+            );
+
+            match Url::parse(&url) {
+                Ok(url) => link(url.to_string(), display),
+                Err(_) => Line::Normal(format!("[invalid] {}", display)),
+            }
+        })
+        .collect()
+}
+
+fn link(url: String, display: String) -> Line {
+    Line::Link {
+        target: Url::parse(&url).ok(),
+        url,
+        name: Some(display).filter(|name| !name.trim().is_empty()),
+    }
+}
+
+/// A text file is shown as it is, in a preformatted block
+fn text_file(text: &str) -> Vec<Line> {
+    let mut lines: Vec<_> = text.lines().collect();
+    // Servers end text files with a line holding a single . and escape lines that start with one
+    if lines.last() == Some(&".") {
+        lines.pop();
+    }
+
+    std::iter::once(Line::PreformatStart {
+        alt: None,
+        collapsed: None,
+    })
+    .chain(lines.into_iter().map(|line| {
+        Line::Preformatted {
+            text: line
+                .strip_prefix("..")
+                .map_or(line.to_string(), |l| format!(".{}", l)),
+            hidden: false,
+        }
+    }))
+    .chain(std::iter::once(Line::PreformatEnd { hidden: false }))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn item() {
+        assert_eq!(
+            super::item(&url("gopher://example.org")),
+            ('1', String::new())
+        );
+        assert_eq!(
+            super::item(&url("gopher://example.org/")),
+            ('1', String::new())
+        );
+        assert_eq!(
+            super::item(&url("gopher://example.org/0/notes/a%20b.txt")),
+            ('0', "/notes/a b.txt".to_string())
+        );
+        assert_eq!(
+            super::item(&url("gopher://example.org/7/search%09rust")),
+            ('7', "/search\trust".to_string())
+        );
+    }
+
+    #[test]
+    fn menu() {
+        let base = url("gopher://example.org:7070/1/");
+        let map = "iWelcome!\tfake\t(NULL)\t0\r\n\
+                   1Phlog\t/phlog\texample.org\t70\r\n\
+                   0About me\t/about.txt\tother.example\t7070\r\n\
+                   9Archive\t/a b.tgz\texample.org\t70\r\n\
+                   hWebsite\tURL:https://example.org/\texample.org\t70\r\n\
+                   7Search\t/search\texample.org\t70\r\n\
+                   1Local\t/local\r\n\
+                   i=> not a link\t\t\t\r\n\
+                   .\r\n\
+                   iAfter the end\t\t\t\r\n";
+
+        assert_eq!(
+            super::menu(&base, map),
+            [
+                Line::Normal("Welcome!".to_string()),
+                link(
+                    "gopher://example.org/1/phlog".to_string(),
+                    "Phlog".to_string()
+                ),
+                link(
+                    "gopher://other.example:7070/0/about.txt".to_string(),
+                    "About me".to_string()
+                ),
+                link(
+                    "gopher://example.org/9/a%20b.tgz".to_string(),
+                    "Archive".to_string()
+                ),
+                link("https://example.org/".to_string(), "Website".to_string()),
+                Line::Normal("[search] Search".to_string()),
+                link(
+                    "gopher://example.org:7070/1/local".to_string(),
+                    "Local".to_string()
+                ),
+                Line::Normal("=> not a link".to_string()),
+            ]
+        );
+
+        let gemtext = super::gemtext(&base, map);
+        assert_eq!(
+            gemtext.lines().last(),
+            Some(" => not a link"),
+            "text that looks like a link stays text"
+        );
+    }
+
+    #[test]
+    fn text_file() {
+        let file = url("gopher://example.org/0/file.txt");
+        assert_eq!(
+            super::gemtext(&file, "Hello\n..dotted\n```\n.\n"),
+            "```\nHello\n.dotted\n ```\n```\n"
+        );
+    }
+
+    #[test]
+    fn transaction() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = url(&format!(
+            "gopher://127.0.0.1:{}/1/menu",
+            listener.local_addr().unwrap().port()
+        ));
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            BufReader::new(&socket).read_line(&mut request).unwrap();
+            socket.write_all(b"iHello\t\t\t\r\n.\r\n").unwrap();
+            request
+        });
+
+        let response = super::transaction(
+            &url,
+            Path::new("."),
+            &Timeouts::default(),
+            Instant::now(),
+            &mut |_, _| true,
+        )
+        .unwrap();
+        assert_eq!(server.join().unwrap(), "/menu\r\n");
+        match response {
+            Response::Body { content, meta, .. } => {
+                assert_eq!(content.as_deref(), Some("Hello\n"));
+                assert_eq!(meta.url, url);
+                assert_eq!(meta.header, "gopher item type 1");
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn mime_type() {
+        assert_eq!(super::mime_type('1', "/").essence_str(), "text/gemini");
+        assert_eq!(
+            super::mime_type('I', "/cat.JPG").essence_str(),
+            "image/jpeg"
+        );
+        assert_eq!(
+            super::mime_type('9', "/a.tgz").essence_str(),
+            "application/octet-stream"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Responses saved byte for byte as they were received, so a page that renders wrong can be
+//! loaded again exactly as it was after the capsule has changed
+//!
+//! Each response is a file named by a hash of the URL it was requested with, holding the header
+//! line followed by the body. Gopher responses have no header and aren't recorded.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use log::warn;
+use mime::Mime;
+use url::Url;
+
+use super::cache::hash;
+use super::status_code::StatusCode;
+use super::temp_file::TempFile;
+use super::{
+    decode_detecting, qualify_url, request_url, PageMeta, Progress, Response, TransactionError,
+};
+
+const EXTENSION: &str = "response";
+
+/// As many redirects as are followed over the network
+const MAX_REDIRECTS: usize = 6;
+
+fn dir() -> &'static Mutex<Option<PathBuf>> {
+    static DIR: OnceLock<Mutex<Option<PathBuf>>> = OnceLock::new();
+    DIR.get_or_init(|| Mutex::new(None))
+}
+
+/// Record every response received from now on into the directory, or stop recording
+pub fn set_dir(recording: Option<PathBuf>) {
+    *dir().lock().unwrap_or_else(|e| e.into_inner()) = recording;
+}
+
+fn recording_dir() -> Option<PathBuf> {
+    dir().lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// The file the response to a request for the URL is recorded in
+pub fn path(dir: &Path, url: &Url) -> PathBuf {
+    let url = request_url(url);
+    dir.join(format!("{:016x}.{}", hash(url.as_str()), EXTENSION))
+}
+
+/// Record a response while recording, the header is as it was received with its CRLF
+pub(super) fn record(url: &Url, header: &str, body: &[u8]) {
+    if let Some(dir) = recording_dir() {
+        if let Err(e) = write(&dir, url, header, &mut &body[..]) {
+            warn!("unable to record {}: {}", url, e);
+        }
+    }
+}
+
+/// Record a response that was saved as a download, it's only read back while recording and then
+/// a chunk at a time
+pub(super) fn record_file(url: &Url, header: &str, file: &Path) {
+    if let Some(dir) = recording_dir() {
+        let recorded =
+            fs::File::open(file).and_then(|mut body| write(&dir, url, header, &mut body));
+        if let Err(e) = recorded {
+            warn!("unable to record {}: {}", url, e);
+        }
+    }
+}
+
+fn write(dir: &Path, url: &Url, header: &str, body: &mut dyn Read) -> io::Result<()> {
+    let mut file = TempFile::create(dir)?;
+    file.write_all(header.as_bytes())?;
+    io::copy(body, &mut file)?;
+    fs::rename(file.keep()?, path(dir, url))
+}
+
+/// Load a URL from the responses recorded in the directory, following recorded redirects
+///
+/// Pages are decoded and downloads saved like they are when they come from the network.
+pub(super) fn replay(
+    dir: &Path,
+    url: &Url,
+    download_dir: &Path,
+    progress: &mut Progress,
+) -> Result<Response, TransactionError> {
+    let mut url = request_url(url);
+
+    for _ in 0..=MAX_REDIRECTS {
+        let recorded =
+            fs::read(path(dir, &url)).map_err(|_| TransactionError::NotRecorded(url.clone()))?;
+        let length = recorded
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(recorded.len(), |end| end + 1);
+        let (header, body) = recorded.split_at(length);
+        let header = String::from_utf8_lossy(header);
+
+        match StatusCode::parse(&header)? {
+            StatusCode::Success { code, mime_type } => {
+                let mime_type =
+                    mime_type.unwrap_or_else(|| "text/gemini".parse::<Mime>().expect("infallible"));
+                if !progress(&mime_type, body.len()) {
+                    return Err(TransactionError::Cancelled);
+                }
+                return Ok(response(
+                    &url,
+                    &header,
+                    code,
+                    mime_type,
+                    body,
+                    download_dir,
+                )?);
+            }
+            StatusCode::TemporaryFailure { code, meta } => {
+                return Err(TransactionError::TemporaryFailure(code, meta))
+            }
+            StatusCode::PermanentFailure { code, meta } => {
+                return Err(TransactionError::PermanentFailure(code, meta))
+            }
+            StatusCode::Redirect {
+                url: redirect_url, ..
+            } => {
+                let redirect_url = redirect_url.unwrap_or_default();
+                url = qualify_url(Some(&url), &redirect_url)
+                    .map(|url| request_url(&url))
+                    .map_err(|_| TransactionError::InvalidRedirect(redirect_url))?;
+            }
+        }
+    }
+
+    Err(TransactionError::RedirectLoop)
+}
+
+fn response(
+    url: &Url,
+    header: &str,
+    code: String,
+    mime_type: Mime,
+    body: &[u8],
+    download_dir: &Path,
+) -> io::Result<Response> {
+    let mut meta = PageMeta {
+        url: url.clone(),
+        header: header.trim_end().to_string(),
+        mime_type: mime_type.clone(),
+        size: body.len(),
+        duration: Duration::default(),
+        tls_version: None,
+        certificate_fingerprint: None,
+        tls_resumed: false,
+        cached: None,
+        detected_charset: None,
+        trust: None,
+        host_mismatch: false,
+    };
+    let status_code = StatusCode::Success {
+        code,
+        mime_type: Some(mime_type.clone()),
+    };
+
+    match (mime_type.type_(), mime_type.subtype()) {
+        (mime::TEXT, name) if name == "gemini" => {
+            let charset = mime_type.get_param("charset").unwrap_or(mime::UTF_8);
+            let (content, detected) = decode_detecting(url, body, charset.as_str());
+            meta.detected_charset = detected;
+
+            Ok(Response::Body {
+                meta,
+                content: Some(content),
+                raw: Some(body.to_vec()),
+                status_code,
+            })
+        }
+        _ => {
+            let mut file = TempFile::create(download_dir)?;
+            file.write_all(body)?;
+
+            Ok(Response::Download {
+                meta,
+                file: file.keep()?,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// A recording of gemini://example.org/ with a redirect to it from /old, an image at /image
+    /// and a failure at /gone
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay");
+
+    fn url(path: &str) -> Url {
+        Url::parse("gemini://example.org/")
+            .unwrap()
+            .join(path)
+            .unwrap()
+    }
+
+    #[test]
+    fn replay() {
+        let dir = env::temp_dir().join(format!("diosk-replay-{}", process::id()));
+        let replay =
+            |path: &str| super::replay(Path::new(FIXTURES), &url(path), &dir, &mut |_, _| true);
+
+        match replay("old#top") {
+            Ok(Response::Body { meta, content, .. }) => {
+                assert_eq!(meta.url, url("/"));
+                assert_eq!(meta.header, "20 text/gemini");
+                assert_eq!(content.as_deref(), Some("# Recorded\n=> /old Moved\n"));
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+        match replay("image") {
+            Ok(Response::Download { file, .. }) => {
+                assert_eq!(fs::read(&file).unwrap(), b"\x89PNG");
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+        assert!(matches!(
+            replay("gone"),
+            Err(TransactionError::PermanentFailure(..))
+        ));
+        match replay("missing") {
+            Err(TransactionError::NotRecorded(missing)) => assert_eq!(missing, url("missing")),
+            r => panic!("unexpected response: {:?}", r),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn record_downloads() {
+        let dir = env::temp_dir().join(format!("diosk-recording-{}", process::id()));
+        let download = env::temp_dir().join(format!("diosk-recorded-{}.png", process::id()));
+        fs::write(&download, b"\x89PNG").unwrap();
+
+        let mut body = fs::File::open(&download).unwrap();
+        write(&dir, &url("image"), "20 image/png\r\n", &mut body).unwrap();
+        let recorded = fs::read(path(&dir, &url("image"))).unwrap();
+        assert_eq!(
+            recorded,
+            fs::read(path(Path::new(FIXTURES), &url("image"))).unwrap()
+        );
+
+        fs::remove_file(&download).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -41,9 +41,8 @@ impl StatusCode {
                 let rest: String = parts.collect();
                 let rest = rest.trim();
 
-                let mime_type: mime::Mime = rest
-                    .parse()
-                    .unwrap_or_else(|_| "text/gemini; charset=utf-8".parse().expect("infallible"));
+                let mime_type = parse_mime_type(rest)
+                    .unwrap_or_else(|| "text/gemini; charset=utf-8".parse().expect("infallible"));
 
                 Ok(StatusCode::Success {
                     code,
@@ -69,7 +68,9 @@ impl StatusCode {
                 let meta = meta.trim().to_string();
                 Ok(StatusCode::PermanentFailure { code, meta })
             }
-            (_, _) => Err(ParseError(input.lines().next().unwrap().to_string())),
+            (_, _) => Err(ParseError(
+                input.lines().next().unwrap_or_default().to_string(),
+            )),
         }
     }
 
@@ -84,17 +85,52 @@ impl StatusCode {
     }
 }
 
+/// Parse a MIME type, quoting parameters that are lists first, like lang=de,fr, since commas
+/// aren't allowed in plain values
+fn parse_mime_type(input: &str) -> Option<Mime> {
+    if let Ok(mime_type) = input.parse() {
+        return Some(mime_type);
+    }
+
+    let quoted: Vec<_> = input
+        .split(';')
+        .map(|part| match part.split_once('=') {
+            Some((name, value)) if value.contains(',') && !value.trim().starts_with('"') => {
+                format!("{}=\"{}\"", name, value.trim())
+            }
+            _ => part.to_string(),
+        })
+        .collect();
+    quoted.join(";").parse().ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn status_code_parse() {
-        assert!(StatusCode::parse(&"20 text/plain\r\n").is_ok());
-        assert!(StatusCode::parse(&"20").is_ok());
-        assert!(StatusCode::parse(&"30").is_ok());
-        assert!(StatusCode::parse(&"50").is_ok());
+        assert!(StatusCode::parse("20 text/plain\r\n").is_ok());
+        assert!(StatusCode::parse("20").is_ok());
+        assert!(StatusCode::parse("30").is_ok());
+        assert!(StatusCode::parse("50").is_ok());
+
+        assert!(StatusCode::parse("").is_err());
+    }
+
+    #[test]
+    fn mime_type_with_a_list() {
+        let mime_type = |header| match StatusCode::parse(header) {
+            Ok(StatusCode::Success { mime_type, .. }) => mime_type.unwrap(),
+            r => panic!("unexpected status: {:?}", r),
+        };
+
+        let list = mime_type("20 text/gemini; charset=iso-8859-1; lang=de,fr\r\n");
+        assert_eq!(list.essence_str(), "text/gemini");
+        assert_eq!(list.get_param("charset").unwrap(), "iso-8859-1");
+        assert_eq!(list.get_param("lang").unwrap(), "de,fr");
 
-        assert!(StatusCode::parse(&"").is_err());
+        let quoted = mime_type("20 text/gemini; lang=\"de,fr\"\r\n");
+        assert_eq!(quoted.get_param("lang").unwrap(), "de,fr");
     }
 }
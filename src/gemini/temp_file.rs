@@ -0,0 +1,89 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A hidden file that a download is streamed into, removed when dropped unless it's kept
+pub struct TempFile {
+    path: PathBuf,
+    file: File,
+    keep: bool,
+}
+
+impl TempFile {
+    /// Create a new temporary file in the directory, creating the directory if needed
+    ///
+    /// The file is created in the same directory as its final destination so moving it into
+    /// place is an atomic rename.
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        loop {
+            let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+            let path = dir.join(format!(".diosk-{}-{}.part", process::id(), id));
+
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    return Ok(Self {
+                        path,
+                        file,
+                        keep: false,
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Flush the contents to disk and keep the file, returning its path
+    pub fn keep(mut self) -> io::Result<PathBuf> {
+        self.file.sync_all()?;
+        self.keep = true;
+        Ok(self.path.clone())
+    }
+}
+
+impl Write for TempFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_unless_kept() {
+        let dir = std::env::temp_dir().join(format!("diosk-temp-file-{}", process::id()));
+
+        let mut dropped = TempFile::create(&dir).unwrap();
+        dropped.write_all(b"partial").unwrap();
+        let dropped_path = dropped.path.clone();
+        drop(dropped);
+        assert!(!dropped_path.exists());
+
+        let mut kept = TempFile::create(&dir).unwrap();
+        kept.write_all(b"complete").unwrap();
+        let kept_path = kept.keep().unwrap();
+        assert_eq!(fs::read(&kept_path).unwrap(), b"complete");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
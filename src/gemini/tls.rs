@@ -1,12 +1,96 @@
+use ring::digest;
 use rustls::{
-    Certificate, ClientConfig, ClientSession, DangerousClientConfig, RootCertStore,
-    ServerCertVerified, ServerCertVerifier, TLSError,
+    Certificate, ClientConfig, ClientSession, ClientSessionMemoryCache, DangerousClientConfig,
+    RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError, WebPKIVerifier,
 };
 use webpki::{DNSNameRef, InvalidDNSNameError};
 
-use std::sync::Arc;
+use super::temp_file::TempFile;
+use crate::dirs;
 
-pub struct NoCertificateVerification {}
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Refuse certificates that aren't for the host requested rather than warn about them
+static STRICT_HOSTNAMES: AtomicBool = AtomicBool::new(false);
+
+/// How many hosts sessions are kept for
+const SESSION_CACHE_SIZE: usize = 256;
+
+/// The certificate first seen for each host, one `host:port fingerprint` per line, in the data
+/// directory
+const KNOWN_HOSTS_FILE: &str = "known_hosts.txt";
+
+thread_local! {
+    /// Set while the thread makes a fetch the user didn't ask for
+    static BACKGROUND: Cell<bool> = const { Cell::new(false) };
+}
+
+/// How far a server's certificate can be trusted, going by the one it presented before
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trust {
+    /// Matches the certificate pinned on an earlier visit
+    Pinned,
+    /// The host's first certificate, pinned this session
+    FirstContact,
+    /// Differs from the pinned certificate or isn't valid, the user accepted it for this session
+    Accepted,
+    /// The host's first certificate, seen by a fetch in the background so it wasn't pinned
+    Unpinned,
+    /// Signed by one of the web's certificate authorities, nothing is pinned
+    Verified,
+}
+
+/// How a server's certificate is checked
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Verification {
+    /// Pinned the first time the host is connected to, trust on first use
+    #[default]
+    Tofu,
+    /// Has to chain to one of the web's root certificates, as a web browser would check it
+    Webpki,
+    /// Signed certificates are checked as the web's, self-signed ones are pinned
+    TofuWebpki,
+}
+
+impl Verification {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "tofu" => Some(Verification::Tofu),
+            "webpki" => Some(Verification::Webpki),
+            "tofu+webpki" => Some(Verification::TofuWebpki),
+            _ => None,
+        }
+    }
+}
+
+/// How certificates are checked, with exceptions for particular hosts
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerificationPolicy {
+    pub default: Verification,
+    pub hosts: HashMap<String, Verification>,
+}
+
+impl VerificationPolicy {
+    pub fn for_host(&self, host: &str) -> Verification {
+        self.hosts
+            .get(&host.to_ascii_lowercase())
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Default)]
+pub struct NoCertificateVerification {
+    /// The server presented its certificate, which it only does for a full handshake
+    verified: AtomicBool,
+}
 
 impl ServerCertVerifier for NoCertificateVerification {
     fn verify_server_cert(
@@ -16,24 +100,601 @@ impl ServerCertVerifier for NoCertificateVerification {
         _dns_name: DNSNameRef<'_>,
         _ocsp_response: &[u8],
     ) -> Result<ServerCertVerified, TLSError> {
+        self.verified.store(true, Ordering::SeqCst);
+
         // TODO: Implement TOFU
         // https://gemini.circumlunar.space/docs/tls-tutorial.gmi
         Ok(ServerCertVerified::assertion())
     }
 }
 
-pub fn client(host: &str) -> Result<ClientSession, InvalidDNSNameError> {
-    let config = new_config();
-    let dns_name = DNSNameRef::try_from_ascii_str(&host)?;
+/// Whether the handshake of a session resumed an earlier one
+pub struct Handshake(Arc<NoCertificateVerification>);
+
+impl Handshake {
+    /// Only meaningful once the handshake is complete
+    pub fn resumed(&self) -> bool {
+        !self.0.verified.load(Ordering::SeqCst)
+    }
+}
+
+pub fn client(host: &str) -> Result<(ClientSession, Handshake), InvalidDNSNameError> {
+    let verifier = Arc::new(NoCertificateVerification::default());
+    let config = new_config(verifier.clone());
+    let dns_name = DNSNameRef::try_from_ascii_str(host)?;
+
+    Ok((
+        ClientSession::new(&Arc::new(config), dns_name),
+        Handshake(verifier),
+    ))
+}
+
+pub fn set_strict_hostnames(strict: bool) {
+    STRICT_HOSTNAMES.store(strict, Ordering::SeqCst);
+}
+
+pub fn strict_hostnames() -> bool {
+    STRICT_HOSTNAMES.load(Ordering::SeqCst)
+}
+
+fn verification_policy() -> &'static Mutex<VerificationPolicy> {
+    static POLICY: OnceLock<Mutex<VerificationPolicy>> = OnceLock::new();
+    POLICY.get_or_init(Mutex::default)
+}
+
+pub fn set_verification(policy: VerificationPolicy) {
+    *verification_policy()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner()) = policy;
+}
+
+/// How the certificate of a host is to be checked
+pub fn verification(host: &str) -> Verification {
+    verification_policy()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .for_host(host)
+}
+
+/// Check a certificate chain against the web's root certificates, the error says why it isn't
+/// trusted
+pub fn verify_webpki(certificates: &[Certificate], host: &str) -> Result<(), String> {
+    static ROOTS: OnceLock<RootCertStore> = OnceLock::new();
+    let roots = ROOTS.get_or_init(|| {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        roots
+    });
+
+    verify_chain(roots, &WebPKIVerifier::new(), certificates, host)
+}
+
+/// Check a certificate chain against the roots at the time the verifier gives
+fn verify_chain(
+    roots: &RootCertStore,
+    verifier: &WebPKIVerifier,
+    certificates: &[Certificate],
+    host: &str,
+) -> Result<(), String> {
+    let dns_name = DNSNameRef::try_from_ascii_str(host).map_err(|e| e.to_string())?;
+    verifier
+        .verify_server_cert(roots, certificates, dns_name, &[])
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Check that a certificate is within its validity period, the error says why it isn't
+///
+/// Ones webpki can't read, e.g. the version 1 certificates some capsules still use, can't be
+/// checked and are let through.
+pub fn verify_validity(certificate: &Certificate, now: SystemTime) -> Result<(), String> {
+    let certificate = match webpki::EndEntityCert::from(&certificate.0) {
+        Ok(certificate) => certificate,
+        Err(_) => return Ok(()),
+    };
+    let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    // The dates are checked before the issuer is looked for, so without any trust anchors the
+    // certificate fails on them first if they're wrong and on its issuer otherwise
+    match certificate.verify_is_valid_tls_server_cert(
+        &[],
+        &webpki::TLSServerTrustAnchors(&[]),
+        &[],
+        webpki::Time::from_seconds_since_unix_epoch(seconds),
+    ) {
+        Err(
+            e @ (webpki::Error::CertExpired
+            | webpki::Error::CertNotValidYet
+            | webpki::Error::InvalidCertValidity),
+        ) => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a certificate is for the host, going by its subject alternative names as webpki checks
+/// them, or the common name of its subject when it has none
+///
+/// Ones webpki can't read are given the benefit of the doubt.
+pub fn covers(certificate: &Certificate, host: &str) -> bool {
+    let parsed = match webpki::EndEntityCert::from(&certificate.0) {
+        Ok(parsed) => parsed,
+        Err(_) => return true,
+    };
+    if DNSNameRef::try_from_ascii_str(host)
+        .is_ok_and(|host| parsed.verify_is_valid_for_dns_name(host).is_ok())
+    {
+        return true;
+    }
+
+    // Plenty of self-signed certificates only name their host in the subject, which webpki
+    // ignores
+    common_names(&certificate.0)
+        .is_some_and(|names| names.iter().any(|name| name_matches(name, host)))
+}
+
+/// Whether a name from a certificate is for the host, a wildcard standing for exactly one label at
+/// the start
+fn name_matches(name: &str, host: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    match name.strip_prefix("*.") {
+        Some(parent) => {
+            parent.contains('.')
+                && host
+                    .split_once('.')
+                    .is_some_and(|(label, rest)| !label.is_empty() && rest == parent)
+        }
+        None => name == host,
+    }
+}
+
+/// The common names in a certificate's subject, None when it has subject alternative names,
+/// which take their place, or can't be read
+fn common_names(der: &[u8]) -> Option<Vec<String>> {
+    // The common name attribute, 2.5.4.3, and the subject alternative name extension, 2.5.29.17
+    const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+    const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+    let (_, certificate, _) = der_element(der)?;
+    let (_, tbs_certificate, _) = der_element(certificate)?;
+    let mut fields = der_elements(tbs_certificate)?;
+    // The version is optional, after it come the serial, signature, issuer, validity and subject
+    if fields.first()?.0 == 0xa0 {
+        fields.remove(0);
+    }
+    let subject = fields.get(4)?.1;
+
+    if let Some((_, extensions)) = fields.iter().find(|(tag, _)| *tag == 0xa3) {
+        let (_, extensions, _) = der_element(extensions)?;
+        for (_, extension) in der_elements(extensions)? {
+            if der_element(extension)?.1 == SUBJECT_ALT_NAME {
+                return None;
+            }
+        }
+    }
+
+    let mut names = Vec::new();
+    for (_, set) in der_elements(subject)? {
+        for (_, attribute) in der_elements(set)? {
+            let (_, oid, value) = der_element(attribute)?;
+            if oid == COMMON_NAME {
+                let (_, value, _) = der_element(value)?;
+                names.push(String::from_utf8_lossy(value).into_owned());
+            }
+        }
+    }
+    Some(names)
+}
+
+/// The tag and contents of the DER element at the start of input, and what comes after it
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&length, rest) = rest.split_first()?;
+    let (length, rest) = match length {
+        0..=0x7f => (length as usize, rest),
+        0x81..=0x84 => {
+            let bytes = (length & 0x7f) as usize;
+            let length = rest
+                .get(..bytes)?
+                .iter()
+                .fold(0, |length, &b| length << 8 | b as usize);
+            (length, &rest[bytes..])
+        }
+        _ => return None,
+    };
+    Some((tag, rest.get(..length)?, &rest[length..]))
+}
+
+/// The tags and contents of the DER elements one after another in input
+fn der_elements(mut input: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut elements = Vec::new();
+    while !input.is_empty() {
+        let (tag, contents, rest) = der_element(input)?;
+        elements.push((tag, contents));
+        input = rest;
+    }
+    Some(elements)
+}
+
+/// Run a fetch the user didn't ask for, e.g. a prefetch, without pinning the certificates it sees
+pub fn in_background<T>(fetch: impl FnOnce() -> T) -> T {
+    struct Reset;
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            BACKGROUND.with(|background| background.set(false));
+        }
+    }
+
+    BACKGROUND.with(|background| background.set(true));
+    let _reset = Reset;
+    fetch()
+}
+
+fn known_hosts() -> &'static Mutex<KnownHosts> {
+    static KNOWN_HOSTS: OnceLock<Mutex<KnownHosts>> = OnceLock::new();
+    KNOWN_HOSTS
+        .get_or_init(|| Mutex::new(KnownHosts::new(&dirs::data_dir().join(KNOWN_HOSTS_FILE))))
+}
+
+/// Check a certificate against the one pinned for the host, pinning it if there isn't one
+///
+/// None when it differs from the pinned certificate and the user hasn't accepted it.
+pub fn trust(host: &str, port: u16, fingerprint: &str) -> Option<Trust> {
+    let pin = !BACKGROUND.with(Cell::get);
+
+    // A panic elsewhere while it was held doesn't leave the pins in a bad state
+    let mut known_hosts = known_hosts().lock().unwrap_or_else(|e| e.into_inner());
+    known_hosts.check(&format!("{}:{}", host, port), fingerprint, pin)
+}
+
+/// Check a certificate that's expired or not valid yet, which is only trusted once the user
+/// accepts it
+pub fn trust_invalid(host: &str, port: u16, fingerprint: &str) -> Option<Trust> {
+    let known_hosts = known_hosts().lock().unwrap_or_else(|e| e.into_inner());
+    known_hosts.check_invalid(&format!("{}:{}", host, port), fingerprint)
+}
+
+/// Trust a certificate that differs from the one pinned for the host or isn't valid, for this
+/// session or pinning it in place of the old one
+pub fn accept(host: &str, port: u16, fingerprint: &str, permanently: bool) -> io::Result<()> {
+    let mut known_hosts = known_hosts().lock().unwrap_or_else(|e| e.into_inner());
+    known_hosts.accept(&format!("{}:{}", host, port), fingerprint, permanently)
+}
+
+/// Certificates pinned the first time each host was connected to, trust on first use
+struct KnownHosts {
+    path: PathBuf,
+    pins: HashMap<String, String>,
+    /// Hosts first pinned this session
+    new: HashSet<String>,
+    /// Hosts and the certificates the user trusts for this session in place of their pins
+    accepted: HashSet<(String, String)>,
+}
+
+impl KnownHosts {
+    fn new(path: &Path) -> Self {
+        let pins = fs::read_to_string(path)
+            .map(|content| {
+                content
+                    .lines()
+                    .filter_map(|line| line.split_once(' '))
+                    .map(|(host, fingerprint)| (host.to_string(), fingerprint.trim().to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            path: path.to_path_buf(),
+            pins,
+            new: HashSet::new(),
+            accepted: HashSet::new(),
+        }
+    }
+
+    /// How far the certificate can be trusted, unknown hosts are only pinned with pin set
+    fn check(&mut self, host: &str, fingerprint: &str, pin: bool) -> Option<Trust> {
+        let trust = match self.pins.get(host) {
+            Some(pinned) if pinned != fingerprint => {
+                let accepted = (host.to_string(), fingerprint.to_string());
+                return self.accepted.contains(&accepted).then_some(Trust::Accepted);
+            }
+            Some(_) if self.new.contains(host) => Trust::FirstContact,
+            Some(_) => Trust::Pinned,
+            None if !pin => Trust::Unpinned,
+            None => {
+                self.pins.insert(host.to_string(), fingerprint.to_string());
+                self.new.insert(host.to_string());
+
+                // The pin is still used for the session if it can't be saved
+                let _ = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .and_then(|mut f| writeln!(f, "{} {}", host, fingerprint));
+                Trust::FirstContact
+            }
+        };
+        Some(trust)
+    }
+
+    /// Accepted for the session whether it's the one pinned or not, its dates are wrong
+    fn check_invalid(&self, host: &str, fingerprint: &str) -> Option<Trust> {
+        let accepted = (host.to_string(), fingerprint.to_string());
+        self.accepted.contains(&accepted).then_some(Trust::Accepted)
+    }
+
+    /// Pinning it doesn't make an invalid certificate valid, so it's accepted for the session
+    /// either way
+    fn accept(&mut self, host: &str, fingerprint: &str, permanently: bool) -> io::Result<()> {
+        self.accepted
+            .insert((host.to_string(), fingerprint.to_string()));
+        if !permanently {
+            return Ok(());
+        }
+
+        self.pins.insert(host.to_string(), fingerprint.to_string());
+        self.new.insert(host.to_string());
+
+        // Written whole and renamed into place, the old pin can't be left in a half written file
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let mut file = TempFile::create(dir)?;
+        for (host, fingerprint) in &self.pins {
+            writeln!(file, "{} {}", host, fingerprint)?;
+        }
+        let written = file.keep()?;
+        fs::rename(&written, &self.path).inspect_err(|_| {
+            let _ = fs::remove_file(&written);
+        })
+    }
+}
+
+/// The SHA-256 fingerprint of a certificate as colon separated hex
+pub fn fingerprint(certificate: &Certificate) -> String {
+    digest::digest(&digest::SHA256, &certificate.0)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
 
-    Ok(ClientSession::new(&Arc::new(config), dns_name))
+/// Sessions are shared by every connection for the life of the process so later connections to
+/// a host can resume rather than make a full handshake
+fn sessions() -> Arc<ClientSessionMemoryCache> {
+    static SESSIONS: OnceLock<Arc<ClientSessionMemoryCache>> = OnceLock::new();
+    SESSIONS
+        .get_or_init(|| ClientSessionMemoryCache::new(SESSION_CACHE_SIZE))
+        .clone()
 }
 
-fn new_config() -> ClientConfig {
+/// A config for one connection, the verifier records whether the session was resumed
+fn new_config(verifier: Arc<NoCertificateVerification>) -> ClientConfig {
     let mut cfg = ClientConfig::new();
+    cfg.set_persistence(sessions());
 
     let mut dangerous_config = DangerousClientConfig { cfg: &mut cfg };
-    dangerous_config.set_certificate_verifier(Arc::new(NoCertificateVerification {}));
+    dangerous_config.set_certificate_verifier(verifier);
 
     cfg
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::{env, process};
+
+    use super::*;
+
+    /// For example.org, valid from 2021 to 2031 and signed by a test root
+    const CERTIFICATE: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/example.org.der"
+    ));
+
+    /// Self-signed for *.example.org, named only by its subject's common name
+    const WILDCARD_CERTIFICATE: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/wildcard.example.org.der"
+    ));
+
+    /// The root the fixture certificate is signed by, valid from 2021 to 2041
+    const ROOT: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/root.der"
+    ));
+
+    fn year(year: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs((year - 1970) * 365 * 24 * 60 * 60)
+    }
+
+    #[test]
+    fn webpki() {
+        let verifier = WebPKIVerifier {
+            time: || {
+                let now = year(2026).duration_since(UNIX_EPOCH).unwrap();
+                Ok(webpki::Time::from_seconds_since_unix_epoch(now.as_secs()))
+            },
+        };
+        let chain = [Certificate(CERTIFICATE.to_vec())];
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(ROOT.to_vec())).unwrap();
+
+        assert_eq!(
+            verify_chain(&roots, &verifier, &chain, "example.org"),
+            Ok(())
+        );
+        assert!(verify_chain(&roots, &verifier, &chain, "example.com").is_err());
+
+        // Not signed by any of the web's roots
+        let mut web_roots = RootCertStore::empty();
+        web_roots.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        assert!(verify_chain(&web_roots, &verifier, &chain, "example.org").is_err());
+        assert!(verify_webpki(&chain, "example.org").is_err());
+    }
+
+    #[test]
+    fn validity() {
+        let certificate = Certificate(CERTIFICATE.to_vec());
+        assert!(verify_validity(&certificate, year(2020)).is_err());
+        assert!(verify_validity(&certificate, year(2026)).is_ok());
+        assert!(verify_validity(&certificate, year(2032)).is_err());
+    }
+
+    #[test]
+    fn host_names() {
+        let certificate = Certificate(CERTIFICATE.to_vec());
+        assert!(covers(&certificate, "example.org"));
+        assert!(covers(&certificate, "EXAMPLE.org"));
+        assert!(!covers(&certificate, "example.com"));
+        assert!(!covers(&certificate, "www.example.org"));
+
+        // Without subject alternative names the common name is used, a wildcard covers one label
+        let certificate = Certificate(WILDCARD_CERTIFICATE.to_vec());
+        assert_eq!(
+            common_names(&certificate.0),
+            Some(vec!["*.example.org".to_string()])
+        );
+        assert!(covers(&certificate, "foo.example.org"));
+        assert!(covers(&certificate, "FOO.example.org"));
+        assert!(!covers(&certificate, "example.org"));
+        assert!(!covers(&certificate, "a.b.example.org"));
+        assert!(!covers(&certificate, "foo.example.com"));
+
+        // The common name doesn't count when there are subject alternative names
+        assert_eq!(common_names(CERTIFICATE), None);
+        assert!(name_matches("example.org", "example.org."));
+        assert!(!name_matches("*.org", "example.org"));
+
+        // Nothing to go on, so nothing to warn about
+        assert!(covers(&Certificate(b"junk".to_vec()), "example.org"));
+    }
+
+    #[test]
+    fn trust_on_first_use() {
+        let path = env::temp_dir().join(format!("diosk-known-hosts-{}.txt", process::id()));
+        let path = path.as_path();
+        fs::write(path, "example.org:1965 AA:BB\n").unwrap();
+
+        let mut known_hosts = KnownHosts::new(path);
+        assert_eq!(
+            known_hosts.check("example.org:1965", "AA:BB", true),
+            Some(Trust::Pinned)
+        );
+        assert_eq!(known_hosts.check("example.org:1965", "CC:DD", true), None);
+        assert_eq!(
+            known_hosts.check("example.org:1966", "CC:DD", true),
+            Some(Trust::FirstContact)
+        );
+        assert_eq!(
+            known_hosts.check("example.org:1966", "CC:DD", true),
+            Some(Trust::FirstContact)
+        );
+
+        // Fetches in the background don't pin
+        assert_eq!(
+            known_hosts.check("example.org:1967", "EE:FF", false),
+            Some(Trust::Unpinned)
+        );
+        assert_eq!(
+            known_hosts.check("example.org:1967", "00:11", true),
+            Some(Trust::FirstContact)
+        );
+
+        // Pinned for good, the mismatch didn't replace the pin
+        let mut known_hosts = KnownHosts::new(path);
+        assert_eq!(
+            known_hosts.check("example.org:1966", "CC:DD", true),
+            Some(Trust::Pinned)
+        );
+        assert_eq!(
+            known_hosts.check("example.org:1965", "AA:BB", true),
+            Some(Trust::Pinned)
+        );
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn accepting_changed_certificates() {
+        let path = env::temp_dir().join(format!("diosk-accepted-hosts-{}.txt", process::id()));
+        let path = path.as_path();
+        fs::write(path, "example.org:1965 AA:BB\nexample.com:1965 CC:DD\n").unwrap();
+
+        // Accepted for the session, the pin stays as it was
+        let mut known_hosts = KnownHosts::new(path);
+        known_hosts
+            .accept("example.org:1965", "EE:FF", false)
+            .unwrap();
+        assert_eq!(
+            known_hosts.check("example.org:1965", "EE:FF", true),
+            Some(Trust::Accepted)
+        );
+        assert_eq!(known_hosts.check("example.org:1965", "00:11", true), None);
+        assert_eq!(
+            KnownHosts::new(path).check("example.org:1965", "EE:FF", true),
+            None
+        );
+
+        // Accepted for good, the new certificate replaces the pin
+        known_hosts
+            .accept("example.org:1965", "EE:FF", true)
+            .unwrap();
+        assert_eq!(
+            known_hosts.check("example.org:1965", "EE:FF", true),
+            Some(Trust::FirstContact)
+        );
+        let mut known_hosts = KnownHosts::new(path);
+        let _ = fs::remove_file(path);
+        assert_eq!(
+            known_hosts.check("example.org:1965", "EE:FF", true),
+            Some(Trust::Pinned)
+        );
+        assert_eq!(
+            known_hosts.check("example.com:1965", "CC:DD", true),
+            Some(Trust::Pinned)
+        );
+    }
+
+    #[test]
+    fn accepting_expired_certificates() {
+        let path = env::temp_dir().join(format!("diosk-expired-hosts-{}.txt", process::id()));
+        let path = path.as_path();
+        let certificate = Certificate(CERTIFICATE.to_vec());
+        let fingerprint = fingerprint(&certificate);
+        fs::write(path, format!("example.org:1965 {}\n", fingerprint)).unwrap();
+        assert!(verify_validity(&certificate, year(2032)).is_err());
+
+        // Matching the pin isn't enough
+        let mut known_hosts = KnownHosts::new(path);
+        assert_eq!(
+            known_hosts.check_invalid("example.org:1965", &fingerprint),
+            None
+        );
+
+        // Accepted once it's shown with the warning like a changed certificate
+        known_hosts
+            .accept("example.org:1965", &fingerprint, false)
+            .unwrap();
+        assert_eq!(
+            known_hosts.check_invalid("example.org:1965", &fingerprint),
+            Some(Trust::Accepted)
+        );
+        assert_eq!(
+            KnownHosts::new(path).check_invalid("example.org:1965", &fingerprint),
+            None
+        );
+
+        // Pinning it doesn't make it valid, it's still only accepted for the session
+        known_hosts
+            .accept("example.org:1966", &fingerprint, true)
+            .unwrap();
+        assert_eq!(
+            known_hosts.check_invalid("example.org:1966", &fingerprint),
+            Some(Trust::Accepted)
+        );
+        assert_eq!(
+            KnownHosts::new(path).check_invalid("example.org:1966", &fingerprint),
+            None
+        );
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,129 @@
+//! Formatting of sizes, durations, and URLs for display
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+/// A byte count using binary units, e.g. "4.2 MiB"
+pub fn bytes(n: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+
+    let mut size = n as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+
+    format!("{:.1} {}", size, unit)
+}
+
+/// A coarse duration, e.g. "5m"
+pub fn duration(d: Duration) -> String {
+    let secs = d.as_secs();
+
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// A URL with percent-escapes decoded, e.g. "/%E6%97%A5%E8%A8%98/" → "/日記/"
+///
+/// Only for showing, requests need the encoded form. Escapes that aren't valid UTF-8 or that
+/// decode to control characters are left as they are.
+pub fn url(url: &str) -> Cow<'_, str> {
+    if !url.contains('%') {
+        return Cow::Borrowed(url);
+    }
+
+    let mut decoded = String::with_capacity(url.len());
+    let mut rest = url;
+    while let Some(start) = rest.find('%') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        // A run of escapes decoded together, characters can take several of them
+        let mut bytes = Vec::new();
+        let mut run = rest;
+        while let Some(byte) = run
+            .strip_prefix('%')
+            .and_then(|hex| hex.get(..2))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            bytes.push(byte);
+            run = &run[3..];
+        }
+
+        if bytes.is_empty() {
+            decoded.push('%');
+            rest = &rest[1..];
+            continue;
+        }
+
+        // Each decoded byte came from three characters of the escapes
+        let escapes = &rest[..rest.len() - run.len()];
+        let mut decoded_bytes = 0;
+        for chunk in bytes.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                let len = c.len_utf8();
+                if c.is_control() {
+                    decoded.push_str(&escapes[decoded_bytes * 3..(decoded_bytes + len) * 3]);
+                } else {
+                    decoded.push(c);
+                }
+                decoded_bytes += len;
+            }
+
+            let len = chunk.invalid().len();
+            decoded.push_str(&escapes[decoded_bytes * 3..(decoded_bytes + len) * 3]);
+            decoded_bytes += len;
+        }
+        rest = run;
+    }
+    decoded.push_str(rest);
+
+    Cow::Owned(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(bytes(0), "0 B");
+        assert_eq!(bytes(1023), "1023 B");
+        assert_eq!(bytes(1024), "1.0 KiB");
+        assert_eq!(bytes(4_404_019), "4.2 MiB");
+    }
+
+    #[test]
+    fn formats_duration() {
+        assert_eq!(duration(Duration::from_secs(5)), "5s");
+        assert_eq!(duration(Duration::from_secs(300)), "5m");
+        assert_eq!(duration(Duration::from_secs(7200)), "2h");
+        assert_eq!(duration(Duration::from_secs(172_800)), "2d");
+    }
+
+    #[test]
+    fn decodes_urls() {
+        assert_eq!(
+            url("gemini://a.org/%E6%97%A5%E8%A8%98/"),
+            "gemini://a.org/日記/"
+        );
+        assert_eq!(url("/a%20b%2Fc"), "/a b/c");
+        // Invalid UTF-8, control characters, and things that aren't escapes
+        assert_eq!(url("/%E6%97x%FF%0A"), "/%E6%97x%FF%0A");
+        assert_eq!(url("/100%/%zz%4"), "/100%/%zz%4");
+        assert_eq!(url("/%e6%97%a5%E6"), "/日%E6");
+    }
+}
@@ -1,42 +1,415 @@
-use std::sync::{Arc, Mutex};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
 
-use crossterm::event::{read, Event, KeyCode, KeyEvent};
-use log::info;
+use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
+use log::{info, warn};
 
 use crate::state::input::InputEnterResult;
-use crate::state::{Mode, State};
+use crate::state::{Align, Event as StateEvent, Mode, Paste, State};
+use crate::terminal::clipboard::LateReply;
 
 mod edit;
+pub mod keymap;
 
 use edit::Command;
+use keymap::{Action, Binding, Lookup};
 
-pub fn run(state: Arc<Mutex<State>>) {
-    loop {
-        let event = read().unwrap();
-        let mut state = state.lock().expect("poisoned");
+/// How long to wait for an event before sending the worker a tick
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-        match event {
-            Event::Key(event) => handle_key_event(&mut state, event),
-            Event::Mouse(event) => info!("{:?}", event),
-            Event::Resize(width, height) => state.new_size(width, height),
+/// Consecutive read errors after which the terminal is assumed to have gone away
+const MAX_READ_ERRORS: usize = 10;
+
+/// Reads the terminal and passes what it reads to the worker, which owns State
+pub struct Reader {
+    tx: mpsc::Sender<StateEvent>,
+    pauses: mpsc::Receiver<mpsc::Receiver<()>>,
+}
+
+/// A way for the worker to take the terminal from the reader, e.g. while a handler runs
+#[derive(Clone)]
+pub struct Pauser {
+    pauses: mpsc::SyncSender<mpsc::Receiver<()>>,
+}
+
+/// The reader stays paused until this is dropped
+pub struct Paused {
+    _resume: mpsc::Sender<()>,
+}
+
+impl Reader {
+    pub fn new(tx: mpsc::Sender<StateEvent>) -> (Self, Pauser) {
+        let (pauser, pauses) = mpsc::sync_channel(0);
+        (Self { tx, pauses }, Pauser { pauses: pauser })
+    }
+
+    /// Read until the worker stops listening, a tick is sent every poll interval without input
+    pub fn run(self) {
+        let mut read_errors = 0;
+        let mut late_reply = LateReply::default();
+
+        loop {
+            if let Ok(resumed) = self.pauses.try_recv() {
+                // Errors once the Paused guard is dropped
+                let _ = resumed.recv();
+            }
+
+            let event = match next_event() {
+                Ok(event) => {
+                    read_errors = 0;
+                    event
+                }
+                Err(e) if !is_fatal(&e) && read_errors < MAX_READ_ERRORS => {
+                    read_errors += 1;
+                    warn!("unable to read input: {:?}", e);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("input has gone away: {:?}", e);
+                    let _ = self.tx.send(StateEvent::InputClosed);
+                    break;
+                }
+            };
+
+            // No catch-all, new kinds of event should be handled deliberately
+            let event = match event {
+                Some(Event::Key(event)) if late_reply.swallow(&event) => continue,
+                Some(Event::Key(event)) => StateEvent::Key(event),
+                Some(Event::Mouse(event)) => {
+                    info!("{:?}", event);
+                    continue;
+                }
+                Some(Event::Resize(width, height)) => StateEvent::Resize(width, height),
+                None => StateEvent::Tick,
+            };
+
+            // The worker has shut down
+            if self.tx.send(event).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Pauser {
+    /// Stop the reader reading the terminal, waiting for it to finish the poll it's in
+    pub fn pause(&self) -> Paused {
+        let (resume, resumed) = mpsc::channel();
+        // Fails when the reader has already stopped, which is as good as paused
+        let _ = self.pauses.send(resumed);
+        Paused { _resume: resume }
+    }
+}
+
+/// Work that's due whether or not a key was pressed, run after each input event
+pub(crate) fn tick(state: &mut State) {
+    expire_pending_keys(state);
+    state.tick_auto_reload();
+    state.tick_deadline();
+    state.tick_clock();
+}
+
+/// Run the commands in a startup script, one per line, before any input is handled
+///
+/// Errors don't stop the script, they're collected for :messages. A missing script is fine.
+pub fn run_script(state: &mut State, path: &Path) {
+    let script = match fs::read_to_string(path) {
+        Ok(script) => script,
+        Err(e) if e.kind() == ErrorKind::NotFound => return,
+        Err(e) => {
+            state.set_error_message(format!("Unable to read {}: {}", path.display(), e));
+            state.clear_screen_and_render_page();
+            return;
+        }
+    };
+
+    // Nothing in the script is there to be repeated, a :set in it isn't what . should do
+    let last_command = state.last_command.take();
+    let mut errors = Vec::new();
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        run_command(state, InputEnterResult::from(line));
+        if let Some(e) = state.take_error_message() {
+            errors.push(format!("{} line {}: {}", path.display(), number + 1, e));
+        }
+    }
+    state.last_command = last_command;
+
+    if !errors.is_empty() {
+        for e in &errors {
+            state.record_message(e);
+        }
+        state.set_error_message(format!(
+            "{} errors in {}, see :messages",
+            errors.len(),
+            path.display()
+        ));
+        state.clear_screen_and_render_page();
+    }
+}
+
+/// The next event if there is one within the poll interval
+fn next_event() -> crossterm::Result<Option<Event>> {
+    match poll(POLL_INTERVAL)? {
+        true => read().map(Some),
+        false => Ok(None),
+    }
+}
+
+/// Whether a read error means the terminal has gone away, e.g. it was closed, rather than being
+/// worth trying again
+fn is_fatal(e: &crossterm::ErrorKind) -> bool {
+    // EIO and ENXIO, what reading a tty that's been hung up gives
+    const TTY_GONE: [i32; 2] = [5, 6];
+
+    match e {
+        crossterm::ErrorKind::IoError(e) => {
+            matches!(
+                e.kind(),
+                ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe | ErrorKind::NotConnected
+            ) || e
+                .raw_os_error()
+                .is_some_and(|code| TTY_GONE.contains(&code))
+        }
+        _ => false,
+    }
+}
+
+/// Finish a key sequence that has waited too long for its next key, running the action bound to
+/// the keys pressed so far if there is one
+fn expire_pending_keys(state: &mut State) {
+    if !state.pending_keys.timed_out() {
+        return;
+    }
+
+    let count = state.pending_keys.count();
+    let keys = state.pending_keys.take();
+    match state.keymap.binding(&keys) {
+        Some(binding) => run_binding(state, binding, count),
+        None => state.clear_screen_and_render_page(),
+    }
+}
+
+fn run_binding(state: &mut State, binding: Binding, count: usize) {
+    match binding {
+        Binding::Action(action) => run_action(state, action, count),
+        Binding::Argument(Action::SetMark, c) => state.set_mark(c),
+        Binding::Argument(Action::GoToMark, c) => state.go_to_mark(c),
+        Binding::Argument(action, _) => run_action(state, action, count),
+        Binding::Commands(commands) => {
+            for command in commands.split('|').map(str::trim) {
+                run_command(state, InputEnterResult::from(command));
+            }
+        }
+    }
+}
+
+fn run_command(state: &mut State, command: InputEnterResult) {
+    if command.is_repeatable() {
+        state.last_command = Some(command.clone());
+    }
+
+    match command {
+        InputEnterResult::Navigate(url) => {
+            state.request(&url);
+            state.clear_screen_and_render_page();
         }
+        InputEnterResult::EditUrl => {
+            state.edit_url();
+        }
+        InputEnterResult::Info => {
+            state.info();
+        }
+        InputEnterResult::Reload { force } => {
+            state.reload(force);
+        }
+        InputEnterResult::Quit { force: false } => {
+            state.confirm_quit();
+        }
+        InputEnterResult::Quit { force: true } => {
+            state.quit();
+        }
+        InputEnterResult::Bindings => {
+            state.request("about:bindings");
+        }
+        InputEnterResult::Map { keys, commands } => {
+            state.mode = Mode::Normal;
+            state.map(&keys, &commands);
+        }
+        InputEnterResult::Unmap(keys) => {
+            state.mode = Mode::Normal;
+            state.unmap(&keys);
+        }
+        InputEnterResult::Set { key, value } => {
+            state.mode = Mode::Normal;
+            state.set_option(&key, &value);
+        }
+        InputEnterResult::Bookmark => {
+            state.mode = Mode::Normal;
+            state.bookmark();
+        }
+        InputEnterResult::ExportBookmarks { path, force } => {
+            state.mode = Mode::Normal;
+            state.export_bookmarks(&path, force);
+        }
+        InputEnterResult::ImportBookmarks { path, all_schemes } => {
+            state.mode = Mode::Normal;
+            state.import_bookmarks(&path, all_schemes);
+        }
+        InputEnterResult::ClearCache => {
+            state.mode = Mode::Normal;
+            state.clear_cache();
+        }
+        InputEnterResult::ToggleOffline => {
+            state.mode = Mode::Normal;
+            state.toggle_offline();
+        }
+        InputEnterResult::AutoReload(seconds) => {
+            state.mode = Mode::Normal;
+            state.set_auto_reload(seconds);
+        }
+        InputEnterResult::Encoding(label) => {
+            state.mode = Mode::Normal;
+            state.set_encoding(&label);
+        }
+        InputEnterResult::FindLink(text) => {
+            state.mode = Mode::Normal;
+            state.find_link(&text);
+        }
+        InputEnterResult::VSplit => {
+            state.mode = Mode::Normal;
+            state.vsplit();
+        }
+        InputEnterResult::ClosePane => {
+            state.mode = Mode::Normal;
+            state.close_pane();
+        }
+        InputEnterResult::OpenOther(url) => {
+            state.mode = Mode::Normal;
+            state.open_in_other_pane(url.as_deref());
+        }
+        InputEnterResult::Hardcopy {
+            path,
+            plain,
+            status_line,
+        } => {
+            state.mode = Mode::Normal;
+            state.hardcopy(path.as_deref(), plain, status_line);
+        }
+        InputEnterResult::Invalid(input) => {
+            state.mode = Mode::Normal;
+            state.set_error_message(format!("Invalid command: {}", input));
+            state.clear_screen_and_render_page();
+        }
+    }
+}
 
-        if state.terminated() {
-            break;
+/// Run an action, motions are repeated count times and other actions ignore the count
+fn run_action(state: &mut State, action: Action, count: usize) {
+    match action {
+        Action::Input => state.input(),
+        Action::Search => state.search(),
+        Action::OpenUrl => state.open_url(),
+        Action::FindLink => state.open_find_link(),
+        Action::EditUrl => state.edit_url(),
+        Action::Paste => state.paste(Paste::Go),
+        Action::PastePrompt => state.paste(Paste::Prompt),
+        Action::Reload => run_command(state, InputEnterResult::Reload { force: true }),
+        Action::DeleteEntry => state.delete_entry(),
+        Action::Undo => state.undo(),
+        Action::Close => state.close_overlay(),
+        Action::Down => state.down_by(count),
+        Action::Up => state.up_by(count),
+        Action::PanLeft => state.pan(-(count as isize)),
+        Action::PanRight => state.pan(count as isize),
+        Action::Top => state.top(),
+        Action::Bottom => state.bottom(),
+        Action::AlignTop => state.align_current_line(Align::Top),
+        Action::AlignCenter => state.align_current_line(Align::Center),
+        Action::AlignBottom => state.align_current_line(Align::Bottom),
+        Action::JumpBack => state.jump_back(),
+        Action::JumpForward => state.jump_forward(),
+        Action::NextPane => state.next_pane(),
+        Action::EnterOther => run_command(state, InputEnterResult::OpenOther(None)),
+        Action::NextUrl => state.next_url(),
+        Action::ToggleBlock => state.toggle_block(),
+        Action::SearchNext => state.search_next(count),
+        Action::SearchPrevious => state.search_previous(count),
+        // Only bound with a mark name, that's handled by run_binding
+        Action::SetMark | Action::GoToMark => {}
+        Action::Enter => state.enter(),
+        Action::Escape => {
+            if state.loading() {
+                state.cancel();
+            } else if state.has_search() {
+                state.clear_search();
+            } else {
+                state.close_overlay();
+            }
         }
+        Action::Parent => state.parent(),
+        Action::Root => state.root(),
+        Action::Repeat => match state.last_command.clone() {
+            Some(command) => {
+                state.set_message(format!("Repeated :{}", command));
+                run_command(state, command);
+            }
+            None => {
+                state.set_error_message("Nothing to repeat".to_string());
+                state.clear_screen_and_render_page();
+            }
+        },
     }
 }
 
-fn handle_key_event(state: &mut State, event: KeyEvent) {
-    state.clear_error_message();
+pub(crate) fn handle_key_event(state: &mut State, event: KeyEvent) {
+    state.clear_messages();
 
     match state.mode() {
-        Mode::Normal => match event.code {
-            KeyCode::Char(':') => state.input(),
-            KeyCode::Char('/') => state.search(),
-            KeyCode::Char('j') => state.down(),
-            KeyCode::Char('k') => state.up(),
-            KeyCode::Enter => state.enter(),
+        Mode::Normal => {
+            // Esc abandons an unfinished sequence rather than being part of it
+            if event.code == KeyCode::Esc && !state.pending_keys.is_empty() {
+                state.pending_keys.take();
+                state.clear_screen_and_render_page();
+                return;
+            }
+
+            if let (KeyCode::Char(c), KeyModifiers::NONE) = (event.code, event.modifiers) {
+                if state.pending_keys.push_digit(c) {
+                    state.clear_screen_and_render_page();
+                    return;
+                }
+            }
+
+            let keys = state.pending_keys.push(keymap::normalize(event)).to_vec();
+            match state.keymap.lookup(&keys) {
+                Lookup::Binding(binding) => {
+                    let count = state.pending_keys.count();
+                    state.pending_keys.take();
+                    run_binding(state, binding, count);
+                }
+                Lookup::Prefix => state.clear_screen_and_render_page(),
+                Lookup::Unbound => {
+                    if keys.len() > 1 {
+                        info!("unbound key sequence: {}", state.pending_keys.display());
+                    }
+                    state.pending_keys.take();
+                    state.clear_screen_and_render_page();
+                }
+            }
+        }
+
+        Mode::Confirm => match event.code {
+            KeyCode::Char('y') | KeyCode::Char('Y') => state.answer(true),
+            KeyCode::Char('a') | KeyCode::Char('A') => state.answer_always(),
+            KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => state.answer(false),
             _ => {}
         },
 
@@ -45,23 +418,34 @@ fn handle_key_event(state: &mut State, event: KeyEvent) {
                 match command {
                     Command::DeleteWord => {
                         state.input.delete_word();
-                        state.clear_screen_and_render_page();
+                        input_changed(state);
                     }
                     Command::DeleteChar => {
                         state.input.delete_char();
-                        state.clear_screen_and_render_page();
+                        input_changed(state);
                     }
                     Command::AddChar(c) => {
                         state.input.input_char(c);
-                        state.clear_screen_and_render_page();
+                        input_changed(state);
                     }
                     Command::Up => {
                         state.input.up(state.mode);
-                        state.clear_screen_and_render_page();
+                        input_changed(state);
                     }
                     Command::Down => {
                         state.input.down(state.mode);
-                        state.clear_screen_and_render_page();
+                        input_changed(state);
+                    }
+                    Command::Complete if matches!(state.mode, Mode::Search) => {}
+                    Command::Complete => {
+                        state.complete();
+                        input_changed(state);
+                    }
+                    Command::Enter if matches!(state.mode, Mode::Search) => {
+                        match state.input.input.is_empty() {
+                            true => state.cancel_search(),
+                            false => state.start_search(),
+                        }
                     }
                     Command::Enter => {
                         if state.input.input.is_empty() {
@@ -69,28 +453,10 @@ fn handle_key_event(state: &mut State, event: KeyEvent) {
                             return;
                         }
 
-                        if matches!(state.mode, Mode::Input) {
-                            match state.input.enter(state.mode) {
-                                InputEnterResult::Navigate(url) => {
-                                    state.request(&url);
-                                    state.clear_screen_and_render_page();
-                                }
-                                InputEnterResult::Quit => {
-                                    state.quit();
-                                }
-                                InputEnterResult::Invalid(input) => {
-                                    state.mode = Mode::Normal;
-                                    state.set_error_message(format!("Invalid command: {}", input));
-                                    state.clear_screen_and_render_page();
-                                }
-                            }
-                        } else {
-                            state.input.search();
-                            state.mode = Mode::Normal;
-                            state.set_error_message(format!("Search not implemented"));
-                            state.clear_screen_and_render_page();
-                        }
+                        let command = state.input.enter();
+                        run_command(state, command);
                     }
+                    Command::Esc if matches!(state.mode, Mode::Search) => state.cancel_search(),
                     Command::Esc => {
                         state.input.cancel();
                         state.mode = Mode::Normal;
@@ -103,3 +469,30 @@ fn handle_key_event(state: &mut State, event: KeyEvent) {
 
     info!("{:?}", &state);
 }
+
+/// Typing a search pattern previews its matches, commands are only run once they're entered
+fn input_changed(state: &mut State) {
+    match state.mode() {
+        Mode::Search => state.preview_search(),
+        _ => state.clear_screen_and_render_page(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn is_fatal() {
+        let io_error = |e: io::Error| super::is_fatal(&crossterm::ErrorKind::IoError(e));
+
+        assert!(io_error(ErrorKind::UnexpectedEof.into()));
+        assert!(io_error(io::Error::from_raw_os_error(5)));
+        assert!(!io_error(ErrorKind::Interrupted.into()));
+        assert!(!super::is_fatal(&crossterm::ErrorKind::FmtError(
+            std::fmt::Error
+        )));
+    }
+}
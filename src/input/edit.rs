@@ -8,6 +8,7 @@ pub enum Command {
     Esc,
     Up,
     Down,
+    Complete,
 }
 
 pub fn command(key_event: KeyEvent) -> Option<Command> {
@@ -21,10 +22,9 @@ pub fn command(key_event: KeyEvent) -> Option<Command> {
         (KeyCode::Esc, _) => Some(Esc),
         (KeyCode::Up, _) => Some(Up),
         (KeyCode::Down, _) => Some(Down),
+        (KeyCode::Tab, KeyModifiers::NONE) => Some(Complete),
 
-        (key_code, modifiers) => {
-            log::info!("{:?} {:?}", key_code, modifiers);
-            None
-        }
+        // Not logged, keys typed here can be sensitive input
+        _ => None,
     }
 }
@@ -0,0 +1,558 @@
+use std::time::{Duration, Instant};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// How long to wait for the rest of a key sequence
+pub const SEQUENCE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Counts are capped so a held down key can't overflow them
+const MAX_COUNT: usize = 9999;
+
+/// Something a key sequence in Normal mode does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Input,
+    Search,
+    OpenUrl,
+    EditUrl,
+    Reload,
+    DeleteEntry,
+    /// Put back the last entry deleted from an internal list page
+    Undo,
+    Close,
+    Down,
+    Up,
+    Top,
+    Enter,
+    /// Cancel the current load, stop highlighting a search, or close an internal page
+    Escape,
+    Parent,
+    Root,
+    /// Repeat the last command
+    Repeat,
+    Bottom,
+    JumpBack,
+    JumpForward,
+    /// Takes the name of the mark as the next key
+    SetMark,
+    GoToMark,
+    /// Scroll so the current line is at the top, middle, or bottom of the screen
+    AlignTop,
+    AlignCenter,
+    AlignBottom,
+    /// Move focus to the other pane of a split
+    NextPane,
+    /// Open the link on the current line in the other pane
+    EnterOther,
+    /// Select the next URL in a plain text line for Enter to follow
+    NextUrl,
+    /// Collapse or expand the preformatted block the current line is in
+    ToggleBlock,
+    /// Move to the next or previous match of the last search
+    SearchNext,
+    SearchPrevious,
+    /// Open the command prompt pre-filled with find-link
+    FindLink,
+    /// Scroll unwrapped lines left or right
+    PanLeft,
+    PanRight,
+    /// Go to the URL on the clipboard, or open the go prompt with it
+    Paste,
+    PastePrompt,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Input => "input",
+            Action::Search => "search",
+            Action::OpenUrl => "open-url",
+            Action::EditUrl => "edit-url",
+            Action::Reload => "reload",
+            Action::DeleteEntry => "delete-entry",
+            Action::Undo => "undo",
+            Action::Close => "close",
+            Action::Down => "down",
+            Action::Up => "up",
+            Action::Top => "top",
+            Action::Enter => "enter",
+            Action::Escape => "escape",
+            Action::Parent => "parent",
+            Action::Root => "root",
+            Action::Repeat => "repeat",
+            Action::Bottom => "bottom",
+            Action::JumpBack => "jump-back",
+            Action::JumpForward => "jump-forward",
+            Action::SetMark => "set-mark",
+            Action::GoToMark => "go-to-mark",
+            Action::AlignTop => "align-top",
+            Action::AlignCenter => "align-center",
+            Action::AlignBottom => "align-bottom",
+            Action::NextPane => "next-pane",
+            Action::EnterOther => "enter-other",
+            Action::NextUrl => "next-url",
+            Action::ToggleBlock => "toggle-block",
+            Action::SearchNext => "search-next",
+            Action::SearchPrevious => "search-previous",
+            Action::FindLink => "find-link",
+            Action::PanLeft => "pan-left",
+            Action::PanRight => "pan-right",
+            Action::Paste => "paste",
+            Action::PastePrompt => "paste-prompt",
+        }
+    }
+
+    /// Whether the action needs a character typed after its keys, like the name of a mark
+    fn takes_char(self) -> bool {
+        matches!(self, Action::SetMark | Action::GoToMark)
+    }
+}
+
+const DEFAULT_BINDINGS: &[(&str, Action)] = &[
+    (":", Action::Input),
+    ("/", Action::Search),
+    ("n", Action::SearchNext),
+    ("N", Action::SearchPrevious),
+    (";", Action::FindLink),
+    ("o", Action::OpenUrl),
+    ("O", Action::EditUrl),
+    ("p", Action::Paste),
+    ("P", Action::PastePrompt),
+    ("R", Action::Reload),
+    ("d", Action::DeleteEntry),
+    ("u", Action::Undo),
+    ("q", Action::Close),
+    ("j", Action::Down),
+    ("k", Action::Up),
+    ("h", Action::PanLeft),
+    ("l", Action::PanRight),
+    ("<Left>", Action::PanLeft),
+    ("<Right>", Action::PanRight),
+    ("gg", Action::Top),
+    ("gu", Action::Parent),
+    ("gU", Action::Root),
+    (".", Action::Repeat),
+    ("G", Action::Bottom),
+    ("<C-o>", Action::JumpBack),
+    // Terminals send Ctrl-I as Tab
+    ("<Tab>", Action::JumpForward),
+    ("m", Action::SetMark),
+    ("'", Action::GoToMark),
+    ("zt", Action::AlignTop),
+    ("zz", Action::AlignCenter),
+    ("zb", Action::AlignBottom),
+    ("<C-w>", Action::NextPane),
+    ("<M-Enter>", Action::EnterOther),
+    ("]u", Action::NextUrl),
+    ("za", Action::ToggleBlock),
+    ("<Enter>", Action::Enter),
+    ("<Esc>", Action::Escape),
+];
+
+/// What a key sequence is bound to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Binding {
+    Action(Action),
+    /// An action and the character typed after its keys
+    Argument(Action, char),
+    /// Commands mapped with :map, separated by |
+    Commands(String),
+}
+
+/// The result of looking up the keys pressed so far
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    Binding(Binding),
+    /// The keys start a longer sequence, wait for more
+    Prefix,
+    Unbound,
+}
+
+/// Normal mode key sequences and what they're bound to
+///
+/// Mappings made with :map take precedence over the built in bindings.
+#[derive(Debug)]
+pub struct Keymap {
+    mappings: Vec<(Vec<KeyEvent>, String)>,
+    bindings: Vec<(Vec<KeyEvent>, Action)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let mut keymap = Self {
+            mappings: Vec::new(),
+            bindings: Vec::new(),
+        };
+        for (keys, action) in DEFAULT_BINDINGS {
+            keymap.bind(&parse(keys).expect("valid default binding"), *action);
+        }
+        keymap
+    }
+}
+
+impl Keymap {
+    /// Bind a key sequence to an action, replacing any existing binding for the same sequence
+    pub fn bind(&mut self, keys: &[KeyEvent], action: Action) {
+        self.bindings.retain(|(bound, _)| bound != keys);
+        self.bindings.push((keys.to_vec(), action));
+    }
+
+    /// Map a key sequence to commands for the session, replacing any existing mapping
+    pub fn map(&mut self, keys: &[KeyEvent], commands: &str) {
+        self.unmap(keys);
+        self.mappings.push((keys.to_vec(), commands.to_string()));
+    }
+
+    /// Remove a mapping, returning false when there wasn't one
+    pub fn unmap(&mut self, keys: &[KeyEvent]) -> bool {
+        let len = self.mappings.len();
+        self.mappings.retain(|(mapped, _)| mapped != keys);
+        self.mappings.len() != len
+    }
+
+    /// Look up the keys pressed so far, a sequence that is bound but also starts a longer one
+    /// waits for more keys
+    pub fn lookup(&self, keys: &[KeyEvent]) -> Lookup {
+        if let Some((last, bound)) = keys.split_last() {
+            if let (Some(Binding::Action(action)), KeyCode::Char(c)) =
+                (self.binding(bound), last.code)
+            {
+                if action.takes_char() {
+                    return Lookup::Binding(Binding::Argument(action, c));
+                }
+            }
+        }
+
+        if let Some(Binding::Action(action)) = self.binding(keys) {
+            if action.takes_char() {
+                return Lookup::Prefix;
+            }
+        }
+
+        let is_prefix = self
+            .mappings
+            .iter()
+            .map(|(bound, _)| bound)
+            .chain(self.bindings.iter().map(|(bound, _)| bound))
+            .any(|bound| bound.len() > keys.len() && bound.starts_with(keys));
+
+        match (is_prefix, self.binding(keys)) {
+            (true, _) => Lookup::Prefix,
+            (false, Some(binding)) => Lookup::Binding(binding),
+            (false, None) => Lookup::Unbound,
+        }
+    }
+
+    /// What exactly these keys are bound to, mappings first
+    pub fn binding(&self, keys: &[KeyEvent]) -> Option<Binding> {
+        let mapping = self
+            .mappings
+            .iter()
+            .find(|(mapped, _)| mapped == keys)
+            .map(|(_, commands)| Binding::Commands(commands.clone()));
+
+        mapping.or_else(|| {
+            self.bindings
+                .iter()
+                .find(|(bound, _)| bound == keys)
+                .map(|(_, action)| Binding::Action(*action))
+        })
+    }
+
+    /// Gemtext listing the mappings then the built in bindings, in the order they're resolved
+    pub fn page(&self) -> String {
+        let mut content = String::from("# Key bindings\n\n## Mappings\n\n");
+        if self.mappings.is_empty() {
+            content.push_str("None, add one with :map <keys> <command>\n");
+        }
+        for (keys, commands) in &self.mappings {
+            content.push_str(&format!("{:<8} :{}\n", display_keys(keys), commands));
+        }
+
+        content.push_str("\n## Built in\n\n");
+        for (keys, action) in &self.bindings {
+            let shadowed = self.mappings.iter().any(|(mapped, _)| mapped == keys);
+            content.push_str(&format!(
+                "{:<8} {}{}\n",
+                display_keys(keys),
+                action.name(),
+                if shadowed { " (mapped)" } else { "" }
+            ));
+        }
+
+        content
+    }
+}
+
+/// Keys pressed so far in an unfinished sequence, and the count typed before it
+#[derive(Debug, Default)]
+pub struct PendingKeys {
+    count: Option<usize>,
+    keys: Vec<KeyEvent>,
+    since: Option<Instant>,
+}
+
+impl PendingKeys {
+    /// Add a digit to the count, returning false when the digit should be treated as a key
+    ///
+    /// A count can only come before the keys of a sequence and can't start with 0.
+    pub fn push_digit(&mut self, c: char) -> bool {
+        let digit = match c.to_digit(10) {
+            Some(digit) if !self.keys.is_empty() || (digit == 0 && self.count.is_none()) => {
+                return false
+            }
+            Some(digit) => digit as usize,
+            None => return false,
+        };
+
+        let count = self.count.unwrap_or(0) * 10 + digit;
+        self.count = Some(count.min(MAX_COUNT));
+        true
+    }
+
+    /// The count for the sequence, 1 when none was given
+    pub fn count(&self) -> usize {
+        self.count.unwrap_or(1)
+    }
+
+    pub fn push(&mut self, key: KeyEvent) -> &[KeyEvent] {
+        self.keys.push(key);
+        self.since = Some(Instant::now());
+        &self.keys
+    }
+
+    /// Take the keys and reset, the count should be read first
+    pub fn take(&mut self) -> Vec<KeyEvent> {
+        self.since = None;
+        self.count = None;
+        std::mem::take(&mut self.keys)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.count.is_none()
+    }
+
+    /// Whether the sequence has been waiting too long for its next key
+    pub fn timed_out(&self) -> bool {
+        self.since
+            .is_some_and(|since| since.elapsed() >= SEQUENCE_TIMEOUT)
+    }
+
+    /// The count and keys as they'd be typed, e.g. "12g" or "<Esc>"
+    pub fn display(&self) -> String {
+        let count = self.count.map(|c| c.to_string()).unwrap_or_default();
+        count + &display_keys(&self.keys)
+    }
+}
+
+/// Parse a key sequence like "gg" or "g<Enter>"
+pub fn parse(keys: &str) -> Option<Vec<KeyEvent>> {
+    let mut parsed = Vec::new();
+    let mut rest = keys;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            if let Some(end) = rest.find('>') {
+                parsed.push(named_key(&rest[1..end])?);
+                rest = &rest[end + 1..];
+                continue;
+            }
+        }
+
+        parsed.push(KeyEvent::from(KeyCode::Char(c)));
+        rest = &rest[c.len_utf8()..];
+    }
+
+    if parsed.is_empty() {
+        None
+    } else {
+        Some(parsed)
+    }
+}
+
+fn named_key(name: &str) -> Option<KeyEvent> {
+    let modifier = match name.get(..2).map(str::to_uppercase).as_deref() {
+        Some("C-") => Some(KeyModifiers::CONTROL),
+        Some("M-") | Some("A-") => Some(KeyModifiers::ALT),
+        _ => None,
+    };
+    if let Some(modifier) = modifier {
+        let key = &name[2..];
+        let code = match key.chars().count() {
+            1 => KeyCode::Char(key.chars().next().expect("infallible")),
+            _ => named_key(key)?.code,
+        };
+        return Some(KeyEvent::new(code, modifier));
+    }
+
+    let code = match name.to_lowercase().as_str() {
+        "enter" | "cr" => Some(KeyCode::Enter),
+        "esc" => Some(KeyCode::Esc),
+        "tab" => Some(KeyCode::Tab),
+        "space" => Some(KeyCode::Char(' ')),
+        "lt" => Some(KeyCode::Char('<')),
+        "bs" => Some(KeyCode::Backspace),
+        "up" => Some(KeyCode::Up),
+        "down" => Some(KeyCode::Down),
+        "left" => Some(KeyCode::Left),
+        "right" => Some(KeyCode::Right),
+        _ => None,
+    }?;
+    Some(KeyEvent::from(code))
+}
+
+/// A key event as it's stored in bindings
+///
+/// Shift is part of the character so it's dropped, otherwise "O" would never match.
+pub fn normalize(key: KeyEvent) -> KeyEvent {
+    match key.code {
+        KeyCode::Char(_) => KeyEvent::new(key.code, key.modifiers - KeyModifiers::SHIFT),
+        _ => key,
+    }
+}
+
+pub fn display_keys(keys: &[KeyEvent]) -> String {
+    keys.iter().map(|key| display(*key)).collect()
+}
+
+fn display(key: KeyEvent) -> String {
+    for (modifier, prefix) in &[(KeyModifiers::CONTROL, "C"), (KeyModifiers::ALT, "M")] {
+        if key.modifiers.contains(*modifier) {
+            let name = display(KeyEvent::new(key.code, key.modifiers - *modifier));
+            let name = name.trim_start_matches('<').trim_end_matches('>');
+            return format!("<{}-{}>", prefix, name);
+        }
+    }
+
+    match key.code {
+        KeyCode::Char(' ') => "<Space>".to_string(),
+        KeyCode::Char('<') => "<lt>".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::Enter => "<Enter>".to_string(),
+        KeyCode::Esc => "<Esc>".to_string(),
+        KeyCode::Tab => "<Tab>".to_string(),
+        KeyCode::Backspace => "<BS>".to_string(),
+        KeyCode::Up => "<Up>".to_string(),
+        KeyCode::Down => "<Down>".to_string(),
+        KeyCode::Left => "<Left>".to_string(),
+        KeyCode::Right => "<Right>".to_string(),
+        key => format!("<{:?}>", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            super::parse("g<Enter>"),
+            Some(vec![
+                KeyEvent::from(KeyCode::Char('g')),
+                KeyEvent::from(KeyCode::Enter)
+            ])
+        );
+        assert_eq!(
+            super::parse("<lt>a"),
+            Some(vec![
+                KeyEvent::from(KeyCode::Char('<')),
+                KeyEvent::from(KeyCode::Char('a'))
+            ])
+        );
+        assert_eq!(
+            super::parse("<C-o>"),
+            Some(vec![KeyEvent::new(
+                KeyCode::Char('o'),
+                KeyModifiers::CONTROL
+            )])
+        );
+        assert_eq!(
+            super::parse("<M-Enter>"),
+            Some(vec![KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT)])
+        );
+        assert_eq!(super::parse("<nope>"), None);
+        assert_eq!(super::parse(""), None);
+    }
+
+    #[test]
+    fn lookup() {
+        let mut keymap = Keymap::default();
+        let keys = |s| super::parse(s).unwrap();
+
+        assert_eq!(keymap.lookup(&keys("g")), Lookup::Prefix);
+        assert_eq!(
+            keymap.lookup(&keys("gu")),
+            Lookup::Binding(Binding::Action(Action::Parent))
+        );
+        assert_eq!(keymap.lookup(&keys("gx")), Lookup::Unbound);
+        assert_eq!(
+            keymap.lookup(&keys("j")),
+            Lookup::Binding(Binding::Action(Action::Down))
+        );
+
+        // A bound prefix waits, its own action is the fallback
+        keymap.bind(&keys("g"), Action::Top);
+        assert_eq!(keymap.lookup(&keys("g")), Lookup::Prefix);
+        assert_eq!(
+            keymap.binding(&keys("g")),
+            Some(Binding::Action(Action::Top))
+        );
+    }
+
+    #[test]
+    fn mappings_take_precedence() {
+        let mut keymap = Keymap::default();
+        let keys = |s| super::parse(s).unwrap();
+
+        keymap.map(&keys("j"), "reload");
+        keymap.map(&keys("xy"), "go gemini://example.org/ | reload");
+        assert_eq!(
+            keymap.binding(&keys("j")),
+            Some(Binding::Commands("reload".to_string()))
+        );
+        assert_eq!(keymap.lookup(&keys("x")), Lookup::Prefix);
+        assert!(keymap.page().contains("down (mapped)"));
+
+        assert!(keymap.unmap(&keys("j")));
+        assert!(!keymap.unmap(&keys("j")));
+        assert_eq!(
+            keymap.binding(&keys("j")),
+            Some(Binding::Action(Action::Down))
+        );
+    }
+
+    #[test]
+    fn count() {
+        let mut pending = PendingKeys::default();
+
+        assert!(!pending.push_digit('0'));
+        assert!(pending.push_digit('1'));
+        assert!(pending.push_digit('0'));
+        assert_eq!(pending.count(), 10);
+
+        pending.push(KeyEvent::from(KeyCode::Char('g')));
+        assert!(!pending.push_digit('2'));
+        assert_eq!(pending.display(), "10g");
+
+        pending.take();
+        assert!(pending.is_empty());
+        assert_eq!(pending.count(), 1);
+    }
+
+    #[test]
+    fn argument() {
+        let keymap = Keymap::default();
+        let keys = |s| super::parse(s).unwrap();
+
+        assert_eq!(keymap.lookup(&keys("m")), Lookup::Prefix);
+        assert_eq!(
+            keymap.lookup(&keys("ma")),
+            Lookup::Binding(Binding::Argument(Action::SetMark, 'a'))
+        );
+        assert_eq!(
+            keymap.lookup(&keys("''")),
+            Lookup::Binding(Binding::Argument(Action::GoToMark, '\''))
+        );
+    }
+}
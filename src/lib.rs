@@ -1,5 +1,26 @@
+//! diosk, a Gemini browser for the terminal
+//!
+//! The browser is built on [`gemini`], which can be used on its own to fetch and parse pages with
+//! [`gemini::Client`] and [`gemini::Document`]. It doesn't touch the terminal, the other modules
+//! are the browser's and aren't meant to be stable. Without the default `tui` feature only
+//! [`gemini`], [`dump`] and the modules they need are built, and crossterm isn't a dependency.
+
+#[cfg(feature = "tui")]
+pub mod config;
+#[cfg(all(unix, feature = "tui"))]
+pub mod control;
+pub mod dirs;
+pub mod dump;
 pub mod gemini;
+pub mod human;
+#[cfg(feature = "tui")]
 pub mod input;
+pub mod logging;
+#[cfg(all(unix, feature = "tui"))]
+pub mod signals;
+#[cfg(feature = "tui")]
 pub mod state;
+#[cfg(feature = "tui")]
 pub mod terminal;
+#[cfg(feature = "tui")]
 pub mod worker;
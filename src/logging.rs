@@ -0,0 +1,122 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::LevelFilter;
+use url::Url;
+
+use crate::dirs;
+
+/// The level used when RUST_LOG isn't set
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Warn;
+
+/// Where the log goes, if anywhere
+#[derive(Debug)]
+pub struct Options {
+    /// None disables logging
+    pub file: Option<PathBuf>,
+    pub level: LevelFilter,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            file: Some(default_path()),
+            level: env::var("RUST_LOG")
+                .ok()
+                .and_then(|level| parse_level(&level))
+                .unwrap_or(DEFAULT_LEVEL),
+        }
+    }
+}
+
+/// Start logging, returning the path of the log file when there is one
+pub fn init(options: &Options) -> io::Result<Option<PathBuf>> {
+    let path = match (&options.file, options.level) {
+        (Some(path), level) if level != LevelFilter::Off => path,
+        _ => return Ok(None),
+    };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    simple_logging::log_to_file(path, options.level)?;
+
+    Ok(Some(path.clone()))
+}
+
+/// Append a panic report to the log file
+///
+/// This writes to the file directly rather than through the logger, which may be the thing that
+/// panicked.
+pub fn write_crash_report(path: &Path, report: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    writeln!(file, "[CRASH] {}", report)?;
+    file.sync_all()
+}
+
+/// diosk.log in the state directory, ~/.local/state/diosk by default
+fn default_path() -> PathBuf {
+    dirs::state_dir().join("diosk.log")
+}
+
+/// Parse a level name like RUST_LOG's, e.g. "info" or "off"
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    level.trim().parse().ok()
+}
+
+/// A URL safe to write to the log
+///
+/// Queries are how input is sent, including sensitive input like passwords, so they're never
+/// logged.
+pub fn redact(url: &Url) -> String {
+    match url.query() {
+        Some(_) => {
+            let mut url = url.clone();
+            url.set_query(Some("redacted"));
+            url.to_string()
+        }
+        None => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact() {
+        let redact = |url: &str| super::redact(&Url::parse(url).unwrap());
+
+        assert_eq!(
+            redact("gemini://example.org/login?hunter2"),
+            "gemini://example.org/login?redacted"
+        );
+        assert_eq!(
+            redact("gemini://example.org/a.gmi"),
+            "gemini://example.org/a.gmi"
+        );
+    }
+
+    #[test]
+    fn parse_level() {
+        assert_eq!(super::parse_level("info"), Some(LevelFilter::Info));
+        assert_eq!(super::parse_level("OFF"), Some(LevelFilter::Off));
+        assert_eq!(super::parse_level("loud"), None);
+    }
+
+    #[test]
+    fn write_crash_report() {
+        let path = env::temp_dir().join(format!("diosk-crash-{}.log", std::process::id()));
+        fs::write(&path, "earlier\n").unwrap();
+
+        super::write_crash_report(&path, "boom").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "earlier\n[CRASH] boom\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
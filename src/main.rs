@@ -1,9 +1,25 @@
-use std::sync::{Arc, Mutex};
+use std::backtrace::Backtrace;
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
 
-use diosk::input::run as run_input_loop;
+#[cfg(unix)]
+use diosk::control;
+use diosk::dirs;
+use diosk::dump;
+use diosk::gemini::recording;
+use diosk::input::{run_script, Reader};
+use diosk::logging;
+#[cfg(unix)]
+use diosk::signals;
 use diosk::state::State;
 use diosk::terminal;
 use diosk::worker::Worker;
+use log::warn;
+use url::Url;
 
 //  ,ogggggggg,
 // dP"""88""""Y8b,                          ,dPYb,
@@ -14,38 +30,305 @@ use diosk::worker::Worker;
 //      88___,dP'_,88_,d8,   ,d8',8'_   8) ,d8    `Yb,
 //     888888P"  8P""YP"Y8888P"  P' "YY8P8P88P      Y8
 
+/// Commands run at startup, one per line, in the config directory
+const RC_FILE: &str = "diosk.rc";
+
+const USAGE: &str = "usage: diosk [--log-file PATH] [--no-log] [--rc PATH] [--offline] [--inline]
+             [--record DIR | --replay DIR] [URL]
+       diosk (--dump | --dump-links) URL [--timeout SECONDS] [--offline]
+             [--record DIR | --replay DIR]
+
+    URL                the page to open, in the diosk that's already running if there is one
+    --log-file PATH    write the log to PATH, the level is set with RUST_LOG
+    --no-log           disable logging
+    --rc PATH          run the commands in PATH at startup, diosk.rc in the config directory
+                       by default
+    --offline          only load pages from the disk cache, :offline switches back
+    --inline           draw below the prompt rather than on a screen of its own, leaving the
+                       last page in the scrollback
+    --record DIR       save every response received to DIR exactly as it was received
+    --replay DIR       load pages from the responses saved to DIR with --record, never the
+                       network
+    --dump URL         write the page to stdout and exit
+    --dump-links URL   write the links on the page to stdout as absolute URLs and exit
+    --timeout SECONDS  give up on --dump or --dump-links after SECONDS";
+
+/// The usage followed by the exit codes
+fn usage() -> String {
+    let codes: Vec<String> = dump::EXIT_CODES
+        .iter()
+        .map(|(code, name, description)| format!("    {}  {:<18} {}", code, name, description))
+        .collect();
+
+    format!("{}\n\nexit codes:\n{}", USAGE, codes.join("\n"))
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    log: logging::Options,
+    /// The startup script, the default is used when not given
+    rc: Option<PathBuf>,
+    url: Option<Url>,
+    offline: bool,
+    /// Draw in the scrollback rather than on the alternate screen, whatever the config says
+    inline: bool,
+    /// Where responses are saved to, or loaded from instead of the network
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    /// Fetch a page without starting the interface
+    dump: Option<dump::Options>,
+}
+
 fn main() {
-    simple_logging::log_to_file("target/out.log", log::LevelFilter::Info)
-        .expect("unable to set up logging");
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, usage());
+            process::exit(dump::USAGE);
+        }
+    };
+    let log_options = args.log;
 
-    // Enhance the panic hook to handle re-setting the terminal
-    let default_panic = std::panic::take_hook();
+    // Carry on without a log rather than refusing to start
+    let log_file = logging::init(&log_options).unwrap_or_else(|e| {
+        eprintln!("diosk: unable to set up logging: {}", e);
+        None
+    });
+
+    recording::set_dir(args.record);
+
+    if let Some(options) = args.dump {
+        process::exit(dump::run(&options));
+    }
+
+    // Replace the panic hook to reset the terminal before reporting the panic, nothing in here
+    // can be allowed to panic again
     std::panic::set_hook(Box::new(move |info| {
-        terminal::teardown().expect("unable to reset terminal");
-        default_panic(info);
+        // Whichever thread it's in, the terminal isn't left in raw mode if nothing recovers
+        let _ = terminal::teardown_for_panic();
+        let backtrace = Backtrace::force_capture();
+
+        let report = format!("{}\n\n{}", info, backtrace);
+        let logged = match &log_file {
+            Some(path) => logging::write_crash_report(path, &report).is_ok(),
+            None => false,
+        };
+
+        // Background threads recover, the worker takes the terminal back and the crash is
+        // reported in the status line
+        if thread::current().name() != Some("main") {
+            return;
+        }
 
-        // Ensure the process is exited if a thread panics
-        std::process::exit(1);
+        // eprintln! panics if stderr is gone
+        let mut stderr = io::stderr();
+        let _ = writeln!(stderr, "{}", info);
+        let _ = match &log_file {
+            Some(path) if logged => {
+                writeln!(stderr, "diosk crashed — details in {}", path.display())
+            }
+            _ => writeln!(stderr, "\n{}\ndiosk crashed", backtrace),
+        };
+
+        process::exit(1);
     }));
 
-    terminal::setup_alternate_screen().expect("unable to setup terminal");
+    let (mut state, rx) = match args.replay {
+        Some(dir) => State::replaying(dir),
+        None => State::new(),
+    };
+    state.set_offline(args.offline);
+
+    // Windows has no Unix sockets, every invocation there starts a diosk of its own
+    #[cfg(unix)]
+    let socket_path = match state.config().single_instance {
+        true => control::socket_path(),
+        false => None,
+    };
+    #[cfg(unix)]
+    if let (Some(path), Some(url)) = (&socket_path, &args.url) {
+        match control::send(path, url) {
+            Ok(true) => process::exit(0),
+            Ok(false) => {}
+            Err(e) => warn!("unable to reach a running diosk: {}", e),
+        }
+    }
+
+    match args.inline || state.config().inline {
+        true => terminal::setup_inline(state.config().inline_rows),
+        false => terminal::setup_alternate_screen(),
+    }
+    .expect("unable to setup terminal");
+
+    state.clear_screen_and_render_page();
+
+    // Without this SIGTERM or SIGHUP would leave the terminal in raw mode
+    #[cfg(unix)]
+    if let Err(e) = signals::listen(state.sender()) {
+        warn!("unable to handle signals: {}", e);
+    }
+
+    // Listen for URLs from other invocations, a second diosk carries on without
+    #[cfg(unix)]
+    let socket = socket_path.and_then(|path| {
+        control::listen(&path, state.sender())
+            .map_err(|e| warn!("unable to listen for other invocations: {}", e))
+            .ok()
+    });
+
+    let rc = args.rc.unwrap_or_else(|| dirs::config_dir().join(RC_FILE));
+    run_script(&mut state, &rc);
 
-    // Initialize State
-    let (state, rx) = {
-        let (mut state, rx) = State::new();
+    if let Some(url) = args.url {
+        state.request(url.as_str());
         state.clear_screen_and_render_page();
-        (Arc::new(Mutex::new(state)), rx)
+    }
+
+    // Hand State to the worker, from here on everything reaches it as an event
+    let (reader, pauser) = Reader::new(state.sender());
+    let worker = Worker::spawn(state, rx, pauser);
+
+    // Read input until the worker stops listening
+    reader.run();
+
+    let shutdown_reason = match worker.join() {
+        Ok(mut state) => {
+            state.wait_for_loads();
+            state.shutdown_reason().map(str::to_string)
+        }
+        Err(_) => Some("the worker thread panicked".to_string()),
     };
 
-    // Spawn the worker thread
-    let worker = Worker::spawn(state.clone(), rx);
+    // Clean up the terminal and the socket, exiting skips destructors. After SIGHUP there's no
+    // terminal left to reset.
+    if let Err(e) = terminal::teardown() {
+        warn!("unable to reset terminal: {}", e);
+    }
+    #[cfg(unix)]
+    drop(socket);
+
+    if let Some(reason) = shutdown_reason {
+        eprintln!("diosk: {}", reason);
+        process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut options = Args::default();
+    let mut timeout = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--log-file" => {
+                let path = args.next().ok_or("--log-file needs a path")?;
+                options.log.file = Some(PathBuf::from(path));
+            }
+            "--no-log" => options.log.file = None,
+            "--rc" => {
+                let path = args.next().ok_or("--rc needs a path")?;
+                options.rc = Some(PathBuf::from(path));
+            }
+            "--dump" | "--dump-links" => {
+                let url = args.next().ok_or(format!("{} needs a URL", arg))?;
+                let output = match arg.as_str() {
+                    "--dump" => dump::Output::Page,
+                    _ => dump::Output::Links,
+                };
+                options.dump = Some(dump::Options {
+                    url,
+                    output,
+                    timeout: None,
+                    offline: false,
+                    replay: None,
+                });
+            }
+            "--offline" => options.offline = true,
+            "--inline" => options.inline = true,
+            "--record" => {
+                let dir = args.next().ok_or("--record needs a directory")?;
+                options.record = Some(PathBuf::from(dir));
+            }
+            "--replay" => {
+                let dir = args.next().ok_or("--replay needs a directory")?;
+                options.replay = Some(PathBuf::from(dir));
+            }
+            "--timeout" => {
+                let seconds = args.next().ok_or("--timeout needs a number of seconds")?;
+                let seconds = seconds
+                    .parse()
+                    .ok()
+                    .filter(|&seconds| seconds > 0)
+                    .ok_or_else(|| format!("invalid timeout: {}", seconds))?;
+                timeout = Some(Duration::from_secs(seconds));
+            }
+            "-h" | "--help" => {
+                println!("{}", usage());
+                process::exit(0);
+            }
+            url if !url.starts_with('-') && options.url.is_none() => {
+                let url = Url::parse(url).map_err(|e| format!("invalid URL {}: {}", url, e))?;
+                options.url = Some(url);
+            }
+            arg => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+
+    // Nothing comes from the network to be recorded while replaying
+    if options.record.is_some() && options.replay.is_some() {
+        return Err("--record and --replay can't be used together".to_string());
+    }
+
+    match (&mut options.dump, timeout) {
+        (Some(dump), timeout) => {
+            dump.timeout = timeout;
+            dump.offline = options.offline;
+            dump.replay = options.replay.clone();
+        }
+        (None, Some(_)) => return Err("--timeout needs --dump or --dump-links".to_string()),
+        (None, None) => {}
+    }
+
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
 
-    // Run a blocking input loop
-    run_input_loop(state);
+    #[test]
+    fn dump_args() {
+        let args = parse(&[
+            "--timeout",
+            "5",
+            "--dump-links",
+            "gemini://example.org/",
+            "--offline",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.dump,
+            Some(dump::Options {
+                url: "gemini://example.org/".to_string(),
+                output: dump::Output::Links,
+                timeout: Some(Duration::from_secs(5)),
+                offline: true,
+                replay: None,
+            })
+        );
 
-    // Wait for the worker thread to finish
-    worker.join().expect("worker thread panicked");
+        assert!(parse(&["--dump"]).is_err());
+        assert!(parse(&["gemini://example.org/", "gemini://example.org/"]).is_err());
+        assert!(parse(&["--timeout", "5"]).is_err());
+        assert!(parse(&["--dump", "gemini://example.org/", "--timeout", "soon"]).is_err());
+        assert!(parse(&["--dump", "gemini://example.org/", "--timeout", "0"]).is_err());
 
-    // Clean up the terminal
-    terminal::teardown().expect("unable to reset terminal");
+        let args = parse(&["--replay", "bug", "--dump", "gemini://example.org/"]).unwrap();
+        assert_eq!(args.dump.unwrap().replay, Some(PathBuf::from("bug")));
+        assert!(parse(&["--record", "bug", "--replay", "bug"]).is_err());
+        assert!(parse(&["--record"]).is_err());
+    }
 }
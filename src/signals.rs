@@ -0,0 +1,36 @@
+//! Signals that would otherwise kill diosk without the terminal being put back
+
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+use log::info;
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGHUP, SIGTERM};
+
+use crate::state::Event;
+
+/// Quit normally on SIGTERM, e.g. at shutdown, and SIGHUP, when the terminal is closed
+///
+/// Each is passed to the worker as `Event::Signal` so histories are flushed and the terminal is
+/// torn down as if the user had quit.
+pub fn listen(tx: mpsc::Sender<Event>) -> io::Result<()> {
+    let signals = Signals::new([SIGTERM, SIGHUP])?;
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let name = match signal {
+                SIGTERM => "SIGTERM",
+                _ => "SIGHUP",
+            };
+            info!("received {}", name);
+
+            // Nothing is listening once diosk has quit
+            if tx.send(Event::Signal(name)).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -1,276 +1,4506 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::ffi::OsStr;
 use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
+use chrono::{Local, Timelike};
+use crossterm::event::KeyEvent;
 use crossterm::terminal::size as terminal_size;
-use log::info;
+use log::{error, info};
+use mime::Mime;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
 use url::Url;
 
-use crate::gemini::gemtext::Line;
+use crate::config::{self, Config, DownloadConflict, OpenDownloads, Segment, SegmentKind};
+use crate::dirs;
+use crate::gemini::cache::{self, DiskCache};
+use crate::gemini::fetcher::{Fetcher, Offline, Replay, Retry};
+use crate::gemini::gemtext::{self, Line};
 use crate::gemini::status_code::StatusCode;
-use crate::gemini::{self, transaction, Response, TransactionError};
-use crate::terminal::{self, Terminal};
+use crate::gemini::tls;
+use crate::gemini::{
+    self, ChangedCertificate, Client, PageMeta, Response, Timeouts, TransactionError, Trust,
+};
+use crate::human;
+use crate::input::keymap::{self, Keymap, PendingKeys};
+use crate::logging::redact;
+use crate::terminal::clipboard;
+use crate::terminal::color_scheme::ColorScheme;
+use crate::terminal::{self, Frame, Highlights, SyntaxSpans, Terminal};
 
+mod auto_reload;
+pub mod bookmarks;
+pub mod download;
+mod favicons;
 pub mod history;
 pub mod input;
+mod jumps;
+pub mod list_page;
+pub mod page_cache;
+mod pool;
+mod positions;
+mod prefetch;
+pub mod search;
+pub mod visited;
 
-use input::Input;
+use auto_reload::AutoReload;
+use bookmarks::{Bookmark, Bookmarks};
+use download::{Download, Downloads, Status};
+use favicons::Favicons;
+use input::{Input, InputEnterResult};
+use jumps::{Jump, JumpList};
+use list_page::ListPage;
+use page_cache::PageCache;
+use pool::Pool;
+use positions::{Position, Positions};
+use prefetch::Prefetcher;
+use search::{Case, Pattern, Search};
+use visited::{Visit, Visited};
 
-#[derive(Debug)]
 pub enum Event {
     TerminateWorker,
-    TransactionComplete(Box<Response>, Url),
-    TransactionError(TransactionError),
+    TransactionComplete(RequestId, Box<Response>, Url),
+    TransactionError(RequestId, TransactionError),
+    PrefetchComplete(Box<Response>, Url, usize),
+    /// The favicon fetched from a favicon URL, None when there isn't one
+    Favicon(Url, Option<String>),
+    /// A large download needs confirming before it continues
+    DownloadPrompt {
+        mime_type: Mime,
+        received: usize,
+        decision: mpsc::Sender<bool>,
+    },
+    /// Bytes received by a load that's being downloaded rather than shown
+    DownloadProgress {
+        request_id: RequestId,
+        url: Url,
+        mime_type: Mime,
+        received: usize,
+    },
+    /// A load failed to connect and is about to try again
+    Retrying {
+        request_id: RequestId,
+        attempt: usize,
+        attempts: usize,
+    },
+    /// A URL given to another invocation of diosk
+    Open(Url),
+    /// A key pressed in the terminal
+    Key(KeyEvent),
+    /// The terminal was resized
+    Resize(u16, u16),
+    /// The poll interval passed without any input
+    Tick,
+    /// The terminal has gone away, nobody is left to type :quit
+    InputClosed,
+    /// A signal asking diosk to exit, by name
+    Signal(&'static str),
 }
 
+/// In the config directory
+const CONFIG_FILE: &str = "config.txt";
+
+/// In the data directory, along with the histories
+const DOWNLOADS_FILE: &str = "downloads.txt";
+const VISITED_FILE: &str = "visited.txt";
+const BOOKMARKS_FILE: &str = "bookmarks.gmi";
+
+/// The wait before retrying a load, doubled for each retry after the first
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How long fetching a favicon can take, it's only worth having if it's quick
+const FAVICON_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A load this close to being given up on shows the time it has left
+const DEADLINE_WARNING: Duration = Duration::from_secs(10);
+
+/// How often a download in progress reports how much it has received
+const DOWNLOAD_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many messages :messages remembers
+const MAX_MESSAGE_HISTORY: usize = 100;
+
+/// Columns h and l pan unwrapped lines by
+const PAN_COLUMNS: usize = 8;
+
+/// Identifies a load so responses to superseded requests can be ignored
+pub type RequestId = usize;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Mode {
     Normal,
     Input,
     Search,
+    /// Waiting for a y/n answer to a prompt
+    Confirm,
+}
+
+// Responses and URLs are left out or redacted as this is logged
+impl fmt::Debug for Event {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::TerminateWorker => write!(fmt, "TerminateWorker"),
+            Event::TransactionComplete(request_id, _, url) => {
+                write!(fmt, "TransactionComplete({}, {})", request_id, redact(url))
+            }
+            Event::TransactionError(request_id, e) => {
+                write!(fmt, "TransactionError({}, {})", request_id, e)
+            }
+            Event::PrefetchComplete(_, url, generation) => {
+                write!(fmt, "PrefetchComplete({}, {})", redact(url), generation)
+            }
+            Event::Favicon(url, icon) => write!(fmt, "Favicon({}, {:?})", redact(url), icon),
+            Event::DownloadPrompt {
+                mime_type,
+                received,
+                ..
+            } => write!(fmt, "DownloadPrompt({}, {})", mime_type, received),
+            Event::DownloadProgress {
+                request_id,
+                url,
+                received,
+                ..
+            } => write!(
+                fmt,
+                "DownloadProgress({}, {}, {})",
+                request_id,
+                redact(url),
+                received
+            ),
+            Event::Retrying {
+                request_id,
+                attempt,
+                attempts,
+            } => write!(fmt, "Retrying({}, {}/{})", request_id, attempt, attempts),
+            Event::Open(url) => write!(fmt, "Open({})", redact(url)),
+            // Keys aren't logged, they could be part of a password
+            Event::Key(_) => write!(fmt, "Key"),
+            Event::Resize(width, height) => write!(fmt, "Resize({}, {})", width, height),
+            Event::Tick => write!(fmt, "Tick"),
+            Event::InputClosed => write!(fmt, "InputClosed"),
+            Event::Signal(name) => write!(fmt, "Signal({})", name),
+        }
+    }
+}
+
+/// An action waiting on the user's confirmation
+struct Confirmation {
+    prompt: String,
+    action: PendingAction,
+}
+
+/// The record behind a line of about:history, about:bookmarks, or about:downloads
+#[derive(Debug, Clone, PartialEq)]
+enum Entry {
+    Visit(String),
+    Bookmark(Url),
+    Download(download::Position),
+}
+
+/// A record deleted from an internal list page, kept so u can put it back
+enum Deleted {
+    Visit(String, Visit),
+    Bookmark(usize, Bookmark),
+    Download(download::Position, Box<Download>),
+}
+
+enum PendingAction {
+    OverwriteDownload {
+        meta: Box<PageMeta>,
+        file: PathBuf,
+        path: PathBuf,
+    },
+    ContinueDownload(mpsc::Sender<bool>),
+    LaunchHandler(PendingOpen),
+    Quit,
+    DeleteEntry(Entry),
+    /// Open a link diosk can't load itself, it can be answered with always for its scheme
+    OpenExternal(PendingOpen),
+    /// Load a page whose certificate differs from the one pinned for its host or isn't valid, it can
+    /// be answered with always to pin the new certificate
+    AcceptCertificate {
+        certificate: Box<ChangedCertificate>,
+        permanently: bool,
+    },
+    #[cfg(feature = "image_preview")]
+    PreviewImage {
+        meta: Box<PageMeta>,
+        file: PathBuf,
+        protocol: terminal::image::Protocol,
+    },
+}
+
+/// A saved download or a link waiting to be opened with a handler
+///
+/// Handlers are run with the input reader paused so a terminal program doesn't have to compete
+/// with it for key presses.
+struct PendingOpen {
+    target: OpenTarget,
+    command: String,
+}
+
+enum OpenTarget {
+    Download(PathBuf),
+    /// A link to somewhere other than Gemini space
+    Url(Url),
+}
+
+impl OpenTarget {
+    fn as_os_str(&self) -> &OsStr {
+        match self {
+            OpenTarget::Download(path) => path.as_os_str(),
+            OpenTarget::Url(url) => OsStr::new(url.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for OpenTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenTarget::Download(path) => write!(f, "{}", path.display()),
+            OpenTarget::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// An image response shown in place of the page
+#[cfg(feature = "image_preview")]
+struct ImagePreview {
+    url: Url,
+    image: image::DynamicImage,
+    protocol: terminal::image::Protocol,
 }
 
 pub struct State {
-    current_line_index: usize,
-    current_row: u16,
-    content: Option<String>,
+    /// The pane with focus
+    pane: Pane,
+    split: Option<Split>,
     pub mode: Mode,
+    /// A prefix key waiting for the rest of a key sequence
+    pub pending_keys: PendingKeys,
+    pub keymap: Keymap,
+    /// The last command worth repeating with .
+    pub last_command: Option<InputEnterResult>,
     tx: mpsc::Sender<Event>,
-    current_url: Option<Url>,
-    last_status_code: Option<StatusCode>,
-    scroll_offset: u16,
     error_message: Option<String>,
+    message: Option<String>,
+    /// Messages waiting for the status line to be free
+    notifications: VecDeque<String>,
+    /// Messages that have been shown, oldest first, for :messages
+    message_history: VecDeque<String>,
     pub input: Input,
     width: u16,
     height: u16,
     terminated: bool,
-    loading: bool,
+    color_scheme: ColorScheme,
+    visited: Visited,
+    bookmarks: Bookmarks,
+    favicons: Favicons,
+    /// Where pages were left, shared by both panes
+    positions: Positions,
+    config: Config,
+    page_cache: PageCache<Response>,
+    /// Only when it's turned on in the config
+    disk_cache: Option<DiskCache>,
+    /// Pages only come from the caches, no network connections are made
+    offline: bool,
+    /// Pages come from a recording made with --record, the caches are left out of it so every
+    /// page is as it was recorded
+    replaying: bool,
+    /// The threads page loads are made on, shared by both panes
+    requests: Pool,
+    /// Favicons are fetched on their own thread so they never hold up a page
+    favicon_requests: Pool,
+    confirmation: Option<Confirmation>,
+    /// The last entry deleted from an internal list page
+    deleted: Option<Deleted>,
+    downloads: Downloads,
+    /// The ID given to the most recent load in any pane
+    last_request_id: RequestId,
+    /// Set while a pane without focus is being updated, it's drawn once it's done
+    background: bool,
+    needs_render: bool,
+    pending_open: Option<PendingOpen>,
+    /// The clipboard is read between events, once the input reader has been paused
+    pending_paste: Option<Paste>,
+    fetcher: Arc<dyn Fetcher>,
+    /// Why diosk stopped, when it wasn't asked to
+    shutdown_reason: Option<String>,
+    /// The time last shown in the status line's clock, it's redrawn when this changes
+    clock_shown: Option<(u32, u32)>,
 }
 
-impl fmt::Debug for State {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_struct("State")
-            .field("current_line_index", &self.current_line_index)
-            .field("current_row", &self.current_row)
-            .field("mode", &self.mode)
-            .field("current_url", &self.current_url)
-            .field("scroll_offset", &self.scroll_offset)
-            .finish()
-    }
+/// A view of a page with its own position and history, the screen can be split between two
+struct Pane {
+    current_line_index: usize,
+    current_row: u16,
+    /// Columns unwrapped lines are scrolled to the left by
+    pan: usize,
+    content: Option<String>,
+    /// The page as it was received, content is decoded again from this by :encoding
+    raw: Option<Vec<u8>>,
+    /// The charset chosen with :encoding in place of the one the page declares
+    encoding: Option<&'static str>,
+    current_url: Option<Url>,
+    last_status_code: Option<StatusCode>,
+    scroll_offset: u16,
+    page_meta: Option<PageMeta>,
+    overlay: Option<Overlay>,
+    jumps: JumpList,
+    /// Lines marked on the current page, ' is where the last jump was made from
+    marks: HashMap<char, usize>,
+    /// The lines opening the preformatted blocks on the current page that are collapsed
+    collapsed: HashSet<usize>,
+    /// The last search made, its matches are found again whenever what's shown changes
+    search: Option<Search>,
+    /// Where the view was when the search prompt was opened, typing a pattern moves away from it
+    search_start: Option<SearchStart>,
+    /// The line to select once the page being loaded is shown
+    restore_line: Option<usize>,
+    /// The scroll offset to go back to along with restore_line, if the page is still long enough
+    restore_scroll_offset: Option<u16>,
+    auto_reload: Option<AutoReload>,
+    /// The line and index of the URL picked out of a plain text line, Enter follows it
+    selected_url: Option<(usize, usize)>,
+    /// Where the link the current line was on when the pane was last drawn leads
+    current_link: Option<Url>,
+    loading: bool,
+    /// The attempt the load is on and how many there can be, once it has had to retry
+    retrying: Option<(usize, usize)>,
+    /// When the current attempt at the load started, its deadline counts from here
+    load_started: Option<Instant>,
+    /// The load turned out to be a download, which has a deadline of its own
+    load_download: bool,
+    /// The time left last shown in the status line, it's redrawn when this changes
+    deadline_shown: Option<u64>,
+    forced_refresh: bool,
+    /// Set to cancel the load in progress
+    cancel_load: Option<Arc<AtomicBool>>,
+    /// The most recent load, waited on when quitting so partial downloads are cleaned up
+    load_thread: Option<pool::Handle>,
+    /// The load responses are expected from, others are stale
+    request_id: RequestId,
+    /// Fetches the links on the page ahead of time
+    prefetcher: Prefetcher,
+    /// The syntax colors of what's shown, with a hash of the text and settings they were worked out
+    /// from so they're only worked out again when one of those changes
+    syntax: Option<(u64, SyntaxSpans)>,
+    /// What's shown parsed with its links resolved, with a hash of what it was parsed from, so
+    /// links are only resolved again when that changes
+    lines: RefCell<Option<(u64, Vec<Line>)>>,
+    #[cfg(feature = "image_preview")]
+    image_preview: Option<ImagePreview>,
 }
 
-impl State {
-    pub fn new() -> (Self, mpsc::Receiver<Event>) {
-        // Set up a channel for State to talk to the worker thread
-        let (tx, rx) = mpsc::channel();
-
-        (Self::new_with_tx(tx), rx)
-    }
-
-    fn new_with_tx(tx: mpsc::Sender<Event>) -> Self {
-        let (width, height) = terminal_size().unwrap();
-
+impl Default for Pane {
+    fn default() -> Self {
         Self {
             current_line_index: 0,
             current_row: 1,
+            pan: 0,
             content: None,
+            raw: None,
+            encoding: None,
             current_url: None,
             last_status_code: None,
-            mode: Mode::Normal,
-            tx,
             scroll_offset: 0,
-            error_message: None,
-            input: Input::new(),
-            width,
-            height,
-            terminated: false,
+            page_meta: None,
+            overlay: None,
+            jumps: JumpList::default(),
+            marks: HashMap::new(),
+            collapsed: HashSet::new(),
+            search: None,
+            search_start: None,
+            restore_line: None,
+            restore_scroll_offset: None,
+            auto_reload: None,
+            selected_url: None,
             loading: false,
+            retrying: None,
+            load_started: None,
+            load_download: false,
+            deadline_shown: None,
+            forced_refresh: false,
+            cancel_load: None,
+            load_thread: None,
+            request_id: 0,
+            prefetcher: Prefetcher::default(),
+            current_link: None,
+            syntax: None,
+            lines: RefCell::new(None),
+            #[cfg(feature = "image_preview")]
+            image_preview: None,
         }
     }
+}
 
-    pub fn request(&mut self, url_or_path: &str) {
-        let url = self.qualify_url(&url_or_path);
-        self.loading = true;
-        self.mode = Mode::Normal;
-        let tx = self.tx.clone();
-        thread::spawn(move || {
-            let response = match transaction(&url) {
-                Ok(response) => tx.send(Event::TransactionComplete(Box::new(response), url)),
-                Err(e) => tx.send(Event::TransactionError(e)),
-            };
+impl Pane {
+    /// The current line and scroll offset on the page, not on an overlay shown over it
+    fn page_position(&self) -> (usize, u16) {
+        match &self.overlay {
+            Some(overlay) => overlay.saved_position,
+            None => (self.current_line_index, self.scroll_offset),
+        }
+    }
 
-            info!("finished navigating");
+    /// A new pane showing the same page, at the same position
+    fn duplicate(&self) -> Self {
+        let (current_line_index, scroll_offset) = self.page_position();
 
-            response
-        });
+        Self {
+            current_line_index,
+            scroll_offset,
+            content: self.content.clone(),
+            raw: self.raw.clone(),
+            encoding: self.encoding,
+            current_url: self.current_url.clone(),
+            last_status_code: self.last_status_code.clone(),
+            page_meta: self.page_meta.clone(),
+            collapsed: self.collapsed.clone(),
+            ..Self::default()
+        }
     }
 
-    pub fn down(&mut self) {
-        self.current_line_index += 1;
+    /// The lines of what's shown, with links resolved against where it's from
+    fn content(&self) -> Vec<Line> {
+        let mut collapsed: Vec<_> = self.collapsed.iter().collect();
+        collapsed.sort_unstable();
+        let mut hasher = DefaultHasher::new();
+        (
+            self.text(),
+            self.overlay.is_some(),
+            &collapsed,
+            self.displayed_url(),
+        )
+            .hash(&mut hasher);
+        let key = hasher.finish();
+        if let Some((_, lines)) = self.lines.borrow().as_ref().filter(|(k, _)| *k == key) {
+            return lines.clone();
+        }
 
-        // Check if we need to scroll
-        let terminal = Terminal::new(self.width, self.height);
-        if self.current_row >= terminal.page_rows() {
-            self.scroll_offset += 1;
+        let mut lines = match (&self.overlay, &self.content) {
+            (Some(overlay), _) => gemtext::parse(&overlay.content),
+            (None, Some(content)) => {
+                let mut lines = gemtext::parse(content);
+                for start in &self.collapsed {
+                    gemtext::collapse(&mut lines, *start);
+                }
+                lines
+            }
+            (None, None) => vec![Line::Normal(String::new())],
+        };
+        if let Some(url) = self.displayed_url() {
+            gemtext::resolve_links(&mut lines, url);
         }
+        *self.lines.borrow_mut() = Some((key, lines.clone()));
+        lines
+    }
 
-        self.clear_screen_and_render_page();
+    /// Start over with the blocks of new content, all collapsed or none, as the old line numbers
+    /// mean nothing in it
+    fn reset_collapsed(&mut self, collapse: bool) {
+        self.collapsed = match (&self.content, collapse) {
+            (Some(content), true) => gemtext::parse(content)
+                .iter()
+                .enumerate()
+                .filter(|(_, line)| matches!(line, Line::PreformatStart { .. }))
+                .map(|(i, _)| i)
+                .collect(),
+            _ => HashSet::new(),
+        };
+        if let Some(start) = &mut self.search_start {
+            start.collapsed = self.collapsed.clone();
+        }
     }
 
-    pub fn up(&mut self) {
-        if self.current_line_index == 0 {
-            info!("top of content");
-            return;
+    /// Expand the collapsed block the current line is hidden in, e.g. after jumping to a mark in
+    /// it, returning the content as it's now shown
+    fn reveal_current_line(&mut self) -> Vec<Line> {
+        let lines = self.content();
+        if !lines
+            .get(self.current_line_index)
+            .is_some_and(Line::is_hidden)
+        {
+            return lines;
+        }
+
+        if let Some(start) = gemtext::block_start(&lines, self.current_line_index) {
+            self.collapsed.remove(&start);
         }
+        self.content()
+    }
+
+    /// The gemtext being shown, the overlay's or the page's
+    fn text(&self) -> Option<&String> {
+        self.overlay
+            .as_ref()
+            .map(|o| &o.content)
+            .or(self.content.as_ref())
+    }
 
-        self.current_line_index -= 1;
+    /// The number of lines content has, without parsing them
+    fn line_count(&self) -> usize {
+        self.text().map_or(1, |c| c.lines().count())
+    }
 
-        // Check if we need to scroll
-        if self.current_row == 1 {
-            self.scroll_offset -= 1;
+    /// The details of the page being shown, none for overlays and previews
+    fn displayed_meta(&self) -> Option<&PageMeta> {
+        #[cfg(feature = "image_preview")]
+        if self.image_preview.is_some() {
+            return None;
         }
 
-        self.clear_screen_and_render_page();
+        self.page_meta.as_ref().filter(|_| self.overlay.is_none())
     }
 
-    pub fn input(&mut self) {
-        self.mode = Mode::Input;
-        self.clear_screen_and_render_page();
+    /// The URL of whatever is being shown, an internal page, image preview, or the current page
+    fn displayed_url(&self) -> Option<&Url> {
+        #[cfg(feature = "image_preview")]
+        if let Some(preview) = &self.image_preview {
+            return Some(&preview.url);
+        }
+
+        self.overlay
+            .as_ref()
+            .map(|o| &o.url)
+            .or(self.current_url.as_ref())
     }
 
-    pub fn search(&mut self) {
-        self.mode = Mode::Search;
-        self.clear_screen_and_render_page();
+    /// Draw the pane, returning the row the current line is on
+    fn render(
+        &mut self,
+        out: &mut Frame,
+        terminal: &Terminal,
+        visited: &Visited,
+    ) -> crossterm::Result<u16> {
+        // Nothing is highlighted until a page is drawn
+        self.current_link = None;
+
+        #[cfg(feature = "image_preview")]
+        if let Some(preview) = &self.image_preview {
+            terminal.render_image(out, &preview.image, preview.protocol)?;
+            return Ok(self.current_row);
+        }
+
+        if self.displayed_url().is_none() {
+            terminal.render_default_page(out)?;
+            return Ok(0);
+        }
+
+        let content = self.reveal_current_line();
+        self.current_link = match content.get(self.current_line_index) {
+            Some(Line::Link { target, .. }) => target.clone(),
+            _ => None,
+        };
+        let mut hasher = DefaultHasher::new();
+        (self.text(), terminal.syntax_settings()).hash(&mut hasher);
+        let key = hasher.finish();
+        if self.syntax.as_ref().is_none_or(|(k, _)| *k != key) {
+            self.syntax = Some((key, terminal.syntax_spans(&content)));
+        }
+
+        let highlights = Highlights {
+            selected_url: self.selected_url(),
+            search: self.search.as_ref(),
+            syntax: self.syntax.as_ref().map(|(_, spans)| spans),
+        };
+        terminal.render_content(
+            out,
+            self.current_line_index,
+            &highlights,
+            content,
+            self.scroll_offset,
+            &|url| visited.contains(url),
+        )
     }
 
-    pub fn quit(&mut self) {
-        self.input.flush_history().expect("unable to flush history");
-        self.terminated = true;
-        self.tx.send(Event::TerminateWorker).unwrap();
+    /// Which of the URLs detected in the current line is selected, the first unless another was
+    /// picked on this line
+    fn selected_url(&self) -> usize {
+        match self.selected_url {
+            Some((line, index)) if line == self.current_line_index => index,
+            _ => 0,
+        }
     }
+}
 
-    pub fn enter(&mut self) {
-        if self.loading {
-            info!("enter while loading");
-            return;
+/// The screen split into two panes side by side
+struct Split {
+    /// The pane without focus
+    other: Pane,
+    focus: Side,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    fn other(self) -> Self {
+        match self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
         }
+    }
+}
 
-        let line = &self.content()[self.current_line_index];
+/// An internal page shown on top of the current page, e.g. about:info
+struct Overlay {
+    url: Url,
+    content: String,
+    /// The current line index and scroll offset of the page underneath
+    saved_position: (usize, u16),
+    /// The load that was in progress when the overlay was opened, it finishes underneath
+    opened_during: Option<RequestId>,
+    /// The records behind the lines of a list page, by line index
+    entries: HashMap<usize, Entry>,
+}
 
-        if let Line::Link { url, .. } = line {
-            self.request(url);
-        } else {
-            // Nothing to do on non-link lines
+/// The view before a search was typed, Esc goes back to it exactly
+struct SearchStart {
+    line: usize,
+    scroll_offset: u16,
+    /// Blocks are expanded when a match in them is shown
+    collapsed: HashSet<usize>,
+    /// The search the new one replaces
+    search: Option<Search>,
+}
+
+/// What to do with the URL on the clipboard once it's been read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Paste {
+    Go,
+    /// Open the go prompt with it to be edited first
+    Prompt,
+}
+
+/// A URL pasted from the clipboard, a bare host like example.org/page is taken to be on Gemini
+fn pasted_url(text: &str) -> Option<Url> {
+    if text.is_empty() || text.contains(char::is_whitespace) {
+        return None;
+    }
+
+    let bare_host = || gemini::qualify_url(None, &format!("gemini://{}", text)).ok();
+    match Url::parse(text) {
+        // example.org:1965/page parses with example.org as its scheme
+        Ok(url) if url.scheme().contains('.') => bare_host(),
+        Ok(_) => gemini::qualify_url(None, text).ok(),
+        Err(url::ParseError::RelativeUrlWithoutBase)
+            if text
+                .split('/')
+                .next()
+                .is_some_and(|host| host.contains('.')) =>
+        {
+            bare_host()
         }
+        Err(_) => None,
     }
+}
 
-    pub fn terminated(&self) -> bool {
-        self.terminated
+/// The start of some pasted text on one line, to show what was pasted without filling the screen
+fn preview(text: &str) -> String {
+    const MAX_CHARS: usize = 40;
+
+    let mut preview: String = text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(MAX_CHARS)
+        .collect();
+    if text.chars().count() > MAX_CHARS {
+        preview.push('…');
     }
+    preview
+}
 
-    fn render_page(&mut self) {
-        let status_line_context = StatusLineContext::new_from_state(&self);
-        let terminal = Terminal::new(self.width, self.height);
+/// Where on the screen to put a line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Align {
+    Top,
+    Center,
+    Bottom,
+}
 
-        self.current_row = terminal
-            .render_page(
-                self.current_line_index,
-                self.content(),
-                self.scroll_offset,
-                status_line_context,
-            )
-            .unwrap();
+/// The scroll offset, in rows, that puts a line where it's wanted on the screen
+///
+/// The offset is clamped so the page never scrolls past its first or last row.
+fn scroll_offset(rows: &[usize], line: usize, page_rows: usize, align: Align) -> usize {
+    let rows_before: usize = rows[..line].iter().sum();
+    let line_rows = rows[line];
+
+    let offset = match align {
+        Align::Top => rows_before,
+        Align::Center => (rows_before + line_rows / 2).saturating_sub(page_rows / 2),
+        Align::Bottom => (rows_before + line_rows).saturating_sub(page_rows),
+    };
+
+    let max_offset = rows.iter().sum::<usize>().saturating_sub(page_rows);
+    offset.min(max_offset)
+}
+
+/// The scroll offset closest to offset that shows all of a line with margin rows around it, as
+/// far as the page allows
+///
+/// The margin is cut down when the screen is too small to fit it on both sides.
+fn keep_visible(
+    rows: &[usize],
+    line: usize,
+    page_rows: usize,
+    offset: usize,
+    margin: usize,
+) -> usize {
+    let top: usize = rows[..line].iter().sum();
+    let bottom = top + rows[line];
+    let margin = margin.min(page_rows.saturating_sub(rows[line]) / 2);
+
+    let mut offset = offset;
+    if bottom + margin > offset + page_rows {
+        offset = bottom + margin - page_rows;
     }
+    if top < offset + margin {
+        offset = top.saturating_sub(margin);
+    }
+
+    let max_offset = rows.iter().sum::<usize>().saturating_sub(page_rows);
+    offset.min(max_offset)
+}
+
+/// Shown for a page that isn't cached while offline, with a link to go online and try again
+fn offline_page(url: &Url) -> String {
+    format!(
+        "# Offline\n\n{} is not in the cache.\n\n=> about:online?{} Go online and try again\n",
+        url,
+        utf8_percent_encode(url.as_str(), NON_ALPHANUMERIC)
+    )
+}
+
+/// The charset a page says it's in, UTF-8 unless it says otherwise
+fn declared_charset(meta: &PageMeta) -> &str {
+    meta.mime_type
+        .get_param(mime::CHARSET)
+        .map_or("utf-8", |charset| charset.as_str())
+}
 
-    /// Parse the URL to ensure it's valid and check if it has a base path
-    fn qualify_url(&self, url_or_path: &str) -> Url {
-        gemini::qualify_url(self.current_url.as_ref(), url_or_path)
+/// A response as the page cache keeps it, without the decoded text when the body it was decoded
+/// from is kept, so a page isn't held twice
+fn for_page_cache(mut response: Response) -> Response {
+    if let Response::Body {
+        content,
+        raw: Some(_),
+        ..
+    } = &mut response
+    {
+        *content = None;
     }
+    response
+}
 
-    // TODO: Store parsed lines directly on Self
-    fn content(&self) -> Vec<Line> {
-        self.content
-            .as_ref()
-            .map(|c| c.lines().map(Line::parse).collect())
-            .unwrap_or_else(|| vec![Line::Normal(String::new())])
+/// A response from the page cache with its text decoded again, as it was when it was fetched
+fn from_page_cache(mut response: Response) -> Response {
+    if let Response::Body {
+        content: content @ None,
+        raw: Some(raw),
+        meta,
+        ..
+    } = &mut response
+    {
+        let charset = meta
+            .detected_charset
+            .unwrap_or_else(|| declared_charset(meta));
+        *content = Some(gemini::decode_body(&meta.url, raw, charset));
     }
+    response
+}
 
-    pub fn set_error_message(&mut self, message: String) {
-        self.error_message = Some(message);
+/// How long loads can take as configured, 0 never gives up
+fn timeouts(config: &Config) -> Timeouts {
+    let timeout = |seconds| Some(Duration::from_secs(seconds)).filter(|t| !t.is_zero());
+    Timeouts {
+        page: timeout(config.timeout),
+        download: timeout(config.download_timeout),
     }
+}
 
-    pub fn clear_error_message(&mut self) {
-        self.error_message = None;
+/// The disk cache as configured, None when it's off
+fn disk_cache(config: &Config) -> Option<DiskCache> {
+    match config.disk_cache {
+        true => Some(DiskCache::new(
+            cache::default_dir(),
+            Duration::from_secs(config.disk_cache_max_age),
+            config.disk_cache_max_bytes,
+        )),
+        false => None,
     }
+}
 
-    pub fn new_size(&mut self, width: u16, height: u16) {
-        self.width = width;
-        self.height = height;
-        info!("New size {}x{}", self.width, self.height);
-        self.clear_screen_and_render_page();
+impl fmt::Debug for State {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("State")
+            .field("current_line_index", &self.pane.current_line_index)
+            .field("current_row", &self.pane.current_row)
+            .field("mode", &self.mode)
+            .field("current_url", &self.pane.current_url.as_ref().map(redact))
+            .field("scroll_offset", &self.pane.scroll_offset)
+            .finish()
     }
+}
 
-    pub fn clear_screen_and_render_page(&mut self) {
-        // TODO: We don't always need to clear the screen. Only for things like scrolling.
-        terminal::clear_screen().unwrap();
+impl State {
+    pub fn new() -> (Self, mpsc::Receiver<Event>) {
+        #[cfg(not(feature = "debug_content"))]
+        let fetcher = Arc::new(gemini::fetcher::Network);
+        #[cfg(feature = "debug_content")]
+        let fetcher = Arc::new(gemini::fetcher::Fixture::new("Foo.\nBar.\nBaz."));
 
-        self.render_page();
+        Self::with_fetcher(fetcher)
     }
 
-    pub fn transaction_complete(&mut self, response: Response, url: Url) {
-        match response {
-            Response::Body {
-                content,
-                status_code,
-            } => {
-                // Move the current line back to the top of the page
-                self.current_line_index = 0;
+    /// Set up State with a different way of loading pages, e.g. canned responses
+    pub fn with_fetcher(fetcher: Arc<dyn Fetcher>) -> (Self, mpsc::Receiver<Event>) {
+        // Set up a channel for State to talk to the worker thread
+        let (tx, rx) = mpsc::channel();
+
+        (Self::new_with_tx(tx, fetcher), rx)
+    }
+
+    /// Set up State to load pages from the responses recorded in a directory with --record
+    pub fn replaying(dir: PathBuf) -> (Self, mpsc::Receiver<Event>) {
+        let (mut state, rx) = Self::with_fetcher(Arc::new(Replay::new(dir)));
+        state.replaying = true;
+        state.disk_cache = None;
+        (state, rx)
+    }
+
+    fn new_with_tx(tx: mpsc::Sender<Event>, fetcher: Arc<dyn Fetcher>) -> Self {
+        // Not being attached to a terminal, e.g. in tests, isn't worth failing over
+        let (width, height) = terminal_size().unwrap_or((80, 24));
+
+        let (config, config_errors) = Config::load(&dirs::config_dir().join(CONFIG_FILE));
+
+        // Everything saved goes here, the stores only create their own files
+        let data_dir = dirs::data_dir();
+        if let Err(e) = fs::create_dir_all(&data_dir) {
+            error!("unable to create {}: {}", data_dir.display(), e);
+        }
+        gemini::dns::set_enabled(config.dns_cache);
+        gemini::tls::set_strict_hostnames(config.strict_hostnames);
+        gemini::tls::set_verification(config.verify.clone());
+        let config_errors: VecDeque<String> = config_errors
+            .iter()
+            .map(|e| format!("Config error: {}", e))
+            .collect();
+        let error_message = config_errors.front().cloned();
+
+        Self {
+            pane: Pane::default(),
+            split: None,
+            mode: Mode::Normal,
+            pending_keys: PendingKeys::default(),
+            keymap: Keymap::default(),
+            last_command: None,
+            tx,
+            error_message,
+            message: None,
+            notifications: VecDeque::new(),
+            message_history: config_errors,
+            input: Input::in_dir(&data_dir),
+            width,
+            height,
+            terminated: false,
+            color_scheme: ColorScheme::default(),
+            visited: Visited::new(&data_dir.join(VISITED_FILE)),
+            bookmarks: Bookmarks::new(&data_dir.join(BOOKMARKS_FILE)),
+            favicons: Favicons::default(),
+            positions: Positions::default(),
+            page_cache: PageCache::new(
+                config.cache_max_entries,
+                config.cache_max_bytes,
+                Duration::from_secs(config.cache_max_age),
+            ),
+            disk_cache: disk_cache(&config),
+            offline: false,
+            replaying: false,
+            requests: Pool::new(pool::WORKERS),
+            favicon_requests: Pool::new(1),
+            confirmation: None,
+            deleted: None,
+            downloads: Downloads::new(&data_dir.join(DOWNLOADS_FILE)),
+            last_request_id: 0,
+            background: false,
+            needs_render: false,
+            pending_open: None,
+            pending_paste: None,
+            fetcher,
+            shutdown_reason: None,
+            clock_shown: None,
+            config,
+        }
+    }
 
-                self.content = content;
-                self.current_url = Some(url);
-                self.last_status_code = Some(status_code);
+    pub fn request(&mut self, url_or_path: &str) {
+        let url = match self.qualify_url(url_or_path) {
+            Some(url) => url,
+            None => return,
+        };
+        if url.scheme() == "file" {
+            if let Ok(path) = url.to_file_path() {
+                if let Some(mime_type) = self.downloads.saved_at(&path).map(|d| d.mime_type.clone())
+                {
+                    self.open_download(path, &mime_type);
+                    return;
+                }
             }
         }
+        if !matches!(url.scheme(), "gemini" | "gopher" | "about") {
+            self.open_external(url);
+            return;
+        }
+        // Only ever followed, a reload or jump back to it doesn't go online
+        if url.scheme() == "about" && url.path() == "online" {
+            self.go_online(&url);
+            return;
+        }
 
-        terminal::clear_screen().unwrap();
-        self.loading = false;
-        self.mode = Mode::Normal;
-        self.render_page();
+        self.record_jump();
+        self.pane.restore_line = None;
+        self.pane.restore_scroll_offset = None;
+        self.pane.auto_reload = None;
+        self.fetch(url, false);
     }
 
-    pub fn transaction_error(&mut self, e: TransactionError) {
-        info!("transaction error: {}", e);
+    /// Load the current page again, a forced reload skips the page cache
+    pub fn reload(&mut self, force: bool) {
+        match self.pane.current_url.clone() {
+            Some(url) => self.fetch(url, force),
+            None => self.set_error_message("No page loaded".to_string()),
+        }
+        self.clear_screen_and_render_page();
+    }
 
-        self.set_error_message(e.to_string());
-        terminal::clear_screen().unwrap();
-        self.loading = false;
-        self.mode = Mode::Normal;
-        self.render_page();
+    /// Reload the current page every so many seconds while it's shown, None stops reloading
+    pub fn set_auto_reload(&mut self, seconds: Option<u64>) {
+        match (seconds, &self.pane.current_url) {
+            (Some(seconds), Some(url)) => {
+                let url = url.clone();
+                self.pane.auto_reload = Some(AutoReload::new(url, Duration::from_secs(seconds)));
+                self.set_message(format!("Reloading every {}s", seconds));
+            }
+            (Some(_), None) => self.set_error_message("No page loaded".to_string()),
+            (None, _) => {
+                self.pane.auto_reload = None;
+                self.set_message("Auto-reload off".to_string());
+            }
+        }
+        self.clear_screen_and_render_page();
     }
 
-    pub fn mode(&self) -> Mode {
-        self.mode
+    /// Reload the pages in either pane if it's time to, or update the countdown in the status line
+    pub fn tick_auto_reload(&mut self) {
+        self.tick_pane_auto_reload();
+        if self.split.is_some() {
+            self.in_other_pane(Self::tick_pane_auto_reload);
+        }
     }
-}
 
-pub struct StatusLineContext<'a> {
-    pub status_code: Option<StatusCode>,
-    pub url: Option<Url>,
-    pub error_message: Option<String>,
-    pub mode: Mode,
-    pub input: &'a str,
-    pub loading: bool,
-}
+    fn tick_pane_auto_reload(&mut self) {
+        let displayed = self.pane.current_url.clone();
+        let auto_reload = match self.pane.auto_reload.as_mut() {
+            Some(auto_reload) => auto_reload,
+            None => return,
+        };
 
-impl<'a> StatusLineContext<'a> {
-    fn new_from_state(state: &'a State) -> Self {
-        Self {
-            status_code: state.last_status_code.clone(),
-            url: state.current_url.clone(),
-            error_message: state.error_message.clone(),
-            mode: state.mode,
-            input: &state.input.input,
-            loading: state.loading,
+        // The page has been navigated away from
+        if displayed.as_ref() != Some(&auto_reload.url) {
+            self.pane.auto_reload = None;
+            self.render_page();
+            return;
+        }
+
+        // Wait until whatever else is happening is finished, a reload would get in the way, or
+        // until back online where there'd be nothing new
+        if self.offline
+            || self.pane.loading
+            || self.pane.overlay.is_some()
+            || !matches!(self.mode, Mode::Normal)
+        {
+            return;
+        }
+
+        if !auto_reload.due() {
+            // Only the pane with focus shows its countdown
+            if auto_reload.countdown_changed() && !self.background {
+                self.render_page();
+            }
+            return;
         }
+
+        auto_reload.restart();
+        let url = auto_reload.url.clone();
+        info!("auto-reloading {}", redact(&url));
+
+        self.pane.restore_line = Some(self.pane.current_line_index);
+        self.pane.restore_scroll_offset = Some(self.pane.scroll_offset);
+        self.fetch(url, true);
+        // The countdown restarting is enough to show it happened
+        self.pane.forced_refresh = false;
+        self.render_page();
+    }
+
+    fn fetch(&mut self, url: Url, force: bool) {
+        if url.scheme() == "about" {
+            self.internal_page(&url);
+            return;
+        }
+
+        self.mode = Mode::Normal;
+
+        // Only one load at a time, starting another cancels the current one, even when the new
+        // one is served from a cache, so a late response can't replace it
+        if let Some(previous) = self.pane.cancel_load.take() {
+            previous.store(true, Ordering::SeqCst);
+            self.downloads
+                .stopped(self.pane.request_id, Status::Cancelled);
+        }
+        self.pane.prefetcher.cancel();
+        let request_id = self.next_request_id();
+        self.pane.request_id = request_id;
+
+        // A forced refresh replaces the cached page, it's not kept to be served again. Replayed
+        // pages always come from the recording
+        if force {
+            self.page_cache.remove(&url);
+        } else if !self.replaying {
+            if let Some((response, prefetched)) = self.page_cache.get(&url) {
+                info!(
+                    "serving from cache: {} (prefetched: {})",
+                    redact(&url),
+                    prefetched
+                );
+                self.transaction_complete(from_page_cache(response), url);
+                return;
+            }
+
+            if let Some((response, fetched)) = self.disk_cache.as_ref().and_then(|c| c.get(&url)) {
+                info!(
+                    "serving from disk cache: {} (fetched: {:?})",
+                    redact(&url),
+                    fetched
+                );
+                self.transaction_complete(response, url);
+                return;
+            }
+        }
+
+        self.pane.forced_refresh = force;
+        self.pane.loading = true;
+        self.pane.retrying = None;
+        self.pane.load_started = Some(Instant::now());
+        self.pane.load_download = false;
+        self.pane.deadline_shown = None;
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.pane.cancel_load = Some(cancelled.clone());
+        let threshold = self.config.download_prompt_bytes;
+        let retries = self.config.retries;
+        let timeouts = timeouts(&self.config);
+        let download_dir = self.download_dir();
+        let fetcher: Arc<dyn Fetcher> = match self.offline {
+            true => Arc::new(Offline::new(self.offline_cache())),
+            false => self.fetcher.clone(),
+        };
+        let disk_cache = self.disk_cache.clone();
+        let tx = self.tx.clone();
+        let load_thread = self.requests.submit(cancelled.clone(), move || {
+            let mut asked = false;
+            let mut reported: Option<Instant> = None;
+            let mut progress = |mime_type: &Mime, received: usize| {
+                if cancelled.load(Ordering::SeqCst) {
+                    return false;
+                }
+
+                // Anything that isn't gemtext is saved rather than shown
+                let due = reported.is_none_or(|at| at.elapsed() >= DOWNLOAD_PROGRESS_INTERVAL);
+                if mime_type.essence_str() != "text/gemini" && due {
+                    reported = Some(Instant::now());
+                    let event = Event::DownloadProgress {
+                        request_id,
+                        url: url.clone(),
+                        mime_type: mime_type.clone(),
+                        received,
+                    };
+                    if tx.send(event).is_err() {
+                        return false;
+                    }
+                }
+
+                // Check before committing to a large download
+                if threshold > 0
+                    && received >= threshold
+                    && !asked
+                    && mime_type.type_() != mime::TEXT
+                {
+                    asked = true;
+                    let (decision_tx, decision_rx) = mpsc::channel();
+                    let event = Event::DownloadPrompt {
+                        mime_type: mime_type.clone(),
+                        received,
+                        decision: decision_tx,
+                    };
+                    if tx.send(event).is_err() {
+                        return false;
+                    }
+                    return decision_rx.recv().unwrap_or(false);
+                }
+
+                true
+            };
+
+            let retry_tx = tx.clone();
+            let retry = Retry {
+                retries,
+                backoff: RETRY_BACKOFF,
+                retrying: &mut |attempt, attempts| {
+                    let _ = retry_tx.send(Event::Retrying {
+                        request_id,
+                        attempt,
+                        attempts,
+                    });
+                },
+                cancelled: &|| cancelled.load(Ordering::SeqCst),
+            };
+
+            // A crash shouldn't leave the load hanging, the client turns it into an error
+            let client = Client::new()
+                .fetcher(fetcher)
+                .timeouts(timeouts)
+                .download_dir(&download_dir);
+            let result = client.fetch_with_retry(&url, &mut progress, retry);
+
+            // Written on the fetch thread so writing to disk doesn't block the worker
+            if let (Some(cache), Ok(response)) = (&disk_cache, &result) {
+                if let Err(e) = cache.insert(&url, response) {
+                    info!("unable to cache {}: {}", redact(&url), e);
+                }
+            }
+
+            // Nothing is listening once diosk has quit
+            let _ = match result {
+                Ok(response) => tx.send(Event::TransactionComplete(
+                    request_id,
+                    Box::new(response),
+                    url,
+                )),
+                Err(e) => tx.send(Event::TransactionError(request_id, e)),
+            };
+
+            info!("finished navigating");
+        });
+        self.pane.load_thread = Some(load_thread);
+    }
+
+    /// Move down count lines, as far as the last, drawing the page once
+    pub fn down_by(&mut self, count: usize) {
+        // Lines in collapsed blocks are stepped over, they take up no rows
+        let lines = self.content();
+        let next = self.pane.current_line_index + 1;
+        match lines
+            .iter()
+            .enumerate()
+            .skip(next)
+            .filter(|(_, line)| !line.is_hidden())
+            .take(count)
+            .last()
+        {
+            Some((i, _)) => self.pane.current_line_index = i,
+            None => {
+                info!("bottom of content");
+                return;
+            }
+        }
+
+        self.scroll_to_current_line();
+        self.clear_screen_and_render_page();
+    }
+
+    /// Move up count lines, as far as the first, drawing the page once
+    pub fn up_by(&mut self, count: usize) {
+        if self.pane.current_line_index == 0 {
+            info!("top of content");
+            return;
+        }
+
+        let lines = self.content();
+        self.pane.current_line_index = lines[..self.pane.current_line_index]
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, line)| !line.is_hidden())
+            .take(count)
+            .last()
+            .map_or(0, |(i, _)| i);
+
+        self.scroll_to_current_line();
+        self.clear_screen_and_render_page();
+    }
+
+    /// Scroll as little as possible to keep the current line on screen, with scrolloff rows
+    /// around it
+    fn scroll_to_current_line(&mut self) {
+        let rows = self.line_rows();
+        if rows.is_empty() {
+            return;
+        }
+        let line = self.pane.current_line_index.min(rows.len() - 1);
+        let page_rows = self.terminal().page_rows() as usize;
+        let offset = keep_visible(
+            &rows,
+            line,
+            page_rows,
+            self.pane.scroll_offset as usize,
+            self.config.scrolloff,
+        );
+        self.pane.scroll_offset = offset as u16;
+    }
+
+    /// Scroll unwrapped lines by steps of PAN_COLUMNS, right for positive steps and left for
+    /// negative, no further right than the end of the longest line
+    pub fn pan(&mut self, steps: isize) {
+        if self.config.wrap {
+            self.set_error_message("Lines are wrapped, :set wrap=false to pan".to_string());
+            self.clear_screen_and_render_page();
+            return;
+        }
+
+        let terminal = self.terminal();
+        let widest = self
+            .content()
+            .iter()
+            .map(|line| terminal.line_width(line))
+            .max()
+            .unwrap_or(0);
+        let max_pan = widest.saturating_sub(terminal.columns());
+        let pan = self.pane.pan as isize + steps.saturating_mul(PAN_COLUMNS as isize);
+        self.pane.pan = (pan.max(0) as usize).min(max_pan);
+
+        self.clear_screen_and_render_page();
+    }
+
+    /// Collapse the preformatted block the current line is in, or expand it if it's collapsed
+    pub fn toggle_block(&mut self) {
+        let lines = self.content();
+        let start = match gemtext::block_start(&lines, self.pane.current_line_index) {
+            Some(start) if self.pane.overlay.is_none() => start,
+            _ => {
+                self.set_error_message("Not in a preformatted block".to_string());
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+
+        if !self.pane.collapsed.remove(&start) {
+            self.pane.collapsed.insert(start);
+            // The block's caption is all that's left of it
+            self.show_line(start);
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    /// Move to the first line of the page
+    pub fn top(&mut self) {
+        self.record_jump();
+        self.pane.current_line_index = 0;
+        self.pane.scroll_offset = 0;
+
+        self.clear_screen_and_render_page();
+    }
+
+    /// Move to the last line of the page
+    pub fn bottom(&mut self) {
+        self.record_jump();
+        self.show_line(self.content().len().saturating_sub(1));
+
+        self.clear_screen_and_render_page();
+    }
+
+    /// Select a line, scrolling to put it at the top of the screen if it isn't already visible
+    fn show_line(&mut self, line: usize) {
+        let rows = self.line_rows();
+        let line = line.min(rows.len().saturating_sub(1));
+        let rows_before: usize = rows[..line].iter().sum();
+        let page_rows = self.terminal().page_rows() as usize;
+        let mut top = self.pane.scroll_offset as usize;
+
+        if rows_before < top || rows_before + rows[line] > top + page_rows {
+            top = scroll_offset(&rows, line, page_rows, Align::Top);
+        }
+        let top = keep_visible(&rows, line, page_rows, top, self.config.scrolloff);
+        self.pane.scroll_offset = top as u16;
+        self.pane.current_line_index = line;
+    }
+
+    /// Scroll so the current line is at the top, middle, or bottom of the screen without
+    /// changing which line is selected
+    pub fn align_current_line(&mut self, align: Align) {
+        let rows = self.line_rows();
+        if rows.is_empty() {
+            return;
+        }
+        let page_rows = self.terminal().page_rows() as usize;
+        let line = self.pane.current_line_index.min(rows.len() - 1);
+
+        self.pane.scroll_offset = scroll_offset(&rows, line, page_rows, align) as u16;
+        self.clear_screen_and_render_page();
+    }
+
+    /// Go back to where restore_line and restore_scroll_offset say once a page is shown
+    fn restore_position(&mut self) {
+        let line = match self.pane.restore_line.take() {
+            Some(line) => line,
+            None => return,
+        };
+
+        match self.pane.restore_scroll_offset.take() {
+            // A reloaded page that has got shorter is shown from the top
+            Some(offset) if line < self.content().len() => {
+                let page_rows = self.terminal().page_rows() as usize;
+                let max_offset = self
+                    .line_rows()
+                    .iter()
+                    .sum::<usize>()
+                    .saturating_sub(page_rows);
+                self.pane.scroll_offset = (offset as usize).min(max_offset) as u16;
+                self.show_line(line);
+            }
+            Some(_) => {}
+            None => self.show_line(line),
+        }
+    }
+
+    /// Put the heading the fragment links to at the top of the screen
+    fn go_to_fragment(&mut self, fragment: &str) {
+        // Slugs of headings in other scripts arrive percent-encoded
+        let fragment = human::url(fragment);
+        let line = self.content().iter().position(|line| match line {
+            Line::Heading { text, .. } => gemtext::slug(text) == fragment,
+            _ => false,
+        });
+
+        match line {
+            Some(line) => {
+                let page_rows = self.terminal().page_rows() as usize;
+                self.pane.scroll_offset =
+                    scroll_offset(&self.line_rows(), line, page_rows, Align::Top) as u16;
+                self.pane.current_line_index = line;
+            }
+            None => self.set_message(format!("Fragment #{} not found", fragment)),
+        }
+    }
+
+    /// The number of rows each line of the content takes up on screen
+    fn line_rows(&self) -> Vec<usize> {
+        let terminal = self.terminal();
+        self.content()
+            .iter()
+            .map(|line| terminal.line_rows(line))
+            .collect()
+    }
+
+    fn current_position(&self) -> Option<Jump> {
+        self.displayed_url().map(|url| Jump {
+            url: url.clone(),
+            line: self.pane.current_line_index,
+        })
+    }
+
+    fn record_jump(&mut self) {
+        self.pane.marks.insert('\'', self.pane.current_line_index);
+
+        if let Some(position) = self.current_position() {
+            self.pane.jumps.push(position);
+        }
+    }
+
+    /// Go back to where the last jump was made from
+    pub fn jump_back(&mut self) {
+        self.walk_jumps(JumpList::back, "Already at the oldest jump");
+    }
+
+    pub fn jump_forward(&mut self) {
+        self.walk_jumps(JumpList::forward, "Already at the newest jump");
+    }
+
+    fn walk_jumps(&mut self, step: fn(&mut JumpList, Jump) -> Option<Jump>, at_end: &str) {
+        let jump = self
+            .current_position()
+            .and_then(|current| step(&mut self.pane.jumps, current));
+
+        match jump {
+            Some(jump) if Some(&jump.url) == self.displayed_url() => {
+                self.show_line(jump.line);
+                self.clear_screen_and_render_page();
+            }
+            // Loaded pages usually come straight from the cache
+            Some(jump) => {
+                self.pane.restore_line = Some(jump.line);
+                self.pane.auto_reload = None;
+                self.fetch(jump.url, false);
+            }
+            None => {
+                self.set_error_message(at_end.to_string());
+                self.clear_screen_and_render_page();
+            }
+        }
+    }
+
+    /// Mark the current line, marks are forgotten when a different page is loaded
+    pub fn set_mark(&mut self, name: char) {
+        if name.is_ascii_alphabetic() {
+            self.pane.marks.insert(name, self.pane.current_line_index);
+            self.set_message(format!(
+                "Marked line {} as {}",
+                self.pane.current_line_index + 1,
+                name
+            ));
+        } else {
+            self.set_error_message(format!("Invalid mark: {}", name));
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    pub fn go_to_mark(&mut self, name: char) {
+        match self.pane.marks.get(&name).copied() {
+            Some(line) => {
+                self.record_jump();
+                self.show_line(line);
+            }
+            None => self.set_error_message(format!("Mark not set: {}", name)),
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    fn jumps_page(&self) -> String {
+        let mut content = format!(
+            "# Jump list\n\n{} jumps, oldest first\n\n",
+            self.pane.jumps.len()
+        );
+
+        for (jump, current) in self.pane.jumps.entries() {
+            content.push_str(&format!(
+                "=> {url} {url} (line {line}){current}\n",
+                url = jump.url,
+                line = jump.line + 1,
+                current = if current { " <- current" } else { "" },
+            ));
+        }
+
+        content
+    }
+
+    fn history_page(&self, recent: bool) -> ListPage<Entry> {
+        let (visits, order) = match recent {
+            true => (self.visited.by_recency(), "most recent first"),
+            false => (self.visited.by_frecency(), "most visited first"),
+        };
+        let mut page = ListPage::new(format!(
+            "# History\n\n{} pages, {}, d on one deletes it\n\n",
+            visits.len(),
+            order
+        ));
+
+        for (url, visit) in visits {
+            let visits = match visit.count {
+                1 => "1 visit".to_string(),
+                count => format!("{} visits", count),
+            };
+            let age = match visit.age() {
+                Some(age) => format!(", {} ago", human::duration(age)),
+                None => String::new(),
+            };
+            page.push_entry(
+                &format!("=> {url} {url} ({}{})\n", visits, age, url = url),
+                Entry::Visit(url.to_string()),
+            );
+        }
+
+        page
+    }
+
+    /// Complete the URL being typed after :go from the most frecent pages in the history,
+    /// pressing it again cycles through the other matches
+    pub fn complete(&mut self) {
+        let urls: Vec<&str> = self
+            .visited
+            .by_frecency()
+            .into_iter()
+            .map(|(url, _)| url)
+            .collect();
+        self.input.complete(&urls);
+    }
+
+    /// Navigate to the parent directory of the current page
+    pub fn parent(&mut self) {
+        self.navigate_with(gemini::parent_url, "Already at the root");
+    }
+
+    /// Navigate to the root of the current capsule
+    pub fn root(&mut self) {
+        self.navigate_with(gemini::root_url, "Already at the root");
+    }
+
+    fn navigate_with(&mut self, f: fn(&Url) -> Option<Url>, no_op_message: &str) {
+        match self.pane.current_url.as_ref().map(f) {
+            Some(Some(url)) => self.request(url.as_str()),
+            Some(None) => self.set_error_message(no_op_message.to_string()),
+            None => self.set_error_message("No page loaded".to_string()),
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    pub fn input(&mut self) {
+        self.mode = Mode::Input;
+        self.clear_screen_and_render_page();
+    }
+
+    /// Open the command prompt pre-filled with a go command for the current URL
+    pub fn edit_url(&mut self) {
+        let url = self
+            .pane
+            .current_url
+            .as_ref()
+            .map(|u| u.to_string())
+            .unwrap_or_default();
+        self.input.prefill(&format!("go {}", url));
+        self.input();
+    }
+
+    /// Open the command prompt pre-filled with an empty go command
+    pub fn open_url(&mut self) {
+        self.input.prefill("go ");
+        self.input();
+    }
+
+    /// Open the command prompt pre-filled with find-link
+    pub fn open_find_link(&mut self) {
+        self.input.prefill("find-link ");
+        self.input();
+    }
+
+    /// Go to the URL on the clipboard, it's read by paste_pending
+    pub fn paste(&mut self, paste: Paste) {
+        self.pending_paste = Some(paste);
+    }
+
+    pub fn has_pending_paste(&self) -> bool {
+        self.pending_paste.is_some()
+    }
+
+    /// Read the clipboard for a paste waiting on it, the input reader must be paused
+    pub fn paste_pending(&mut self) {
+        let paste = match self.pending_paste.take() {
+            Some(paste) => paste,
+            None => return,
+        };
+
+        let text = match clipboard::read_text(self.config.paste_command.as_deref()) {
+            Ok(text) => text,
+            Err(e) => {
+                self.set_error_message(format!("Unable to paste: {}", e));
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+        let text = text.trim();
+
+        match paste {
+            Paste::Go => match pasted_url(text) {
+                Some(url) => self.request(url.as_str()),
+                None => self.set_error_message(format!("Not a URL: {}", preview(text))),
+            },
+            Paste::Prompt => {
+                let line = text.lines().next().unwrap_or_default();
+                self.input.prefill(&format!("go {}", line));
+                self.input();
+                return;
+            }
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    /// Move to the next link after the current line with text in its name or URL, ignoring case,
+    /// going round to the top after the last
+    pub fn find_link(&mut self, text: &str) {
+        let lowercase = text.to_lowercase();
+        let has_text = |line: &Line| match line {
+            Line::Link { url, name, .. } => {
+                url.to_lowercase().contains(&lowercase)
+                    || name
+                        .as_deref()
+                        .is_some_and(|name| name.to_lowercase().contains(&lowercase))
+            }
+            _ => false,
+        };
+
+        let lines = self.content();
+        let current = self.pane.current_line_index;
+        let found = (current + 1..lines.len())
+            .chain(0..=current)
+            .find(|&i| lines.get(i).is_some_and(has_text));
+        match found {
+            Some(line) => {
+                if line <= current {
+                    self.set_message("Found a link back at the top".to_string());
+                }
+                self.record_jump();
+                self.show_line(line);
+            }
+            None => self.set_error_message(format!("No link matching {}", text)),
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    pub fn search(&mut self) {
+        self.mode = Mode::Search;
+        self.pane.search_start = Some(SearchStart {
+            line: self.pane.current_line_index,
+            scroll_offset: self.pane.scroll_offset,
+            collapsed: self.pane.collapsed.clone(),
+            search: self.pane.search.take(),
+        });
+        self.clear_screen_and_render_page();
+    }
+
+    /// The search prompt's input has changed, show the first match of it from where the search
+    /// started
+    ///
+    /// Patterns that aren't valid yet, like a regular expression part way through being typed,
+    /// show nothing.
+    pub fn preview_search(&mut self) {
+        let start = match &self.pane.search_start {
+            Some(start) => start,
+            None => {
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+        self.pane.current_line_index = start.line;
+        self.pane.scroll_offset = start.scroll_offset;
+        self.pane.collapsed = start.collapsed.clone();
+
+        self.pane.search = match self.search_pattern() {
+            Ok(pattern) if !self.input.input.is_empty() => {
+                let mut search = self.find(pattern);
+                if let Some(line) = search.next(self.pane.current_line_index) {
+                    self.show_line(line);
+                }
+                Some(search)
+            }
+            _ => None,
+        };
+        self.clear_screen_and_render_page();
+    }
+
+    /// Close the search prompt, going back to where the search started
+    pub fn cancel_search(&mut self) {
+        self.input.cancel();
+        self.mode = Mode::Normal;
+        if let Some(start) = self.pane.search_start.take() {
+            self.pane.current_line_index = start.line;
+            self.pane.scroll_offset = start.scroll_offset;
+            self.pane.collapsed = start.collapsed;
+            self.pane.search = start.search;
+            self.refresh_search();
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    /// Search what's shown for the pattern typed, moving to the first match from where the
+    /// search started
+    ///
+    /// An invalid regular expression is reported and left to be fixed.
+    pub fn start_search(&mut self) {
+        let pattern = match self.search_pattern() {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                // The error points out where the pattern went wrong over a few lines, the last
+                // says what's wrong
+                let error = e.to_string();
+                let reason = error.lines().last().unwrap_or_default();
+                let reason = reason.trim_start_matches("error: ");
+                self.set_error_message(format!("Invalid pattern: {}", reason));
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+
+        self.input.search();
+        self.mode = Mode::Normal;
+        // The jump is recorded from where the search started rather than the match previewed
+        if let Some(start) = self.pane.search_start.take() {
+            self.pane.current_line_index = start.line;
+            self.pane.scroll_offset = start.scroll_offset;
+        }
+        let mut search = self.find(pattern);
+        if let Some(line) = search.next(self.pane.current_line_index) {
+            self.record_jump();
+            self.show_line(line);
+        }
+        self.pane.search = Some(search);
+        self.clear_screen_and_render_page();
+    }
+
+    /// Move count matches on
+    pub fn search_next(&mut self, count: usize) {
+        self.step_search(true, count);
+    }
+
+    /// Move count matches back
+    pub fn search_previous(&mut self, count: usize) {
+        self.step_search(false, count);
+    }
+
+    /// Move through the matches of the search forwards or backwards, drawing the page once
+    fn step_search(&mut self, forwards: bool, count: usize) {
+        let line = self.pane.current_line_index;
+        let search = match self.pane.search.as_mut() {
+            Some(search) => search,
+            None => {
+                self.set_error_message("No search".to_string());
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+
+        let next = (0..count.max(1)).try_fold(line, |line, _| match forwards {
+            true => search.next(line),
+            false => search.previous(line),
+        });
+        match next {
+            Some(line) => {
+                self.record_jump();
+                self.show_line(line);
+            }
+            None => {
+                let message = format!("Pattern not found: {}", search.pattern());
+                self.set_error_message(message);
+            }
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    pub fn has_search(&self) -> bool {
+        self.pane.search.is_some()
+    }
+
+    /// Stop highlighting the matches of the search
+    pub fn clear_search(&mut self) {
+        self.pane.search = None;
+        self.clear_screen_and_render_page();
+    }
+
+    /// The pattern typed into the search prompt, with the search settings
+    fn search_pattern(&self) -> Result<Pattern, regex::Error> {
+        let case = Case {
+            ignore: self.config.ignorecase,
+            smart: self.config.smartcase,
+        };
+        Pattern::parse(&self.input.input, self.config.search_regex, case)
+    }
+
+    /// The matches of a pattern in what's shown, in the text as it's drawn
+    fn find(&self, pattern: Pattern) -> Search {
+        let terminal = self.terminal();
+        Search::new(pattern, &self.content(), |line, text| {
+            terminal.display_line_text(line, text)
+        })
+    }
+
+    /// Find the matches of the search again once what's shown has changed
+    fn refresh_search(&mut self) {
+        if let Some(search) = self.pane.search.take() {
+            self.pane.search = Some(self.find(search.pattern().clone()));
+        }
+    }
+
+    /// Quit, asking first if a page or download is still loading
+    pub fn confirm_quit(&mut self) {
+        if !self.pane.loading && !self.split.as_ref().is_some_and(|split| split.other.loading) {
+            self.quit();
+            return;
+        }
+
+        self.confirm(
+            "A load is in progress, quit anyway? y/n".to_string(),
+            PendingAction::Quit,
+        );
+        self.clear_screen_and_render_page();
+    }
+
+    pub fn quit(&mut self) {
+        self.cancel_loads();
+        self.input.flush_history().expect("unable to flush history");
+        self.visited.flush().expect("unable to flush visited");
+        self.terminated = true;
+        let _ = self.tx.send(Event::TerminateWorker);
+    }
+
+    /// Cancel loads in every pane, `wait_for_loads` gives them a moment to finish
+    fn cancel_loads(&mut self) {
+        let mut panes = vec![&mut self.pane];
+        if let Some(split) = &mut self.split {
+            panes.push(&mut split.other);
+        }
+
+        let mut cancelled_requests = Vec::new();
+        for pane in panes {
+            if let Some(cancelled) = pane.cancel_load.take() {
+                cancelled.store(true, Ordering::SeqCst);
+                cancelled_requests.push(pane.request_id);
+            }
+        }
+        for request_id in cancelled_requests {
+            self.downloads.stopped(request_id, Status::Cancelled);
+        }
+    }
+
+    /// Give loads a moment to remove any partial downloads once they're cancelled
+    ///
+    /// Called once the worker has stopped, so a load waiting on it e.g. to ask about a large
+    /// download isn't left waiting.
+    pub fn wait_for_loads(&mut self) {
+        const GRACE: Duration = Duration::from_secs(1);
+
+        self.cancel_loads();
+        let mut threads = vec![self.pane.load_thread.take()];
+        if let Some(split) = &mut self.split {
+            threads.push(split.other.load_thread.take());
+        }
+        let threads: Vec<_> = threads.into_iter().flatten().collect();
+
+        // A load stuck connecting only notices when it times out, it's not worth waiting for
+        let deadline = Instant::now() + GRACE;
+        while threads.iter().any(|thread| !thread.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Stop because something has gone wrong, the reason is reported once the terminal has been
+    /// restored
+    pub fn shutdown(&mut self, reason: String) {
+        error!("shutting down: {}", reason);
+
+        // Best effort, there's nowhere left to report these errors
+        let _ = self.input.flush_history();
+        let _ = self.visited.flush();
+
+        self.shutdown_reason.get_or_insert(reason);
+        self.terminated = true;
+        let _ = self.tx.send(Event::TerminateWorker);
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// A way for other threads to send events to the worker
+    pub fn sender(&self) -> mpsc::Sender<Event> {
+        self.tx.clone()
+    }
+
+    pub fn shutdown_reason(&self) -> Option<&str> {
+        self.shutdown_reason.as_deref()
+    }
+
+    pub fn enter(&mut self) {
+        if self.pane.loading {
+            info!("enter while loading");
+            return;
+        }
+
+        let url = match &self.content()[self.pane.current_line_index] {
+            Line::Link {
+                target: Some(target),
+                ..
+            } => target.to_string(),
+            Line::Link { url, .. } => match self.qualify_url(url) {
+                Some(url) => url.to_string(),
+                None => return,
+            },
+            Line::Normal(text)
+            | Line::Heading { text, .. }
+            | Line::ListItem(text)
+            | Line::Quote(text) => {
+                let urls = gemtext::find_urls(text, self.config.detect_urls.schemes());
+                match urls.get(self.pane.selected_url()) {
+                    Some(range) => text[range.clone()].to_string(),
+                    None => return,
+                }
+            }
+            // Nothing to do on invalid links or preformatted text
+            Line::InvalidLink
+            | Line::PreformatStart { .. }
+            | Line::Preformatted { .. }
+            | Line::PreformatEnd { .. } => return,
+        };
+
+        self.last_command = Some(InputEnterResult::Navigate(url.clone()));
+        self.request(&url);
+    }
+
+    /// Select the next URL detected in the current line, going back to the first after the last
+    pub fn next_url(&mut self) {
+        let urls = match &self.content()[self.pane.current_line_index] {
+            Line::Normal(text)
+            | Line::Heading { text, .. }
+            | Line::ListItem(text)
+            | Line::Quote(text) => {
+                gemtext::find_urls(text, self.config.detect_urls.schemes()).len()
+            }
+            _ => 0,
+        };
+        if urls == 0 {
+            self.set_error_message("No URLs on this line".to_string());
+            self.clear_screen_and_render_page();
+            return;
+        }
+
+        let index = (self.pane.selected_url() + 1) % urls;
+        self.pane.selected_url = Some((self.pane.current_line_index, index));
+        self.render_page();
+    }
+
+    pub fn loading(&self) -> bool {
+        self.pane.loading
+    }
+
+    pub fn terminated(&self) -> bool {
+        self.terminated
+    }
+
+    fn render_page(&mut self) {
+        if self.background {
+            self.needs_render = true;
+            return;
+        }
+
+        let mut frame = Frame::start();
+        let other_terminal = self.split.as_ref().map(|split| {
+            self.terminal_for(Some(split.focus.other()))
+                .pan(split.other.pan)
+        });
+        if let (Some(split), Some(terminal)) = (self.split.as_mut(), other_terminal) {
+            split
+                .other
+                .render(&mut frame, &terminal, &self.visited)
+                .unwrap();
+            terminal.render_separator(&mut frame).unwrap();
+        }
+
+        let terminal = self.terminal();
+        self.pane.current_row = self
+            .pane
+            .render(&mut frame, &terminal, &self.visited)
+            .unwrap();
+
+        let status_line_context = StatusLineContext::new_from_state(self);
+        terminal
+            .render_status_line(&mut frame, status_line_context)
+            .unwrap();
+        frame.finish().unwrap();
+    }
+
+    /// The terminal, narrowed to the pane being worked on when the screen is split
+    fn terminal(&self) -> Terminal {
+        let side = self.split.as_ref().map(|split| match self.background {
+            true => split.focus.other(),
+            false => split.focus,
+        });
+        self.terminal_for(side).pan(self.pane.pan)
+    }
+
+    fn terminal_for(&self, side: Option<Side>) -> Terminal {
+        let height = terminal::drawn_rows(self.height);
+        let terminal = Terminal::with_color_scheme(self.width, height, self.color_scheme)
+            .detect_urls(self.config.detect_urls.schemes())
+            .decode_urls(self.config.decode_urls)
+            .show_urls(self.config.show_urls)
+            .highlight_syntax(self.config.highlight_syntax)
+            .tab_width(self.config.tab_width)
+            .wrap(self.config.wrap);
+        let (left, right) = terminal::split_columns(self.width);
+
+        match side {
+            None => terminal,
+            Some(Side::Left) => terminal.pane(0, left),
+            Some(Side::Right) => terminal.pane(left + 1, right),
+        }
+    }
+
+    /// The URL a link or what was typed leads to, an error is shown when it doesn't make sense
+    fn qualify_url(&mut self, url_or_path: &str) -> Option<Url> {
+        match gemini::qualify_url(self.pane.current_url.as_ref(), url_or_path) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                self.set_error_message(format!("Invalid URL {}: {}", url_or_path, e));
+                self.clear_screen_and_render_page();
+                None
+            }
+        }
+    }
+
+    fn content(&self) -> Vec<Line> {
+        self.pane.content()
+    }
+
+    /// Show gemtext generated by diosk on top of the current page
+    fn show_overlay(&mut self, url: &str, content: String) {
+        let saved_position = match self.pane.overlay.take() {
+            Some(overlay) => overlay.saved_position,
+            None => (self.pane.current_line_index, self.pane.scroll_offset),
+        };
+
+        self.pane.overlay = Some(Overlay {
+            url: Url::parse(url).expect("valid internal URL"),
+            content,
+            saved_position,
+            opened_during: if self.pane.loading {
+                Some(self.pane.request_id)
+            } else {
+                None
+            },
+            entries: HashMap::new(),
+        });
+        self.pane.current_line_index = 0;
+        self.pane.scroll_offset = 0;
+        self.restore_position();
+        self.refresh_search();
+        self.mode = Mode::Normal;
+        self.clear_screen_and_render_page();
+    }
+
+    /// Show a list page, its entries can be deleted with d
+    fn show_list(&mut self, url: &str, page: ListPage<Entry>) {
+        let (content, entries) = page.into_parts();
+        self.show_overlay(url, content);
+        if let Some(overlay) = self.pane.overlay.as_mut() {
+            overlay.entries = entries;
+        }
+    }
+
+    /// Generate the list page being shown again, keeping the current position
+    fn refresh_list(&mut self) {
+        let page = match self.overlay_url().and_then(|url| self.list_page(url)) {
+            Some(page) => page,
+            None => return,
+        };
+
+        let (content, entries) = page.into_parts();
+        if let Some(overlay) = self.pane.overlay.as_mut() {
+            overlay.entries = entries;
+        }
+        self.refresh_overlay(content);
+    }
+
+    /// The internal pages listing records that can be deleted
+    fn list_page(&self, url: &str) -> Option<ListPage<Entry>> {
+        match url {
+            "about:history" => Some(self.history_page(false)),
+            "about:history?recent" => Some(self.history_page(true)),
+            "about:bookmarks" => Some(self.bookmarks.page().map(Entry::Bookmark)),
+            "about:downloads" => Some(self.downloads.page().map(Entry::Download)),
+            _ => None,
+        }
+    }
+
+    /// Replace the content of the internal page being shown, keeping the current position
+    fn refresh_overlay(&mut self, content: String) {
+        if let Some(overlay) = self.pane.overlay.as_mut() {
+            overlay.content = content;
+        }
+
+        let last_line = self.content().len().saturating_sub(1);
+        self.pane.current_line_index = self.pane.current_line_index.min(last_line);
+        self.refresh_search();
+        self.clear_screen_and_render_page();
+    }
+
+    fn displayed_url(&self) -> Option<&Url> {
+        self.pane.displayed_url()
+    }
+
+    fn overlay_url(&self) -> Option<&str> {
+        self.pane.overlay.as_ref().map(|o| o.url.as_str())
+    }
+
+    /// Linked from the page shown for something that isn't cached while offline, loading it again
+    /// once online
+    fn go_online(&mut self, url: &Url) {
+        let retry = url
+            .query()
+            .map(|query| percent_decode_str(query).decode_utf8_lossy().into_owned());
+        self.set_offline(false);
+        match retry {
+            Some(retry) => self.request(&retry),
+            None => self.clear_screen_and_render_page(),
+        }
+    }
+
+    fn internal_page(&mut self, url: &Url) {
+        match url.path() {
+            "info" => self.info(),
+            "cache" => {
+                let content = self.cache_page();
+                self.show_overlay("about:cache", content);
+            }
+            "downloads" | "bookmarks" | "history" => match self.list_page(url.as_str()) {
+                Some(page) => self.show_list(url.as_str(), page),
+                None => {
+                    self.set_error_message(format!("Unknown page: {}", url));
+                    self.clear_screen_and_render_page();
+                }
+            },
+            "jumps" => {
+                let content = self.jumps_page();
+                self.show_overlay("about:jumps", content);
+            }
+            "messages" => {
+                let content = self.messages_page();
+                self.show_overlay("about:messages", content);
+            }
+            "bindings" => {
+                let content = self.keymap.page();
+                self.show_overlay("about:bindings", content);
+            }
+            _ => {
+                self.set_error_message(format!("Unknown page: {}", url));
+                self.clear_screen_and_render_page();
+            }
+        }
+    }
+
+    fn cache_page(&self) -> String {
+        let mut content = format!(
+            "Page cache: {} entries, {} of {}\nPress d on an entry to evict it\n\n",
+            self.page_cache.len(),
+            human::bytes(self.page_cache.total_bytes()),
+            human::bytes(self.page_cache.max_bytes()),
+        );
+
+        for entry in self.page_cache.entries() {
+            content.push_str(&format!(
+                "=> {url} {url} ({size}, {age} ago{prefetched})\n",
+                url = entry.url,
+                size = human::bytes(entry.size),
+                age = human::duration(entry.age),
+                prefetched = if entry.prefetched { ", prefetched" } else { "" },
+            ));
+        }
+
+        content
+    }
+
+    /// Delete the entry under the cursor on an internal list page
+    pub fn delete_entry(&mut self) {
+        let url = match self.content().get(self.pane.current_line_index) {
+            Some(Line::Link { target, .. }) => target.clone(),
+            _ => None,
+        };
+
+        if let (Some("about:cache"), Some(url)) = (self.overlay_url(), url) {
+            self.page_cache.remove(&url);
+            let content = self.cache_page();
+            self.refresh_overlay(content);
+            return;
+        }
+
+        let entry = self
+            .pane
+            .overlay
+            .as_ref()
+            .and_then(|overlay| overlay.entries.get(&self.pane.current_line_index))
+            .cloned();
+        let description = match self.content().get(self.pane.current_line_index) {
+            Some(Line::Link { url, .. }) => url.clone(),
+            _ => "this entry".to_string(),
+        };
+        if let Some(entry) = entry {
+            self.confirm(
+                format!("Delete {}? y/n", description),
+                PendingAction::DeleteEntry(entry),
+            );
+            self.clear_screen_and_render_page();
+        }
+    }
+
+    /// Delete a record from the store behind a list page, it's kept until the next deletion so
+    /// it can be undone
+    fn remove_entry(&mut self, entry: Entry) {
+        let removed = match entry {
+            Entry::Visit(url) => self
+                .visited
+                .remove(&url)
+                .map(|visit| visit.map(|visit| Deleted::Visit(url, visit))),
+            Entry::Bookmark(url) => self
+                .bookmarks
+                .remove(&url)
+                .map(|removed| removed.map(|(index, bookmark)| Deleted::Bookmark(index, bookmark))),
+            Entry::Download(position) => self.downloads.remove(position).map(|download| {
+                download.map(|download| Deleted::Download(position, Box::new(download)))
+            }),
+        };
+
+        match removed {
+            Ok(Some(deleted)) => {
+                self.deleted = Some(deleted);
+                self.set_message("Deleted, u to undo".to_string());
+            }
+            Ok(None) => self.set_error_message("Already deleted".to_string()),
+            Err(e) => self.set_error_message(format!("Unable to delete: {}", e)),
+        }
+        self.refresh_list();
+    }
+
+    /// Put back the last entry deleted from a list page
+    pub fn undo(&mut self) {
+        let restored = match self.deleted.take() {
+            Some(Deleted::Visit(url, visit)) => self.visited.restore(url, visit),
+            Some(Deleted::Bookmark(index, bookmark)) => self.bookmarks.restore(index, bookmark),
+            Some(Deleted::Download(position, download)) => {
+                self.downloads.restore(position, *download)
+            }
+            None => {
+                self.set_error_message("Nothing to undo".to_string());
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+
+        match restored {
+            Ok(()) => self.set_message("Restored".to_string()),
+            Err(e) => self.set_error_message(format!("Unable to restore: {}", e)),
+        }
+        self.refresh_list();
+        self.clear_screen_and_render_page();
+    }
+
+    /// Close an internal page, returning to where we were on the current page
+    pub fn close_overlay(&mut self) {
+        #[cfg(feature = "image_preview")]
+        if let Some(preview) = self.pane.image_preview.take() {
+            terminal::clear_image(preview.protocol).unwrap();
+            self.clear_screen_and_render_page();
+            return;
+        }
+
+        if let Some(overlay) = self.pane.overlay.take() {
+            let (current_line_index, scroll_offset) = overlay.saved_position;
+            self.pane.current_line_index = current_line_index;
+            self.pane.scroll_offset = scroll_offset;
+            self.refresh_search();
+            self.clear_screen_and_render_page();
+        }
+    }
+
+    /// Show metadata about the current page
+    pub fn info(&mut self) {
+        let meta = match &self.pane.page_meta {
+            Some(meta) => meta,
+            None => {
+                self.set_error_message("No page loaded".to_string());
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+
+        let lines: Vec<_> = self
+            .pane
+            .content
+            .as_deref()
+            .map(gemtext::parse)
+            .unwrap_or_default();
+        let links = lines
+            .iter()
+            .filter(|l| matches!(l, Line::Link { .. }))
+            .count();
+        let charset = meta
+            .mime_type
+            .get_param(mime::CHARSET)
+            .map_or_else(|| "utf-8 (default)".to_string(), |c| c.to_string());
+        let encoding = match (self.pane.encoding, meta.detected_charset) {
+            (Some(encoding), _) => format!("{} (set with :encoding)", encoding),
+            (None, Some(detected)) => format!(
+                "{} (auto-detected, {} gave garbage)",
+                detected,
+                declared_charset(meta)
+            ),
+            (None, None) => declared_charset(meta).to_string(),
+        };
+        let unknown = || "-".to_string();
+        let languages: Vec<_> = meta.languages().collect();
+        let tls_session = match (&meta.tls_version, meta.tls_resumed) {
+            (None, _) => unknown(),
+            (Some(_), true) => "resumed".to_string(),
+            (Some(_), false) => "full handshake".to_string(),
+        };
+        let cached = match meta.cached.map(|fetched| fetched.elapsed()) {
+            Some(Ok(age)) => format!(
+                "served from the cache, fetched {} ago",
+                human::duration(age)
+            ),
+            Some(Err(_)) => "served from the cache".to_string(),
+            None => "no".to_string(),
+        };
+
+        let content = format!(
+            "Page info\n\n\
+             URL: {url}\n\
+             Header: {header}\n\
+             MIME type: {mime_type}\n\
+             Charset: {charset}\n\
+             Encoding: {encoding}\n\
+             Language: {language}\n\
+             Size: {size} bytes\n\
+             Lines: {lines}\n\
+             Links: {links}\n\
+             Fetch duration: {duration} ms\n\
+             TLS version: {tls_version}\n\
+             Certificate fingerprint: {fingerprint}\n\
+             Certificate trust: {trust}\n\
+             Certificate host: {certificate_host}\n\
+             TLS session: {tls_session}\n\
+             Disk cache: {cached}\n\
+             \n\
+             => {url}",
+            url = meta.url,
+            header = meta.header,
+            mime_type = meta.mime_type.essence_str(),
+            charset = charset,
+            encoding = encoding,
+            language = languages.join(", "),
+            size = meta.size,
+            lines = lines.len(),
+            links = links,
+            duration = meta.duration.as_millis(),
+            tls_version = meta.tls_version.clone().unwrap_or_else(unknown),
+            fingerprint = meta.certificate_fingerprint.clone().unwrap_or_else(unknown),
+            trust = match meta.trust {
+                Some(Trust::Pinned) => "matches the pinned certificate".to_string(),
+                Some(Trust::FirstContact) => "pinned this session".to_string(),
+                Some(Trust::Accepted) => {
+                    "DOESN'T MATCH the pinned certificate or ISN'T VALID, accepted this session"
+                        .to_string()
+                }
+                Some(Trust::Unpinned) => "not pinned, fetched in the background".to_string(),
+                Some(Trust::Verified) => "signed by a trusted certificate authority".to_string(),
+                None => unknown(),
+            },
+            certificate_host = match (meta.host_mismatch, &meta.certificate_fingerprint) {
+                (true, _) => "MISMATCH, the certificate isn't for this host".to_string(),
+                (false, Some(_)) => "matches".to_string(),
+                (false, None) => unknown(),
+            },
+            tls_session = tls_session,
+            cached = cached,
+        );
+
+        self.show_overlay("about:info", content);
+    }
+
+    /// Decode the current page again with a charset given by its WHATWG label, or with the one
+    /// it declares for auto
+    pub fn set_encoding(&mut self, label: &str) {
+        let encoding = match label {
+            "auto" => None,
+            label => match gemini::charset_name(label) {
+                Some(name) => Some(name),
+                None => {
+                    self.set_error_message(format!("Unknown encoding: {}", label));
+                    self.clear_screen_and_render_page();
+                    return;
+                }
+            },
+        };
+
+        let (raw, meta) = match (&self.pane.raw, &self.pane.page_meta) {
+            (Some(raw), Some(meta)) => (raw, meta),
+            (None, Some(meta)) => {
+                let message = format!(
+                    "Can't change the encoding of {} from the disk cache, reload! it first",
+                    meta.url
+                );
+                self.set_error_message(message);
+                self.clear_screen_and_render_page();
+                return;
+            }
+            (_, None) => {
+                self.set_error_message("No page loaded".to_string());
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+
+        let charset = encoding
+            .or(meta.detected_charset)
+            .unwrap_or_else(|| declared_charset(meta));
+        self.pane.content = Some(gemini::decode_body(&meta.url, raw, charset));
+        self.pane.encoding = encoding;
+        self.set_message(format!("Decoded as {}", charset));
+        self.pane.reset_collapsed(self.config.collapse_preformatted);
+
+        let last_line = self.pane.line_count().saturating_sub(1);
+        self.pane.current_line_index = self.pane.current_line_index.min(last_line);
+        self.refresh_search();
+        self.clear_screen_and_render_page();
+    }
+
+    pub fn set_error_message(&mut self, message: String) {
+        self.record_message(&message);
+        self.error_message = Some(message);
+    }
+
+    /// Take the error message being shown, it's forgotten by :messages too
+    pub fn take_error_message(&mut self) -> Option<String> {
+        let message = self.error_message.take()?;
+        if self.message_history.back() == Some(&message) {
+            self.message_history.pop_back();
+        }
+        Some(message)
+    }
+
+    /// Show an informational message in the status line
+    pub fn set_message(&mut self, message: String) {
+        self.record_message(&message);
+        self.message = Some(message);
+    }
+
+    /// Add a message to those listed by :messages without showing it
+    pub fn record_message(&mut self, message: &str) {
+        if self.message_history.len() == MAX_MESSAGE_HISTORY {
+            self.message_history.pop_front();
+        }
+        self.message_history.push_back(message.to_string());
+    }
+
+    fn messages_page(&self) -> String {
+        let mut content = format!(
+            "# Messages\n\n{} messages, oldest first\n\n",
+            self.message_history.len()
+        );
+
+        for message in &self.message_history {
+            content.push_str(&format!("* {}\n", message));
+        }
+
+        content
+    }
+
+    /// Change a setting for the rest of the session
+    pub fn set_option(&mut self, key: &str, value: &str) {
+        if let Err(e) = self.config.set(key, value) {
+            self.set_error_message(e.to_string());
+            self.clear_screen_and_render_page();
+            return;
+        }
+
+        // Settings that are only read when diosk starts
+        gemini::dns::set_enabled(self.config.dns_cache);
+        gemini::tls::set_strict_hostnames(self.config.strict_hostnames);
+        gemini::tls::set_verification(self.config.verify.clone());
+        self.page_cache.set_limits(
+            self.config.cache_max_entries,
+            self.config.cache_max_bytes,
+            Duration::from_secs(self.config.cache_max_age),
+        );
+        self.disk_cache = disk_cache(&self.config).filter(|_| !self.replaying);
+
+        // Wrapping and tab stops change how many rows lines take up
+        if key == "wrap" {
+            self.pane.pan = 0;
+        }
+        self.scroll_to_current_line();
+
+        self.set_message(format!("{}={}", key, value));
+        self.clear_screen_and_render_page();
+    }
+
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+        if offline {
+            self.pane.prefetcher.cancel();
+            if let Some(split) = &self.split {
+                split.other.prefetcher.cancel();
+            }
+        }
+    }
+
+    pub fn toggle_offline(&mut self) {
+        self.set_offline(!self.offline);
+        let message = match self.offline {
+            true => "Offline, pages are only loaded from the cache",
+            false => "Online",
+        };
+        self.set_message(message.to_string());
+        self.clear_screen_and_render_page();
+    }
+
+    /// The disk cache pages are served from while offline, even when it isn't being added to
+    fn offline_cache(&self) -> DiskCache {
+        self.disk_cache.clone().unwrap_or_else(|| {
+            DiskCache::new(
+                cache::default_dir(),
+                Duration::from_secs(self.config.disk_cache_max_age),
+                self.config.disk_cache_max_bytes,
+            )
+        })
+    }
+
+    /// Forget every cached page, in memory and on disk
+    pub fn clear_cache(&mut self) {
+        self.page_cache.clear();
+
+        let cache = match &self.disk_cache {
+            Some(cache) => cache,
+            None => {
+                self.set_message("Cleared the page cache".to_string());
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+        match cache.clear() {
+            Ok(entries) => self.set_message(format!(
+                "Cleared the page cache and {} pages on disk",
+                entries
+            )),
+            Err(e) => self.set_error_message(format!("Unable to clear the disk cache: {}", e)),
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    /// Clear the status line, showing the next notification if there is one
+    pub fn clear_messages(&mut self) {
+        self.error_message = None;
+        self.message = self.notifications.pop_front();
+    }
+
+    /// Show a message without replacing one that's already being read
+    fn notify(&mut self, message: String) {
+        if self.error_message.is_some() || self.message.is_some() {
+            self.record_message(&message);
+            self.notifications.push_back(message);
+        } else {
+            self.set_message(message);
+        }
+
+        if self.config.bell {
+            terminal::bell().unwrap();
+        }
+    }
+
+    /// Whether an internal page was opened on top of the current load while it was in progress
+    fn overlay_opened_during_load(&self) -> bool {
+        self.pane
+            .overlay
+            .as_ref()
+            .is_some_and(|overlay| overlay.opened_during == Some(self.pane.request_id))
+    }
+
+    /// Whether something else got the user's attention while the current load was in progress,
+    /// e.g. the other pane, an internal page, or the command line
+    fn looking_elsewhere(&self) -> bool {
+        self.background || self.overlay_opened_during_load() || !matches!(self.mode, Mode::Normal)
+    }
+
+    pub fn new_size(&mut self, width: u16, height: u16) {
+        self.width = width;
+        self.height = height;
+        terminal::resized(height);
+        info!("New size {}x{}", self.width, self.height);
+        self.clear_screen_and_render_page();
+    }
+
+    pub fn clear_screen_and_render_page(&mut self) {
+        if self.background {
+            self.needs_render = true;
+            return;
+        }
+
+        // TODO: We don't always need to clear the screen. Only for things like scrolling.
+        terminal::clear_screen().unwrap();
+
+        self.render_page();
+    }
+
+    fn next_request_id(&mut self) -> RequestId {
+        self.last_request_id += 1;
+        self.last_request_id
+    }
+
+    /// Show the response to a load in the pane that asked for it, a download nobody is waiting
+    /// for anymore is deleted
+    pub fn load_complete(&mut self, request_id: RequestId, response: Response, url: Url) {
+        match (self.is_current_request(request_id), response) {
+            (true, response) => self.in_request_pane(request_id, |state| {
+                state.transaction_complete(response, url)
+            }),
+            (false, Response::Download { file, .. }) => {
+                info!("deleting superseded download {}", file.display());
+                let _ = fs::remove_file(&file);
+            }
+            (false, Response::Body { .. }) => {}
+        }
+    }
+
+    /// Whether either pane is still waiting on the load
+    fn is_current_request(&self, request_id: RequestId) -> bool {
+        self.pane.request_id == request_id
+            || self
+                .split
+                .as_ref()
+                .is_some_and(|split| split.other.request_id == request_id)
+    }
+
+    /// Run f against the pane a response is for, responses to superseded loads are ignored
+    pub fn in_request_pane(&mut self, request_id: RequestId, f: impl FnOnce(&mut Self)) {
+        if self.pane.request_id == request_id {
+            f(self);
+        } else if let Some(split) = &self.split {
+            if split.other.request_id == request_id {
+                self.in_other_pane(f);
+            }
+        }
+    }
+
+    /// Run f against the pane without focus, as if it had focus, then redraw the screen if it
+    /// changed
+    fn in_other_pane(&mut self, f: impl FnOnce(&mut Self)) {
+        let split = match self.split.as_mut() {
+            Some(split) => split,
+            None => return,
+        };
+
+        mem::swap(&mut self.pane, &mut split.other);
+        self.background = true;
+        f(self);
+        self.background = false;
+        if let Some(split) = self.split.as_mut() {
+            mem::swap(&mut self.pane, &mut split.other);
+        }
+
+        if mem::take(&mut self.needs_render) {
+            self.clear_screen_and_render_page();
+        }
+    }
+
+    /// Show the current page in a second pane alongside this one
+    pub fn vsplit(&mut self) {
+        if self.split.is_some() {
+            self.set_error_message("Already split".to_string());
+        } else {
+            self.split();
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    fn split(&mut self) {
+        self.split = Some(Split {
+            other: Pane::default(),
+            focus: Side::Left,
+        });
+        // Lines wrap differently in half the width
+        self.show_line(self.pane.current_line_index);
+        if let Some(split) = self.split.as_mut() {
+            split.other = self.pane.duplicate();
+        }
+    }
+
+    /// Load a URL, or the link on the current line, in the other pane keeping focus in this one,
+    /// the screen is split first if it needs to be
+    pub fn open_in_other_pane(&mut self, url_or_path: Option<&str>) {
+        let url_or_path = match (
+            url_or_path,
+            self.content().get(self.pane.current_line_index),
+        ) {
+            (Some(url_or_path), _) => url_or_path.to_string(),
+            (
+                None,
+                Some(Line::Link {
+                    target: Some(target),
+                    ..
+                }),
+            ) => target.to_string(),
+            (None, Some(Line::Link { url, .. })) => url.clone(),
+            _ => {
+                self.set_error_message("Not on a link".to_string());
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+        let url = match self.qualify_url(&url_or_path) {
+            Some(url) => url,
+            None => return,
+        };
+
+        if self.split.is_none() {
+            self.split();
+        }
+        self.in_other_pane(|state| state.request(url.as_str()));
+        self.clear_screen_and_render_page();
+    }
+
+    /// Close the pane with focus, the other pane takes the whole screen
+    pub fn close_pane(&mut self) {
+        match self.split.take() {
+            Some(split) => {
+                if let Some(cancelled) = self.pane.cancel_load.take() {
+                    cancelled.store(true, Ordering::SeqCst);
+                }
+                self.pane = split.other;
+                self.show_line(self.pane.current_line_index);
+            }
+            None => self.set_error_message("Only one pane is open".to_string()),
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    /// Move focus to the other pane
+    pub fn next_pane(&mut self) {
+        match self.split.as_mut() {
+            Some(split) => {
+                mem::swap(&mut self.pane, &mut split.other);
+                split.focus = split.focus.other();
+            }
+            None => self.set_error_message("Only one pane is open".to_string()),
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    pub fn transaction_complete(&mut self, response: Response, url: Url) {
+        self.pane.cancel_load = None;
+
+        if let Response::Download { file, meta } = response {
+            let request_id = self.pane.request_id;
+            self.downloads
+                .received(request_id, &meta.url, &meta.mime_type, meta.size, &file);
+            self.pane.loading = false;
+            self.pane.forced_refresh = false;
+            // Only the pane with focus decides the mode
+            if !self.background {
+                self.mode = Mode::Normal;
+            }
+
+            #[cfg(feature = "image_preview")]
+            let (meta, file) = match self.offer_image_preview(meta, file) {
+                Some(download) => download,
+                None => {
+                    self.clear_screen_and_render_page();
+                    return;
+                }
+            };
+
+            self.save_download(meta, file);
+            self.clear_screen_and_render_page();
+            return;
+        }
+
+        if !self.replaying {
+            let size = response.meta().size;
+            self.page_cache
+                .insert(&url, for_page_cache(response.clone()), size, false);
+        }
+
+        if self.pane.forced_refresh {
+            self.pane.forced_refresh = false;
+            self.set_message(format!("Refreshed {}", url));
+        }
+
+        match response {
+            Response::Body {
+                mut content,
+                raw,
+                status_code,
+                meta,
+            } => {
+                // The page is loaded underneath whatever is being looked at, and announced unless
+                // it's a reload
+                if self.looking_elsewhere() && self.pane.current_url.as_ref() != Some(&meta.url) {
+                    self.notify(format!("Finished loading {}", meta.url));
+                }
+
+                // Remembered for when the page being left is opened again
+                match &self.pane.current_url {
+                    Some(left) if *left != meta.url && self.config.remember_positions => {
+                        let (line, scroll_offset) = self.pane.page_position();
+                        self.positions.leave(
+                            left,
+                            Position {
+                                line,
+                                scroll_offset,
+                            },
+                        );
+                    }
+                    _ => {}
+                }
+
+                // Move the current line back to the top of the page
+                let keep_overlay = self.overlay_opened_during_load();
+                match self.pane.overlay.as_mut() {
+                    Some(overlay) if keep_overlay => {
+                        overlay.saved_position = (0, 0);
+                        self.pane.restore_line = None;
+                        self.pane.restore_scroll_offset = None;
+                    }
+                    _ => {
+                        self.pane.current_line_index = 0;
+                        self.pane.scroll_offset = 0;
+                        self.pane.overlay = None;
+                    }
+                }
+
+                let new_page = self.pane.current_url.as_ref() != Some(&meta.url);
+                // A page opened again goes back to where it was left, unless it's opened at a
+                // fragment, gg is there for the top
+                let remembered = match (new_page, keep_overlay, url.fragment()) {
+                    (true, false, None) if self.config.remember_positions => {
+                        self.positions.get(&meta.url)
+                    }
+                    _ => None,
+                };
+                if new_page {
+                    self.pane.marks.clear();
+                    self.pane.pan = 0;
+                    self.pane.encoding = None;
+                    self.pane.search = None;
+                    self.pane.search_start = None;
+                }
+                // An encoding chosen for a page sticks to it when it's reloaded
+                if let (Some(encoding), Some(raw)) = (self.pane.encoding, &raw) {
+                    content = Some(gemini::decode_body(&meta.url, raw, encoding));
+                }
+
+                // Links on the page are relative to where it was served from, after redirects
+                // Reloads don't count as another visit, nor does a redirect to the same page
+                if new_page {
+                    self.visited.insert(&meta.url);
+                    if gemini::normalize_url(&url) != gemini::normalize_url(&meta.url) {
+                        self.visited.insert(&url);
+                    }
+                }
+                self.pane.content = content;
+                self.pane.reset_collapsed(self.config.collapse_preformatted);
+                self.pane.raw = raw;
+                self.pane.selected_url = None;
+                self.pane.current_url = Some(meta.url.clone());
+                self.pane.last_status_code = Some(status_code);
+                // Only refused with strict_hostnames, the page is shown but not without a word
+                if let (true, Some(host)) = (meta.host_mismatch, meta.url.host_str()) {
+                    self.set_error_message(format!("Warning: the certificate isn't for {}", host));
+                }
+                self.pane.page_meta = Some(meta);
+                self.refresh_search();
+
+                if let (Some(position), None) = (remembered, self.pane.restore_line) {
+                    self.pane.restore_line = Some(position.line);
+                    self.pane.restore_scroll_offset = Some(position.scroll_offset);
+                }
+
+                // Going back or reloading puts the page back where it was instead
+                let restoring = self.pane.restore_line.is_some();
+                self.restore_position();
+                match url.fragment() {
+                    Some(fragment) if !restoring && !keep_overlay => self.go_to_fragment(fragment),
+                    _ => {}
+                }
+            }
+            Response::Download { .. } => unreachable!("handled above"),
+        }
+
+        self.pane.loading = false;
+        self.clear_screen_and_render_page();
+
+        self.prefetch_links();
+        self.fetch_favicon();
+    }
+
+    /// Fetch the favicon of the page's capsule in the background, the first time it's visited
+    ///
+    /// It's fetched after the page has loaded so it can't hold anything up.
+    fn fetch_favicon(&mut self) {
+        if !self.config.favicons || self.offline {
+            return;
+        }
+        let favicon_url = match self.pane.current_url.as_ref() {
+            Some(url) => match self.favicons.start(url) {
+                Some(favicon_url) => favicon_url,
+                None => return,
+            },
+            None => return,
+        };
+
+        let fetcher = self.fetcher.clone();
+        let tx = self.tx.clone();
+        // Never cancelled, it's only a few bytes
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.favicon_requests.submit(cancelled, move || {
+            let timeouts = Timeouts {
+                page: Some(FAVICON_TIMEOUT),
+                download: Some(FAVICON_TIMEOUT),
+            };
+            let mut progress = |mime_type: &Mime, received| {
+                mime_type.essence_str() == "text/plain" && received <= favicons::MAX_BYTES
+            };
+
+            // Saved as a download, only ever a few bytes, and not something to pin a certificate on
+            let icon = tls::in_background(|| {
+                fetcher.fetch(&favicon_url, &env::temp_dir(), &timeouts, &mut progress)
+            })
+            .ok()
+            .and_then(favicons::read);
+            info!("favicon for {}: {:?}", redact(&favicon_url), icon);
+            let _ = tx.send(Event::Favicon(favicon_url, icon));
+        });
+    }
+
+    pub fn favicon_received(&mut self, favicon_url: Url, icon: Option<String>) {
+        let shown = icon.is_some();
+        self.favicons.received(&favicon_url, icon);
+        if shown {
+            self.render_page();
+        }
+    }
+
+    /// Fetch the first few same host links on the page into the page cache
+    fn prefetch_links(&mut self) {
+        let current_url = match (&self.pane.current_url, self.config.prefetch) {
+            (Some(url), n) if n > 0 && !self.offline && !self.replaying => url,
+            _ => return,
+        };
+
+        let mut urls: Vec<Url> = Vec::new();
+        for line in self.content() {
+            if let Line::Link {
+                target: Some(url), ..
+            } = line
+            {
+                let key = gemini::normalize_url(&url);
+
+                if url.scheme() == "gemini"
+                    && url.host() == current_url.host()
+                    && key != gemini::normalize_url(current_url)
+                    && !self.page_cache.contains(&url)
+                    && !urls.iter().any(|u| gemini::normalize_url(u) == key)
+                {
+                    urls.push(url);
+                }
+            }
+            if urls.len() >= self.config.prefetch {
+                break;
+            }
+        }
+
+        self.pane
+            .prefetcher
+            .start(&self.fetcher, urls, timeouts(&self.config), &self.tx);
+    }
+
+    pub fn prefetch_complete(&mut self, response: Response, url: Url, generation: usize) {
+        let current = self.pane.prefetcher.is_current(generation)
+            || self
+                .split
+                .as_ref()
+                .is_some_and(|split| split.other.prefetcher.is_current(generation));
+        if !current {
+            info!("ignoring stale prefetch: {}", redact(&url));
+            return;
+        }
+
+        if let Response::Body { .. } = response {
+            info!("prefetched: {}", redact(&url));
+            let size = response.meta().size;
+            self.page_cache
+                .insert(&url, for_page_cache(response), size, true);
+        }
+    }
+
+    /// Ask whether an image should be shown inline rather than downloaded, when the terminal
+    /// supports it
+    ///
+    /// Returns the download back when the image can't be previewed.
+    #[cfg(feature = "image_preview")]
+    fn offer_image_preview(
+        &mut self,
+        meta: PageMeta,
+        file: PathBuf,
+    ) -> Option<(PageMeta, PathBuf)> {
+        let protocol = match terminal::image::Protocol::detect() {
+            Some(protocol) if self.config.image_preview => protocol,
+            _ => return Some((meta, file)),
+        };
+        if !matches!(meta.mime_type.essence_str(), "image/png" | "image/jpeg") {
+            return Some((meta, file));
+        }
+
+        self.confirm(
+            format!(
+                "View {} inline? y/n (n saves it)",
+                meta.mime_type.essence_str()
+            ),
+            PendingAction::PreviewImage {
+                meta: Box::new(meta),
+                file,
+                protocol,
+            },
+        );
+        None
+    }
+
+    #[cfg(feature = "image_preview")]
+    fn show_image_preview(
+        &mut self,
+        meta: PageMeta,
+        file: PathBuf,
+        protocol: terminal::image::Protocol,
+    ) {
+        let image = fs::read(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|data| terminal::image::decode(&data).map_err(|e| e.to_string()));
+
+        match image {
+            Ok(image) => {
+                // Viewed rather than saved
+                let _ = fs::remove_file(&file);
+                self.downloads.viewed(&file);
+                self.pane.image_preview = Some(ImagePreview {
+                    image,
+                    protocol,
+                    url: meta.url,
+                });
+            }
+            Err(e) => {
+                self.set_error_message(format!("Unable to decode image: {}", e));
+                self.save_download(meta, file);
+            }
+        }
+    }
+
+    fn download_dir(&self) -> PathBuf {
+        self.config
+            .download_dir
+            .clone()
+            .unwrap_or_else(download::default_dir)
+    }
+
+    /// Move a response that can't be displayed from its temporary file to its place in the
+    /// download directory
+    fn save_download(&mut self, meta: PageMeta, file: PathBuf) {
+        let dir = self.download_dir();
+        let name = download::file_name(&meta.url);
+        let path = match self.config.download_conflict {
+            DownloadConflict::Rename => download::unique_path(&dir, &name),
+            DownloadConflict::Prompt => dir.join(&name),
+        };
+
+        if path.exists() {
+            self.confirm(
+                format!("{} already exists, overwrite it? y/n", path.display()),
+                PendingAction::OverwriteDownload {
+                    meta: Box::new(meta),
+                    file,
+                    path,
+                },
+            );
+        } else {
+            self.write_download(meta, file, path);
+        }
+    }
+
+    fn write_download(&mut self, meta: PageMeta, file: PathBuf, path: PathBuf) {
+        // The temporary file is in the same directory so this is atomic
+        if let Err(e) = fs::rename(&file, &path) {
+            let _ = fs::remove_file(&file);
+            self.downloads
+                .discarded(&file, Status::Failed(e.to_string()));
+            self.set_error_message(format!("Unable to save {}: {}", path.display(), e));
+            return;
+        }
+        self.downloads.saved(&file, &path);
+
+        let PageMeta {
+            url,
+            mime_type,
+            size,
+            ..
+        } = meta;
+
+        info!("saved {} to {}", redact(&url), path.display());
+        self.set_message(format!("Saved {} ({})", path.display(), human::bytes(size)));
+
+        let pending_open = PendingOpen {
+            target: OpenTarget::Download(path.clone()),
+            command: self.config.handler(&mime_type).to_string(),
+        };
+        match self.config.open_downloads {
+            OpenDownloads::Never => {}
+            OpenDownloads::Always => self.pending_open = Some(pending_open),
+            OpenDownloads::Ask => self.confirm(
+                format!(
+                    "Saved {}, open it with {}? y/n",
+                    path.display(),
+                    pending_open.command
+                ),
+                PendingAction::LaunchHandler(pending_open),
+            ),
+        }
+    }
+
+    /// Open a download saved to path with the handler for its MIME type
+    fn open_download(&mut self, path: PathBuf, mime_type: &Mime) {
+        self.pending_open = Some(PendingOpen {
+            command: self.config.handler(mime_type).to_string(),
+            target: OpenTarget::Download(path),
+        });
+    }
+
+    /// Whether a download or link is waiting for open_pending
+    pub fn has_pending_open(&self) -> bool {
+        self.pending_open.is_some()
+    }
+
+    /// Run the handler for a download or link waiting to be opened, suspending the terminal while
+    /// it runs so both GUI and terminal programs work
+    pub fn open_pending(&mut self) {
+        let PendingOpen { target, command } = match self.pending_open.take() {
+            Some(pending_open) => pending_open,
+            None => return,
+        };
+
+        // A download is already saved so on failure make sure the user knows where to find it
+        let saved_to = match &target {
+            OpenTarget::Download(path) => format!(", saved to {}", path.display()),
+            OpenTarget::Url(_) => String::new(),
+        };
+
+        info!("opening {} with {}", target, command);
+        if let Err(e) = terminal::suspend() {
+            self.set_error_message(format!("Unable to suspend the terminal: {}{}", e, saved_to));
+            self.clear_screen_and_render_page();
+            return;
+        }
+        let status = download::open(&command, target.as_os_str());
+        if let Err(e) = terminal::resume() {
+            info!("unable to resume the terminal: {}", e);
+        }
+
+        match status {
+            Ok(status) if status.success() => {
+                self.set_message(format!("Opened {} with {}", target, command))
+            }
+            Ok(status) => {
+                self.set_error_message(format!("{} exited with {}{}", command, status, saved_to))
+            }
+            Err(e) => {
+                self.set_error_message(format!("Unable to launch {}: {}{}", command, e, saved_to))
+            }
+        }
+
+        self.clear_screen_and_render_page();
+    }
+
+    /// Hand a link diosk can't load to its handler, asking first unless its scheme is allowed
+    fn open_external(&mut self, url: Url) {
+        let scheme = url.scheme().to_string();
+        let pending_open = PendingOpen {
+            command: self.config.scheme_handler(&scheme).to_string(),
+            target: OpenTarget::Url(url),
+        };
+
+        if self.config.allowed_schemes.contains(&scheme) {
+            self.pending_open = Some(pending_open);
+            return;
+        }
+
+        self.confirm(
+            format!(
+                "Open {} with {}? y/n/a(lways for {})",
+                pending_open.target, pending_open.command, scheme
+            ),
+            PendingAction::OpenExternal(pending_open),
+        );
+        self.clear_screen_and_render_page();
+    }
+
+    /// Answer yes to opening a link and stop asking for its scheme, or to loading a page and pin
+    /// its new certificate, other prompts ignore this
+    pub fn answer_always(&mut self) {
+        if let Some(Confirmation {
+            action: PendingAction::AcceptCertificate { permanently, .. },
+            ..
+        }) = &mut self.confirmation
+        {
+            *permanently = true;
+            self.answer(true);
+            return;
+        }
+
+        let scheme = match &self.confirmation {
+            Some(Confirmation {
+                action:
+                    PendingAction::OpenExternal(PendingOpen {
+                        target: OpenTarget::Url(url),
+                        ..
+                    }),
+                ..
+            }) => url.scheme().to_string(),
+            _ => return,
+        };
+
+        self.config.allowed_schemes.push(scheme.clone());
+        let allowed = self.config.allowed_schemes.join(",");
+        let config_file = dirs::config_dir().join(CONFIG_FILE);
+        if let Err(e) = Config::save(&config_file, "allowed_schemes", &allowed) {
+            self.set_error_message(format!("Unable to save allowed_schemes: {}", e));
+        }
+
+        self.answer(true);
+    }
+
+    /// Bookmark the page being shown, titled with its first heading
+    pub fn bookmark(&mut self) {
+        let url = match &self.pane.current_url {
+            Some(url) => url.clone(),
+            None => {
+                self.set_error_message("No page loaded".to_string());
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+        let title = self
+            .pane
+            .content
+            .as_deref()
+            .and_then(|content| content.lines().find_map(gemtext::heading))
+            .map(str::to_string)
+            .unwrap_or_else(|| url.to_string());
+
+        match self.bookmarks.insert(url, &title) {
+            Ok(true) => self.set_message(format!("Bookmarked {}", title)),
+            Ok(false) => self.set_message("Already bookmarked".to_string()),
+            Err(e) => self.set_error_message(format!("Unable to save bookmarks: {}", e)),
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    /// Add the bookmarks in a gemtext or browser bookmarks file, reporting what was imported
+    pub fn import_bookmarks(&mut self, path: &str, all_schemes: bool) {
+        let path = config::expand_home(path);
+        let imported = fs::read_to_string(&path)
+            .and_then(|content| self.bookmarks.import(&content, all_schemes));
+        match imported {
+            Ok(import) => self.set_message(import.to_string()),
+            Err(e) => self.set_error_message(format!("Unable to import {}: {}", path.display(), e)),
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    /// Write the bookmarks to a gemtext file, an existing file is only replaced when forced
+    pub fn export_bookmarks(&mut self, path: &str, force: bool) {
+        let path = config::expand_home(path);
+        match self.bookmarks.export(&path, force) {
+            Ok(()) => self.set_message(format!(
+                "Exported {} bookmarks to {}",
+                self.bookmarks.len(),
+                path.display()
+            )),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => self.set_error_message(format!(
+                "{} already exists, add ! to overwrite it",
+                path.display()
+            )),
+            Err(e) => self.set_error_message(format!("Unable to write {}: {}", path.display(), e)),
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    /// Write the page as it's shown to a file, with colors as ANSI escape sequences unless it's
+    /// plain text
+    ///
+    /// Without a path it's saved to the download directory, named after the page.
+    pub fn hardcopy(&mut self, path: Option<&str>, plain: bool, status_line: bool) {
+        #[cfg(feature = "image_preview")]
+        if self.pane.image_preview.is_some() {
+            self.set_error_message("Images can't be written as text".to_string());
+            self.clear_screen_and_render_page();
+            return;
+        }
+
+        let url = match self.displayed_url() {
+            Some(url) => url.clone(),
+            None => {
+                self.set_error_message("No page loaded".to_string());
+                self.clear_screen_and_render_page();
+                return;
+            }
+        };
+
+        let terminal = self.terminal();
+        let status_line_context = if status_line {
+            Some(StatusLineContext::new_from_state(self))
+        } else {
+            None
+        };
+        let highlights = Highlights {
+            selected_url: self.pane.selected_url(),
+            search: self.pane.search.as_ref(),
+            syntax: None,
+        };
+        let mut contents = terminal
+            .hardcopy(
+                self.pane.current_line_index,
+                &highlights,
+                self.content(),
+                self.pane.scroll_offset,
+                &|url| self.visited.contains(url),
+                status_line_context,
+            )
+            .unwrap();
+        if plain {
+            contents = terminal::strip_ansi(&contents).into_bytes();
+        }
+
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let extension = if plain { "txt" } else { "ans" };
+                self.download_dir()
+                    .join(format!("{}.{}", download::file_name(&url), extension))
+            }
+        };
+        match fs::write(&path, &contents) {
+            Ok(()) => self.set_message(format!(
+                "Wrote {} ({})",
+                path.display(),
+                human::bytes(contents.len())
+            )),
+            Err(e) => self.set_error_message(format!("Unable to write {}: {}", path.display(), e)),
+        }
+        self.clear_screen_and_render_page();
+    }
+
+    /// Map keys to commands for the session
+    pub fn map(&mut self, keys: &str, commands: &str) {
+        match keymap::parse(keys) {
+            Some(parsed) => {
+                self.keymap.map(&parsed, commands);
+                self.set_message(format!("Mapped {} to :{}", keys, commands));
+            }
+            None => self.set_error_message(format!("Invalid keys: {}", keys)),
+        }
+        self.keymap_changed();
+    }
+
+    pub fn unmap(&mut self, keys: &str) {
+        match keymap::parse(keys) {
+            Some(parsed) if self.keymap.unmap(&parsed) => {
+                self.set_message(format!("Unmapped {}", keys))
+            }
+            _ => self.set_error_message(format!("No mapping for {}", keys)),
+        }
+        self.keymap_changed();
+    }
+
+    fn keymap_changed(&mut self) {
+        if self.overlay_url() == Some("about:bindings") {
+            let content = self.keymap.page();
+            self.refresh_overlay(content);
+        } else {
+            self.clear_screen_and_render_page();
+        }
+    }
+
+    /// Update the time left in the status line as the load nears its deadline
+    /// Redraw the status line's clock when the minute changes
+    pub fn tick_clock(&mut self) {
+        let has_clock = self
+            .config
+            .status_line
+            .iter()
+            .any(|segment| segment.kind == SegmentKind::Clock);
+        let now = Local::now();
+        let clock = Some((now.hour(), now.minute())).filter(|_| has_clock);
+        if clock != self.clock_shown {
+            self.clock_shown = clock;
+            self.render_page();
+        }
+    }
+
+    pub fn tick_deadline(&mut self) {
+        let remaining = self.deadline_remaining();
+        if remaining != self.pane.deadline_shown {
+            self.pane.deadline_shown = remaining;
+            self.render_page();
+        }
+    }
+
+    /// Seconds until the load is given up on, once there are few enough to be worth showing
+    fn deadline_remaining(&self) -> Option<u64> {
+        if !self.pane.loading {
+            return None;
+        }
+
+        let timeouts = timeouts(&self.config);
+        let timeout = match self.pane.load_download {
+            true => timeouts.download,
+            false => timeouts.page,
+        };
+        let deadline = self.pane.load_started? + timeout?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        (remaining <= DEADLINE_WARNING)
+            .then(|| remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0))
+    }
+
+    /// Show that the load is trying again after failing to connect
+    pub fn retrying(&mut self, attempt: usize, attempts: usize) {
+        if self.pane.loading {
+            self.pane.retrying = Some((attempt, attempts));
+            self.pane.load_started = Some(Instant::now());
+            self.render_page();
+        }
+    }
+
+    /// Record how much a download has received, updating the downloads page if it's shown
+    pub fn download_progress(
+        &mut self,
+        request_id: RequestId,
+        url: &Url,
+        mime_type: &Mime,
+        received: usize,
+    ) {
+        // Sent before the load was cancelled
+        if !self.is_current_request(request_id) {
+            return;
+        }
+
+        self.downloads
+            .progress(request_id, url, mime_type, received);
+        self.in_request_pane(request_id, |state| state.pane.load_download = true);
+
+        if self.overlay_url() == Some("about:downloads") {
+            self.refresh_list();
+        }
+    }
+
+    pub fn download_prompt(
+        &mut self,
+        mime_type: Mime,
+        received: usize,
+        decision: mpsc::Sender<bool>,
+    ) {
+        self.confirm(
+            format!(
+                "Receiving {}, {} so far — keep downloading? y/n",
+                mime_type.essence_str(),
+                human::bytes(received)
+            ),
+            PendingAction::ContinueDownload(decision),
+        );
+        self.clear_screen_and_render_page();
+    }
+
+    /// Cancel the load in progress, if there is one
+    pub fn cancel(&mut self) {
+        if let Some(cancelled) = self.pane.cancel_load.take() {
+            cancelled.store(true, Ordering::SeqCst);
+            self.downloads
+                .stopped(self.pane.request_id, Status::Cancelled);
+
+            // Don't wait for the request thread to notice, anything it sends now is stale
+            self.pane.request_id = self.next_request_id();
+            self.pane.loading = false;
+            self.set_message("Cancelled".to_string());
+            self.clear_screen_and_render_page();
+        }
+    }
+
+    /// Trust a certificate that differs from its host's pin and load the page it stopped
+    fn accept_certificate(&mut self, certificate: ChangedCertificate, permanently: bool) {
+        let ChangedCertificate {
+            url,
+            host,
+            port,
+            fingerprint,
+        } = certificate;
+        if let Err(e) = tls::accept(&host, port, &fingerprint, permanently) {
+            self.set_error_message(format!("Unable to save the pin for {}: {}", host, e));
+        }
+        self.fetch(url, false);
+    }
+
+    /// Ask the user to confirm an action before it happens
+    fn confirm(&mut self, prompt: String, action: PendingAction) {
+        self.confirmation = Some(Confirmation { prompt, action });
+        self.mode = Mode::Confirm;
+    }
+
+    /// Handle the answer to the pending confirmation prompt
+    pub fn answer(&mut self, yes: bool) {
+        self.mode = Mode::Normal;
+
+        if let Some(Confirmation { action, .. }) = self.confirmation.take() {
+            match (action, yes) {
+                (PendingAction::OverwriteDownload { meta, file, path }, true) => {
+                    self.write_download(*meta, file, path)
+                }
+                (PendingAction::OverwriteDownload { file, .. }, false) => {
+                    let _ = fs::remove_file(&file);
+                    self.downloads.discarded(&file, Status::Cancelled);
+                    self.set_message("Download discarded".to_string())
+                }
+                (PendingAction::ContinueDownload(decision), yes) => {
+                    // The load may have finished or been cancelled in the meantime
+                    let _ = decision.send(yes);
+                }
+                (
+                    PendingAction::LaunchHandler(pending_open)
+                    | PendingAction::OpenExternal(pending_open),
+                    true,
+                ) => self.pending_open = Some(pending_open),
+                (PendingAction::LaunchHandler(_) | PendingAction::OpenExternal(_), false) => {}
+                (PendingAction::Quit, true) => {
+                    self.quit();
+                    return;
+                }
+                (PendingAction::Quit, false) => {}
+                (PendingAction::DeleteEntry(entry), true) => self.remove_entry(entry),
+                (PendingAction::DeleteEntry(_), false) => {}
+                (
+                    PendingAction::AcceptCertificate {
+                        certificate,
+                        permanently,
+                    },
+                    true,
+                ) => self.accept_certificate(*certificate, permanently),
+                (PendingAction::AcceptCertificate { .. }, false) => {}
+                #[cfg(feature = "image_preview")]
+                (
+                    PendingAction::PreviewImage {
+                        meta,
+                        file,
+                        protocol,
+                    },
+                    yes,
+                ) => {
+                    if yes {
+                        self.show_image_preview(*meta, file, protocol);
+                    } else {
+                        self.save_download(*meta, file);
+                    }
+                }
+            }
+        }
+
+        self.clear_screen_and_render_page();
+    }
+
+    pub fn transaction_error(&mut self, e: TransactionError) {
+        info!("transaction error: {}", e);
+
+        let status = match &e {
+            TransactionError::Cancelled => Status::Cancelled,
+            e => Status::Failed(e.to_string()),
+        };
+        self.downloads.stopped(self.pane.request_id, status);
+
+        let mut changed = None;
+        let not_cached = match e {
+            TransactionError::Cancelled => {
+                self.set_message("Cancelled".to_string());
+                None
+            }
+            TransactionError::NotCached(url) => Some(url),
+            // Only the pane with focus can ask, the other shows the error until it's reloaded
+            TransactionError::CertificateChanged(certificate) if !self.background => {
+                let prompt = format!(
+                    "The certificate of {} has changed, load the page anyway? y/n/a(lways trust it)",
+                    certificate.host
+                );
+                let action = PendingAction::AcceptCertificate {
+                    certificate,
+                    permanently: false,
+                };
+                changed = Some((prompt, action));
+                None
+            }
+            TransactionError::CertificateInvalid {
+                certificate,
+                reason,
+            } if !self.background => {
+                let prompt = format!(
+                    "The certificate of {} isn't valid ({}), load the page anyway? y/n",
+                    certificate.host, reason
+                );
+                let action = PendingAction::AcceptCertificate {
+                    certificate,
+                    permanently: false,
+                };
+                changed = Some((prompt, action));
+                None
+            }
+            e => {
+                self.set_error_message(e.to_string());
+                None
+            }
+        };
+        self.pane.cancel_load = None;
+        self.pane.forced_refresh = false;
+        self.pane.restore_line = None;
+        self.pane.restore_scroll_offset = None;
+        self.pane.loading = false;
+        if !self.background {
+            self.mode = Mode::Normal;
+        }
+        if let Some((prompt, action)) = changed {
+            self.confirm(prompt, action);
+        }
+
+        match not_cached {
+            Some(url) => self.show_overlay("about:offline", offline_page(&url)),
+            None => self.clear_screen_and_render_page(),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+}
+
+pub struct StatusLineContext<'a> {
+    pub status_code: Option<&'a StatusCode>,
+    pub url: Option<&'a Url>,
+    pub error_message: Option<&'a str>,
+    pub message: Option<&'a str>,
+    pub mode: Mode,
+    pub input: &'a str,
+    pub loading: bool,
+    pub confirmation: Option<&'a str>,
+    pub segments: &'a [Segment],
+    pub line_index: usize,
+    pub line_count: usize,
+    pub pending_keys: String,
+    /// Seconds until the page is reloaded
+    pub auto_reload: Option<u64>,
+    pub offline: bool,
+    /// The attempt a load that had to retry is on and how many there can be
+    pub retrying: Option<(usize, usize)>,
+    /// Seconds until the load is given up on, once it's close
+    pub deadline: Option<u64>,
+    /// Requests being made or waiting for a free thread, in either pane
+    pub pending_requests: usize,
+    /// The emoji of the capsule the page is on
+    pub favicon: Option<&'a str>,
+    /// The match of the search moved to, 0 before any has been, and how many there are
+    pub search: Option<(usize, usize)>,
+    /// The lang parameter of the page being shown, language tags separated by commas
+    pub lang: Option<&'a str>,
+    /// The first column in view, counted from 1, while lines aren't wrapped
+    pub column: Option<usize>,
+    /// The charset the page was decoded with when it had to be auto-detected
+    pub detected_charset: Option<&'a str>,
+    /// How the certificate of the page being shown compared to the pinned one
+    pub trust: Option<Trust>,
+    /// Where the link the current line is on leads, when links don't show their URLs
+    pub link: Option<&'a Url>,
+    /// The hour and minute, when the status line has a clock
+    pub clock: Option<(u32, u32)>,
+}
+
+impl<'a> StatusLineContext<'a> {
+    fn new_from_state(state: &'a State) -> Self {
+        Self {
+            status_code: state.pane.last_status_code.as_ref(),
+            url: state.displayed_url(),
+            error_message: state.error_message.as_deref(),
+            message: state.message.as_deref(),
+            mode: state.mode,
+            input: &state.input.input,
+            loading: state.pane.loading,
+            confirmation: state.confirmation.as_ref().map(|c| c.prompt.as_str()),
+            segments: &state.config.status_line,
+            line_index: state.pane.current_line_index,
+            line_count: state.pane.line_count(),
+            pending_keys: state.pending_keys.display(),
+            auto_reload: state.pane.auto_reload.as_ref().map(AutoReload::remaining),
+            offline: state.offline,
+            retrying: state.pane.retrying,
+            deadline: state.pane.deadline_shown,
+            pending_requests: state.requests.pending(),
+            favicon: state
+                .displayed_url()
+                .and_then(|url| state.favicons.get(url)),
+            search: state.pane.search.as_ref().map(Search::position),
+            lang: state.pane.displayed_meta().and_then(PageMeta::lang),
+            column: Some(state.pane.pan + 1).filter(|_| !state.config.wrap),
+            detected_charset: state
+                .pane
+                .displayed_meta()
+                .filter(|_| state.pane.encoding.is_none())
+                .and_then(|meta| meta.detected_charset),
+            trust: state.pane.displayed_meta().and_then(|meta| meta.trust),
+            link: state
+                .pane
+                .current_link
+                .as_ref()
+                .filter(|_| !state.config.show_urls),
+            clock: state.clock_shown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::process;
+
+    use super::*;
+
+    /// A page as the fixture serves it
+    fn page(url: &Url, content: &str) -> Response {
+        gemini::fetcher::Fixture::new(content)
+            .fetch(url, Path::new("."), &Timeouts::default(), &mut |_, _| true)
+            .unwrap()
+    }
+
+    #[test]
+    fn relative_links_are_visited() {
+        let mut visited = Visited::new(Path::new("/nonexistent/visited.txt"));
+        visited.insert(&Url::parse("gemini://example.org/notes/foo.gmi").unwrap());
+        let pane = Pane {
+            current_url: Some(Url::parse("gemini://example.org/notes/").unwrap()),
+            content: Some(
+                "=> foo.gmi\n=> /notes/foo.gmi\n=> gemini://example.org/notes/foo.gmi\n=> bar.gmi"
+                    .to_string(),
+            ),
+            ..Pane::default()
+        };
+
+        let is_visited: Vec<bool> = pane
+            .content()
+            .iter()
+            .map(|line| match line {
+                Line::Link {
+                    target: Some(target),
+                    ..
+                } => visited.contains(target),
+                _ => false,
+            })
+            .collect();
+        assert_eq!(is_visited, [true, true, true, false]);
+    }
+
+    #[test]
+    fn links_resolve_against_what_is_shown() {
+        let mut pane = Pane {
+            current_url: Some(Url::parse("gemini://example.org/a/").unwrap()),
+            content: Some("=> foo".to_string()),
+            ..Pane::default()
+        };
+        let target = |pane: &Pane| match pane.content().remove(0) {
+            Line::Link { target, .. } => target.map(|url| url.to_string()),
+            line => panic!("not a link: {:?}", line),
+        };
+        assert_eq!(target(&pane).as_deref(), Some("gemini://example.org/a/foo"));
+
+        pane.current_url = Some(Url::parse("gemini://example.org/b/").unwrap());
+        assert_eq!(target(&pane).as_deref(), Some("gemini://example.org/b/foo"));
+
+        pane.overlay = Some(Overlay {
+            url: Url::parse("gemini://example.org/c/").unwrap(),
+            content: "=> foo".to_string(),
+            saved_position: (0, 0),
+            opened_during: None,
+            entries: HashMap::new(),
+        });
+        assert_eq!(target(&pane).as_deref(), Some("gemini://example.org/c/foo"));
+    }
+
+    #[test]
+    fn keep_visible() {
+        // 20 lines, the fifth wraps over 3 rows, on a 10 row screen
+        let mut rows = vec![1; 20];
+        rows[4] = 3;
+        let keep = |line, offset, margin| super::keep_visible(&rows, line, 10, offset, margin);
+
+        // Without a margin the screen only moves once the line is off it
+        assert_eq!(keep(7, 0, 0), 0);
+        assert_eq!(keep(8, 0, 0), 1);
+        assert_eq!(keep(3, 5, 0), 3);
+
+        // The margin is counted in rows, wrapped lines included
+        assert_eq!(keep(6, 0, 3), 2);
+        assert_eq!(keep(5, 6, 3), 4);
+        assert_eq!(keep(4, 5, 1), 3);
+
+        // Clamped to the top and bottom of the page, and to what fits on screen
+        assert_eq!(keep(1, 0, 3), 0);
+        assert_eq!(keep(19, 12, 3), 12);
+        assert_eq!(keep(4, 0, 10), 0);
+        assert_eq!(keep(10, 0, 10), 7);
+    }
+
+    #[test]
+    fn pasted_url() {
+        let pasted = |text| super::pasted_url(text).map(|url| url.to_string());
+
+        assert_eq!(
+            pasted("gemini://example.org").as_deref(),
+            Some("gemini://example.org/")
+        );
+        assert_eq!(
+            pasted("example.org/a.gmi").as_deref(),
+            Some("gemini://example.org/a.gmi")
+        );
+        assert_eq!(
+            pasted("example.org:1965/").as_deref(),
+            Some("gemini://example.org:1965/")
+        );
+        assert_eq!(
+            pasted("https://example.org/").as_deref(),
+            Some("https://example.org/")
+        );
+        assert_eq!(pasted(""), None);
+        assert_eq!(pasted("two words"), None);
+        assert_eq!(pasted("notes/a.gmi"), None);
+
+        assert_eq!(preview("a\nb"), "a b");
+        assert_eq!(preview(&"x".repeat(41)), format!("{}…", "x".repeat(40)));
+    }
+
+    #[test]
+    fn scroll_offset() {
+        // 20 lines, the fifth wraps over 3 rows, on a 10 row screen
+        let mut rows = vec![1; 20];
+        rows[4] = 3;
+        let offset = |line, align| super::scroll_offset(&rows, line, 10, align);
+
+        assert_eq!(offset(4, Align::Top), 4);
+        assert_eq!(offset(4, Align::Center), 0);
+        assert_eq!(offset(10, Align::Center), 7);
+        assert_eq!(offset(10, Align::Bottom), 3);
+
+        // Clamped to the first and last rows
+        assert_eq!(offset(1, Align::Bottom), 0);
+        assert_eq!(offset(19, Align::Top), 12);
+    }
+
+    #[test]
+    fn notifications_wait_for_the_status_line() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.set_error_message("Something went wrong".to_string());
+
+        state.notify("Finished loading".to_string());
+        assert_eq!(state.message, None);
+
+        state.clear_messages();
+        assert_eq!(state.error_message, None);
+        assert_eq!(state.message.as_deref(), Some("Finished loading"));
+
+        state.clear_messages();
+        assert_eq!(state.message, None);
+    }
+
+    #[test]
+    fn message_history() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.message_history.clear();
+
+        state.set_error_message("Something went wrong".to_string());
+        state.notify("Finished loading".to_string());
+        state.set_error_message("Unknown page".to_string());
+        assert_eq!(state.take_error_message().as_deref(), Some("Unknown page"));
+
+        assert_eq!(
+            state.message_history,
+            ["Something went wrong", "Finished loading"]
+        );
+        assert!(state.messages_page().contains("* Finished loading\n"));
+    }
+
+    #[test]
+    fn set_encoding() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        let response = Response::Body {
+            content: Some("caf\u{fffd}".to_string()),
+            raw: Some(b"caf\xe9".to_vec()),
+            status_code: StatusCode::Success {
+                code: "20".to_string(),
+                mime_type: None,
+            },
+            meta: PageMeta {
+                url: Url::parse("gemini://example.org/").unwrap(),
+                header: "20 text/gemini; charset=utf-8".to_string(),
+                mime_type: "text/gemini; charset=utf-8".parse().unwrap(),
+                size: 4,
+                duration: Duration::default(),
+                tls_version: None,
+                certificate_fingerprint: None,
+                tls_resumed: false,
+                cached: None,
+                detected_charset: None,
+                trust: None,
+                host_mismatch: false,
+            },
+        };
+        if let Response::Body {
+            content, raw, meta, ..
+        } = response
+        {
+            state.pane.content = content;
+            state.pane.raw = raw;
+            state.pane.page_meta = Some(meta);
+        }
+
+        // Blocks collapsed in the old text don't carry over to the new
+        state.pane.collapsed = HashSet::from([3]);
+        state.set_encoding("latin1");
+        assert_eq!(state.pane.content.as_deref(), Some("café"));
+        assert_eq!(state.message.as_deref(), Some("Decoded as windows-1252"));
+        assert!(state.pane.collapsed.is_empty());
+
+        state.set_encoding("auto");
+        assert_eq!(state.pane.content.as_deref(), Some("caf\u{fffd}"));
+
+        state.set_encoding("klingon");
+        assert_eq!(
+            state.error_message.as_deref(),
+            Some("Unknown encoding: klingon")
+        );
+    }
+
+    #[test]
+    fn collapsed_blocks() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        state.pane.content = Some("Text\n``` alt\na\nb\n```\nAfter".to_string());
+        state.pane.current_line_index = 2;
+
+        state.toggle_block();
+        assert_eq!(state.pane.collapsed, HashSet::from([1]));
+        assert_eq!(state.pane.current_line_index, 1);
+
+        // The hidden lines are stepped over
+        state.down_by(1);
+        assert_eq!(state.pane.current_line_index, 5);
+        state.pane.current_row = 3;
+        state.up_by(1);
+        assert_eq!(state.pane.current_line_index, 1);
+
+        // Counts stop at either end
+        state.up_by(9);
+        assert_eq!(state.pane.current_line_index, 0);
+        state.down_by(2);
+        assert_eq!(state.pane.current_line_index, 5);
+        state.down_by(9);
+        assert_eq!(state.pane.current_line_index, 5);
+
+        // Landing in a collapsed block expands it
+        state.pane.current_line_index = 3;
+        assert!(!state.pane.reveal_current_line()[3].is_hidden());
+        assert!(state.pane.collapsed.is_empty());
+
+        state.pane.current_line_index = 5;
+        state.toggle_block();
+        assert_eq!(
+            state.error_message.as_deref(),
+            Some("Not in a preformatted block")
+        );
+    }
+
+    #[test]
+    fn search() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        state.pane.content = Some("Cat\nDog\n=> /cats Cats\nA dog".to_string());
+        state.pane.current_line_index = 1;
+
+        // The first match from the current line on
+        state.input.input = "cat".to_string();
+        state.start_search();
+        assert_eq!(state.pane.current_line_index, 2);
+        assert_eq!(
+            StatusLineContext::new_from_state(&state).search,
+            Some((2, 2))
+        );
+        state.search_next(1);
+        assert_eq!(state.pane.current_line_index, 0);
+        state.search_previous(1);
+        assert_eq!(state.pane.current_line_index, 2);
+        assert_eq!(state.pane.marks.get(&'\''), Some(&0));
+        state.search_next(2);
+        assert_eq!(state.pane.current_line_index, 2);
+
+        // n goes on from the cursor once it's moved away from the match
+        state.pane.current_line_index = 1;
+        state.search_next(1);
+        assert_eq!(state.pane.current_line_index, 2);
+
+        // Matches are found again when the content changes
+        state.show_overlay("about:messages", "No cats here".to_string());
+        assert_eq!(
+            state.pane.search.as_ref().map(Search::position),
+            Some((0, 1))
+        );
+
+        state.input.input = "Dog".to_string();
+        state.start_search();
+        assert_eq!(
+            state.pane.search.as_ref().map(Search::position),
+            Some((0, 0))
+        );
+        state.search_next(1);
+        assert_eq!(
+            state.error_message.as_deref(),
+            Some("Pattern not found: Dog")
+        );
+
+        // An invalid regular expression is left to be fixed
+        state.search();
+        state.input.input = "(/r".to_string();
+        state.start_search();
+        assert!(matches!(state.mode, Mode::Search));
+        assert_eq!(state.input.input, "(/r");
+        assert_eq!(
+            state.error_message.as_deref(),
+            Some("Invalid pattern: unclosed group")
+        );
+
+        state.set_option("ignorecase", "false");
+        state.input.input = "no".to_string();
+        state.start_search();
+        assert_eq!(
+            state.pane.search.as_ref().map(Search::position),
+            Some((0, 0))
+        );
+    }
+
+    #[test]
+    fn find_link() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        state.pane.content = Some(
+            "=> /about About\nText about\n=> gemini://example.org/ABOUT.gmi\n=> /x Other"
+                .to_string(),
+        );
+
+        // Names and URLs below the current line, then from the top again
+        state.find_link("about");
+        assert_eq!(state.pane.current_line_index, 2);
+        state.find_link("ABOUT");
+        assert_eq!(state.pane.current_line_index, 0);
+        assert_eq!(
+            state.message.as_deref(),
+            Some("Found a link back at the top")
+        );
+
+        state.find_link("missing");
+        assert_eq!(state.pane.current_line_index, 0);
+        assert_eq!(
+            state.error_message.as_deref(),
+            Some("No link matching missing")
+        );
+    }
+
+    #[test]
+    fn delete_and_undo_entries() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        let store = env::temp_dir().join(format!("diosk-delete-entry-{}.gmi", std::process::id()));
+        state.bookmarks = Bookmarks::new(&store);
+        for url in &["gemini://a.org/", "gemini://b.org/", "gemini://c.org/"] {
+            state
+                .bookmarks
+                .insert(Url::parse(url).unwrap(), url)
+                .unwrap();
+        }
+
+        state.internal_page(&Url::parse("about:bookmarks").unwrap());
+        let line = |state: &State, url: &str| {
+            state
+                .content()
+                .iter()
+                .position(|l| matches!(l, Line::Link { url: u, .. } if u == url))
+        };
+        state.pane.current_line_index = line(&state, "gemini://b.org/").unwrap();
+        let position = state.pane.current_line_index;
+
+        // Nothing happens until it's confirmed
+        state.delete_entry();
+        assert!(matches!(state.mode, Mode::Confirm));
+        state.answer(false);
+        assert_eq!(state.bookmarks.len(), 3);
+
+        state.delete_entry();
+        state.answer(true);
+        assert_eq!(state.bookmarks.len(), 2);
+        assert_eq!(line(&state, "gemini://b.org/"), None);
+        assert_eq!(state.pane.current_line_index, position);
+        assert_eq!(Bookmarks::new(&store).len(), 2);
+
+        state.undo();
+        assert_eq!(line(&state, "gemini://b.org/"), Some(position));
+        assert_eq!(Bookmarks::new(&store).len(), 3);
+        state.undo();
+        assert_eq!(state.error_message.as_deref(), Some("Nothing to undo"));
+
+        // Lines that aren't entries can't be deleted
+        state.pane.current_line_index = 0;
+        state.delete_entry();
+        assert!(matches!(state.mode, Mode::Normal));
+
+        fs::remove_file(store).unwrap();
+    }
+
+    #[test]
+    fn pan() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        state.width = 40;
+        state.height = 10;
+        state.pane.content = Some(format!("Short\n{} end", "x".repeat(100)));
+
+        state.pan(1);
+        assert_eq!(state.pane.pan, 0);
+        assert!(state.error_message.is_some());
+
+        state.set_option("wrap", "false");
+        state.pan(1);
+        assert_eq!(state.pane.pan, 8);
+        assert_eq!(StatusLineContext::new_from_state(&state).column, Some(9));
+
+        // No further than the end of the longest line
+        state.pan(20);
+        assert_eq!(state.pane.pan, 64);
+        state.pan(-3);
+        assert_eq!(state.pane.pan, 40);
+        state.pan(-10);
+        assert_eq!(state.pane.pan, 0);
+
+        state.pan(2);
+        state.set_option("wrap", "true");
+        assert_eq!(state.pane.pan, 0);
+        assert_eq!(StatusLineContext::new_from_state(&state).column, None);
+    }
+
+    #[test]
+    fn incremental_search() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        let mut content = vec!["Line"; 40];
+        content[10] = "```";
+        content[11] = "A cat";
+        content[12] = "```";
+        content[30] = "A dog";
+        state.pane.content = Some(content.join("\n"));
+        state.pane.current_line_index = 5;
+        state.pane.scroll_offset = 2;
+        state.pane.collapsed = HashSet::from([10]);
+
+        // Each change to the pattern shows its first match from where the search started
+        state.search();
+        state.input.input = "d".to_string();
+        state.preview_search();
+        assert_eq!(state.pane.current_line_index, 30);
+        state.input.input = "ca".to_string();
+        state.preview_search();
+        assert_eq!(state.pane.current_line_index, 11);
+        assert_eq!(state.pane.scroll_offset, 2);
+        state.pane.reveal_current_line();
+        assert!(state.pane.collapsed.is_empty());
+
+        // Esc goes back to exactly how things were
+        state.cancel_search();
+        assert!(matches!(state.mode, Mode::Normal));
+        assert_eq!(state.pane.current_line_index, 5);
+        assert_eq!(state.pane.scroll_offset, 2);
+        assert_eq!(state.pane.collapsed, HashSet::from([10]));
+        assert!(state.pane.search.is_none());
+
+        // Enter stays on the match, the jump is from where the search started
+        state.search();
+        state.input.input = "dog".to_string();
+        state.preview_search();
+        state.start_search();
+        assert_eq!(state.pane.current_line_index, 30);
+        assert_eq!(state.pane.marks.get(&'\''), Some(&5));
+    }
+
+    #[test]
+    fn duplicate_pane() {
+        let mut pane = Pane {
+            current_line_index: 3,
+            scroll_offset: 2,
+            content: Some("Foo.".to_string()),
+            loading: true,
+            ..Pane::default()
+        };
+        pane.overlay = Some(Overlay {
+            url: Url::parse("about:info").unwrap(),
+            content: String::new(),
+            saved_position: (5, 4),
+            opened_during: None,
+            entries: HashMap::new(),
+        });
+
+        // The page underneath the overlay, without the load in progress
+        let duplicate = pane.duplicate();
+        assert_eq!(duplicate.current_line_index, 5);
+        assert_eq!(duplicate.scroll_offset, 4);
+        assert_eq!(duplicate.content, pane.content);
+        assert!(duplicate.overlay.is_none());
+        assert!(!duplicate.loading);
+    }
+
+    #[test]
+    fn page_cache_hits_cancel_the_load() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        let url = Url::parse("gemini://example.org/").unwrap();
+        state
+            .page_cache
+            .insert(&url, page(&url, "Cached"), 6, false);
+        let previous = Arc::new(AtomicBool::new(false));
+        state.pane.cancel_load = Some(previous.clone());
+        state.pane.request_id = state.next_request_id();
+        let stale = state.pane.request_id;
+
+        state.fetch(url.clone(), false);
+        assert!(previous.load(Ordering::SeqCst));
+        assert_eq!(state.pane.content.as_deref(), Some("Cached"));
+
+        // The cancelled load's response arrives anyway
+        state.in_request_pane(stale, |state| {
+            state.transaction_complete(page(&url, "Late"), url.clone())
+        });
+        assert_eq!(state.pane.content.as_deref(), Some("Cached"));
+    }
+
+    #[test]
+    fn page_cache_keeps_the_raw_body() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        let url = Url::parse("gemini://example.org/").unwrap();
+        state.transaction_complete(page(&url, "# Caf\u{e9}"), url.clone());
+
+        // Only the body is kept, the text is decoded from it again when it's served
+        match state.page_cache.get(&url) {
+            Some((Response::Body { content, raw, .. }, _)) => {
+                assert_eq!(content, None);
+                assert_eq!(raw.as_deref(), Some("# Caf\u{e9}".as_bytes()));
+            }
+            _ => panic!("not cached"),
+        }
+        state.pane.content = None;
+        state.fetch(url.clone(), false);
+        assert_eq!(state.pane.content.as_deref(), Some("# Caf\u{e9}"));
+    }
+
+    #[test]
+    fn replays_skip_the_caches() {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay");
+        let (mut state, rx) = State::replaying(PathBuf::from(fixtures));
+        state.background = true;
+        assert!(state.disk_cache.is_none());
+        let url = Url::parse("gemini://example.org/").unwrap();
+        state
+            .page_cache
+            .insert(&url, page(&url, "Cached"), 6, false);
+
+        state.fetch(url.clone(), false);
+        assert!(state.pane.loading);
+        match rx.recv().unwrap() {
+            Event::TransactionComplete(request_id, response, url) => {
+                state.load_complete(request_id, *response, url)
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert_eq!(
+            state.pane.content.as_deref(),
+            Some("# Recorded\n=> /old Moved\n")
+        );
+    }
+
+    #[test]
+    fn going_online() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        state.set_offline(true);
+        let url = Url::parse("about:online").unwrap();
+
+        // Loading it any other way than following it, e.g. from the jump list, does nothing
+        state.fetch(url, false);
+        assert!(state.offline);
+        assert_eq!(
+            state.error_message.as_deref(),
+            Some("Unknown page: about:online")
+        );
+
+        state.request("about:online");
+        assert!(!state.offline);
+    }
+
+    #[test]
+    fn disk_cache_hits_cancel_the_load() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        let dir = env::temp_dir().join(format!("diosk-state-cache-{}", process::id()));
+        let cache = DiskCache::new(dir.clone(), Duration::from_secs(60), 1024);
+        let url = Url::parse("gemini://example.org/").unwrap();
+        cache.insert(&url, &page(&url, "Cached")).unwrap();
+        state.disk_cache = Some(cache);
+        state.pane.request_id = state.next_request_id();
+        let stale = state.pane.request_id;
+
+        state.fetch(url.clone(), false);
+        assert_eq!(state.pane.content.as_deref(), Some("Cached"));
+
+        state.in_request_pane(stale, |state| {
+            state.transaction_complete(page(&url, "Late"), url.clone())
+        });
+        assert_eq!(state.pane.content.as_deref(), Some("Cached"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn superseded_downloads_are_deleted() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.pane.request_id = 2;
+        let url = Url::parse("gemini://example.org/a.png").unwrap();
+        let file = env::temp_dir().join(format!(".diosk-superseded-{}.part", process::id()));
+        fs::write(&file, b"\x89PNG").unwrap();
+
+        let meta = page(&url, "").meta().clone();
+        state.load_complete(
+            1,
+            Response::Download {
+                file: file.clone(),
+                meta,
+            },
+            url,
+        );
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn progress_after_cancelling_is_ignored() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        let url = Url::parse("gemini://example.org/a.zip").unwrap();
+        let mime_type: Mime = "application/zip".parse().unwrap();
+        state.pane.cancel_load = Some(Arc::new(AtomicBool::new(false)));
+        state.pane.request_id = state.next_request_id();
+        let cancelled = state.pane.request_id;
+
+        state.cancel();
+        state.download_progress(cancelled, &url, &mime_type, 1024);
+        assert!(state.downloads.is_empty());
+    }
+
+    #[test]
+    fn loads_leave_the_other_panes_prefetches() {
+        let (mut state, rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        state.config.prefetch = 1;
+        state.split = Some(Split {
+            other: Pane {
+                current_url: Some(Url::parse("gemini://example.org/").unwrap()),
+                content: Some("=> /b".to_string()),
+                ..Pane::default()
+            },
+            focus: Side::Left,
+        });
+        state.in_other_pane(State::prefetch_links);
+        let (response, url, generation) = match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Event::PrefetchComplete(response, url, generation) => (response, url, generation),
+            e => panic!("unexpected event: {:?}", e),
+        };
+
+        state.fetch(Url::parse("gemini://example.org/c").unwrap(), false);
+        state.prefetch_complete(*response, url.clone(), generation);
+        assert!(state.page_cache.contains(&url));
+    }
+
+    #[test]
+    fn responses_go_to_the_pane_that_asked() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.pane.request_id = 1;
+        state.split = Some(Split {
+            other: Pane {
+                request_id: 2,
+                ..Pane::default()
+            },
+            focus: Side::Left,
+        });
+
+        state.in_request_pane(2, |state| state.pane.current_line_index = 7);
+        state.in_request_pane(1, |state| state.pane.current_line_index = 3);
+        state.in_request_pane(0, |state| state.pane.current_line_index = 9);
+
+        assert_eq!(state.pane.current_line_index, 3);
+        assert_eq!(state.split.unwrap().other.current_line_index, 7);
+    }
+
+    #[cfg(feature = "image_preview")]
+    #[test]
+    fn image_previews() {
+        let (mut state, _rx) = State::with_fetcher(Arc::new(gemini::fetcher::Fixture::new("")));
+        state.background = true;
+        let dir = env::temp_dir().join(format!("diosk-image-preview-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        state.config.download_dir = Some(dir.clone());
+
+        let url = Url::parse("gemini://example.org/cat.png").unwrap();
+        let mut meta = page(&url, "").meta().clone();
+        meta.mime_type = "image/png".parse().unwrap();
+        let preview = |state: &mut State, data: &[u8]| {
+            let file = dir.join(".cat.png.part");
+            fs::write(&file, data).unwrap();
+            let action = PendingAction::PreviewImage {
+                meta: Box::new(meta.clone()),
+                file: file.clone(),
+                protocol: terminal::image::Protocol::Kitty,
+            };
+            state.confirm("View image/png inline?".to_string(), action);
+            state.answer(true);
+            file
+        };
+
+        // Shown in place of the page, the file it arrived in isn't kept
+        let mut png = Vec::new();
+        ::image::DynamicImage::new_rgb8(2, 2)
+            .write_to(&mut png, ::image::ImageOutputFormat::Png)
+            .unwrap();
+        let file = preview(&mut state, &png);
+        assert!(state.pane.image_preview.is_some());
+        assert_eq!(state.pane.displayed_url(), Some(&url));
+        assert!(state.pane.displayed_meta().is_none());
+        assert!(!file.exists());
+
+        // One that can't be decoded is saved as a download instead
+        state.pane.image_preview = None;
+        preview(&mut state, b"not a png");
+        assert!(state.pane.image_preview.is_none());
+        assert!(dir.join("cat.png").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
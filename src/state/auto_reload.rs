@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+/// Reloads a page every so often while it's the one being shown
+pub struct AutoReload {
+    pub url: Url,
+    interval: Duration,
+    next: Instant,
+    /// The countdown last shown in the status line, it's redrawn when this changes
+    shown: u64,
+}
+
+impl AutoReload {
+    pub fn new(url: Url, interval: Duration) -> Self {
+        Self {
+            url,
+            interval,
+            next: Instant::now() + interval,
+            shown: interval.as_secs(),
+        }
+    }
+
+    /// Seconds until the next reload, rounded up so it never shows 0 before the reload is due
+    pub fn remaining(&self) -> u64 {
+        let remaining = self.next.saturating_duration_since(Instant::now());
+        remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)
+    }
+
+    pub fn due(&self) -> bool {
+        Instant::now() >= self.next
+    }
+
+    /// Start counting down again, from when the reload was made
+    pub fn restart(&mut self) {
+        self.next = Instant::now() + self.interval;
+    }
+
+    /// Whether the countdown has changed since it was last shown
+    pub fn countdown_changed(&mut self) -> bool {
+        let remaining = self.remaining();
+        let changed = remaining != self.shown;
+        self.shown = remaining;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn countdown() {
+        let url = Url::parse("gemini://example.org/").unwrap();
+        let mut auto_reload = AutoReload::new(url.clone(), Duration::from_secs(60));
+
+        assert!(!auto_reload.due());
+        assert_eq!(auto_reload.remaining(), 60);
+        assert!(!auto_reload.countdown_changed());
+
+        let mut auto_reload = AutoReload::new(url, Duration::from_secs(0));
+        assert!(auto_reload.due());
+        assert_eq!(auto_reload.remaining(), 0);
+
+        auto_reload.interval = Duration::from_secs(5);
+        auto_reload.restart();
+        assert!(!auto_reload.due());
+        assert!(auto_reload.countdown_changed());
+    }
+}
@@ -0,0 +1,419 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use url::Url;
+
+use super::list_page::ListPage;
+use crate::gemini::gemtext::Line;
+use crate::gemini::normalize_url;
+use crate::gemini::temp_file::TempFile;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bookmark {
+    pub url: Url,
+    pub title: String,
+}
+
+/// What came of importing a file of bookmarks
+#[derive(Debug, Default, PartialEq)]
+pub struct Import {
+    pub imported: usize,
+    pub duplicates: usize,
+    /// Links that couldn't be understood, e.g. relative URLs
+    pub malformed: usize,
+    /// Links to schemes other than gemini, only skipped unless every scheme is imported
+    pub other_schemes: usize,
+}
+
+impl fmt::Display for Import {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Imported {}, skipped {} duplicates",
+            self.imported, self.duplicates
+        )?;
+        if self.other_schemes > 0 {
+            write!(f, ", {} non-gemini links", self.other_schemes)?;
+        }
+        if self.malformed > 0 {
+            write!(f, ", {} malformed lines", self.malformed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Saved pages, kept in a gemtext file of link lines in the order they were added
+pub struct Bookmarks {
+    path: PathBuf,
+    bookmarks: Vec<Bookmark>,
+}
+
+impl Bookmarks {
+    pub fn new(path: &Path) -> Self {
+        let bookmarks = fs::read_to_string(path)
+            .map(|content| content.lines().filter_map(parse_line).collect())
+            .unwrap_or_default();
+
+        Self {
+            path: path.to_path_buf(),
+            bookmarks,
+        }
+    }
+
+    /// Add a bookmark, false if the URL is already bookmarked
+    pub fn insert(&mut self, url: Url, title: &str) -> io::Result<bool> {
+        if self.contains(&url) {
+            return Ok(false);
+        }
+
+        self.bookmarks.push(Bookmark {
+            title: sanitize(title),
+            url,
+        });
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Merge in bookmarks from a gemtext document or the Netscape bookmarks HTML web browsers
+    /// export, only gemini links are taken unless all_schemes is set
+    pub fn import(&mut self, content: &str, all_schemes: bool) -> io::Result<Import> {
+        let entries = match is_netscape_html(content) {
+            true => netscape_entries(content),
+            false => gemtext_entries(content),
+        };
+
+        let mut import = Import::default();
+        for entry in entries {
+            let (url, title) = match entry {
+                Some(entry) => entry,
+                None => {
+                    import.malformed += 1;
+                    continue;
+                }
+            };
+            if url.scheme() != "gemini" && !all_schemes {
+                import.other_schemes += 1;
+            } else if self.contains(&url) {
+                import.duplicates += 1;
+            } else {
+                let title = title
+                    .filter(|title| !title.trim().is_empty())
+                    .unwrap_or_else(|| url.to_string());
+                self.bookmarks.push(Bookmark {
+                    title: sanitize(&title),
+                    url,
+                });
+                import.imported += 1;
+            }
+        }
+
+        if import.imported > 0 {
+            self.save()?;
+        }
+        Ok(import)
+    }
+
+    pub fn contains(&self, url: &Url) -> bool {
+        let key = normalize_url(url);
+        self.bookmarks.iter().any(|b| normalize_url(&b.url) == key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bookmarks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bookmarks.is_empty()
+    }
+
+    /// Remove a bookmark, with where it was so it can be restored
+    pub fn remove(&mut self, url: &Url) -> io::Result<Option<(usize, Bookmark)>> {
+        let index = match self.bookmarks.iter().position(|b| &b.url == url) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        let bookmark = self.bookmarks.remove(index);
+        self.save()?;
+        Ok(Some((index, bookmark)))
+    }
+
+    /// Put a removed bookmark back where it was
+    pub fn restore(&mut self, index: usize, bookmark: Bookmark) -> io::Result<()> {
+        if !self.contains(&bookmark.url) {
+            self.bookmarks
+                .insert(index.min(self.bookmarks.len()), bookmark);
+        }
+        self.save()
+    }
+
+    /// The bookmarks in the order they were added, for about:bookmarks
+    pub fn page(&self) -> ListPage<Url> {
+        let mut page = ListPage::new(format!(
+            "# Bookmarks\n\n{} bookmarks, d on one deletes it\n\n",
+            self.bookmarks.len()
+        ));
+        for bookmark in &self.bookmarks {
+            page.push_entry(&links(iter::once(bookmark)), bookmark.url.clone());
+        }
+        page
+    }
+
+    /// Write the bookmarks to a standalone gemtext document, sorted by title then URL so the same
+    /// bookmarks always give the same file
+    ///
+    /// An existing file is only replaced when overwrite is set.
+    pub fn export(&self, path: &Path, overwrite: bool) -> io::Result<()> {
+        let mut sorted: Vec<&Bookmark> = self.bookmarks.iter().collect();
+        sorted.sort_by(|a, b| {
+            (a.title.to_lowercase(), a.url.as_str()).cmp(&(b.title.to_lowercase(), b.url.as_str()))
+        });
+
+        let document = format!("# Bookmarks\n\n{}", links(sorted.into_iter()));
+
+        let mut file = match overwrite {
+            true => OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?,
+            false => OpenOptions::new().write(true).create_new(true).open(path)?,
+        };
+        file.write_all(document.as_bytes())
+    }
+
+    /// Written whole and renamed into place, so the bookmarks can't be lost to a half written file
+    fn save(&self) -> io::Result<()> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let mut file = TempFile::create(dir)?;
+        file.write_all(links(self.bookmarks.iter()).as_bytes())?;
+        let written = file.keep()?;
+        fs::rename(&written, &self.path).inspect_err(|_| {
+            let _ = fs::remove_file(&written);
+        })
+    }
+}
+
+fn links<'a>(bookmarks: impl Iterator<Item = &'a Bookmark>) -> String {
+    bookmarks
+        .map(|b| format!("=> {} {}\n", b.url, b.title))
+        .collect()
+}
+
+fn parse_line(line: &str) -> Option<Bookmark> {
+    match Line::parse(line) {
+        Line::Link { url, name, .. } => {
+            let url = Url::parse(&url).ok()?;
+            let title = name.unwrap_or_else(|| url.to_string());
+            Some(Bookmark { url, title })
+        }
+        _ => None,
+    }
+}
+
+/// Every link line as a URL and title, None for those that aren't absolute URLs
+fn gemtext_entries(content: &str) -> Vec<Option<(Url, Option<String>)>> {
+    content
+        .lines()
+        .filter(|line| line.starts_with("=>"))
+        .map(|line| match Line::parse(line) {
+            Line::Link { url, name, .. } => Url::parse(&url).ok().map(|url| (url, name)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn is_netscape_html(content: &str) -> bool {
+    let start = content
+        .trim_start()
+        .get(..64)
+        .unwrap_or(content)
+        .to_ascii_uppercase();
+    start.starts_with("<!DOCTYPE NETSCAPE-BOOKMARK-FILE") || start.starts_with("<HTML")
+}
+
+/// Every `<A HREF="...">title</A>` as a URL and title, None for those without a usable HREF
+///
+/// Folders are ignored, the bookmarks in them are all imported.
+fn netscape_entries(content: &str) -> Vec<Option<(Url, Option<String>)>> {
+    let upper = content.to_ascii_uppercase();
+    let mut entries = Vec::new();
+    let mut rest = 0;
+
+    // Uppercasing ASCII doesn't move anything so offsets in one are offsets in the other
+    while let Some(start) = upper[rest..].find("<A ").map(|i| rest + i) {
+        let tag_end = match upper[start..].find('>') {
+            Some(i) => start + i,
+            None => {
+                entries.push(None);
+                break;
+            }
+        };
+        let text_end = upper[tag_end..]
+            .find("</A>")
+            .map_or(upper.len(), |i| tag_end + i);
+        rest = text_end;
+
+        let href = attribute(&content[start..tag_end], &upper[start..tag_end], "HREF");
+        let title = unescape(content[tag_end + 1..text_end].trim());
+        entries.push(
+            href.and_then(|href| Url::parse(&unescape(href)).ok())
+                .map(|url| (url, Some(title))),
+        );
+    }
+
+    entries
+}
+
+/// The quoted value of an attribute in a tag
+fn attribute<'a>(tag: &'a str, upper: &str, name: &str) -> Option<&'a str> {
+    let start = upper.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let len = tag[start..].find('"')?;
+    Some(&tag[start..start + len])
+}
+
+/// Decode the HTML entities browsers use in exported bookmarks
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// A title on a single line, a newline would end the link line and start another
+fn sanitize(title: &str) -> String {
+    title.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::ErrorKind;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn export() {
+        let store = env::temp_dir().join(format!("diosk-bookmarks-{}.gmi", process::id()));
+        let path = env::temp_dir().join(format!("diosk-export-{}.gmi", process::id()));
+        let url = |s: &str| Url::parse(s).unwrap();
+
+        let mut bookmarks = Bookmarks::new(&store);
+        assert!(bookmarks.insert(url("gemini://b.org/"), "b").unwrap());
+        assert!(bookmarks
+            .insert(url("gemini://a.org/"), "A\ntitle  over\r\nlines")
+            .unwrap());
+        assert!(bookmarks.insert(url("gemini://c.org/"), "b").unwrap());
+        assert!(!bookmarks.insert(url("gemini://A.org:1965/"), "A").unwrap());
+
+        bookmarks.export(&path, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# Bookmarks\n\n\
+             => gemini://a.org/ A title over lines\n\
+             => gemini://b.org/ b\n\
+             => gemini://c.org/ b\n"
+        );
+
+        let refused = bookmarks.export(&path, false).unwrap_err();
+        assert_eq!(refused.kind(), ErrorKind::AlreadyExists);
+        bookmarks.export(&path, true).unwrap();
+
+        // The store keeps the order they were added
+        let store = store.as_path();
+        let reloaded = Bookmarks::new(store);
+        assert_eq!(reloaded.len(), 3);
+        assert!(reloaded
+            .page()
+            .content()
+            .contains("=> gemini://b.org/ b\n=> gemini://a.org/"));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(store).unwrap();
+    }
+
+    #[test]
+    fn import() {
+        let store = env::temp_dir().join(format!("diosk-import-{}.gmi", process::id()));
+        let mut bookmarks = Bookmarks::new(&store);
+        bookmarks
+            .insert(Url::parse("gemini://a.org/").unwrap(), "A")
+            .unwrap();
+
+        let gemtext = "# Links\n\
+                       => gemini://a.org/ A again\n\
+                       => gemini://b.org/\n\
+                       => relative.gmi\n\
+                       =>\n\
+                       => https://c.org/ C\n";
+        let import = bookmarks.import(gemtext, false).unwrap();
+        assert_eq!(
+            import.to_string(),
+            "Imported 1, skipped 1 duplicates, 1 non-gemini links, 2 malformed lines"
+        );
+
+        let html = "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n\
+                    <DL><p>\n\
+                    <DT><H3>Folder</H3>\n\
+                    <DL><p>\n\
+                    <DT><A HREF=\"gemini://d.org/?a=1&amp;b=2\" ADD_DATE=\"1\">D &amp; co</A>\n\
+                    <DT><a href=\"https://e.org/\">E</a>\n\
+                    <DT><A ADD_DATE=\"1\">No link</A>\n\
+                    </DL><p>\n\
+                    </DL>\n";
+        let import = bookmarks.import(html, true).unwrap();
+        assert_eq!(
+            import,
+            Import {
+                imported: 2,
+                duplicates: 0,
+                malformed: 1,
+                other_schemes: 0,
+            }
+        );
+        assert!(bookmarks
+            .page()
+            .content()
+            .contains("=> gemini://d.org/?a=1&b=2 D & co\n=> https://e.org/ E\n"));
+
+        fs::remove_file(store).unwrap();
+    }
+
+    #[test]
+    fn remove_and_restore() {
+        let store = env::temp_dir().join(format!("diosk-remove-{}.gmi", process::id()));
+        let store = store.as_path();
+        let url = |s| Url::parse(s).unwrap();
+        let mut bookmarks = Bookmarks::new(store);
+        for u in &["gemini://a.org/", "gemini://b.org/", "gemini://c.org/"] {
+            bookmarks.insert(url(u), u).unwrap();
+        }
+
+        let (index, removed) = bookmarks.remove(&url("gemini://b.org/")).unwrap().unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(Bookmarks::new(store).len(), 2);
+        assert_eq!(bookmarks.remove(&url("gemini://b.org/")).unwrap(), None);
+
+        // The page links each entry back to its bookmark
+        let page = bookmarks.page();
+        let line = page
+            .content()
+            .lines()
+            .position(|l| l.contains("c.org"))
+            .unwrap();
+        assert_eq!(page.entry(line), Some(&url("gemini://c.org/")));
+
+        bookmarks.restore(index, removed).unwrap();
+        assert!(Bookmarks::new(store)
+            .page()
+            .content()
+            .contains("a.org/\n=> gemini://b.org/ gemini://b.org/\n=> gemini://c.org/"));
+
+        fs::remove_file(store).unwrap();
+    }
+}
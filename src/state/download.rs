@@ -0,0 +1,575 @@
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mime::Mime;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use super::list_page::ListPage;
+use super::RequestId;
+use crate::dirs;
+use crate::human;
+
+/// How many downloads from earlier sessions are read back from the log
+const EARLIER_DOWNLOADS: usize = 50;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    /// Bytes are still arriving, or it's waiting on a prompt before it's saved
+    InProgress,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+/// A download started this session, or one read back from the log
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub url: Url,
+    /// Where it was saved, once it has been
+    pub path: Option<PathBuf>,
+    /// Bytes received so far
+    pub size: usize,
+    pub mime_type: Mime,
+    pub status: Status,
+    /// The load the download came from, only for downloads this session
+    request_id: Option<RequestId>,
+    /// The temporary file the response was streamed to, until it's saved or discarded
+    file: Option<PathBuf>,
+    finished: Option<SystemTime>,
+}
+
+/// Where a download is in the list, among those of this session or earlier ones
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Session(usize),
+    Earlier(usize),
+}
+
+/// Every download of the session, with those that have finished also written to a log
+pub struct Downloads {
+    log: PathBuf,
+    session: Vec<Download>,
+    earlier: Vec<Download>,
+}
+
+impl Downloads {
+    pub fn new(log: &Path) -> Self {
+        let earlier = fs::read_to_string(log)
+            .map(|content| {
+                let mut earlier: Vec<Download> = content.lines().filter_map(parse_entry).collect();
+                earlier.reverse();
+                earlier.truncate(EARLIER_DOWNLOADS);
+                earlier
+            })
+            .unwrap_or_default();
+
+        Self {
+            log: log.to_path_buf(),
+            session: Vec::new(),
+            earlier,
+        }
+    }
+
+    /// Record bytes received by a load that's a download, starting an entry for its first bytes
+    ///
+    /// Progress that arrives after the download has stopped is ignored.
+    pub fn progress(
+        &mut self,
+        request_id: RequestId,
+        url: &Url,
+        mime_type: &Mime,
+        received: usize,
+    ) {
+        let stopped = self.session.iter().any(|download| {
+            download.request_id == Some(request_id) && download.status != Status::InProgress
+        });
+        match self.by_request(request_id) {
+            Some(download) => download.size = received,
+            None if stopped => {}
+            None => self.session.push(Download {
+                url: url.clone(),
+                path: None,
+                size: received,
+                mime_type: mime_type.clone(),
+                status: Status::InProgress,
+                request_id: Some(request_id),
+                file: None,
+                finished: None,
+            }),
+        }
+    }
+
+    /// The response is complete and in its temporary file, it's followed by that from here on
+    pub fn received(
+        &mut self,
+        request_id: RequestId,
+        url: &Url,
+        mime_type: &Mime,
+        size: usize,
+        file: &Path,
+    ) {
+        self.progress(request_id, url, mime_type, size);
+        if let Some(download) = self.by_request(request_id) {
+            download.url = url.clone();
+            download.file = Some(file.to_path_buf());
+        }
+    }
+
+    /// A load that was downloading stopped without a response
+    pub fn stopped(&mut self, request_id: RequestId, status: Status) {
+        if let Some(download) = self.by_request(request_id) {
+            download.status = status;
+            self.finish(|download| download.request_id == Some(request_id));
+        }
+    }
+
+    /// The temporary file was saved to its place in the download directory
+    pub fn saved(&mut self, file: &Path, path: &Path) {
+        if let Some(download) = self.by_file(file) {
+            download.path = Some(path.to_path_buf());
+            download.status = Status::Done;
+            self.finish(|download| download.file.as_deref() == Some(file));
+        }
+    }
+
+    /// The temporary file was thrown away, e.g. rather than overwrite another file
+    pub fn discarded(&mut self, file: &Path, status: Status) {
+        if let Some(download) = self.by_file(file) {
+            download.status = status;
+            self.finish(|download| download.file.as_deref() == Some(file));
+        }
+    }
+
+    /// The response was shown rather than saved, so it was never really a download
+    pub fn viewed(&mut self, file: &Path) {
+        self.session
+            .retain(|download| download.file.as_deref() != Some(file));
+    }
+
+    /// The download saved at a path, this session or earlier
+    pub fn saved_at(&self, path: &Path) -> Option<&Download> {
+        self.session
+            .iter()
+            .chain(&self.earlier)
+            .find(|download| download.path.as_deref() == Some(path))
+    }
+
+    pub fn len(&self) -> usize {
+        self.session.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.session.is_empty()
+    }
+
+    /// The downloads as gemtext, saved files link to the file and the others to where they came
+    /// from so they can be tried again
+    ///
+    /// Those still in progress can't be removed so they aren't entries.
+    pub fn page(&self) -> ListPage<Position> {
+        let mut page = ListPage::new(format!(
+            "# Downloads\n\n{} this session\n\n",
+            self.session.len()
+        ));
+        for (i, download) in self.session.iter().enumerate().rev() {
+            match download.status {
+                Status::InProgress => page.push(&entry_line(download)),
+                _ => page.push_entry(&entry_line(download), Position::Session(i)),
+            }
+        }
+
+        if !self.earlier.is_empty() {
+            page.push("\n## Earlier\n\n");
+            for (i, download) in self.earlier.iter().enumerate() {
+                page.push_entry(&entry_line(download), Position::Earlier(i));
+            }
+        }
+
+        page
+    }
+
+    /// Forget a download that has finished, removing it from the log too, the file it was saved
+    /// to is left alone
+    pub fn remove(&mut self, position: Position) -> io::Result<Option<Download>> {
+        let list = match position {
+            Position::Session(i) if i < self.session.len() => &mut self.session,
+            Position::Earlier(i) if i < self.earlier.len() => &mut self.earlier,
+            _ => return Ok(None),
+        };
+        let index = match position {
+            Position::Session(i) | Position::Earlier(i) => i,
+        };
+        if list[index].status == Status::InProgress {
+            return Ok(None);
+        }
+
+        let download = list.remove(index);
+        let entry = log_entry(&download);
+        let mut lines = self.log_lines()?;
+        if let Some(i) = lines
+            .iter()
+            .rposition(|line| *line == entry.trim_end_matches('\n'))
+        {
+            lines.remove(i);
+        }
+        self.write_log(&lines)?;
+
+        Ok(Some(download))
+    }
+
+    /// Put a removed download back where it was, in the log in the order it finished
+    pub fn restore(&mut self, position: Position, download: Download) -> io::Result<()> {
+        let entry = log_entry(&download);
+        let (list, index) = match position {
+            Position::Session(i) => (&mut self.session, i),
+            Position::Earlier(i) => (&mut self.earlier, i),
+        };
+        list.insert(index.min(list.len()), download);
+
+        let mut lines = self.log_lines()?;
+        let finished = |line: &str| -> u64 {
+            line.split('\t')
+                .next()
+                .and_then(|f| f.parse().ok())
+                .unwrap_or(0)
+        };
+        let at = lines
+            .iter()
+            .position(|line| finished(line) > finished(&entry))
+            .unwrap_or(lines.len());
+        lines.insert(at, entry.trim_end_matches('\n').to_string());
+        self.write_log(&lines)
+    }
+
+    fn log_lines(&self) -> io::Result<Vec<String>> {
+        match fs::read_to_string(&self.log) {
+            Ok(content) => Ok(content.lines().map(str::to_string).collect()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_log(&self, lines: &[String]) -> io::Result<()> {
+        let content: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+        fs::write(&self.log, content)
+    }
+
+    fn by_request(&mut self, request_id: RequestId) -> Option<&mut Download> {
+        self.session.iter_mut().find(|download| {
+            download.request_id == Some(request_id) && download.status == Status::InProgress
+        })
+    }
+
+    fn by_file(&mut self, file: &Path) -> Option<&mut Download> {
+        self.session.iter_mut().find(|download| {
+            download.file.as_deref() == Some(file) && download.status == Status::InProgress
+        })
+    }
+
+    /// Mark a download that has stopped as finished and add it to the log
+    fn finish(&mut self, matches: impl Fn(&Download) -> bool) {
+        let download = match self.session.iter_mut().find(|download| matches(download)) {
+            Some(download) => download,
+            None => return,
+        };
+        download.file = None;
+        download.finished = Some(SystemTime::now());
+
+        // Only a convenience, not worth interrupting anything over
+        let entry = log_entry(download);
+        let _ = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log)
+            .and_then(|mut log| log.write_all(entry.as_bytes()));
+    }
+}
+
+fn entry_line(download: &Download) -> String {
+    let name = match &download.path {
+        Some(path) => path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string()),
+        None => file_name(&download.url),
+    };
+    let size = human::bytes(download.size);
+    let ago = download
+        .finished
+        .and_then(|finished| SystemTime::now().duration_since(finished).ok())
+        .map(|age| format!(", {} ago", human::duration(age)))
+        .unwrap_or_default();
+
+    let link = |url: &str, description: String| format!("=> {} {} — {}\n", url, name, description);
+    match (&download.status, &download.path) {
+        (Status::InProgress, _) => format!("* {} — {} so far\n", name, size),
+        (Status::Done, Some(path)) => match Url::from_file_path(path) {
+            Ok(url) => link(url.as_str(), format!("{}{}", size, ago)),
+            Err(_) => format!("* {} — {}, saved to {}\n", name, size, path.display()),
+        },
+        (Status::Failed(reason), _) if !reason.is_empty() => {
+            link(download.url.as_str(), format!("failed: {}{}", reason, ago))
+        }
+        (Status::Failed(_), _) | (Status::Done, None) => {
+            link(download.url.as_str(), format!("failed{}", ago))
+        }
+        (Status::Cancelled, _) => link(download.url.as_str(), format!("cancelled{}", ago)),
+    }
+}
+
+/// A line of the log: when it finished, status, size, MIME type, URL, and where it was saved
+fn log_entry(download: &Download) -> String {
+    let finished = download
+        .finished
+        .and_then(|finished| finished.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default()
+        .as_secs();
+    let status = match download.status {
+        Status::InProgress => "in-progress",
+        Status::Done => "done",
+        Status::Failed(_) => "failed",
+        Status::Cancelled => "cancelled",
+    };
+    let path = download
+        .path
+        .as_ref()
+        .map(|path| path.display().to_string())
+        .unwrap_or_default();
+
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\n",
+        finished, status, download.size, download.mime_type, download.url, path
+    )
+}
+
+fn parse_entry(line: &str) -> Option<Download> {
+    let mut fields = line.splitn(6, '\t');
+    let finished = UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+    let status = match fields.next()? {
+        "done" => Status::Done,
+        "failed" => Status::Failed(String::new()),
+        "cancelled" => Status::Cancelled,
+        _ => return None,
+    };
+    let size = fields.next()?.parse().ok()?;
+    let mime_type = fields.next()?.parse().ok()?;
+    let url = Url::parse(fields.next()?).ok()?;
+    let path = Some(fields.next()?)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from);
+
+    Some(Download {
+        url,
+        path,
+        size,
+        mime_type,
+        status,
+        request_id: None,
+        file: None,
+        finished: Some(finished),
+    })
+}
+
+/// Run a handler command with the path or URL as its final argument, waiting for it to exit
+///
+/// The command is split on whitespace so arguments can be given, e.g. `mpv --loop`.
+pub fn open(command: &str, target: &OsStr) -> io::Result<ExitStatus> {
+    let mut words = command.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+
+    Command::new(program).args(words).arg(target).status()
+}
+
+/// The directory downloads are saved to when none is configured, the platform's download
+/// directory or Downloads in the home directory
+pub fn default_dir() -> PathBuf {
+    if let Some(dir) = dirs::download_dir() {
+        return dir;
+    }
+
+    dirs::home_dir()
+        .map(|home| home.join("Downloads"))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// A safe file name derived from the final path segment of a URL
+///
+/// Path separators and control characters are removed, as are leading dots so the file can't be
+/// hidden. URLs without a usable path segment are named after their host.
+pub fn file_name(url: &Url) -> String {
+    let segment = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned())
+        .unwrap_or_default();
+
+    let name = sanitize(&segment);
+    if !name.is_empty() {
+        return name;
+    }
+
+    let host = sanitize(url.host_str().unwrap_or_default());
+    if host.is_empty() {
+        "download".to_string()
+    } else {
+        host
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !matches!(c, '/' | '\\') && !c.is_control())
+        .collect();
+
+    name.trim().trim_start_matches('.').to_string()
+}
+
+/// A path in the directory that doesn't exist yet, adding name(1).ext style suffixes when needed
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i..]),
+        _ => (name, ""),
+    };
+
+    (1..)
+        .map(|n| dir.join(format!("{}({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .expect("infallible")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn name(url: &str) -> String {
+        file_name(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(name("gemini://example.org/files/a.zip"), "a.zip");
+        assert_eq!(name("gemini://example.org/files/a%20b.zip?x=1"), "a b.zip");
+        assert_eq!(
+            name("gemini://example.org/..%2F..%2Fetc%2Fpasswd"),
+            "etcpasswd"
+        );
+        assert_eq!(name("gemini://example.org/.hidden"), "hidden");
+        assert_eq!(name("gemini://example.org/a%1B%5B2Jb"), "a[2Jb");
+        assert_eq!(name("gemini://example.org/"), "example.org");
+        assert_eq!(name("gemini://example.org"), "example.org");
+        assert_eq!(name("gemini://example.org/.."), "example.org");
+    }
+
+    #[test]
+    fn downloads() {
+        let log = env::temp_dir().join(format!("diosk-downloads-{}.txt", std::process::id()));
+        let log = log.as_path();
+        let url = Url::parse("gemini://example.org/a.zip").unwrap();
+        let mime_type: Mime = "application/zip".parse().unwrap();
+
+        let mut downloads = Downloads::new(log);
+        downloads.progress(1, &url, &mime_type, 0);
+        downloads.progress(1, &url, &mime_type, 2048);
+        assert!(downloads
+            .page()
+            .content()
+            .contains("* a.zip — 2.0 KiB so far\n"));
+
+        downloads.received(1, &url, &mime_type, 4096, Path::new("/tmp/.a.part"));
+        downloads.saved(Path::new("/tmp/.a.part"), Path::new("/tmp/a.zip"));
+        downloads.progress(2, &url, &mime_type, 0);
+        downloads.stopped(2, Status::Failed("IO error".to_string()));
+        // Sent before the load stopped but arriving after
+        downloads.progress(2, &url, &mime_type, 1024);
+        assert_eq!(downloads.len(), 2);
+        assert!(downloads.saved_at(Path::new("/tmp/a.zip")).is_some());
+
+        let page = downloads.page();
+        let page = page.content();
+        assert!(page.contains("=> gemini://example.org/a.zip a.zip — failed: IO error, 0s ago\n"));
+        assert!(page.contains("=> file:///tmp/a.zip a.zip — 4.0 KiB, 0s ago\n"));
+
+        // The next session only knows about what was logged
+        let downloads = Downloads::new(log);
+        assert!(downloads.is_empty());
+        assert!(downloads.page().content().contains("## Earlier\n\n=> gemini://example.org/a.zip a.zip — failed, 0s ago\n=> file:///tmp/a.zip"));
+
+        fs::remove_file(log).unwrap();
+    }
+
+    #[test]
+    fn remove_and_restore() {
+        let log =
+            env::temp_dir().join(format!("diosk-remove-downloads-{}.txt", std::process::id()));
+        let log = log.as_path();
+        let url = Url::parse("gemini://example.org/a.zip").unwrap();
+        let mime_type: Mime = "application/zip".parse().unwrap();
+
+        let mut downloads = Downloads::new(log);
+        for request_id in 1..=3 {
+            downloads.progress(request_id, &url, &mime_type, request_id);
+            downloads.stopped(request_id, Status::Cancelled);
+        }
+        downloads.progress(4, &url, &mime_type, 0);
+
+        // Those still in progress can't be removed
+        let page = downloads.page();
+        let line = page
+            .content()
+            .lines()
+            .position(|l| l.contains("so far"))
+            .unwrap();
+        assert_eq!(page.entry(line), None);
+        assert_eq!(
+            downloads
+                .remove(Position::Session(3))
+                .unwrap()
+                .map(|d| d.size),
+            None
+        );
+
+        let removed = downloads.remove(Position::Session(1)).unwrap().unwrap();
+        assert_eq!(removed.size, 2);
+        let logged = || fs::read_to_string(log).unwrap();
+        assert_eq!(logged().lines().count(), 2);
+        assert!(!logged().contains("\tcancelled\t2\t"));
+
+        downloads.restore(Position::Session(1), removed).unwrap();
+        assert_eq!(downloads.session[1].size, 2);
+        assert_eq!(logged().lines().count(), 3);
+        assert!(logged().contains("\tcancelled\t2\t"));
+
+        fs::remove_file(log).unwrap();
+    }
+
+    #[test]
+    fn unique_paths() {
+        let dir = env::temp_dir().join(format!("diosk-unique-path-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(unique_path(&dir, "a.zip"), dir.join("a.zip"));
+
+        fs::write(dir.join("a.zip"), "").unwrap();
+        fs::write(dir.join("a(1).zip"), "").unwrap();
+        fs::write(dir.join("README"), "").unwrap();
+        assert_eq!(unique_path(&dir, "a.zip"), dir.join("a(2).zip"));
+        assert_eq!(unique_path(&dir, "README"), dir.join("README(1)"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fs;
+
+use url::Url;
+
+use crate::gemini::Response;
+
+/// The most a favicon response can be, anything bigger isn't a single emoji
+pub const MAX_BYTES: usize = 64;
+
+/// The emoji capsules serve at /favicon.txt, fetched once per capsule for the session
+///
+/// Capsules without one are remembered too so they aren't asked again.
+#[derive(Default)]
+pub struct Favicons {
+    /// Keyed by host then port, so looking one up for every frame doesn't allocate
+    icons: HashMap<String, HashMap<u16, Option<String>>>,
+}
+
+impl Favicons {
+    /// The favicon of the capsule the URL is on, if it has one and it's been fetched
+    pub fn get(&self, url: &Url) -> Option<&str> {
+        let (host, port) = capsule(url)?;
+        self.icons.get(host)?.get(&port)?.as_deref()
+    }
+
+    /// The favicon URL of the capsule to fetch, None once it's been asked for
+    pub fn start(&mut self, url: &Url) -> Option<Url> {
+        let (host, port) = capsule(url)?;
+        let ports = self.icons.entry(host.to_string()).or_default();
+        if ports.contains_key(&port) {
+            return None;
+        }
+
+        ports.insert(port, None);
+        url.join("/favicon.txt").ok()
+    }
+
+    /// Record the favicon fetched for a capsule, None when it has none
+    pub fn received(&mut self, favicon_url: &Url, icon: Option<String>) {
+        if let Some((host, port)) = capsule(favicon_url) {
+            self.icons
+                .entry(host.to_string())
+                .or_default()
+                .insert(port, icon);
+        }
+    }
+}
+
+/// The host and port of the capsule a URL is on, gemini URLs only
+fn capsule(url: &Url) -> Option<(&str, u16)> {
+    match url.scheme() {
+        "gemini" => Some((url.host_str()?, url.port().unwrap_or(1965))),
+        _ => None,
+    }
+}
+
+/// The favicon in a response, the file it was downloaded to is removed
+pub fn read(response: Response) -> Option<String> {
+    let body = match response {
+        Response::Download { file, meta } => {
+            let body = fs::read_to_string(&file).ok();
+            let _ = fs::remove_file(&file);
+            body.filter(|_| meta.mime_type.essence_str() == "text/plain")
+        }
+        Response::Body { .. } => None,
+    };
+
+    parse(&body?)
+}
+
+/// A body that's a single emoji, surrounded by whitespace at most
+pub fn parse(body: &str) -> Option<String> {
+    let icon = body.trim();
+    if icon.is_empty() || icon.len() > MAX_BYTES {
+        return None;
+    }
+
+    // Emoji joined together or followed by modifiers make up one
+    let mut emoji = 0;
+    let mut joined = false;
+    for c in icon.chars() {
+        if c == '\u{200d}' {
+            joined = true;
+        } else if is_modifier(c) {
+            continue;
+        } else if is_emoji(c) {
+            if !joined {
+                emoji += 1;
+            }
+            joined = false;
+        } else {
+            return None;
+        }
+    }
+
+    // Flags are a pair of regional indicators
+    let flag = emoji == 2 && icon.chars().all(is_regional_indicator);
+    match emoji == 1 || flag {
+        true => Some(icon.to_string()),
+        false => None,
+    }
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x1f000..=0x1faff | 0x2300..=0x23ff | 0x2600..=0x27bf | 0x2b00..=0x2bff
+    )
+}
+
+/// Variation selectors, skin tones, keycaps and the tags in subdivision flags
+fn is_modifier(c: char) -> bool {
+    matches!(
+        c as u32,
+        0xfe0e | 0xfe0f | 0x20e3 | 0x1f3fb..=0x1f3ff | 0xe0020..=0xe007f
+    )
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c as u32, 0x1f1e6..=0x1f1ff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(super::parse("🦀\n").as_deref(), Some("🦀"));
+        assert_eq!(super::parse(" ☕️ ").as_deref(), Some("☕️"));
+        assert_eq!(super::parse("👩🏽‍🚀").as_deref(), Some("👩🏽‍🚀"));
+        assert_eq!(super::parse("🇳🇿").as_deref(), Some("🇳🇿"));
+
+        assert_eq!(super::parse(""), None);
+        assert_eq!(super::parse("🦀🦀"), None);
+        assert_eq!(super::parse("🦀 🦀"), None);
+        assert_eq!(super::parse("a"), None);
+        assert_eq!(super::parse("# Not found"), None);
+        assert_eq!(super::parse("\u{fe0f}"), None);
+        assert_eq!(super::parse(&"🦀".repeat(20)), None);
+    }
+
+    #[test]
+    fn favicons() {
+        let page = Url::parse("gemini://example.org/a/b.gmi").unwrap();
+        let mut favicons = Favicons::default();
+
+        let favicon_url = favicons.start(&page).unwrap();
+        assert_eq!(favicon_url.as_str(), "gemini://example.org/favicon.txt");
+        assert_eq!(favicons.start(&page), None);
+        assert_eq!(favicons.get(&page), None);
+
+        favicons.received(&favicon_url, Some("🦀".to_string()));
+        let other_page = Url::parse("gemini://example.org:1965/c").unwrap();
+        assert_eq!(favicons.get(&other_page), Some("🦀"));
+
+        // Another port is another capsule
+        let other_capsule = Url::parse("gemini://example.org:1966/").unwrap();
+        assert!(favicons.start(&other_capsule).is_some());
+        assert_eq!(favicons.start(&Url::parse("about:help").unwrap()), None);
+    }
+}
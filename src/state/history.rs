@@ -1,17 +1,22 @@
 use std::fs::OpenOptions;
 use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 
+/// What's been typed into a prompt, in this session and earlier ones
 #[derive(Default)]
 pub struct History {
+    /// The file it's kept in between sessions, entries from this session are added on flush
+    path: Option<PathBuf>,
     index: Option<usize>,
     existing: Vec<String>,
     local: Vec<String>,
 }
 
 impl History {
-    pub fn new(path: &str) -> Self {
+    pub fn new(path: &Path) -> Self {
         let f = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .write(true)
             .read(true)
             .open(path)
@@ -19,6 +24,7 @@ impl History {
         let f = BufReader::new(f);
 
         Self {
+            path: Some(path.to_path_buf()),
             index: None,
             existing: f.lines().map(|s| s.unwrap()).collect(),
             local: Vec::new(),
@@ -63,10 +69,12 @@ impl History {
     }
 
     pub fn flush(&mut self) -> io::Result<()> {
-        let mut f = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("target/history.txt")?;
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
         for line in &self.local {
             writeln!(f, "{}", line)?;
         }
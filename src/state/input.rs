@@ -1,40 +1,328 @@
+use std::fmt;
 use std::io;
+use std::mem;
+use std::path::Path;
 
 use crate::state::history::History;
 use crate::state::Mode;
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum InputEnterResult {
     Navigate(String),
-    Quit,
+    EditUrl,
+    Info,
+    Reload {
+        force: bool,
+    },
+    Quit {
+        force: bool,
+    },
+    /// List the key bindings
+    Bindings,
+    Map {
+        keys: String,
+        commands: String,
+    },
+    Unmap(String),
+    /// Change a setting for the session, as if it were in the config file
+    Set {
+        key: String,
+        value: String,
+    },
+    /// Bookmark the current page
+    Bookmark,
+    /// Write the bookmarks to a gemtext file, replacing an existing file only when forced
+    ExportBookmarks {
+        path: String,
+        force: bool,
+    },
+    /// Add the links in a gemtext or browser bookmarks file to the bookmarks, every scheme or
+    /// just gemini
+    ImportBookmarks {
+        path: String,
+        all_schemes: bool,
+    },
+    /// Empty the page caches, in memory and on disk
+    ClearCache,
+    /// Switch between loading pages from the network and only from the disk cache
+    ToggleOffline,
+    /// Reload the current page every so many seconds, None stops reloading
+    AutoReload(Option<u64>),
+    /// Decode the current page with a charset given by its WHATWG label, or auto for the one it
+    /// declares
+    Encoding(String),
+    /// Move to the next link with this text in its name or URL
+    FindLink(String),
+    /// Split the screen into two panes side by side
+    VSplit,
+    ClosePane,
+    /// Load a URL, or the link on the current line, in the other pane
+    OpenOther(Option<String>),
+    /// Write what's on screen to a file
+    Hardcopy {
+        path: Option<String>,
+        plain: bool,
+        status_line: bool,
+    },
     Invalid(String),
 }
 
 impl InputEnterResult {
+    /// Whether . repeats this command, motions and commands that only show something aren't
+    /// worth repeating
+    pub fn is_repeatable(&self) -> bool {
+        matches!(
+            self,
+            InputEnterResult::Navigate(_)
+                | InputEnterResult::Reload { .. }
+                | InputEnterResult::OpenOther(_)
+                | InputEnterResult::FindLink(_)
+                | InputEnterResult::Set { .. }
+        )
+    }
+
     pub fn from(input: &str) -> Self {
         use InputEnterResult::*;
 
         if let Some(url) = input.strip_prefix("go ") {
             Navigate(url.to_owned())
+        } else if input == "cache" {
+            Navigate("about:cache".to_owned())
+        } else if input == "bookmark" {
+            Bookmark
+        } else if input == "bookmarks" {
+            Navigate("about:bookmarks".to_owned())
+        } else if let Some(rest) = input.strip_prefix("bookmarks-export") {
+            let (force, path) = match rest.strip_prefix('!') {
+                Some(path) => (true, path),
+                None => (false, rest),
+            };
+            match path.strip_prefix(' ').map(str::trim) {
+                Some(path) if !path.is_empty() => ExportBookmarks {
+                    path: path.to_owned(),
+                    force,
+                },
+                _ => Invalid(input.to_owned()),
+            }
+        } else if let Some(rest) = input.strip_prefix("bookmarks-import ") {
+            let (all_schemes, path) = match rest.trim().strip_prefix("--all ") {
+                Some(path) => (true, path.trim()),
+                None => (false, rest.trim()),
+            };
+            match path {
+                "" | "--all" => Invalid(input.to_owned()),
+                path => ImportBookmarks {
+                    path: path.to_owned(),
+                    all_schemes,
+                },
+            }
+        } else if input == "cache-clear" {
+            ClearCache
+        } else if input == "offline" {
+            ToggleOffline
+        } else if input == "downloads" {
+            Navigate("about:downloads".to_owned())
+        } else if input == "history" {
+            Navigate("about:history".to_owned())
+        } else if input == "history --recent" {
+            Navigate("about:history?recent".to_owned())
+        } else if input == "jumps" {
+            Navigate("about:jumps".to_owned())
+        } else if input == "messages" {
+            Navigate("about:messages".to_owned())
+        } else if input == "edit-url" {
+            EditUrl
+        } else if input == "info" {
+            Info
+        } else if input == "reload" {
+            Reload { force: false }
+        } else if input == "reload!" {
+            Reload { force: true }
+        } else if let Some(rest) = input.strip_prefix("open ") {
+            let mut other = false;
+            let mut url = None;
+            for arg in rest.split_whitespace() {
+                match arg {
+                    "--other" => other = true,
+                    _ if url.is_none() => url = Some(arg.to_owned()),
+                    _ => return Invalid(input.to_owned()),
+                }
+            }
+
+            match (other, url) {
+                (true, url) => OpenOther(url),
+                (false, Some(url)) => Navigate(url),
+                (false, None) => Invalid(input.to_owned()),
+            }
+        } else if input == "hardcopy" || input.starts_with("hardcopy ") {
+            let mut plain = false;
+            let mut status_line = false;
+            let mut path = None;
+            for arg in input.split_whitespace().skip(1) {
+                match arg {
+                    "--plain" => plain = true,
+                    "--status" => status_line = true,
+                    _ if path.is_none() => path = Some(arg.to_owned()),
+                    _ => return Invalid(input.to_owned()),
+                }
+            }
+
+            Hardcopy {
+                path,
+                plain,
+                status_line,
+            }
+        } else if input == "vsplit" {
+            VSplit
+        } else if input == "close" {
+            ClosePane
         } else if input == "quit" || input == "q" {
-            Quit
+            Quit { force: false }
+        } else if input == "quit!" || input == "q!" {
+            Quit { force: true }
+        } else if input == "map" || input == "bindings" {
+            Bindings
+        } else if let Some(rest) = input.strip_prefix("map ") {
+            match rest.trim().split_once(' ') {
+                Some((keys, commands)) if !commands.trim().is_empty() => Map {
+                    keys: keys.to_owned(),
+                    commands: commands.trim().to_owned(),
+                },
+                _ => Invalid(input.to_owned()),
+            }
+        } else if let Some(keys) = input.strip_prefix("unmap ") {
+            Unmap(keys.trim().to_owned())
+        } else if let Some(setting) = input.strip_prefix("set ") {
+            match setting.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => Set {
+                    key: key.trim().to_owned(),
+                    value: value.trim().to_owned(),
+                },
+                // Switches are turned on and off by name like in vim, set x and set nox
+                None if !setting.trim().is_empty() && !setting.trim().contains(' ') => {
+                    let (key, value) = match setting.trim().strip_prefix("no") {
+                        Some(key) if !key.is_empty() => (key, "false"),
+                        _ => (setting.trim(), "true"),
+                    };
+                    Set {
+                        key: key.to_owned(),
+                        value: value.to_owned(),
+                    }
+                }
+                _ => Invalid(input.to_owned()),
+            }
+        } else if let Some(seconds) = input.strip_prefix("autoreload ") {
+            match seconds.trim() {
+                "off" => AutoReload(None),
+                seconds => match seconds.parse() {
+                    Ok(seconds) if seconds > 0 => AutoReload(Some(seconds)),
+                    _ => Invalid(input.to_owned()),
+                },
+            }
+        } else if let Some(label) = input.strip_prefix("encoding ") {
+            match label.trim() {
+                "" => Invalid(input.to_owned()),
+                label => Encoding(label.to_owned()),
+            }
+        } else if let Some(text) = input.strip_prefix("find-link ") {
+            match text.trim() {
+                "" => Invalid(input.to_owned()),
+                text => FindLink(text.to_owned()),
+            }
         } else {
             Invalid(input.to_owned())
         }
     }
 }
 
+/// The command as it would be typed
+impl fmt::Display for InputEnterResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InputEnterResult::*;
+
+        match self {
+            Navigate(url) => write!(f, "go {}", url),
+            EditUrl => write!(f, "edit-url"),
+            Info => write!(f, "info"),
+            Reload { force: false } => write!(f, "reload"),
+            Reload { force: true } => write!(f, "reload!"),
+            Quit { force: false } => write!(f, "quit"),
+            Quit { force: true } => write!(f, "quit!"),
+            Bindings => write!(f, "bindings"),
+            Map { keys, commands } => write!(f, "map {} {}", keys, commands),
+            Unmap(keys) => write!(f, "unmap {}", keys),
+            Set { key, value } => write!(f, "set {}={}", key, value),
+            Bookmark => write!(f, "bookmark"),
+            ExportBookmarks { path, force } => {
+                write!(
+                    f,
+                    "bookmarks-export{} {}",
+                    if *force { "!" } else { "" },
+                    path
+                )
+            }
+            ImportBookmarks { path, all_schemes } => write!(
+                f,
+                "bookmarks-import {}{}",
+                if *all_schemes { "--all " } else { "" },
+                path
+            ),
+            ClearCache => write!(f, "cache-clear"),
+            ToggleOffline => write!(f, "offline"),
+            AutoReload(Some(seconds)) => write!(f, "autoreload {}", seconds),
+            AutoReload(None) => write!(f, "autoreload off"),
+            Encoding(label) => write!(f, "encoding {}", label),
+            FindLink(text) => write!(f, "find-link {}", text),
+            VSplit => write!(f, "vsplit"),
+            ClosePane => write!(f, "close"),
+            OpenOther(Some(url)) => write!(f, "open --other {}", url),
+            OpenOther(None) => write!(f, "open --other"),
+            Hardcopy {
+                path,
+                plain,
+                status_line,
+            } => {
+                write!(f, "hardcopy")?;
+                if *plain {
+                    write!(f, " --plain")?;
+                }
+                if *status_line {
+                    write!(f, " --status")?;
+                }
+                match path {
+                    Some(path) => write!(f, " {}", path),
+                    None => Ok(()),
+                }
+            }
+            Invalid(input) => write!(f, "{}", input),
+        }
+    }
+}
+
+/// How many of the most frecent URLs :go completes from
+const COMPLETIONS: usize = 10;
+
 #[derive(Default)]
 pub struct Input {
     pub input: String,
     command_history: History,
     search_history: History,
+    completion: Option<Completion>,
+}
+
+/// A completion being cycled through, forgotten once the input is changed some other way
+struct Completion {
+    typed: String,
+    index: usize,
+    shown: String,
 }
 
 impl Input {
-    pub fn new() -> Self {
+    /// Input with its command and search history kept in files of their own in a directory
+    pub fn in_dir(dir: &Path) -> Self {
         Self {
-            command_history: History::new("target/command_history.txt"),
-            search_history: History::new("target/search_history.txt"),
+            command_history: History::new(&dir.join("command_history.txt")),
+            search_history: History::new(&dir.join("search_history.txt")),
             ..Self::default()
         }
     }
@@ -43,8 +331,15 @@ impl Input {
         self.input.push(c);
     }
 
+    /// Replace the input with some text, it's only added to history if it's submitted
+    pub fn prefill(&mut self, text: &str) {
+        self.input = text.to_string();
+    }
+
     pub fn cancel(&mut self) {
         self.input.clear();
+        self.command_history.reset_index();
+        self.search_history.reset_index();
     }
 
     pub fn delete_word(&mut self) {
@@ -60,38 +355,281 @@ impl Input {
         self.input = chars.collect();
     }
 
+    /// Recall an older entry from the history of the prompt being typed in
     pub fn up(&mut self, mode: Mode) {
-        self.history(mode).up();
-        self.input = self.history(mode).get();
+        if let Some(history) = self.history(mode) {
+            history.up();
+            self.input = history.get();
+        }
     }
 
     pub fn down(&mut self, mode: Mode) {
-        if self.history(mode).down() {
-            self.input = self.history(mode).get();
+        if let Some(history) = self.history(mode) {
+            if history.down() {
+                self.input = history.get();
+            }
         }
     }
 
-    pub fn enter(&mut self, mode: Mode) -> InputEnterResult {
-        let input = self.input.clone();
-        self.input.clear();
-        self.history(mode).push(input.clone());
-        self.history(mode).reset_index();
-        InputEnterResult::from(&input)
+    /// Complete the URL typed after go with the first of the URLs containing it, or the next
+    /// one when the last completion hasn't been changed
+    pub fn complete(&mut self, urls: &[&str]) {
+        let (typed, index) = match self.completion.take() {
+            Some(completion) if completion.shown == self.input => {
+                (completion.typed, completion.index + 1)
+            }
+            _ => (self.input.clone(), 0),
+        };
+        let query = match typed.strip_prefix("go ") {
+            Some(query) => query.trim().to_lowercase(),
+            None => return,
+        };
+
+        let matches: Vec<&str> = urls
+            .iter()
+            .filter(|url| url.to_lowercase().contains(&query))
+            .take(COMPLETIONS)
+            .copied()
+            .collect();
+        if matches.is_empty() {
+            return;
+        }
+
+        let index = index % matches.len();
+        self.input = format!("go {}", matches[index]);
+        self.completion = Some(Completion {
+            typed,
+            index,
+            shown: self.input.clone(),
+        });
+    }
+
+    /// Submit the command, adding it to the history
+    pub fn enter(&mut self) -> InputEnterResult {
+        let input = mem::take(&mut self.input);
+        let command = InputEnterResult::from(&input);
+        self.command_history.push(input);
+        self.command_history.reset_index();
+        command
     }
 
+    /// Submit the search pattern, adding it to the history
     pub fn search(&mut self) {
-        self.input.clear();
+        let pattern = mem::take(&mut self.input);
+        self.search_history.push(pattern);
+        self.search_history.reset_index();
     }
 
-    pub fn history(&mut self, mode: Mode) -> &mut History {
+    /// The history of the prompt a mode types into, modes without one have none
+    pub fn history(&mut self, mode: Mode) -> Option<&mut History> {
         match mode {
-            Mode::Input => &mut self.command_history,
-            Mode::Search => &mut self.search_history,
-            _ => panic!("no history for mode: {:?}", mode),
+            Mode::Input => Some(&mut self.command_history),
+            Mode::Search => Some(&mut self.search_history),
+            Mode::Normal | Mode::Confirm => None,
         }
     }
 
+    /// Add what's been entered this session to the history files
     pub fn flush_history(&mut self) -> io::Result<()> {
+        self.command_history.flush()?;
         self.search_history.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn history() {
+        let dir = env::temp_dir().join(format!("diosk-history-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut input = Input::in_dir(&dir);
+        input.prefill("go gemini://example.org/");
+        input.enter();
+        input.prefill("cat");
+        input.search();
+
+        // Each prompt recalls its own entries
+        input.up(Mode::Search);
+        assert_eq!(input.input, "cat");
+        input.cancel();
+        input.up(Mode::Input);
+        assert_eq!(input.input, "go gemini://example.org/");
+        input.cancel();
+        input.up(Mode::Normal);
+        assert_eq!(input.input, "");
+
+        input.flush_history().unwrap();
+        let read = |name| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("search_history.txt"), "cat\n");
+        assert_eq!(read("command_history.txt"), "go gemini://example.org/\n");
+
+        // And the next session recalls them too
+        let mut input = Input::in_dir(&dir);
+        input.up(Mode::Search);
+        assert_eq!(input.input, "cat");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn map() {
+        match InputEnterResult::from("map X go gemini://example.org/ | reload") {
+            InputEnterResult::Map { keys, commands } => {
+                assert_eq!(keys, "X");
+                assert_eq!(commands, "go gemini://example.org/ | reload");
+            }
+            _ => panic!("expected a mapping"),
+        }
+
+        assert!(matches!(
+            InputEnterResult::from("map X"),
+            InputEnterResult::Invalid(_)
+        ));
+        assert!(matches!(
+            InputEnterResult::from("map"),
+            InputEnterResult::Bindings
+        ));
+        assert!(matches!(
+            InputEnterResult::from("unmap X"),
+            InputEnterResult::Unmap(keys) if keys == "X"
+        ));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for command in &[
+            "go gemini://example.org/",
+            "reload!",
+            "map X reload | info",
+            "set bell=on",
+            "quit!",
+            "cache-clear",
+            "bookmarks-export! ~/bookmarks.gmi",
+            "bookmarks-import --all bookmarks.html",
+            "offline",
+            "encoding latin1",
+            "find-link about",
+        ] {
+            assert_eq!(InputEnterResult::from(command).to_string(), *command);
+        }
+        assert!(InputEnterResult::from("reload").is_repeatable());
+        assert!(InputEnterResult::from("find-link about").is_repeatable());
+        assert!(InputEnterResult::from("set wrap=false").is_repeatable());
+        assert!(!InputEnterResult::from("info").is_repeatable());
+    }
+
+    #[test]
+    fn set() {
+        assert!(matches!(
+            InputEnterResult::from("set prefetch = 2"),
+            InputEnterResult::Set { key, value } if key == "prefetch" && value == "2"
+        ));
+        assert!(matches!(
+            InputEnterResult::from("set smartcase"),
+            InputEnterResult::Set { key, value } if key == "smartcase" && value == "true"
+        ));
+        assert!(matches!(
+            InputEnterResult::from("set noignorecase"),
+            InputEnterResult::Set { key, value } if key == "ignorecase" && value == "false"
+        ));
+        assert!(matches!(
+            InputEnterResult::from("set pre fetch"),
+            InputEnterResult::Invalid(_)
+        ));
+    }
+
+    #[test]
+    fn autoreload() {
+        let autoreload = |input| match InputEnterResult::from(input) {
+            InputEnterResult::AutoReload(seconds) => Ok(seconds),
+            _ => Err(()),
+        };
+
+        assert_eq!(autoreload("autoreload 60"), Ok(Some(60)));
+        assert_eq!(autoreload("autoreload off"), Ok(None));
+        assert_eq!(autoreload("autoreload 0"), Err(()));
+        assert_eq!(autoreload("autoreload soon"), Err(()));
+    }
+
+    #[test]
+    fn open() {
+        assert_eq!(
+            InputEnterResult::from("open --other"),
+            InputEnterResult::OpenOther(None)
+        );
+        assert_eq!(
+            InputEnterResult::from("open --other gemini://example.org/"),
+            InputEnterResult::OpenOther(Some("gemini://example.org/".to_owned()))
+        );
+        assert_eq!(
+            InputEnterResult::from("open gemini://example.org/ --other"),
+            InputEnterResult::OpenOther(Some("gemini://example.org/".to_owned()))
+        );
+        assert_eq!(
+            InputEnterResult::from("open gemini://example.org/"),
+            InputEnterResult::Navigate("gemini://example.org/".to_owned())
+        );
+        assert!(matches!(
+            InputEnterResult::from("open a b"),
+            InputEnterResult::Invalid(_)
+        ));
+    }
+
+    #[test]
+    fn hardcopy() {
+        let command = InputEnterResult::from("hardcopy --plain page.txt");
+        assert_eq!(
+            command,
+            InputEnterResult::Hardcopy {
+                path: Some("page.txt".to_owned()),
+                plain: true,
+                status_line: false,
+            }
+        );
+        assert_eq!(command.to_string(), "hardcopy --plain page.txt");
+
+        assert_eq!(
+            InputEnterResult::from("hardcopy"),
+            InputEnterResult::Hardcopy {
+                path: None,
+                plain: false,
+                status_line: false,
+            }
+        );
+    }
+
+    #[test]
+    fn complete() {
+        let urls = [
+            "gemini://example.org/",
+            "gemini://station.example/",
+            "gemini://EXAMPLE.com/",
+        ];
+        let mut input = Input::default();
+
+        input.prefill("go example.");
+        input.complete(&urls);
+        assert_eq!(input.input, "go gemini://example.org/");
+        input.complete(&urls);
+        assert_eq!(input.input, "go gemini://EXAMPLE.com/");
+        input.complete(&urls);
+        assert_eq!(input.input, "go gemini://example.org/");
+
+        // Editing the completion starts again from what's typed now
+        input.delete_char();
+        input.complete(&urls);
+        assert_eq!(input.input, "go gemini://example.org/");
+
+        input.prefill("go nowhere");
+        input.complete(&urls);
+        assert_eq!(input.input, "go nowhere");
+        input.prefill("reload");
+        input.complete(&urls);
+        assert_eq!(input.input, "reload");
+    }
+}
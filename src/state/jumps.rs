@@ -0,0 +1,131 @@
+use url::Url;
+
+/// The most jumps remembered, the oldest are forgotten first
+const MAX_JUMPS: usize = 100;
+
+/// A position jumped away from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Jump {
+    pub url: Url,
+    pub line: usize,
+}
+
+/// Positions jumped away from, walked back with Ctrl-O and forward with Ctrl-I
+#[derive(Debug, Default)]
+pub struct JumpList {
+    jumps: Vec<Jump>,
+    /// Where we are while walking the list, the end of the list when not walking it
+    index: usize,
+}
+
+impl JumpList {
+    /// Record the position being jumped away from, forgetting anything ahead of it
+    pub fn push(&mut self, jump: Jump) {
+        self.jumps.truncate(self.index);
+        if self.jumps.last() != Some(&jump) {
+            self.jumps.push(jump);
+        }
+        if self.jumps.len() > MAX_JUMPS {
+            self.jumps.remove(0);
+        }
+        self.index = self.jumps.len();
+    }
+
+    /// Step back from the current position, which is remembered so it can be returned to
+    pub fn back(&mut self, current: Jump) -> Option<Jump> {
+        if self.index == 0 {
+            return None;
+        }
+
+        if self.index == self.jumps.len() {
+            self.jumps.push(current);
+            if self.jumps.len() > MAX_JUMPS {
+                self.jumps.remove(0);
+                self.index -= 1;
+            }
+        } else {
+            self.jumps[self.index] = current;
+        }
+
+        self.index -= 1;
+        Some(self.jumps[self.index].clone())
+    }
+
+    pub fn forward(&mut self, current: Jump) -> Option<Jump> {
+        if self.index + 1 >= self.jumps.len() {
+            return None;
+        }
+
+        self.jumps[self.index] = current;
+        self.index += 1;
+        Some(self.jumps[self.index].clone())
+    }
+
+    /// The jumps oldest first, paired with whether they're the current position
+    pub fn entries(&self) -> impl Iterator<Item = (&Jump, bool)> {
+        self.jumps
+            .iter()
+            .enumerate()
+            .map(move |(i, jump)| (jump, i == self.index))
+    }
+
+    pub fn len(&self) -> usize {
+        self.jumps.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jump(path: &str, line: usize) -> Jump {
+        Jump {
+            url: Url::parse("gemini://example.org/")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+            line,
+        }
+    }
+
+    #[test]
+    fn back_and_forward() {
+        let mut jumps = JumpList::default();
+        jumps.push(jump("a", 1));
+        jumps.push(jump("b", 2));
+
+        assert_eq!(jumps.back(jump("c", 3)), Some(jump("b", 2)));
+        assert_eq!(jumps.back(jump("b", 5)), Some(jump("a", 1)));
+        assert_eq!(jumps.back(jump("a", 1)), None);
+
+        // The position left is remembered, including moves made since arriving
+        assert_eq!(jumps.forward(jump("a", 1)), Some(jump("b", 5)));
+        assert_eq!(jumps.forward(jump("b", 5)), Some(jump("c", 3)));
+        assert_eq!(jumps.forward(jump("c", 3)), None);
+    }
+
+    #[test]
+    fn push_while_walking_forgets_forward_jumps() {
+        let mut jumps = JumpList::default();
+        jumps.push(jump("a", 1));
+        jumps.push(jump("b", 2));
+        jumps.back(jump("c", 3));
+
+        jumps.push(jump("b", 4));
+
+        assert_eq!(jumps.len(), 2);
+        assert_eq!(jumps.forward(jump("d", 0)), None);
+        assert_eq!(jumps.back(jump("d", 0)), Some(jump("b", 4)));
+    }
+
+    #[test]
+    fn bounded() {
+        let mut jumps = JumpList::default();
+        for line in 0..MAX_JUMPS + 10 {
+            jumps.push(jump("a", line));
+        }
+
+        assert_eq!(jumps.len(), MAX_JUMPS);
+        assert_eq!(jumps.entries().next().unwrap().0.line, 10);
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+/// Gemtext for an internal page listing the records of a store, with the line each record is on
+/// so they can be edited from the page
+#[derive(Debug)]
+pub struct ListPage<K> {
+    content: String,
+    /// The lines in the content so far, kept so adding a record doesn't count them again
+    lines: usize,
+    entries: HashMap<usize, K>,
+}
+
+impl<K> ListPage<K> {
+    /// A page starting with lines that aren't records, e.g. a heading
+    pub fn new(header: String) -> Self {
+        Self {
+            lines: header.matches('\n').count(),
+            content: header,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Add lines that aren't records
+    pub fn push(&mut self, text: &str) {
+        self.lines += text.matches('\n').count();
+        self.content.push_str(text);
+    }
+
+    /// Add a line for a record, the line ends with a newline
+    pub fn push_entry(&mut self, line: &str, key: K) {
+        self.entries.insert(self.lines, key);
+        self.push(line);
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// The record on a line, if there is one
+    pub fn entry(&self, line: usize) -> Option<&K> {
+        self.entries.get(&line)
+    }
+
+    /// Key the records some other way, e.g. by which store they're in
+    pub fn map<L>(self, f: impl Fn(K) -> L) -> ListPage<L> {
+        ListPage {
+            content: self.content,
+            lines: self.lines,
+            entries: self.entries.into_iter().map(|(i, k)| (i, f(k))).collect(),
+        }
+    }
+
+    pub fn into_parts(self) -> (String, HashMap<usize, K>) {
+        (self.content, self.entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gemini::gemtext::{self, Line};
+
+    #[test]
+    fn entries_are_on_their_lines() {
+        let mut page = ListPage::new("# Heading\n\n".to_string());
+        page.push_entry("=> gemini://a.org/ A\n", 'a');
+        page.push("\n## More\n\n");
+        page.push_entry("=> gemini://b.org/ B\n", 'b');
+        let page = page.map(|key| key.to_ascii_uppercase());
+
+        let lines = gemtext::parse(page.content());
+        for (key, url) in &[('A', "gemini://a.org/"), ('B', "gemini://b.org/")] {
+            let (line, _) = page
+                .entries
+                .iter()
+                .find(|(_, k)| *k == key)
+                .expect("an entry");
+            assert!(matches!(&lines[*line], Line::Link { url: u, .. } if u == url));
+        }
+        assert_eq!(page.entry(0), None);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use crate::gemini::normalize_url;
+
+/// Pages kept in memory, keyed by normalized URL, with least recently used entries evicted once
+/// either the entry or byte limit is exceeded
+///
+/// Entries older than the max age aren't served, they're dropped when they're asked for.
+pub struct PageCache<T> {
+    max_entries: usize,
+    max_bytes: usize,
+    max_age: Duration,
+    entries: HashMap<String, Entry<T>>,
+    tick: u64,
+}
+
+struct Entry<T> {
+    value: T,
+    size: usize,
+    /// Fetched ahead of time rather than navigated to
+    prefetched: bool,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+pub struct EntryInfo<'a> {
+    pub url: &'a str,
+    pub size: usize,
+    pub age: Duration,
+    pub prefetched: bool,
+}
+
+impl<T: Clone> PageCache<T> {
+    pub fn new(max_entries: usize, max_bytes: usize, max_age: Duration) -> Self {
+        Self {
+            max_entries,
+            max_bytes,
+            max_age,
+            entries: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn set_limits(&mut self, max_entries: usize, max_bytes: usize, max_age: Duration) {
+        self.max_entries = max_entries;
+        self.max_bytes = max_bytes;
+        self.max_age = max_age;
+        self.evict();
+    }
+
+    /// Look up a page, marking it as recently used
+    pub fn get(&mut self, url: &Url) -> Option<(T, bool)> {
+        self.tick += 1;
+        let tick = self.tick;
+        let key = normalize_url(url);
+
+        let expired = self
+            .entries
+            .get(&key)
+            .is_some_and(|entry| entry.inserted_at.elapsed() > self.max_age);
+        if expired {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        self.entries.get_mut(&key).map(|entry| {
+            entry.last_used = tick;
+            (entry.value.clone(), entry.prefetched)
+        })
+    }
+
+    pub fn contains(&self, url: &Url) -> bool {
+        self.entries
+            .get(&normalize_url(url))
+            .is_some_and(|entry| entry.inserted_at.elapsed() <= self.max_age)
+    }
+
+    /// Add a page, a prefetched page never replaces an existing entry
+    pub fn insert(&mut self, url: &Url, value: T, size: usize, prefetched: bool) {
+        let key = normalize_url(url);
+        if prefetched && self.entries.contains_key(&key) {
+            return;
+        }
+
+        self.tick += 1;
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                prefetched,
+                inserted_at: Instant::now(),
+                last_used: self.tick,
+            },
+        );
+        self.evict();
+    }
+
+    pub fn remove(&mut self, url: &Url) -> bool {
+        self.entries.remove(&normalize_url(url)).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.entries.values().map(|e| e.size).sum()
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// The cached entries, most recently used first
+    pub fn entries(&self) -> Vec<EntryInfo<'_>> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(_, e)| std::cmp::Reverse(e.last_used));
+        entries
+            .into_iter()
+            .map(|(url, e)| EntryInfo {
+                url,
+                size: e.size,
+                age: e.inserted_at.elapsed(),
+                prefetched: e.prefetched,
+            })
+            .collect()
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.max_entries || self.total_bytes() > self.max_bytes {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone());
+
+            match oldest {
+                Some(key) => self.entries.remove(&key),
+                None => break,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(path: &str) -> Url {
+        Url::parse("gemini://example.org/")
+            .unwrap()
+            .join(path)
+            .unwrap()
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entry() {
+        let mut cache = PageCache::new(2, 1000, Duration::from_secs(60));
+        cache.insert(&url("a"), "a", 1, false);
+        cache.insert(&url("b"), "b", 1, false);
+
+        // Using a makes b the least recently used
+        assert_eq!(cache.get(&url("a")), Some(("a", false)));
+        cache.insert(&url("c"), "c", 1, false);
+
+        assert!(cache.contains(&url("a")));
+        assert!(!cache.contains(&url("b")));
+        assert!(cache.contains(&url("c")));
+    }
+
+    #[test]
+    fn evicts_to_fit_max_bytes() {
+        let mut cache = PageCache::new(10, 10, Duration::from_secs(60));
+        cache.insert(&url("a"), "a", 4, false);
+        cache.insert(&url("b"), "b", 4, false);
+        cache.insert(&url("c"), "c", 4, false);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.total_bytes(), 8);
+        assert!(!cache.contains(&url("a")));
+
+        // An entry bigger than the whole cache isn't kept
+        cache.insert(&url("d"), "d", 11, false);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn prefetch_does_not_replace_entry() {
+        let mut cache = PageCache::new(10, 100, Duration::from_secs(60));
+        cache.insert(&url("a"), "visited", 1, false);
+        cache.insert(&url("a"), "prefetched", 1, true);
+
+        assert_eq!(cache.get(&url("a")), Some(("visited", false)));
+    }
+
+    #[test]
+    fn lookups_are_normalized() {
+        let mut cache = PageCache::new(10, 100, Duration::from_secs(60));
+        cache.insert(&url("a#top"), "a", 1, false);
+
+        assert!(cache.contains(&url("a")));
+        assert!(cache.remove(&url("a")));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn expired_entries_are_not_served() {
+        let mut cache = PageCache::new(10, 100, Duration::from_secs(0));
+        cache.insert(&url("a"), "a", 1, false);
+        std::thread::sleep(Duration::from_millis(1));
+
+        assert!(!cache.contains(&url("a")));
+        assert_eq!(cache.get(&url("a")), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn shrinking_limits_evicts() {
+        let mut cache = PageCache::new(10, 100, Duration::from_secs(60));
+        cache.insert(&url("a"), "a", 1, false);
+        cache.insert(&url("b"), "b", 1, false);
+
+        cache.set_limits(1, 100, Duration::from_secs(60));
+        assert_eq!(cache.len(), 1);
+        assert!(cache.contains(&url("b")));
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+/// How many requests can be made at once, any more wait in the queue
+pub const WORKERS: usize = 3;
+
+/// A fixed number of threads requests are made on, so following links faster than they load
+/// can't pile up threads and sockets
+///
+/// Requests are cancelled with the flag they're submitted with, ones that haven't started yet
+/// are dropped without ever running. One that's already running can't be stopped until it
+/// notices, e.g. while it's connecting, so it no longer takes up one of the workers and another
+/// thread is started in its place when there's something waiting.
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+    /// How many requests run at once, not counting cancelled ones yet to finish
+    workers: usize,
+    /// Names the threads
+    spawned: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    /// The cancel flags of the jobs running
+    running: Vec<Arc<AtomicBool>>,
+    threads: usize,
+    idle: usize,
+    /// The pool has been dropped, the workers stop
+    closed: bool,
+}
+
+impl Queue {
+    fn running_cancelled(&self) -> usize {
+        self.running
+            .iter()
+            .filter(|cancelled| cancelled.load(Ordering::SeqCst))
+            .count()
+    }
+}
+
+struct Job {
+    run: Box<dyn FnOnce() + Send>,
+    cancelled: Arc<AtomicBool>,
+    state: Arc<JobState>,
+}
+
+#[derive(Default)]
+struct JobState {
+    started: AtomicBool,
+    finished: AtomicBool,
+}
+
+/// Whether a submitted request is still going
+pub struct Handle {
+    cancelled: Arc<AtomicBool>,
+    state: Arc<JobState>,
+}
+
+impl Handle {
+    /// Finished running, or cancelled before it started
+    pub fn is_finished(&self) -> bool {
+        let state = &self.state;
+        state.finished.load(Ordering::SeqCst)
+            || (self.cancelled.load(Ordering::SeqCst) && !state.started.load(Ordering::SeqCst))
+    }
+}
+
+impl Pool {
+    pub fn new(workers: usize) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::default(),
+            available: Condvar::new(),
+            workers,
+            spawned: AtomicUsize::new(0),
+        });
+
+        {
+            let mut queue = shared.lock();
+            for _ in 0..workers {
+                spawn(&shared, &mut queue);
+            }
+        }
+
+        Self { shared }
+    }
+
+    /// Queue a request, it's run once a worker is free unless it's cancelled first
+    pub fn submit(
+        &self,
+        cancelled: Arc<AtomicBool>,
+        run: impl FnOnce() + Send + 'static,
+    ) -> Handle {
+        let state = Arc::new(JobState::default());
+        let mut queue = self.shared.lock();
+        prune(&mut queue);
+        queue.jobs.push_back(Job {
+            run: Box::new(run),
+            cancelled: cancelled.clone(),
+            state: state.clone(),
+        });
+
+        // Take the place of a cancelled request that's yet to give up its thread
+        let usable = queue.threads - queue.running_cancelled();
+        if queue.idle < queue.jobs.len() && usable < self.shared.workers {
+            spawn(&self.shared, &mut queue);
+        }
+        self.shared.available.notify_one();
+
+        Handle { cancelled, state }
+    }
+
+    /// The requests being made and waiting to be, not counting cancelled ones
+    pub fn pending(&self) -> usize {
+        let queue = self.shared.lock();
+        let queued = queue
+            .jobs
+            .iter()
+            .filter(|job| !job.cancelled.load(Ordering::SeqCst))
+            .count();
+        queued + queue.running.len() - queue.running_cancelled()
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.available.notify_all();
+    }
+}
+
+impl Shared {
+    // A request that panicked is caught before it gets here, but the queue is fine either way
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Drop the requests cancelled while they waited
+fn prune(queue: &mut Queue) {
+    queue
+        .jobs
+        .retain(|job| !job.cancelled.load(Ordering::SeqCst));
+}
+
+/// Start a worker thread
+fn spawn(shared: &Arc<Shared>, queue: &mut Queue) {
+    let n = shared.spawned.fetch_add(1, Ordering::SeqCst);
+    let worker = shared.clone();
+    // Without a worker everything would wait forever, better to find out straight away
+    thread::Builder::new()
+        .name(format!("request-{}", n))
+        .spawn(move || work(&worker))
+        .expect("unable to start a request thread");
+    queue.threads += 1;
+}
+
+fn work(shared: &Shared) {
+    let mut queue = shared.lock();
+    loop {
+        if queue.closed {
+            queue.threads -= 1;
+            return;
+        }
+        let Job {
+            run,
+            cancelled,
+            state,
+        } = match queue.jobs.pop_front() {
+            Some(job) => job,
+            None => {
+                queue.idle += 1;
+                queue = shared
+                    .available
+                    .wait(queue)
+                    .unwrap_or_else(|e| e.into_inner());
+                queue.idle -= 1;
+                continue;
+            }
+        };
+
+        // Marked as started before checking so a handle never sees a job that's about to run as
+        // finished
+        state.started.store(true, Ordering::SeqCst);
+        if !cancelled.load(Ordering::SeqCst) {
+            queue.running.push(cancelled.clone());
+            drop(queue);
+            run();
+            queue = shared.lock();
+            if let Some(i) = queue
+                .running
+                .iter()
+                .position(|running| Arc::ptr_eq(running, &cancelled))
+            {
+                queue.running.swap_remove(i);
+            }
+        }
+        state.finished.store(true, Ordering::SeqCst);
+
+        // A thread started in place of a cancelled request isn't needed once that's finished
+        if queue.threads - queue.running_cancelled() > shared.workers && queue.jobs.is_empty() {
+            queue.threads -= 1;
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn bounded_and_cancellable() {
+        let pool = Pool::new(2);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+
+        let mut handles = Vec::new();
+        let mut flags = Vec::new();
+        for n in 0..4 {
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let started_tx = started_tx.clone();
+            let release_rx = release_rx.clone();
+            handles.push(pool.submit(cancelled.clone(), move || {
+                started_tx.send(n).unwrap();
+                let _ = release_rx.lock().unwrap().recv();
+            }));
+            flags.push(cancelled);
+        }
+
+        // Only as many run as there are workers
+        let timeout = Duration::from_secs(5);
+        let mut started = vec![
+            started_rx.recv_timeout(timeout).unwrap(),
+            started_rx.recv_timeout(timeout).unwrap(),
+        ];
+        started.sort_unstable();
+        assert_eq!(started, [0, 1]);
+        assert_eq!(pool.pending(), 4);
+
+        // A queued request is gone as soon as it's cancelled
+        flags[2].store(true, Ordering::SeqCst);
+        assert!(handles[2].is_finished());
+        assert!(!handles[3].is_finished());
+        assert_eq!(pool.pending(), 3);
+
+        for _ in 0..3 {
+            release_tx.send(()).unwrap();
+        }
+        assert_eq!(started_rx.recv_timeout(timeout).unwrap(), 3);
+        assert!(started_rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn cancelled_requests_give_up_their_worker() {
+        let pool = Pool::new(1);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let timeout = Duration::from_secs(5);
+
+        // Stuck, e.g. connecting, until it's released
+        let stuck = Arc::new(AtomicBool::new(false));
+        let tx = started_tx.clone();
+        let handle = pool.submit(stuck.clone(), move || {
+            tx.send("stuck").unwrap();
+            let _ = release_rx.recv();
+        });
+        assert_eq!(started_rx.recv_timeout(timeout).unwrap(), "stuck");
+
+        stuck.store(true, Ordering::SeqCst);
+        assert_eq!(pool.pending(), 0);
+        pool.submit(Arc::new(AtomicBool::new(false)), move || {
+            started_tx.send("next").unwrap();
+        });
+        assert_eq!(started_rx.recv_timeout(timeout).unwrap(), "next");
+
+        release_tx.send(()).unwrap();
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.shared.lock().threads, 1);
+    }
+}
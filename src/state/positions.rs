@@ -0,0 +1,83 @@
+use std::collections::{HashMap, VecDeque};
+
+use url::Url;
+
+use crate::gemini;
+
+/// The most pages a position is remembered for, the least recently left are forgotten first
+const MAX_POSITIONS: usize = 500;
+
+/// Where a page was left
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub scroll_offset: u16,
+}
+
+/// Where pages were left this session, so opening one again goes back there rather than the top
+#[derive(Debug, Default)]
+pub struct Positions {
+    /// Keyed by normalized URL
+    positions: HashMap<String, Position>,
+    /// The keys, least recently left first
+    order: VecDeque<String>,
+}
+
+impl Positions {
+    /// Remember where a page was left, leaving it at the top forgets it
+    pub fn leave(&mut self, url: &Url, position: Position) {
+        let key = gemini::normalize_url(url);
+        self.order.retain(|k| *k != key);
+
+        if position == Position::default() {
+            self.positions.remove(&key);
+            return;
+        }
+
+        self.positions.insert(key.clone(), position);
+        self.order.push_back(key);
+        if self.order.len() > MAX_POSITIONS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.positions.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, url: &Url) -> Option<Position> {
+        self.positions.get(&gemini::normalize_url(url)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions() {
+        let url = |n: usize| Url::parse(&format!("gemini://example.org/{}", n)).unwrap();
+        let position = |line| Position {
+            line,
+            scroll_offset: 1,
+        };
+        let mut positions = Positions::default();
+
+        positions.leave(&url(0), position(3));
+        positions.leave(&url(0), position(5));
+        assert_eq!(positions.get(&url(0)), Some(position(5)));
+        // Normalized, e.g. the fragment doesn't matter
+        assert_eq!(
+            positions.get(&Url::parse("gemini://example.org/0#top").unwrap()),
+            Some(position(5))
+        );
+
+        positions.leave(&url(0), Position::default());
+        assert_eq!(positions.get(&url(0)), None);
+
+        for n in 0..=MAX_POSITIONS {
+            positions.leave(&url(n), position(n));
+        }
+        assert_eq!(positions.get(&url(0)), None);
+        assert_eq!(positions.get(&url(1)), Some(position(1)));
+        assert_eq!(positions.positions.len(), MAX_POSITIONS);
+    }
+}
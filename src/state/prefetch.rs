@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use log::info;
+use mime::Mime;
+use url::Url;
+
+use crate::gemini::fetcher::Fetcher;
+use crate::gemini::tls;
+use crate::gemini::Timeouts;
+use crate::state::Event;
+
+/// The most prefetch requests that can be in flight at once
+const CONCURRENCY: usize = 2;
+
+/// Generations are numbered across every prefetcher, so a response is only ever current for the
+/// pane that asked for it
+static NEXT_GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+/// Fetches links in the background so following them is instant, each pane has its own
+///
+/// Each page load starts a new generation, requests from older generations are cancelled before
+/// they start and their responses are ignored.
+#[derive(Default)]
+pub struct Prefetcher {
+    generation: Arc<AtomicUsize>,
+}
+
+impl Prefetcher {
+    /// Cancel any outstanding prefetches
+    pub fn cancel(&self) {
+        self.generation.store(next_generation(), Ordering::SeqCst);
+    }
+
+    pub fn is_current(&self, generation: usize) -> bool {
+        self.generation.load(Ordering::SeqCst) == generation
+    }
+
+    pub fn start(
+        &self,
+        fetcher: &Arc<dyn Fetcher>,
+        urls: Vec<Url>,
+        timeouts: Timeouts,
+        tx: &mpsc::Sender<Event>,
+    ) {
+        let generation = next_generation();
+        self.generation.store(generation, Ordering::SeqCst);
+        if urls.is_empty() {
+            return;
+        }
+
+        info!("prefetching {} links", urls.len());
+        let queue = Arc::new(Mutex::new(urls.into_iter().collect::<VecDeque<_>>()));
+
+        for _ in 0..CONCURRENCY {
+            let queue = queue.clone();
+            let current = self.generation.clone();
+            let fetcher = fetcher.clone();
+            let tx = tx.clone();
+
+            thread::spawn(move || loop {
+                if current.load(Ordering::SeqCst) != generation {
+                    break;
+                }
+
+                let url = match queue.lock().unwrap_or_else(|e| e.into_inner()).pop_front() {
+                    Some(url) => url,
+                    None => break,
+                };
+
+                // Anything big enough to be downloaded isn't worth prefetching
+                let mut progress = |mime_type: &Mime, _| mime_type.essence_str() == "text/gemini";
+
+                // Anything else is cancelled before its first chunk, so the part file it was going
+                // into is removed and nothing is left in the directory. The user didn't ask for
+                // the page, so it doesn't pin the host's certificate
+                if let Ok(response) = tls::in_background(|| {
+                    fetcher.fetch(&url, &env::temp_dir(), &timeouts, &mut progress)
+                }) {
+                    let event = Event::PrefetchComplete(Box::new(response), url, generation);
+                    if tx.send(event).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
+
+// A closed pane's prefetches stop with it
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+fn next_generation() -> usize {
+    NEXT_GENERATION.fetch_add(1, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::gemini::fetcher::Fixture;
+
+    #[test]
+    fn start() {
+        let prefetcher = Prefetcher::default();
+        let fetcher: Arc<dyn Fetcher> = Arc::new(Fixture::new("Foo."));
+        let (tx, rx) = mpsc::channel();
+        let urls = vec![
+            Url::parse("gemini://example.org/a").unwrap(),
+            Url::parse("gemini://example.org/b").unwrap(),
+            Url::parse("gemini://example.org/c").unwrap(),
+        ];
+
+        prefetcher.start(&fetcher, urls.clone(), Timeouts::default(), &tx);
+
+        let mut prefetched: Vec<_> = (0..urls.len())
+            .map(|_| match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Event::PrefetchComplete(_, url, generation) => {
+                    assert!(prefetcher.is_current(generation));
+                    url
+                }
+                _ => panic!("unexpected event"),
+            })
+            .collect();
+        prefetched.sort();
+        assert_eq!(prefetched, urls);
+    }
+}
@@ -0,0 +1,315 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::gemini::gemtext::Line;
+
+/// What a search looks for, as typed into the prompt
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    typed: String,
+    /// Text is searched for with a regular expression that matches just it
+    regex: Regex,
+}
+
+/// How letter case is matched, like vim's ignorecase and smartcase
+#[derive(Debug, Clone, Copy)]
+pub struct Case {
+    /// Match letters whatever their case
+    pub ignore: bool,
+    /// Unless the pattern has capitals in it
+    pub smart: bool,
+}
+
+impl Pattern {
+    /// The pattern typed into the search prompt, a regular expression when it ends in /r or
+    /// they're the default
+    pub fn parse(typed: &str, regex: bool, case: Case) -> Result<Self, regex::Error> {
+        let (source, capitals) = match typed.strip_suffix("/r") {
+            Some(source) if !source.is_empty() => (source.to_string(), regex_has_capitals(source)),
+            _ if regex => (typed.to_string(), regex_has_capitals(typed)),
+            _ => (regex::escape(typed), typed.chars().any(char::is_uppercase)),
+        };
+
+        let regex = RegexBuilder::new(&source)
+            .case_insensitive(case.ignore && !(case.smart && capitals))
+            .build()?;
+        Ok(Self {
+            typed: typed.to_string(),
+            regex,
+        })
+    }
+
+    /// Where the pattern appears in text, without overlapping
+    fn find<'t>(&'t self, text: &'t str) -> impl Iterator<Item = Range<usize>> + 't {
+        self.regex.find_iter(text).map(|m| m.range())
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.typed)
+    }
+}
+
+/// Whether a regular expression has capitals in it, other than in escapes like \S and \p{Lu}
+fn regex_has_capitals(source: &str) -> bool {
+    let mut chars = source.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some('p' | 'P') = chars.next() {
+                    if chars.clone().next() == Some('{') {
+                        chars.by_ref().find(|&c| c == '}');
+                    }
+                }
+            }
+            c if c.is_uppercase() => return true,
+            _ => {}
+        }
+    }
+
+    false
+}
+
+/// Where a search pattern matched, a byte range in the text of a line as it's displayed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub line: usize,
+    pub range: Range<usize>,
+}
+
+/// A search of the page being shown, every match is found up front so they can be counted
+#[derive(Debug)]
+pub struct Search {
+    pattern: Pattern,
+    matches: Vec<Match>,
+    /// The match n and N last moved to, an index into matches
+    current: Option<usize>,
+}
+
+impl Search {
+    /// Search text, preformatted text, and link names, display turns a line's text into what's drawn so
+    /// the ranges line up with it
+    ///
+    /// Matches of a regular expression that are empty, like ^, are kept, they're moved to but
+    /// there's nothing to highlight.
+    pub fn new<'t>(
+        pattern: Pattern,
+        lines: &'t [Line],
+        display: impl Fn(&'t Line, &'t str) -> Cow<'t, str>,
+    ) -> Self {
+        let mut matches = Vec::new();
+
+        for (i, line) in lines.iter().enumerate() {
+            let text = match line {
+                Line::Normal(text)
+                | Line::Heading { text, .. }
+                | Line::ListItem(text)
+                | Line::Quote(text)
+                | Line::Preformatted { text, .. } => text,
+                Line::Link {
+                    name: Some(name), ..
+                } => name,
+                Line::Link { url, .. } => url,
+                _ => continue,
+            };
+
+            let text = display(line, text);
+            matches.extend(pattern.find(&text).map(|range| Match { line: i, range }));
+        }
+
+        Self {
+            pattern,
+            matches,
+            current: None,
+        }
+    }
+
+    pub fn pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    /// Move to the next match, returning the line it's on
+    ///
+    /// It's the one after the match last moved to while that's on the line, otherwise the first
+    /// from the line on.
+    pub fn next(&mut self, line: usize) -> Option<usize> {
+        if self.matches.is_empty() {
+            return None;
+        }
+
+        let next = match self.current {
+            Some(current) if self.matches[current].line == line => {
+                (current + 1) % self.matches.len()
+            }
+            _ => self
+                .matches
+                .iter()
+                .position(|m| m.line >= line)
+                .unwrap_or(0),
+        };
+        self.current = Some(next);
+        Some(self.matches[next].line)
+    }
+
+    /// Move to the previous match, returning the line it's on
+    ///
+    /// It's the one before the match last moved to while that's on the line, otherwise the last
+    /// before the line.
+    pub fn previous(&mut self, line: usize) -> Option<usize> {
+        if self.matches.is_empty() {
+            return None;
+        }
+
+        let previous = match self.current {
+            Some(current) if self.matches[current].line == line => {
+                current.checked_sub(1).unwrap_or(self.matches.len() - 1)
+            }
+            _ => self
+                .matches
+                .iter()
+                .rposition(|m| m.line < line)
+                .unwrap_or(self.matches.len() - 1),
+        };
+        self.current = Some(previous);
+        Some(self.matches[previous].line)
+    }
+
+    /// The match moved to, counted from 1 with 0 before any has been, and how many there are
+    pub fn position(&self) -> (usize, usize) {
+        (
+            self.current.map_or(0, |current| current + 1),
+            self.matches.len(),
+        )
+    }
+
+    /// The matches on a line, along with whether each is the one moved to
+    pub fn on_line(&self, line: usize) -> Vec<(Range<usize>, bool)> {
+        self.matches
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.line == line)
+            .map(|(i, m)| (m.range.clone(), self.current == Some(i)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gemini::gemtext;
+
+    fn new(pattern: &str, content: &str) -> Search {
+        let lines = gemtext::parse(content);
+        let pattern = Pattern::parse(pattern, false, SMARTCASE).unwrap();
+        Search::new(pattern, &lines, |_, text| Cow::Borrowed(text))
+    }
+
+    const SMARTCASE: Case = Case {
+        ignore: true,
+        smart: true,
+    };
+
+    /// The start and end of each match
+    fn find(pattern: &str, regex: bool, case: Case, text: &str) -> Vec<(usize, usize)> {
+        let pattern = Pattern::parse(pattern, regex, case).unwrap();
+        pattern.find(text).map(|m| (m.start, m.end)).collect()
+    }
+
+    #[test]
+    fn case() {
+        let ignore = Case {
+            ignore: true,
+            smart: false,
+        };
+        let exact = Case {
+            ignore: false,
+            smart: true,
+        };
+
+        assert_eq!(
+            find("foo", false, SMARTCASE, "Foo foo FOO"),
+            [(0, 3), (4, 7), (8, 11)]
+        );
+        assert_eq!(
+            find("Foo", false, SMARTCASE, "Foo foo Foo"),
+            [(0, 3), (8, 11)]
+        );
+        assert_eq!(
+            find("Foo", false, ignore, "Foo foo FOO"),
+            [(0, 3), (4, 7), (8, 11)]
+        );
+        assert_eq!(find("foo", false, exact, "Foo foo Foo"), [(4, 7)]);
+        assert_eq!(find("aa", false, SMARTCASE, "aaaa"), [(0, 2), (2, 4)]);
+        assert_eq!(find("a.c", false, SMARTCASE, "abc a.c"), [(4, 7)]);
+
+        // Capitals and case folding beyond ASCII, the ranges are in the text as it is
+        assert_eq!(
+            find("école", false, SMARTCASE, "ÉCOLE école"),
+            [(0, 6), (7, 13)]
+        );
+        assert_eq!(find("École", false, SMARTCASE, "ÉCOLE École"), [(7, 13)]);
+        assert_eq!(find("σοφία", false, SMARTCASE, "ΣΟΦΊΑ"), [(0, 10)]);
+        assert!(find("ΣΟΦΊΑ", false, SMARTCASE, "σοφία").is_empty());
+
+        // Escapes in regular expressions aren't capitals
+        assert_eq!(find("\\S+x", true, SMARTCASE, "aX"), [(0, 2)]);
+        assert_eq!(find("\\p{Lu}x", true, SMARTCASE, "AX"), [(0, 2)]);
+        assert_eq!(find("\\p{Lu}X", true, SMARTCASE, "Ax AX"), [(3, 5)]);
+    }
+
+    #[test]
+    fn next_and_previous() {
+        let mut search = new(
+            "gemini",
+            "Gemini one\nNothing\n=> /a Gemini two\ngemini three gemini four",
+        );
+        assert_eq!(search.position(), (0, 4));
+        assert_eq!(search.on_line(3), [(0..6, false), (13..19, false)]);
+
+        assert_eq!(search.next(1), Some(2));
+        assert_eq!(search.position(), (2, 4));
+        assert_eq!(search.next(2), Some(3));
+        assert_eq!(search.next(3), Some(3));
+        assert_eq!(search.on_line(3), [(0..6, false), (13..19, true)]);
+        assert_eq!(search.next(3), Some(0));
+        assert_eq!(search.previous(0), Some(3));
+        assert_eq!(search.position(), (4, 4));
+
+        // Moving away goes on from where the cursor is rather than the last match
+        assert_eq!(search.next(1), Some(2));
+        assert_eq!(search.previous(1), Some(0));
+
+        let mut search = new("Gemini", "gemini\nGemini");
+        assert_eq!(search.position(), (0, 1));
+        assert_eq!(search.previous(0), Some(1));
+
+        let mut search = new("missing", "gemini");
+        assert_eq!(search.next(0), None);
+        assert_eq!(search.position(), (0, 0));
+    }
+
+    #[test]
+    fn regex() {
+        let search = new("\\d{4}-\\d\\d/r", "On 2021-05 and 2022-01\n```\n1999-12");
+        assert_eq!(search.position(), (0, 3));
+        assert_eq!(search.on_line(0), [(3..10, false), (15..22, false)]);
+        assert_eq!(search.pattern().to_string(), "\\d{4}-\\d\\d/r");
+
+        assert_eq!(find("g.mini", true, SMARTCASE, "GEMINI"), [(0, 6)]);
+        assert!(Pattern::parse("g(mini", true, SMARTCASE).is_err());
+        assert_eq!(find("/r", false, SMARTCASE, "a/r"), [(1, 3)]);
+
+        // Empty matches are stepped through like any other
+        let mut search = new("x*/r", "ab\nc");
+        assert_eq!(search.position(), (0, 5));
+        assert_eq!(search.on_line(1), [(0..0, false), (1..1, false)]);
+        assert_eq!(search.next(1), Some(1));
+        assert_eq!(search.next(1), Some(1));
+        assert_eq!(search.next(1), Some(0));
+        assert_eq!(search.next(0), Some(0));
+    }
+}
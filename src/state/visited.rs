@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use url::Url;
+
+use crate::gemini::normalize_url;
+
+/// How many times a URL has been visited and when it last was
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Visit {
+    pub count: u32,
+    /// Seconds since the Unix epoch, 0 when it isn't known
+    pub last: u64,
+}
+
+impl Visit {
+    /// How often and how recently the URL was visited rolled into one, visits count for less
+    /// the longer ago the last one was
+    pub fn frecency(&self, now: u64) -> u64 {
+        const DAY: u64 = 24 * 60 * 60;
+
+        let weight = match now.saturating_sub(self.last) {
+            _ if self.last == 0 => 10,
+            age if age < 4 * DAY => 100,
+            age if age < 14 * DAY => 70,
+            age if age < 31 * DAY => 50,
+            age if age < 90 * DAY => 30,
+            _ => 10,
+        };
+        u64::from(self.count) * weight
+    }
+
+    /// How long ago the last visit was, if it's known
+    pub fn age(&self) -> Option<Duration> {
+        match self.last {
+            0 => None,
+            last => Some(Duration::from_secs(now().saturating_sub(last))),
+        }
+    }
+}
+
+/// The URLs that have been navigated to, keyed by their normalized form, with how often and when
+///
+/// Stored one per line as the URL, visit count, and time of the last visit separated by tabs.
+/// Files from before counts were kept, with just a URL on each line, are read as one visit at an
+/// unknown time and rewritten on the next flush.
+#[derive(Default)]
+pub struct Visited {
+    path: PathBuf,
+    visits: HashMap<String, Visit>,
+    /// Visits made since the last flush, added to whatever the file has by then
+    local: Vec<(String, u64)>,
+    /// The file is in the old format
+    migrate: bool,
+}
+
+impl Visited {
+    pub fn new(path: &Path) -> Self {
+        let (visits, migrate) = read(path);
+
+        Self {
+            path: path.to_path_buf(),
+            visits,
+            local: Vec::new(),
+            migrate,
+        }
+    }
+
+    /// Record a visit to the URL
+    pub fn insert(&mut self, url: &Url) {
+        let url = normalize_url(url);
+        let now = now();
+
+        let visit = self
+            .visits
+            .entry(url.clone())
+            .or_insert(Visit { count: 0, last: 0 });
+        visit.count += 1;
+        visit.last = now;
+        self.local.push((url, now));
+    }
+
+    pub fn contains(&self, url: &Url) -> bool {
+        self.visits.contains_key(&normalize_url(url))
+    }
+
+    /// Every URL visited, the highest frecency first
+    pub fn by_frecency(&self) -> Vec<(&str, Visit)> {
+        let now = now();
+        let mut visits = self.entries();
+        visits.sort_by(|(a_url, a), (b_url, b)| {
+            (b.frecency(now), b.last)
+                .cmp(&(a.frecency(now), a.last))
+                .then_with(|| a_url.cmp(b_url))
+        });
+        visits
+    }
+
+    /// Every URL visited, the most recent first
+    pub fn by_recency(&self) -> Vec<(&str, Visit)> {
+        let mut visits = self.entries();
+        visits.sort_by(|(a_url, a), (b_url, b)| b.last.cmp(&a.last).then_with(|| a_url.cmp(b_url)));
+        visits
+    }
+
+    fn entries(&self) -> Vec<(&str, Visit)> {
+        self.visits
+            .iter()
+            .map(|(url, visit)| (url.as_str(), *visit))
+            .collect()
+    }
+
+    /// Forget a URL was ever visited, in the file too
+    pub fn remove(&mut self, url: &str) -> io::Result<Option<Visit>> {
+        let visit = match self.visits.remove(url) {
+            Some(visit) => visit,
+            None => return Ok(None),
+        };
+        self.local.retain(|(local, _)| local != url);
+
+        self.rewrite(|visits| {
+            visits.remove(url);
+        })?;
+        Ok(Some(visit))
+    }
+
+    /// Put back the visits to a URL that was removed
+    pub fn restore(&mut self, url: String, visit: Visit) -> io::Result<()> {
+        self.rewrite(|visits| {
+            let restored = visits.entry(url).or_insert(Visit { count: 0, last: 0 });
+            restored.count += visit.count;
+            restored.last = restored.last.max(visit.last);
+        })
+    }
+
+    /// Add the visits made since the last flush to the file, along with any made by other
+    /// instances in the meantime
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.local.is_empty() && !self.migrate {
+            return Ok(());
+        }
+
+        self.rewrite(|_| {})
+    }
+
+    /// Write what's in the file, the visits since the last flush, and a change to them
+    fn rewrite(&mut self, change: impl FnOnce(&mut HashMap<String, Visit>)) -> io::Result<()> {
+        let (mut visits, _) = read(&self.path);
+        for (url, at) in self.local.drain(..) {
+            let visit = visits.entry(url).or_insert(Visit { count: 0, last: 0 });
+            visit.count += 1;
+            visit.last = visit.last.max(at);
+        }
+        change(&mut visits);
+
+        let mut lines: Vec<_> = visits.iter().collect();
+        lines.sort_by(|(a_url, a), (b_url, b)| b.last.cmp(&a.last).then_with(|| a_url.cmp(b_url)));
+
+        // Written next to the file and renamed into place so it's never left half written
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut f = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&temp)?;
+        for (url, visit) in lines {
+            writeln!(f, "{}\t{}\t{}", url, visit.count, visit.last)?;
+        }
+        f.flush()?;
+        fs::rename(&temp, &self.path)?;
+
+        self.visits = visits;
+        self.migrate = false;
+
+        Ok(())
+    }
+}
+
+/// The visits in a file, and whether it's in the old format of one URL per line
+fn read(path: &Path) -> (HashMap<String, Visit>, bool) {
+    let mut visits = HashMap::new();
+    let mut old_format = false;
+
+    let lines: Vec<String> = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .map(|f| BufReader::new(f).lines().map_while(Result::ok).collect())
+        .unwrap_or_default();
+    for line in lines {
+        let mut fields = line.split('\t');
+        let url = match fields.next() {
+            Some(url) if !url.is_empty() => url.to_string(),
+            _ => continue,
+        };
+        let visit = match (fields.next(), fields.next()) {
+            (Some(count), Some(last)) => Visit {
+                count: count.parse().unwrap_or(1),
+                last: last.parse().unwrap_or(0),
+            },
+            _ => {
+                old_format = true;
+                Visit { count: 1, last: 0 }
+            }
+        };
+
+        let merged = visits.entry(url).or_insert(Visit { count: 0, last: 0 });
+        merged.count += visit.count;
+        merged.last = merged.last.max(visit.last);
+    }
+
+    (visits, old_format)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn contains_normalized() {
+        let mut visited = Visited::default();
+        visited.insert(&Url::parse("gemini://example.org/foo.gmi#top").unwrap());
+
+        assert!(visited.contains(&Url::parse("gemini://EXAMPLE.org/foo.gmi").unwrap()));
+        assert!(visited.contains(&Url::parse("gemini://example.org:1965/foo.gmi").unwrap()));
+        assert!(!visited.contains(&Url::parse("gemini://example.org/bar.gmi").unwrap()));
+    }
+
+    #[test]
+    fn frecency() {
+        const DAY: u64 = 24 * 60 * 60;
+        let now = 1000 * DAY;
+        let visit = |count, days_ago| Visit {
+            count,
+            last: now - days_ago * DAY,
+        };
+
+        assert_eq!(visit(1, 0).frecency(now), 100);
+        assert_eq!(visit(3, 10).frecency(now), 210);
+        assert_eq!(visit(2, 20).frecency(now), 100);
+        assert_eq!(visit(1, 60).frecency(now), 30);
+        assert_eq!(visit(5, 365).frecency(now), 50);
+        assert_eq!(Visit { count: 4, last: 0 }.frecency(now), 40);
+
+        // A page visited often a while ago can still beat one visited once today
+        assert!(visit(5, 10).frecency(now) > visit(1, 0).frecency(now));
+        assert!(visit(2, 10).frecency(now) < visit(2, 1).frecency(now));
+    }
+
+    #[test]
+    fn migrate_and_flush() {
+        let path = env::temp_dir().join(format!("diosk-visited-{}.txt", process::id()));
+        let path = path.as_path();
+        fs::write(path, "gemini://a.example/\ngemini://b.example/\n").unwrap();
+
+        let mut visited = Visited::new(path);
+        assert_eq!(visited.by_frecency().len(), 2);
+        visited.insert(&Url::parse("gemini://b.example/").unwrap());
+        assert_eq!(visited.by_frecency()[0].0, "gemini://b.example/");
+        assert_eq!(visited.by_recency()[0].1.count, 2);
+
+        // Another instance visiting in the meantime isn't lost
+        let mut other = Visited::new(path);
+        other.insert(&Url::parse("gemini://a.example/").unwrap());
+        other.flush().unwrap();
+        visited.flush().unwrap();
+
+        let contents = fs::read_to_string(path).unwrap();
+        let _ = fs::remove_file(path);
+        let lines: Vec<Vec<&str>> = contents.lines().map(|l| l.split('\t').collect()).collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines
+            .iter()
+            .all(|fields| fields[1] == "2" && fields[2] != "0"));
+    }
+
+    #[test]
+    fn remove_and_restore() {
+        let path = env::temp_dir().join(format!("diosk-visited-remove-{}.txt", process::id()));
+        let path = path.as_path();
+        fs::write(
+            path,
+            "gemini://a.example/\t3\t100\ngemini://b.example/\t1\t200\n",
+        )
+        .unwrap();
+
+        let mut visited = Visited::new(path);
+        let url = Url::parse("gemini://a.example/").unwrap();
+        let removed = visited.remove("gemini://a.example/").unwrap().unwrap();
+        assert_eq!(
+            removed,
+            Visit {
+                count: 3,
+                last: 100
+            }
+        );
+        assert!(!visited.contains(&url));
+        assert!(!Visited::new(path).contains(&url));
+        assert_eq!(visited.remove("gemini://a.example/").unwrap(), None);
+
+        visited
+            .restore("gemini://a.example/".to_string(), removed)
+            .unwrap();
+        let reloaded = Visited::new(path);
+        let _ = fs::remove_file(path);
+        assert_eq!(reloaded.by_frecency()[0], ("gemini://a.example/", removed));
+    }
+}
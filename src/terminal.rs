@@ -1,15 +1,37 @@
 use std::borrow::Cow;
-use std::io::{stdout, Write};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, stdout, BufWriter, StdoutLock, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 
 use crossterm::cursor;
-use crossterm::style::{Print, SetBackgroundColor as Bg, SetForegroundColor as Fg};
+use crossterm::style::{
+    Attribute, Color, Colors, Print, ResetColor, SetAttribute, SetBackgroundColor as Bg, SetColors,
+    SetForegroundColor as Fg,
+};
 use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
 use crossterm::{ExecutableCommand, QueueableCommand};
+use log::debug;
+use url::Url;
 
-use crate::gemini::gemtext::Line;
+use crate::config::SegmentKind;
+use crate::gemini::{
+    self,
+    gemtext::{self, Line},
+    Trust,
+};
+use crate::state::search::Search;
 use crate::state::{Mode, StatusLineContext};
 
+pub mod clipboard;
+pub mod color_scheme;
 pub mod colors;
+#[cfg(feature = "syntax_highlighting")]
+mod highlight;
+#[cfg(feature = "image_preview")]
+pub mod image;
+
+use color_scheme::ColorScheme;
 
 const LOGO: &str = r#"
      ,ogggggggg,
@@ -26,6 +48,9 @@ const LOGO: &str = r#"
                     :quit<Enter> :q<Enter>
 "#;
 
+/// Columns at the right of the status line kept for echoing pending keys and counts
+const PENDING_KEYS_WIDTH: usize = 6;
+
 #[derive(Debug)]
 struct CursorPosition {
     x: u16,
@@ -34,54 +59,314 @@ struct CursorPosition {
 
 impl CursorPosition {
     fn move_to(&self) -> cursor::MoveTo {
-        cursor::MoveTo(self.x, self.y)
+        move_to(self.x, self.y)
     }
 }
 
-#[derive(Debug)]
+/// How wrapped text is drawn, URLs and search matches are colored over it
+#[derive(Debug, Clone, Copy)]
+struct TextStyle {
+    fg: Color,
+    bold: bool,
+    /// Drawn in prefix_fg before the first row and before the rows it wraps onto, the same
+    /// width as each other
+    prefixes: (&'static str, &'static str),
+    prefix_fg: Color,
+}
+
+/// The bullet of a list item, rows it wraps onto line up under its text
+const BULLET: (&str, &str) = ("• ", "  ");
+
+/// The gutter beside a quote, on every row it wraps onto
+const GUTTER: (&str, &str) = ("┃ ", "┃ ");
+
+/// The rows asked for when drawing inline, 0 when diosk has the alternate screen to itself
+static INLINE_ROWS: AtomicU16 = AtomicU16::new(0);
+
+/// The row of the terminal drawing starts from, only ever below the top when drawing inline
+static TOP: AtomicU16 = AtomicU16::new(0);
+
+/// Move to a position in the rows diosk draws in
+fn move_to(x: u16, y: u16) -> cursor::MoveTo {
+    cursor::MoveTo(x, TOP.load(Ordering::SeqCst) + y)
+}
+
+/// How many of the terminal's rows diosk draws in, all of them unless it's drawing inline
+pub fn drawn_rows(height: u16) -> u16 {
+    match INLINE_ROWS.load(Ordering::SeqCst) {
+        0 => height,
+        rows => rows.min(height),
+    }
+}
+
+/// Keep the rows drawn inline on screen after the terminal has been resized
+pub fn resized(height: u16) {
+    let top = TOP.load(Ordering::SeqCst);
+    TOP.store(top.min(height - drawn_rows(height)), Ordering::SeqCst);
+}
+
+/// The colors of preformatted lines in blocks of a language, keyed by line
+pub type SyntaxSpans = HashMap<usize, Vec<(Range<usize>, Color)>>;
+
+/// What's picked out on a page besides the current line
+#[derive(Default)]
+pub struct Highlights<'a> {
+    /// Which of the URLs detected in the current line is selected
+    pub selected_url: usize,
+    pub search: Option<&'a Search>,
+    /// The syntax colors of the page if they've already been worked out
+    pub syntax: Option<&'a SyntaxSpans>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Terminal {
     width: u16,
     height: u16,
+    /// The columns pages are drawn in, the whole width unless the screen is split
+    left: u16,
+    columns: u16,
+    color_scheme: ColorScheme,
+    /// Schemes of the URLs in plain text lines that are shown like links
+    url_schemes: &'static [&'static str],
+    /// Show URLs with percent-escapes decoded
+    decode_urls: bool,
+    /// Show the URLs of links beside their names
+    show_urls: bool,
+    /// Columns between tab stops
+    tab_width: usize,
+    /// Text is wrapped at the edge of the pane, otherwise lines are cut off there
+    wrap: bool,
+    /// Color preformatted blocks whose alt text names a language
+    highlight_syntax: bool,
+    /// Columns unwrapped text is scrolled to the left by
+    pan: usize,
+    /// Fill rows to the edge of the pane so the current line's background covers it
+    pad: bool,
 }
 
 impl Terminal {
     pub fn new(width: u16, height: u16) -> Self {
-        Self { width, height }
+        Self::with_color_scheme(width, height, ColorScheme::default())
+    }
+
+    pub fn with_color_scheme(width: u16, height: u16, color_scheme: ColorScheme) -> Self {
+        Self {
+            width,
+            height,
+            left: 0,
+            columns: width,
+            color_scheme,
+            url_schemes: &[],
+            decode_urls: false,
+            show_urls: false,
+            tab_width: 8,
+            wrap: true,
+            highlight_syntax: false,
+            pan: 0,
+            pad: true,
+        }
     }
 
-    pub fn render_page(
+    pub fn decode_urls(self, decode_urls: bool) -> Self {
+        Self {
+            decode_urls,
+            ..self
+        }
+    }
+
+    pub fn show_urls(self, show_urls: bool) -> Self {
+        Self { show_urls, ..self }
+    }
+
+    /// Only has an effect with the syntax_highlighting feature
+    pub fn highlight_syntax(self, highlight_syntax: bool) -> Self {
+        Self {
+            highlight_syntax,
+            ..self
+        }
+    }
+
+    /// What the syntax colors of a page depend on besides the page, the tab width while they're
+    /// shown
+    pub fn syntax_settings(&self) -> Option<usize> {
+        Some(self.tab_width).filter(|_| self.highlight_syntax)
+    }
+
+    /// A URL as it's shown, decoded unless the wire form was asked for
+    fn display_url<'u>(&self, url: &'u str) -> Cow<'u, str> {
+        match self.decode_urls {
+            true => crate::human::url(url),
+            false => Cow::Borrowed(url),
+        }
+    }
+
+    pub fn tab_width(self, tab_width: usize) -> Self {
+        Self {
+            tab_width: tab_width.max(1),
+            ..self
+        }
+    }
+
+    pub fn wrap(self, wrap: bool) -> Self {
+        Self { wrap, ..self }
+    }
+
+    /// Scroll text to the left by some columns, when it isn't wrapped
+    pub fn pan(self, pan: usize) -> Self {
+        Self { pan, ..self }
+    }
+
+    /// Text from a page as it's drawn, with control characters sanitized and tabs expanded
+    pub fn display_text<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        self.display_text_at(text, 0)
+    }
+
+    /// The text of a line as it's drawn, a link's name is drawn after the "=> " and any tag
+    pub fn display_line_text<'t>(&self, line: &Line, text: &'t str) -> Cow<'t, str> {
+        match line {
+            Line::Link { url, .. } => self.display_text_at(text, link_column(&link_tag(url))),
+            _ => self.display_text(text),
+        }
+    }
+
+    /// Like `display_text`, for text drawn from a column other than the first so its tabs line
+    /// up with the rest of the screen
+    fn display_text_at<'t>(&self, text: &'t str, column: usize) -> Cow<'t, str> {
+        match sanitize(text) {
+            Cow::Borrowed(text) => expand_tabs(text, self.tab_width, column),
+            Cow::Owned(text) => Cow::Owned(expand_tabs(&text, self.tab_width, column).into_owned()),
+        }
+    }
+
+    /// Show URLs with these schemes in plain text lines like links
+    pub fn detect_urls(self, url_schemes: &'static [&'static str]) -> Self {
+        Self {
+            url_schemes,
+            ..self
+        }
+    }
+
+    /// Draw pages in some of the columns, the status line still takes the whole width
+    pub fn pane(self, left: u16, columns: u16) -> Self {
+        Self {
+            left,
+            columns,
+            ..self
+        }
+    }
+
+    /// Draw the visible part of a page, returning the row the current line is on
+    pub fn render_content(
         &self,
+        out: &mut impl Write,
         current_line_index: usize,
+        highlights: &Highlights,
         content: Vec<Line>,
         scroll_offset: u16,
-        status_line_context: StatusLineContext,
+        is_visited: &dyn Fn(&Url) -> bool,
     ) -> crossterm::Result<u16> {
-        if status_line_context.url.is_none() {
-            self.render_default_page(status_line_context)?;
-            stdout().flush()?;
-            return Ok(0);
+        let (rows, current_row) = self.visible_rows(
+            current_line_index,
+            highlights,
+            content,
+            scroll_offset,
+            is_visited,
+        )?;
+
+        for (y, row) in rows.iter().enumerate() {
+            out.queue(move_to(self.left, y as u16))?;
+            out.write_all(row)?;
+        }
+
+        Ok(current_row.expect("no current row"))
+    }
+
+    /// The visible part of a page as it's drawn, and optionally the status line, as lines of text
+    /// with colors as ANSI escape sequences
+    pub fn hardcopy(
+        &self,
+        current_line_index: usize,
+        highlights: &Highlights,
+        content: Vec<Line>,
+        scroll_offset: u16,
+        is_visited: &dyn Fn(&Url) -> bool,
+        status_line_context: Option<StatusLineContext>,
+    ) -> crossterm::Result<Vec<u8>> {
+        // Only the screen needs the background filled in, saved rows don't end in spaces
+        let unpadded = Self {
+            pad: false,
+            ..self.clone()
+        };
+        let (mut rows, _) = unpadded.visible_rows(
+            current_line_index,
+            highlights,
+            content,
+            scroll_offset,
+            is_visited,
+        )?;
+        if let Some(status_line_context) = status_line_context {
+            rows.push(self.status_row(&status_line_context)?);
         }
 
+        let mut out = Vec::new();
+        for row in rows {
+            out.write_all(&row)?;
+            out.queue(ResetColor)?;
+            out.write_all(b"\n")?;
+        }
+
+        Ok(out)
+    }
+
+    /// The rows of a page that fit on screen at the scroll offset, and the row the current line
+    /// is on
+    fn visible_rows(
+        &self,
+        current_line_index: usize,
+        highlights: &Highlights,
+        content: Vec<Line>,
+        scroll_offset: u16,
+        is_visited: &dyn Fn(&Url) -> bool,
+    ) -> crossterm::Result<(Vec<Vec<u8>>, Option<u16>)> {
         let start_printing_from_row = scroll_offset + 1;
         let mut row = 0;
+        let mut visible = Vec::new();
 
-        let mut cursor_pos = CursorPosition { x: 0, y: 0 };
-
-        // The return value represents the row that the cursor is on, indexed from the top of the
-        // screen
+        // The row that the cursor is on, indexed from the top of the screen
         let mut current_row = None;
 
+        let worked_out;
+        let syntax = match highlights.syntax {
+            Some(syntax) => syntax,
+            None => {
+                worked_out = self.syntax_spans(&content);
+                &worked_out
+            }
+        };
+
         for (i, line) in content.iter().enumerate() {
             let is_active = current_line_index == i;
+            let is_visited = match line {
+                Line::Link {
+                    target: Some(target),
+                    ..
+                } => is_visited(target),
+                _ => false,
+            };
 
-            let rows = self.render_line(line, is_active)?;
+            let selected_url = Some(highlights.selected_url).filter(|_| is_active);
+            let matches = highlights
+                .search
+                .map(|search| search.on_line(i))
+                .unwrap_or_default();
+            let syntax = syntax.get(&i).map_or(&[][..], Vec::as_slice);
+            let rows =
+                self.render_line(line, is_active, is_visited, selected_url, &matches, syntax)?;
             for row_buffer in rows {
                 row += 1;
 
                 // Don't print before we're in view
                 if row < start_printing_from_row {
-                    // Reset the cursor position because we haven't drawn anything to the screen yet
-                    cursor_pos.y = 0;
                     continue;
                 }
 
@@ -91,172 +376,898 @@ impl Terminal {
                 }
 
                 // If we're going to overflow the screen, stop printing
-                if cursor_pos.y >= self.page_rows() {
+                if visible.len() >= self.page_rows() as usize {
                     break;
                 }
 
-                stdout().queue(&cursor_pos.move_to())?;
-                stdout().write_all(&row_buffer).unwrap();
-
-                cursor_pos.x = 0;
-                cursor_pos.y += 1;
+                visible.push(row_buffer);
             }
         }
 
-        self.draw_status_line(status_line_context);
+        Ok((visible, current_row))
+    }
 
-        stdout().flush()?;
+    /// The colors of preformatted lines in blocks of a language, keyed by line
+    #[cfg_attr(not(feature = "syntax_highlighting"), allow(unused_variables))]
+    pub fn syntax_spans(&self, content: &[Line]) -> SyntaxSpans {
+        match self.highlight_syntax {
+            #[cfg(feature = "syntax_highlighting")]
+            true => highlight::spans(content, |text| self.display_text(text)),
+            _ => HashMap::new(),
+        }
+    }
 
-        Ok(current_row.expect("no current row"))
+    /// Draw an image in place of the page content
+    #[cfg(feature = "image_preview")]
+    pub fn render_image(
+        &self,
+        out: &mut impl Write,
+        image: &::image::DynamicImage,
+        protocol: image::Protocol,
+    ) -> crossterm::Result<()> {
+        out.queue(move_to(self.left, 0))?;
+        image::write(out, protocol, image, self.columns, self.page_rows())?;
+
+        Ok(())
     }
 
-    fn render_default_page(&self, status_line_context: StatusLineContext) -> crossterm::Result<()> {
+    pub fn render_default_page(&self, out: &mut impl Write) -> crossterm::Result<()> {
         let logo_height: u16 = LOGO.lines().count() as _;
         let logo_width: u16 = LOGO.lines().map(|l| l.len()).max().expect("infallible") as _;
 
-        let x = (self.width / 2) - (logo_width / 2);
-        let y = (self.page_rows() / 2) - (logo_height / 2);
-
         // Move logo to the left slightly as its asymmetrical
-        let x = x - 6;
+        let x = (self.columns / 2).saturating_sub(logo_width / 2 + 6);
+        let y = (self.page_rows() / 2).saturating_sub(logo_height / 2);
 
-        let mut cursor_pos = CursorPosition { x, y };
+        let mut cursor_pos = CursorPosition {
+            x: self.left + x,
+            y,
+        };
 
         for line in LOGO.lines() {
-            print!("{}{}", cursor::MoveTo(cursor_pos.x, cursor_pos.y), line);
+            let line: String = line.chars().take((self.columns - x) as usize).collect();
+            out.queue(cursor_pos.move_to())?.queue(Print(line))?;
             cursor_pos.y += 1;
         }
 
-        self.draw_status_line(status_line_context);
+        Ok(())
+    }
 
-        stdout().flush()?;
+    /// Draw the line between split panes, to the left of the pane on the right
+    pub fn render_separator(&self, out: &mut impl Write) -> crossterm::Result<()> {
+        let (left, _) = split_columns(self.width);
+        let colors = &self.color_scheme;
+
+        for y in 0..self.page_rows() {
+            out.queue(move_to(left, y))?
+                .queue(Fg(colors.link_prefix))?
+                .queue(Bg(colors.background))?
+                .queue(Print('│'))?;
+        }
 
         Ok(())
     }
 
-    fn render_line(&self, line: &Line, is_active: bool) -> crossterm::Result<Vec<Vec<u8>>> {
+    /// Queue the status line to be drawn with the rest of the frame
+    pub fn render_status_line(
+        &self,
+        out: &mut impl Write,
+        status_line_context: StatusLineContext,
+    ) -> crossterm::Result<()> {
+        self.draw_status_line(out, &status_line_context)
+    }
+
+    fn render_line(
+        &self,
+        line: &Line,
+        is_active: bool,
+        is_visited: bool,
+        selected_url: Option<usize>,
+        matches: &[(Range<usize>, bool)],
+        syntax: &[(Range<usize>, Color)],
+    ) -> crossterm::Result<Vec<Vec<u8>>> {
         let mut rows = Vec::new();
+        let colors = &self.color_scheme;
 
         // Highlight the current line
         let bg_color = if is_active {
-            Bg(colors::REGENT_GREY)
+            Bg(colors.active_line_bg)
         } else {
-            Bg(colors::BACKGROUND)
+            Bg(colors.background)
         };
 
         match line {
             Line::Normal(content) => {
-                for mut part in textwrap::wrap(&content, self.width as usize) {
-                    // If we've got a blank line, render a space so we can
-                    // see it when it's highlighted
-                    if content.is_empty() {
-                        part = Cow::from(" ");
-                    }
+                let style = TextStyle {
+                    fg: colors.foreground,
+                    bold: false,
+                    prefixes: ("", ""),
+                    prefix_fg: colors.foreground,
+                };
+                rows = self.text_rows(content, style, bg_color, selected_url, matches)?;
+            }
+            Line::Heading { level, text } => {
+                let fg = match level {
+                    1 => colors.heading_1,
+                    2 => colors.heading_2,
+                    _ => colors.heading_3,
+                };
+                let style = TextStyle {
+                    fg,
+                    bold: true,
+                    prefixes: ("", ""),
+                    prefix_fg: fg,
+                };
+                rows = self.text_rows(text, style, bg_color, selected_url, matches)?;
+            }
+            Line::ListItem(text) => {
+                let style = TextStyle {
+                    fg: colors.foreground,
+                    bold: false,
+                    prefixes: BULLET,
+                    prefix_fg: colors.list_bullet,
+                };
+                rows = self.text_rows(text, style, bg_color, selected_url, matches)?;
+            }
+            Line::Quote(text) => {
+                let style = TextStyle {
+                    fg: colors.quote,
+                    bold: false,
+                    prefixes: GUTTER,
+                    prefix_fg: colors.quote_gutter,
+                };
+                rows = self.text_rows(text, style, bg_color, selected_url, matches)?;
+            }
+            Line::Link { url, name, .. } => {
+                // Links get a more assertive highlight than other lines so it's clear that Enter
+                // will navigate
+                let (bg_color, prefix_color, name_color, url_color) = if is_active {
+                    (
+                        Bg(colors.active_link_bg),
+                        Fg(colors.active_link_fg),
+                        Fg(colors.active_link_fg),
+                        Fg(colors.active_link_fg),
+                    )
+                } else if is_visited {
+                    (
+                        Bg(colors.background),
+                        Fg(colors.visited_link),
+                        Fg(colors.visited_link),
+                        Fg(colors.link_url),
+                    )
+                } else {
+                    (
+                        Bg(colors.background),
+                        Fg(colors.link_prefix),
+                        Fg(colors.foreground),
+                        Fg(colors.link_url),
+                    )
+                };
+
+                let tag_color = match is_active {
+                    true => prefix_color,
+                    false => Fg(colors.foreign_link),
+                };
+                let (tag, name, url) = self.link_text(url, name.as_deref());
+
+                if !self.wrap {
+                    // Unwrapped links are cut short to stay in their pane
+                    let width = (self.columns as usize)
+                        .saturating_sub(3 + textwrap::core::display_width(&tag));
+                    let name = truncate(&name, width);
+                    let url = truncate(
+                        &url,
+                        width.saturating_sub(textwrap::core::display_width(&name)),
+                    );
 
                     let mut row = Vec::new();
-                    row.queue(Fg(colors::FOREGROUND))?
-                        .queue(bg_color)?
-                        .queue(Print(part))?;
+                    row.queue(bg_color)?
+                        .queue(prefix_color)?
+                        .queue(Print("=> "))?
+                        .queue(tag_color)?
+                        .queue(Print(&tag))?;
+                    let matches = shift_spans(matches, 0, name.len());
+                    self.print_spans(
+                        &mut row,
+                        &name,
+                        &[],
+                        &matches,
+                        &[],
+                        (name_color.0, bg_color.0),
+                    )?;
+                    row.queue(url_color)?.queue(Print(&url))?;
+                    let width = 3
+                        + textwrap::core::display_width(&tag)
+                        + textwrap::core::display_width(&name)
+                        + textwrap::core::display_width(&url);
+                    self.pad(&mut row, width, bg_color)?;
                     rows.push(row);
+                } else {
+                    // Each part keeps its color across the rows it's wrapped onto, which line up
+                    // under the text after the =>
+                    let text = format!("{}{}{}", tag, name, url);
+                    // Matches are found in the name, which comes after the tag
+                    let matches: Vec<_> = matches
+                        .iter()
+                        .map(|(m, current)| (m.start + tag.len()..m.end + tag.len(), *current))
+                        .collect();
+                    let segments = [
+                        (0..tag.len(), tag_color),
+                        (tag.len()..tag.len() + name.len(), name_color),
+                        (tag.len() + name.len()..text.len(), url_color),
+                    ];
+                    let columns = (self.columns as usize).saturating_sub(3).max(1);
+                    for (i, (start, part)) in wrap_parts(&text, columns).into_iter().enumerate() {
+                        let mut row = Vec::new();
+                        row.queue(bg_color)?
+                            .queue(prefix_color)?
+                            .queue(Print(if i == 0 { "=> " } else { "   " }))?;
+
+                        let end = start + part.len();
+                        for (segment, color) in &segments {
+                            let from = segment.start.max(start);
+                            let to = segment.end.min(end);
+                            if from < to {
+                                let matches = shift_spans(&matches, from, to - from);
+                                self.print_spans(
+                                    &mut row,
+                                    &part[from - start..to - start],
+                                    &[],
+                                    &matches,
+                                    &[],
+                                    (color.0, bg_color.0),
+                                )?;
+                            }
+                        }
+                        self.pad(&mut row, 3 + textwrap::core::display_width(&part), bg_color)?;
+                        rows.push(row);
+                    }
                 }
             }
-            Line::Link { url, name } => {
-                // TODO: Handle wrapping
-
+            Line::InvalidLink => {
                 let mut row = Vec::new();
                 row.queue(bg_color)?
-                    .queue(Fg(colors::MANTIS))?
+                    .queue(Fg(colors.link_prefix))?
                     .queue(Print("=> "))?
-                    .queue(Fg(colors::FOREGROUND))?
-                    .queue(Print(name.as_ref().unwrap_or(url)))?
-                    .queue(Fg(colors::REGENT_GREY))?
-                    .queue(Print(" "))?
-                    .queue(Print(url))?; // TODO: Hide if we don't have a name because the URL is already being displayed
+                    .queue(Fg(colors.invalid_link))?
+                    .queue(Print("[INVALID LINK]"))?;
+                self.pad(&mut row, 17, bg_color)?;
                 rows.push(row);
             }
-            Line::InvalidLink => {
+            Line::PreformatStart { alt, collapsed } => {
+                let alt = alt.as_deref().unwrap_or("Preformatted text");
+                let caption = match collapsed {
+                    Some(1) => format!("▸ {} (1 line hidden)", alt),
+                    Some(lines) => format!("▸ {} ({} lines hidden)", alt, lines),
+                    None => format!("▾ {}", alt),
+                };
+
+                let caption = truncate(&self.display_text(&caption), self.columns as usize);
+
                 let mut row = Vec::new();
                 row.queue(bg_color)?
-                    .queue(Fg(colors::MANTIS))?
-                    .queue(Print("=> "))?
-                    .queue(Fg(colors::OLD_BRICK))?
-                    .queue(Print("[INVALID LINK]"))?;
+                    .queue(Fg(colors.caption))?
+                    .queue(SetAttribute(Attribute::Italic))?
+                    .queue(Print(&caption))?
+                    .queue(SetAttribute(Attribute::NoItalic))?;
+                self.pad(&mut row, textwrap::core::display_width(&caption), bg_color)?;
+                rows.push(row);
+            }
+            // Preformatted text isn't wrapped, it's cut short to stay in its pane
+            Line::Preformatted { text, hidden } if !hidden => {
+                let text = match text.is_empty() {
+                    true => Cow::from(" "),
+                    false => self.display_text(text),
+                };
+                let (start, text) = match self.wrap {
+                    true => (0, truncate(&text, self.columns as usize)),
+                    false => {
+                        let range = pan_range(&text, self.pan, self.columns as usize);
+                        (range.start, text[range].to_string())
+                    }
+                };
+
+                let mut row = Vec::new();
+                row.queue(bg_color)?.queue(Fg(colors.foreground))?;
+                let matches = shift_spans(matches, start, text.len());
+                let syntax = shift_spans(syntax, start, text.len());
+                self.print_spans(
+                    &mut row,
+                    &text,
+                    &[],
+                    &matches,
+                    &syntax,
+                    (colors.foreground, bg_color.0),
+                )?;
+                self.pad(&mut row, textwrap::core::display_width(&text), bg_color)?;
+                rows.push(row);
+            }
+            Line::PreformatEnd { hidden } if !hidden => {
+                let mut row = Vec::new();
+                row.queue(bg_color)?.queue(Print(" "))?;
+                self.pad(&mut row, 1, bg_color)?;
                 rows.push(row);
             }
+            // Hidden in a collapsed block
+            Line::Preformatted { .. } | Line::PreformatEnd { .. } => {}
         }
 
         Ok(rows)
     }
 
-    fn draw_status_line(&self, status_line_context: StatusLineContext) {
+    /// Text wrapped to the pane or panned, with the URLs detected in it and search matches
+    /// highlighted
+    fn text_rows(
+        &self,
+        content: &str,
+        style: TextStyle,
+        bg_color: Bg,
+        selected_url: Option<usize>,
+        matches: &[(Range<usize>, bool)],
+    ) -> crossterm::Result<Vec<Vec<u8>>> {
+        let colors = &self.color_scheme;
+        let mut rows = Vec::new();
+
+        let content = self.display_text(content);
+        // Found in the whole line, a URL can be wrapped over rows
+        let urls: Vec<_> = gemtext::find_urls(&content, self.url_schemes)
+            .into_iter()
+            .enumerate()
+            .map(|(i, url)| match selected_url == Some(i) {
+                true => (url, (colors.active_link_fg, colors.active_link_bg)),
+                false => (url, (colors.link_url, bg_color.0)),
+            })
+            .collect();
+        let prefix_width = textwrap::core::display_width(style.prefixes.0);
+        let columns = (self.columns as usize).saturating_sub(prefix_width).max(1);
+
+        // Each row and where it starts in content, matches are found in all of it
+        let parts = match self.wrap {
+            true => wrap_parts(&content, columns),
+            false => {
+                let range = pan_range(&content, self.pan, columns);
+                vec![(range.start, Cow::from(&content[range]))]
+            }
+        };
+
+        for (i, (start, part)) in parts.into_iter().enumerate() {
+            let prefix = match i {
+                0 => style.prefixes.0,
+                _ => style.prefixes.1,
+            };
+            let mut row = Vec::new();
+            row.queue(bg_color)?
+                .queue(Fg(style.prefix_fg))?
+                .queue(Print(prefix))?
+                .queue(Fg(style.fg))?;
+            if style.bold {
+                row.queue(SetAttribute(Attribute::Bold))?;
+            }
+
+            let urls: Vec<_> = shift_spans(&urls, start, part.len())
+                .into_iter()
+                .map(|(url, (fg, bg))| (url, fg, bg))
+                .collect();
+            let matches = shift_spans(matches, start, part.len());
+            self.print_spans(
+                &mut row,
+                &part,
+                &urls,
+                &matches,
+                &[],
+                (style.fg, bg_color.0),
+            )?;
+            if style.bold {
+                row.queue(SetAttribute(Attribute::NormalIntensity))?;
+            }
+            self.pad(
+                &mut row,
+                prefix_width + textwrap::core::display_width(&part),
+                bg_color,
+            )?;
+            rows.push(row);
+        }
+
+        Ok(rows)
+    }
+
+    /// Fill the rest of a row after text as wide as width, so the current line is highlighted
+    /// across the whole pane, wrapped or not
+    fn pad(&self, row: &mut Vec<u8>, width: usize, bg: Bg) -> crossterm::Result<()> {
+        if self.pad {
+            let padding = (self.columns as usize).saturating_sub(width);
+            row.queue(bg)?.queue(Print(" ".repeat(padding)))?;
+        }
+        Ok(())
+    }
+
+    /// Print text in its syntax colors with the URLs detected in it underlined in their colors,
+    /// and search matches on top of those
+    fn print_spans(
+        &self,
+        row: &mut Vec<u8>,
+        text: &str,
+        urls: &[(Range<usize>, Color, Color)],
+        matches: &[(Range<usize>, bool)],
+        syntax: &[(Range<usize>, Color)],
+        (fg, bg): (Color, Color),
+    ) -> crossterm::Result<()> {
+        let colors = &self.color_scheme;
+
+        // The text is printed in pieces between wherever a URL, match or color starts or ends
+        let mut breaks: BTreeSet<usize> = urls
+            .iter()
+            .map(|(url, _, _)| url)
+            .chain(matches.iter().map(|(m, _)| m))
+            .chain(syntax.iter().map(|(s, _)| s))
+            .flat_map(|range| vec![range.start, range.end])
+            .filter(|&i| text.is_char_boundary(i))
+            .collect();
+        breaks.insert(text.len());
+
+        let mut printed = 0;
+        for end in breaks {
+            if end == printed {
+                continue;
+            }
+
+            let fg = syntax
+                .iter()
+                .find(|(s, _)| s.contains(&printed))
+                .map_or(fg, |&(_, color)| color);
+            let url = urls.iter().find(|(url, _, _)| url.contains(&printed));
+            let (mut fg, mut bg) = url.map_or((fg, bg), |&(_, fg, bg)| (fg, bg));
+            match matches.iter().find(|(m, _)| m.contains(&printed)) {
+                Some((_, true)) => {
+                    fg = colors.current_match_fg;
+                    bg = colors.current_match_bg;
+                }
+                Some((_, false)) => bg = colors.search_match_bg,
+                None => {}
+            }
+
+            row.queue(Fg(fg))?.queue(Bg(bg))?;
+            match url {
+                Some(_) => row
+                    .queue(SetAttribute(Attribute::Underlined))?
+                    .queue(Print(&text[printed..end]))?
+                    .queue(SetAttribute(Attribute::NoUnderline))?,
+                None => row.queue(Print(&text[printed..end]))?,
+            };
+            printed = end;
+        }
+
+        Ok(())
+    }
+
+    /// Queue the status line and the prompt below it, nothing is flushed
+    fn draw_status_line(
+        &self,
+        out: &mut impl Write,
+        status_line_context: &StatusLineContext,
+    ) -> crossterm::Result<()> {
+        let colors = &self.color_scheme;
+        let text_colors = Colors::new(colors.foreground, colors.background);
+
+        out.queue(move_to(0, self.height - 2))?;
+        out.write_all(&self.status_row(status_line_context)?)?;
+
+        if let Some(prompt) = status_line_context.confirmation {
+            out.queue(move_to(0, self.height - 1))?
+                .queue(SetColors(text_colors))?
+                .queue(Print(sanitize(prompt)))?;
+        }
+
+        if matches!(status_line_context.mode, Mode::Input | Mode::Search) {
+            let c = if matches!(status_line_context.mode, Mode::Input) {
+                ':'
+            } else {
+                '/'
+            };
+
+            // The cursor is a block after the input
+            out.queue(move_to(0, self.height - 1))?
+                .queue(SetColors(text_colors))?
+                .queue(Print(c))?
+                .queue(Print(sanitize(status_line_context.input)))?
+                .queue(SetColors(Colors::new(colors.foreground, colors.foreground)))?
+                .queue(Print(' '))?
+                .queue(Bg(colors.background))?;
+        }
+
+        Ok(())
+    }
+
+    /// The status line row as it's drawn, the full width of the terminal
+    fn status_row(&self, status_line_context: &StatusLineContext) -> io::Result<Vec<u8>> {
+        let colors = &self.color_scheme;
+        let mut row = Vec::new();
+
         if status_line_context.loading {
-            let cursor_pos = cursor::MoveTo(0, self.height - 2);
-            print!(
-                "{cursor_pos}{fg_1}{bg_1} Loading... {fg_2}{bg_2}",
-                cursor_pos = cursor_pos,
-                fg_1 = Fg(colors::GREEN_SMOKE),
-                bg_1 = Bg(colors::COSTA_DEL_SOL),
-                fg_2 = Fg(colors::FOREGROUND),
-                bg_2 = Bg(colors::BACKGROUND),
-            );
+            let mut loading = match status_line_context.retrying {
+                Some((attempt, attempts)) => format!(" Retrying ({}/{})… ", attempt, attempts),
+                None => " Loading... ".to_string(),
+            };
+            if let Some(seconds) = status_line_context.deadline {
+                loading.push_str(&format!("{}s left ", seconds));
+            }
+            if status_line_context.pending_requests > 1 {
+                loading.push_str(&format!(
+                    "({} pending) ",
+                    status_line_context.pending_requests
+                ));
+            }
+            write!(
+                row,
+                "{fg_1}{bg_1}{loading}{fg_2}{bg_2}{:width$}",
+                "",
+                loading = loading,
+                fg_1 = Fg(colors.status_fg),
+                bg_1 = Bg(colors.status_bg),
+                fg_2 = Fg(colors.foreground),
+                bg_2 = Bg(colors.background),
+                width = (self.width as usize)
+                    .saturating_sub(textwrap::core::display_width(&loading) + PENDING_KEYS_WIDTH),
+            )?;
+        } else if !status_line_context.segments.is_empty() {
+            self.write_status_segments(&mut row, status_line_context)?;
         } else {
-            let cursor_pos = cursor::MoveTo(0, self.height - 2);
-            let status_code = status_line_context
+            let mut status_code = status_line_context
                 .status_code
                 .map(|s| s.code())
                 .unwrap_or_else(|| "--".to_string());
+            if let Some(seconds) = status_line_context.auto_reload {
+                status_code = format!("{} {}", status_code, auto_reload_indicator(seconds));
+            }
+            if status_line_context.offline {
+                status_code = format!("{} {}", status_code, OFFLINE_INDICATOR);
+            }
+            if let Some(position) = status_line_context.search {
+                status_code = format!("{} {}", status_code, search_indicator(position));
+            }
+            if let Some(column) = status_line_context.column {
+                status_code = format!("{} {}", status_code, column_indicator(column));
+            }
+            if let Some(charset) = status_line_context.detected_charset {
+                status_code = format!("{} {}", status_code, encoding_indicator(charset));
+            }
 
             let (fg_1, bg_1, message) =
                 if let Some(error_message) = status_line_context.error_message {
-                    (Fg(colors::TEMPTRESS), Bg(colors::OLD_BRICK), error_message)
+                    (
+                        Fg(colors.error_fg),
+                        Bg(colors.error_bg),
+                        Cow::Borrowed(error_message),
+                    )
+                } else if let Some(message) = status_line_context.message {
+                    (
+                        Fg(colors.status_fg),
+                        Bg(colors.status_bg),
+                        Cow::Borrowed(message),
+                    )
                 } else {
-                    let url = status_line_context
-                        .url
-                        .map(|u| u.to_string())
-                        .unwrap_or_else(|| "-".to_string());
-                    (Fg(colors::GREEN_SMOKE), Bg(colors::COSTA_DEL_SOL), url)
+                    (
+                        Fg(colors.status_fg),
+                        Bg(colors.status_bg),
+                        self.status_url(status_line_context),
+                    )
                 };
+            let favicon = favicon_prefix(status_line_context);
+            let trust_width = status_line_context.trust.map_or(0, |trust| {
+                textwrap::core::display_width(trust_indicator(trust)) + 1
+            });
+            let width = (self.width as usize).saturating_sub(
+                textwrap::core::display_width(&status_code)
+                    + trust_width
+                    + 3
+                    + favicon_width(&favicon)
+                    + PENDING_KEYS_WIDTH,
+            );
+
+            // Goes in front of the status code, a certificate accepted in place of its pin stands
+            // out in the error colors
+            let trust = match status_line_context.trust {
+                Some(Trust::Accepted) => format!(
+                    "{}{} {}",
+                    Fg(colors.error_fg),
+                    Bg(colors.error_bg),
+                    trust_indicator(Trust::Accepted)
+                ),
+                Some(trust) => format!("{}{} {}", fg_1, bg_1, trust_indicator(trust)),
+                None => String::new(),
+            };
 
-            print!(
-                "{cursor_pos}{fg_1}{bg_1} {status_code} {fg_2}{bg_2} {message:width$}",
-                cursor_pos = cursor_pos,
+            // Padded by hand as the formatter counts characters rather than columns
+            let message = truncate(&sanitize(&message), width);
+            let padding = width.saturating_sub(textwrap::core::display_width(&message));
+            write!(
+                row,
+                "{trust}{fg_1}{bg_1} {status_code} {fg_2}{bg_2} {favicon}{message}{:padding$}",
+                "",
+                trust = trust,
                 fg_1 = fg_1,
                 bg_1 = bg_1,
-                fg_2 = Fg(colors::FOREGROUND),
-                bg_2 = Bg(colors::BACKGROUND),
+                fg_2 = Fg(colors.foreground),
+                bg_2 = Bg(colors.background),
                 status_code = status_code,
+                favicon = favicon,
                 message = message,
-                width = self.width as usize - 5
-            );
+                padding = padding,
+            )?;
         }
 
-        if matches!(status_line_context.mode, Mode::Input | Mode::Search) {
-            let cursor_pos = cursor::MoveTo(0, self.height - 1);
-            let cursor_color = colors::FOREGROUND;
+        // Echo an unfinished key sequence in the space reserved for it
+        let pending_keys = &status_line_context.pending_keys;
+        let skip = pending_keys
+            .chars()
+            .count()
+            .saturating_sub(PENDING_KEYS_WIDTH);
+        write!(
+            row,
+            "{fg}{bg}{keys:>width$}",
+            fg = Fg(colors.foreground),
+            bg = Bg(colors.background),
+            keys = pending_keys.chars().skip(skip).collect::<String>(),
+            width = PENDING_KEYS_WIDTH,
+        )?;
 
-            let c = if matches!(status_line_context.mode, Mode::Input) {
-                ':'
-            } else {
-                '/'
-            };
+        Ok(row)
+    }
 
-            print!(
-                "{cursor_pos}{fg_1}{bg_1}{c}{input}{fg_2}{bg_2} {bg_3}",
-                cursor_pos = cursor_pos,
-                fg_1 = Fg(colors::FOREGROUND),
-                bg_1 = Bg(colors::BACKGROUND),
-                c = c,
-                bg_2 = Bg(cursor_color),
-                fg_2 = Fg(cursor_color),
-                bg_3 = Bg(colors::BACKGROUND),
-                input = status_line_context.input,
-            );
+    /// The URL of the page, or where the current line leads when it's a link whose URL isn't
+    /// shown
+    fn status_url<'u>(&self, status_line_context: &StatusLineContext<'u>) -> Cow<'u, str> {
+        match status_line_context.link {
+            Some(link) => Cow::Owned(format!("→ {}", self.display_url(link.as_str()))),
+            None => status_line_context
+                .url
+                .map_or(Cow::Borrowed("-"), |u| self.display_url(u.as_str())),
+        }
+    }
+
+    /// Write the configured status line segments, the URL is truncated and spacers are expanded
+    /// to fill the width of the terminal
+    fn write_status_segments(
+        &self,
+        out: &mut impl Write,
+        status_line_context: &StatusLineContext,
+    ) -> io::Result<()> {
+        let colors = &self.color_scheme;
+        let width = (self.width as usize).saturating_sub(PENDING_KEYS_WIDTH);
+
+        let default_colors = |kind| match kind {
+            SegmentKind::Mode
+            | SegmentKind::StatusCode
+            | SegmentKind::Position
+            | SegmentKind::AutoReload
+            | SegmentKind::Offline
+            | SegmentKind::Search
+            | SegmentKind::Lang
+            | SegmentKind::Encoding
+            | SegmentKind::Column
+            | SegmentKind::Trust
+            | SegmentKind::Clock => (colors.status_fg, colors.status_bg),
+            SegmentKind::Url | SegmentKind::Spacer => (colors.foreground, colors.background),
+        };
+
+        let mut pieces: Vec<(SegmentKind, String, (Color, Color))> = status_line_context
+            .segments
+            .iter()
+            .map(|segment| {
+                let mut segment_colors = segment
+                    .colors
+                    .unwrap_or_else(|| default_colors(segment.kind));
+                let text = match segment.kind {
+                    SegmentKind::Mode => format!(" {} ", mode_name(status_line_context.mode)),
+                    SegmentKind::StatusCode => format!(
+                        " {} ",
+                        status_line_context
+                            .status_code
+                            .map(|s| s.code())
+                            .unwrap_or_else(|| "--".to_string())
+                    ),
+                    SegmentKind::Url => {
+                        if let Some(error_message) = status_line_context.error_message {
+                            segment_colors = (colors.error_fg, colors.error_bg);
+                            format!(" {} ", error_message)
+                        } else if let Some(message) = status_line_context.message {
+                            format!(" {} ", message)
+                        } else {
+                            format!(" {} ", self.status_url(status_line_context))
+                        }
+                    }
+                    SegmentKind::Spacer => String::new(),
+                    SegmentKind::AutoReload => status_line_context
+                        .auto_reload
+                        .map(|seconds| format!(" {} ", auto_reload_indicator(seconds)))
+                        .unwrap_or_default(),
+                    SegmentKind::Offline => match status_line_context.offline {
+                        true => format!(" {} ", OFFLINE_INDICATOR),
+                        false => String::new(),
+                    },
+                    SegmentKind::Search => status_line_context
+                        .search
+                        .map(|position| format!(" {} ", search_indicator(position)))
+                        .unwrap_or_default(),
+                    SegmentKind::Column => status_line_context
+                        .column
+                        .map(|column| format!(" {} ", column_indicator(column)))
+                        .unwrap_or_default(),
+                    SegmentKind::Trust => match status_line_context.trust {
+                        Some(trust) => {
+                            if trust == Trust::Accepted && segment.colors.is_none() {
+                                segment_colors = (colors.error_fg, colors.error_bg);
+                            }
+                            format!(" {} ", trust_indicator(trust))
+                        }
+                        None => String::new(),
+                    },
+                    SegmentKind::Encoding => status_line_context
+                        .detected_charset
+                        .map(|charset| format!(" {} ", encoding_indicator(charset)))
+                        .unwrap_or_default(),
+                    SegmentKind::Lang => {
+                        let languages: Vec<_> = status_line_context
+                            .lang
+                            .into_iter()
+                            .flat_map(gemini::languages)
+                            .collect();
+                        match languages.is_empty() {
+                            true => String::new(),
+                            false => format!(" {} ", languages.join(",")),
+                        }
+                    }
+                    SegmentKind::Clock => status_line_context
+                        .clock
+                        .map(|(hour, minute)| format!(" {:02}:{:02} ", hour, minute))
+                        .unwrap_or_default(),
+                    SegmentKind::Position => format!(
+                        " {}/{} {}% ",
+                        status_line_context.line_index + 1,
+                        status_line_context.line_count,
+                        (status_line_context.line_index + 1) * 100
+                            / status_line_context.line_count.max(1)
+                    ),
+                };
+                (segment.kind, sanitize(&text).into_owned(), segment_colors)
+            })
+            .collect();
+
+        // Truncate the URL to whatever space the fixed width segments leave
+        let fixed_width: usize = pieces
+            .iter()
+            .filter(|(kind, _, _)| *kind != SegmentKind::Url)
+            .map(|(_, text, _)| textwrap::core::display_width(text))
+            .sum();
+        let favicon = favicon_prefix(status_line_context);
+        let mut remaining = width.saturating_sub(fixed_width);
+        for (kind, text, _) in pieces.iter_mut() {
+            if *kind == SegmentKind::Url {
+                // The favicon goes inside the segment's leading space, when there's room for it
+                let favicon_width = match favicon_width(&favicon) {
+                    width if width < remaining => width,
+                    _ => 0,
+                };
+                *text = truncate(text, remaining - favicon_width);
+                remaining -= textwrap::core::display_width(text) + favicon_width;
+                if favicon_width > 0 {
+                    let at = usize::from(text.starts_with(' '));
+                    text.insert_str(at, &favicon);
+                }
+            }
+        }
+
+        // Give the left over space to the first spacer
+        if let Some((_, text, _)) = pieces
+            .iter_mut()
+            .find(|(kind, _, _)| *kind == SegmentKind::Spacer)
+        {
+            *text = " ".repeat(remaining);
+            remaining = 0;
+        }
+
+        for (_, text, (fg, bg)) in pieces {
+            write!(out, "{}{}{}", Fg(fg), Bg(bg), truncate(&text, width))?;
         }
+        write!(
+            out,
+            "{}{}{:width$}",
+            Fg(colors.foreground),
+            Bg(colors.background),
+            "",
+            width = remaining
+        )
     }
 
     /// The number of rows a line takes up when wrapped
     pub fn line_wrapped_rows(&self, line: &str) -> u16 {
-        textwrap::wrap(line, self.width as usize).len() as _
+        textwrap::wrap(&self.display_text(line), self.columns as usize).len() as _
+    }
+
+    /// The number of rows a line takes up once wrapped
+    pub fn line_rows(&self, line: &Line) -> usize {
+        match line {
+            Line::Normal(_) | Line::Heading { .. } | Line::ListItem(_) | Line::Quote(_)
+                if !self.wrap =>
+            {
+                1
+            }
+            Line::Normal(content) | Line::Heading { text: content, .. } => {
+                self.wrapped_rows(content, "")
+            }
+            Line::ListItem(content) => self.wrapped_rows(content, BULLET.0),
+            Line::Link { url, name, .. } if self.wrap => {
+                let (tag, name, url) = self.link_text(url, name.as_deref());
+                self.wrapped_rows(&format!("{}{}{}", tag, name, url), "=> ")
+            }
+            Line::Quote(content) => self.wrapped_rows(content, GUTTER.0),
+            line if line.is_hidden() => 0,
+            Line::Link { .. }
+            | Line::InvalidLink
+            | Line::PreformatStart { .. }
+            | Line::Preformatted { .. }
+            | Line::PreformatEnd { .. } => 1,
+        }
+    }
+
+    /// The tag, name and URL drawn for a link, the URL with a space before it
+    ///
+    /// Links elsewhere than Gemini space are tagged with their scheme. Links without names show
+    /// their URL in its place, and those with names only show it as well with show_urls.
+    fn link_text(&self, url: &str, name: Option<&str>) -> (String, String, String) {
+        let tag = link_tag(url);
+        let column = link_column(&tag);
+        match name {
+            Some(name) => {
+                let name = self.display_text_at(name, column).into_owned();
+                let url = match self.show_urls {
+                    true => {
+                        let column = column + textwrap::core::display_width(&name) + 1;
+                        format!(" {}", self.display_text_at(&self.display_url(url), column))
+                    }
+                    false => String::new(),
+                };
+                (tag, name, url)
+            }
+            None => {
+                let url = self
+                    .display_text_at(&self.display_url(url), column)
+                    .into_owned();
+                (tag, url, String::new())
+            }
+        }
+    }
+
+    /// The rows text takes up wrapped beside a prefix, at least one even when it's empty
+    fn wrapped_rows(&self, content: &str, prefix: &str) -> usize {
+        let prefix_width = textwrap::core::display_width(prefix);
+        let columns = (self.columns as usize).saturating_sub(prefix_width).max(1);
+        textwrap::wrap(&self.display_text(content), columns)
+            .len()
+            .max(1)
+    }
+
+    /// The columns the text of a line would take up if it weren't cut off, for panning
+    pub fn line_width(&self, line: &Line) -> usize {
+        match line {
+            Line::Normal(text) | Line::Heading { text, .. } | Line::Preformatted { text, .. } => {
+                textwrap::core::display_width(&self.display_text(text))
+            }
+            Line::ListItem(text) => {
+                textwrap::core::display_width(BULLET.0)
+                    + textwrap::core::display_width(&self.display_text(text))
+            }
+            Line::Quote(text) => {
+                textwrap::core::display_width(GUTTER.0)
+                    + textwrap::core::display_width(&self.display_text(text))
+            }
+            _ => 0,
+        }
+    }
+
+    /// The columns unwrapped lines are cut off at
+    pub fn columns(&self) -> usize {
+        self.columns as usize
     }
 
     pub fn page_rows(&self) -> u16 {
@@ -265,17 +1276,368 @@ impl Terminal {
     }
 }
 
+fn mode_name(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Normal => "NORMAL",
+        Mode::Input => "INPUT",
+        Mode::Search => "SEARCH",
+        Mode::Confirm => "CONFIRM",
+    }
+}
+
+/// Text with the ANSI escape sequences used for colors and cursor movement taken out
+pub fn strip_ansi(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            plain.push(c);
+        } else if chars.next() == Some('[') {
+            // Parameters up to and including the final byte
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+
+    plain
+}
+
+/// The scheme of a link that isn't handled by diosk itself, relative links are always on the same
+/// capsule
+fn foreign_scheme(url: &str) -> Option<String> {
+    Url::parse(url)
+        .ok()
+        .map(|url| url.scheme().to_string())
+        .filter(|scheme| !matches!(scheme.as_str(), "gemini" | "gopher" | "about"))
+}
+
+/// The widths of the left and right panes when the screen is split, with a column between them
+pub fn split_columns(width: u16) -> (u16, u16) {
+    let columns = width.saturating_sub(1);
+    let left = columns / 2;
+    (left, columns - left)
+}
+
+/// Shown in the status line while no network connections are made
+const OFFLINE_INDICATOR: &str = "offline";
+
+/// The capsule's favicon and a space, shown before the URL but not messages that replace it
+fn favicon_prefix(status_line_context: &StatusLineContext) -> String {
+    match status_line_context.favicon {
+        Some(icon)
+            if status_line_context.error_message.is_none()
+                && status_line_context.message.is_none() =>
+        {
+            format!("{} ", icon)
+        }
+        _ => String::new(),
+    }
+}
+
+/// The columns a favicon prefix takes up, emoji are two wide however many chars make them up
+fn favicon_width(prefix: &str) -> usize {
+    match prefix.is_empty() {
+        true => 0,
+        false => 3,
+    }
+}
+
+/// Which match of a search was last moved to and how many there are
+fn search_indicator((current, total): (usize, usize)) -> String {
+    match (current, total) {
+        (_, 0) => "no matches".to_string(),
+        (0, 1) => "1 match".to_string(),
+        (0, total) => format!("{} matches", total),
+        (current, total) => format!("match {}/{}", current, total),
+    }
+}
+
+/// A countdown to the page being reloaded
+fn auto_reload_indicator(seconds: u64) -> String {
+    format!("↻{}s", seconds)
+}
+
+/// The first column in view while lines aren't wrapped
+fn column_indicator(column: usize) -> String {
+    format!("col {}", column)
+}
+
+/// A lock, closed once the certificate has been seen on an earlier visit or is signed by a
+/// certificate authority, open the first time or when it's changed since
+fn trust_indicator(trust: Trust) -> &'static str {
+    match trust {
+        Trust::Pinned | Trust::Verified => "🔒",
+        Trust::FirstContact | Trust::Accepted | Trust::Unpinned => "🔓",
+    }
+}
+
+/// The charset a page was decoded with after the declared one gave garbage
+fn encoding_indicator(charset: &str) -> String {
+    format!("≈{}", charset)
+}
+
+/// Text from the network with control characters, other than tab, swapped for visible
+/// placeholders so they can't be used to move the cursor, change colors or retitle the window
+fn sanitize(text: &str) -> Cow<'_, str> {
+    if !text.chars().any(is_control) {
+        return Cow::Borrowed(text);
+    }
+
+    Cow::Owned(
+        text.chars()
+            .map(|c| match c as u32 {
+                // The control pictures, ␀ to ␟ then ␡
+                code @ 0x00..=0x1f => char::from_u32(0x2400 + code).expect("infallible"),
+                0x7f => '\u{2421}',
+                _ if is_control(c) => char::REPLACEMENT_CHARACTER,
+                _ => c,
+            })
+            .collect(),
+    )
+}
+
+/// What a link to another protocol is marked with
+fn link_tag(url: &str) -> String {
+    foreign_scheme(url)
+        .map(|scheme| format!("[{}] ", scheme))
+        .unwrap_or_default()
+}
+
+/// The column a link's text starts from, after the "=> " and its tag
+fn link_column(tag: &str) -> usize {
+    3 + textwrap::core::display_width(tag)
+}
+
+/// Tabs replaced with spaces up to the next tab stop, so they line up however far along the line
+/// they are, the text starting at the column given
+fn expand_tabs(text: &str, tab_width: usize, start: usize) -> Cow<'_, str> {
+    if !text.contains('\t') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut expanded = String::with_capacity(text.len());
+    let mut column = start;
+    for c in text.chars() {
+        match c {
+            '\t' => {
+                let spaces = tab_width - column % tab_width;
+                expanded.push_str(&" ".repeat(spaces));
+                column += spaces;
+            }
+            c => {
+                expanded.push(c);
+                column += textwrap::core::display_width(c.encode_utf8(&mut [0; 4]));
+            }
+        }
+    }
+    Cow::Owned(expanded)
+}
+
+/// C0 and C1 control characters and delete, apart from tab
+fn is_control(c: char) -> bool {
+    c != '\t' && c.is_control()
+}
+
+/// Text wrapped to a width, each row with where it starts in the text
+///
+/// Empty text is a single space so the row can be seen when it's highlighted.
+fn wrap_parts(text: &str, width: usize) -> Vec<(usize, Cow<'_, str>)> {
+    let mut offset = 0;
+    textwrap::wrap(text, width)
+        .into_iter()
+        .map(|part| {
+            let start = offset + text[offset..].find(&*part).unwrap_or(0);
+            offset = start + part.len();
+            match text.is_empty() {
+                true => (start, Cow::from(" ")),
+                false => (start, part),
+            }
+        })
+        .collect()
+}
+
+/// The part of text that's in view when it's panned across by some columns, as a byte range
+///
+/// Wide characters that would straddle either edge are left out.
+fn pan_range(text: &str, pan: usize, width: usize) -> Range<usize> {
+    let mut column = 0;
+    let mut start = text.len();
+    let mut end = text.len();
+
+    for (i, c) in text.char_indices() {
+        let c_width = textwrap::core::display_width(c.encode_utf8(&mut [0; 4]));
+        if column >= pan && start == text.len() {
+            start = i;
+        }
+        if column + c_width > pan + width {
+            end = i;
+            break;
+        }
+        column += c_width;
+    }
+
+    start..end.max(start)
+}
+
+/// Matches or colors in a line moved to be relative to a part of it, cut short to fit the part
+fn shift_spans<T: Copy>(
+    spans: &[(Range<usize>, T)],
+    start: usize,
+    len: usize,
+) -> Vec<(Range<usize>, T)> {
+    let end = start + len;
+    spans
+        .iter()
+        .filter(|(m, _)| m.start < end && m.end > start)
+        .map(|(m, value)| {
+            let range = m.start.max(start) - start..m.end.min(end) - start;
+            (range, *value)
+        })
+        .collect()
+}
+
+/// Truncate a string to a number of columns, marking the truncation with an ellipsis
+///
+/// A wide character that would straddle the last column is left out along with the rest.
+fn truncate(s: &str, width: usize) -> String {
+    if textwrap::core::display_width(s) <= width {
+        return s.to_string();
+    }
+    if width == 0 {
+        return String::new();
+    }
+
+    let mut truncated = String::new();
+    let mut column = 0;
+    for c in s.chars() {
+        column += textwrap::core::display_width(c.encode_utf8(&mut [0; 4]));
+        if column > width - 1 {
+            break;
+        }
+        truncated.push(c);
+    }
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(feature = "image_preview")]
+pub fn clear_image(protocol: image::Protocol) -> crossterm::Result<()> {
+    image::clear(&mut stdout(), protocol)?;
+    stdout().flush()?;
+    Ok(())
+}
+
+/// Room for a full screen of a large page, so a frame is written in one go
+const FRAME_CAPACITY: usize = 64 * 1024;
+
+/// Everything drawn for one screenful, buffered and written to the terminal when it's finished
+///
+/// Stdout is locked for the whole frame rather than for each command queued on it.
+pub struct Frame {
+    out: BufWriter<CountingWriter<StdoutLock<'static>>>,
+}
+
+impl Frame {
+    /// Lock stdout for the frame
+    pub fn start() -> Self {
+        Self {
+            out: BufWriter::with_capacity(FRAME_CAPACITY, CountingWriter::new(stdout().lock())),
+        }
+    }
+
+    /// Write the frame to the terminal
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()?;
+        let counter = self.out.get_ref();
+        debug!(
+            "frame took {} writes, {} bytes",
+            counter.writes, counter.bytes
+        );
+        Ok(())
+    }
+}
+
+impl Write for Frame {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Counts the writes made to the terminal, to check frames go out in as few as they should
+struct CountingWriter<W> {
+    inner: W,
+    writes: usize,
+    bytes: usize,
+}
+
+impl<W> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            writes: 0,
+            bytes: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.writes += 1;
+        self.bytes += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Ring the terminal bell
+pub fn bell() -> crossterm::Result<()> {
+    print!("\x07");
+    stdout().flush()?;
+    Ok(())
+}
+
 pub fn clear_screen() -> crossterm::Result<()> {
-    stdout()
-        .execute(terminal::Clear(terminal::ClearType::All))?
-        .execute(Bg(colors::BACKGROUND))?
-        .execute(cursor::MoveTo(1, 1))?;
+    let mut out = stdout();
+    // Inline, only the rows below the top are diosk's to clear
+    match INLINE_ROWS.load(Ordering::SeqCst) {
+        0 => out.execute(terminal::Clear(terminal::ClearType::All))?,
+        _ => out
+            .execute(move_to(0, 0))?
+            .execute(terminal::Clear(terminal::ClearType::FromCursorDown))?,
+    }
+    .execute(Bg(colors::BACKGROUND))?
+    .execute(move_to(1, 1))?;
 
     Ok(())
 }
 
+/// The terminal is set up for diosk and needs putting back
+static SET_UP: AtomicBool = AtomicBool::new(false);
+
+/// The terminal was put back for a panic, diosk takes it again if it carries on
+static CRASHED: AtomicBool = AtomicBool::new(false);
+
 pub fn setup_alternate_screen() -> crossterm::Result<()> {
+    // Frames are written as escape sequences, which the Windows console only understands once
+    // virtual terminal processing is turned on. Checking turns it on.
+    #[cfg(windows)]
+    crossterm::ansi_support::supports_ansi();
+
     terminal::enable_raw_mode()?;
+    SET_UP.store(true, Ordering::SeqCst);
 
     stdout()
         .queue(EnterAlternateScreen)?
@@ -289,9 +1651,661 @@ pub fn setup_alternate_screen() -> crossterm::Result<()> {
     Ok(())
 }
 
+/// Draw in some rows at the cursor rather than on the alternate screen, so the last page is left
+/// in the scrollback
+///
+/// The terminal is scrolled to make room when there are fewer rows than that below the cursor.
+pub fn setup_inline(rows: u16) -> crossterm::Result<()> {
+    #[cfg(windows)]
+    crossterm::ansi_support::supports_ansi();
+
+    terminal::enable_raw_mode()?;
+    SET_UP.store(true, Ordering::SeqCst);
+    INLINE_ROWS.store(rows, Ordering::SeqCst);
+
+    let (_, height) = terminal::size()?;
+    let (_, row) = cursor::position()?;
+    let rows = drawn_rows(height);
+    TOP.store(row.min(height - rows), Ordering::SeqCst);
+
+    // Raw mode, so each of these only moves down a row, scrolling once at the bottom
+    let mut out = stdout();
+    out.queue(cursor::Hide)?;
+    for _ in 1..rows {
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Hand the terminal over to another program, `resume` takes it back
+pub fn suspend() -> crossterm::Result<()> {
+    teardown()
+}
+
+/// Take the terminal back, drawing inline again below whatever the other program left if that's
+/// how it was being drawn in
+pub fn resume() -> crossterm::Result<()> {
+    match INLINE_ROWS.load(Ordering::SeqCst) {
+        0 => setup_alternate_screen(),
+        rows => setup_inline(rows),
+    }
+}
+
+/// Put the terminal back how it was for a panic to be reported, `recover` takes it back when the
+/// panic was in a thread diosk carries on without
+pub fn teardown_for_panic() -> crossterm::Result<()> {
+    if SET_UP.load(Ordering::SeqCst) {
+        CRASHED.store(true, Ordering::SeqCst);
+    }
+    teardown()
+}
+
+/// Take the terminal back if it was put back for a panic, returning whether it was
+pub fn recover() -> crossterm::Result<bool> {
+    if !CRASHED.swap(false, Ordering::SeqCst) {
+        return Ok(false);
+    }
+    resume()?;
+    Ok(true)
+}
+
+/// Put the terminal back how it was, only the first call after setting it up does anything so
+/// it's safe to call from every way out
 pub fn teardown() -> crossterm::Result<()> {
-    stdout().queue(LeaveAlternateScreen)?.queue(cursor::Show)?;
+    if !SET_UP.swap(false, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let mut out = stdout();
+    match INLINE_ROWS.load(Ordering::SeqCst) {
+        0 => out.queue(LeaveAlternateScreen)?,
+        // The page is left where it is, the shell's prompt goes where the status line was
+        _ => {
+            let (_, height) = terminal::size()?;
+            out.queue(ResetColor)?
+                .queue(move_to(0, drawn_rows(height).saturating_sub(2)))?
+                .queue(terminal::Clear(terminal::ClearType::FromCursorDown))?
+        }
+    }
+    .queue(cursor::Show)?;
     terminal::disable_raw_mode()?;
-    stdout().flush()?;
+    out.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufWriter, Write};
+
+    use url::Url;
+
+    use std::borrow::Cow;
+
+    use crossterm::style::SetBackgroundColor as Bg;
+
+    use super::{ColorScheme, CountingWriter, Highlights};
+
+    use crate::config::{Segment, SegmentKind};
+    use crate::gemini::gemtext::{self, Line};
+    use crate::gemini::Trust;
+    use crate::state::search::{Case, Pattern, Search};
+    use crate::state::{Mode, StatusLineContext};
+
+    #[test]
+    fn strip_ansi() {
+        assert_eq!(
+            super::strip_ansi(b"\x1b[38;5;15m\x1b[48;2;1;2;3m=> \x1b[0mfoo\n"),
+            "=> foo\n"
+        );
+    }
+
+    #[test]
+    fn hardcopy() {
+        let terminal = super::Terminal::new(12, 5);
+        let content = vec![
+            Line::parse("The first line wraps"),
+            Line::parse("=> gemini://example.org/ Example"),
+            Line::parse("Off screen"),
+        ];
+
+        let hardcopy = terminal
+            .hardcopy(0, &Highlights::default(), content, 0, &|_| false, None)
+            .unwrap();
+        assert_eq!(
+            super::strip_ansi(&hardcopy),
+            "The first\nline wraps\n=> Example\n"
+        );
+    }
+
+    #[test]
+    fn links_wrap() {
+        let terminal = super::Terminal::new(16, 8).show_urls(true);
+        let colors = ColorScheme::default();
+        let line = Line::parse("=> gemini://example.org/a/long/path Example");
+
+        let rows = terminal
+            .render_line(&line, true, false, None, &[], &[])
+            .unwrap();
+        let plain: Vec<String> = rows.iter().map(|row| super::strip_ansi(row)).collect();
+        assert_eq!(
+            plain,
+            [
+                "=> Example      ",
+                "   gemini://exam",
+                "   ple.org/a/lon",
+                "   g/path       "
+            ]
+        );
+        assert_eq!(rows.len(), terminal.line_rows(&line));
+        // Every row is highlighted as the current line
+        for row in &rows {
+            let row = String::from_utf8_lossy(row);
+            assert!(row.contains(&format!("{}", Bg(colors.active_link_bg))));
+        }
+    }
+
+    #[test]
+    fn unwrapped_lines() {
+        let content =
+            || gemtext::parse("The first line is cut off\nWide 日本語\n```\nPreformatted");
+        let hardcopy = |pan| {
+            let terminal = super::Terminal::new(10, 6).wrap(false).pan(pan);
+            let hardcopy = terminal
+                .hardcopy(0, &Highlights::default(), content(), 0, &|_| false, None)
+                .unwrap();
+            super::strip_ansi(&hardcopy)
+        };
+
+        // Every line is a row of its own
+        assert_eq!(
+            hardcopy(0),
+            "The first \nWide 日本\n▾ Preform…\nPreformatt\n"
+        );
+        // Wide characters straddling the edge are left out
+        assert_eq!(hardcopy(6), "rst line i\n本語\n▾ Preform…\nmatted\n");
+        assert_eq!(
+            super::Terminal::new(10, 6)
+                .wrap(false)
+                .line_rows(&content()[0]),
+            1
+        );
+    }
+
+    #[test]
+    fn detected_urls_are_underlined() {
+        let hardcopy = |terminal: super::Terminal| {
+            let content = vec![Line::parse("See gemini://a.org/.")];
+            let hardcopy = terminal
+                .hardcopy(0, &Highlights::default(), content, 0, &|_| false, None)
+                .unwrap();
+            String::from_utf8(hardcopy).unwrap()
+        };
+
+        let underlined = "\x1b[4mgemini://a.org/\x1b[24m";
+        assert!(!hardcopy(super::Terminal::new(40, 5)).contains(underlined));
+        assert!(
+            hardcopy(super::Terminal::new(40, 5).detect_urls(&["gemini://"])).contains(underlined)
+        );
+
+        // Found before the line is wrapped, the rest of a URL on the next row is still one
+        let content = vec![Line::parse("gemini://a.org/abcdef")];
+        let hardcopy = super::Terminal::new(12, 5)
+            .detect_urls(&["gemini://"])
+            .hardcopy(0, &Highlights::default(), content, 0, &|_| false, None)
+            .unwrap();
+        let hardcopy = String::from_utf8(hardcopy).unwrap();
+        assert!(hardcopy.contains("\x1b[4mgemini://a.o\x1b[24m"));
+        assert!(hardcopy.contains("\x1b[4mrg/abcdef\x1b[24m"));
+    }
+
+    #[test]
+    fn decoded_link_urls() {
+        let hardcopy = |terminal: super::Terminal| {
+            let content = vec![Line::parse("=> /%E6%97%A5%E8%A8%98/")];
+            let hardcopy = terminal
+                .hardcopy(0, &Highlights::default(), content, 0, &|_| false, None)
+                .unwrap();
+            super::strip_ansi(&hardcopy)
+        };
+
+        assert_eq!(
+            hardcopy(super::Terminal::new(60, 3)),
+            "=> /%E6%97%A5%E8%A8%98/\n"
+        );
+        assert_eq!(
+            hardcopy(super::Terminal::new(60, 3).decode_urls(true)),
+            "=> /日記/\n"
+        );
+    }
+
+    #[test]
+    fn foreign_links_are_tagged() {
+        let terminal = super::Terminal::new(40, 5).show_urls(true);
+        let content = vec![
+            Line::parse("=> https://example.org/ Web"),
+            Line::parse("=> mailto:a@example.org"),
+            Line::parse("=> /local Local"),
+        ];
+
+        let hardcopy = terminal
+            .hardcopy(0, &Highlights::default(), content, 0, &|_| false, None)
+            .unwrap();
+        assert_eq!(
+            super::strip_ansi(&hardcopy),
+            "=> [https] Web https://example.org/\n\
+             => [mailto] mailto:a@example.org\n\
+             => Local /local\n"
+        );
+    }
+
+    #[test]
+    fn status_line() {
+        let terminal = super::Terminal::new(30, 5);
+        let url = Url::parse("gemini://example.org/").unwrap();
+        let context = StatusLineContext {
+            status_code: None,
+            url: Some(&url),
+            error_message: None,
+            message: None,
+            mode: Mode::Input,
+            input: "go foo",
+            loading: false,
+            confirmation: None,
+            segments: &[],
+            line_index: 0,
+            line_count: 0,
+            pending_keys: String::new(),
+            auto_reload: None,
+            offline: false,
+            retrying: None,
+            deadline: None,
+            pending_requests: 0,
+            favicon: Some("🦀"),
+            search: None,
+            lang: None,
+            column: None,
+            detected_charset: None,
+            trust: None,
+            link: None,
+            clock: None,
+        };
+
+        let mut out = Vec::new();
+        terminal.draw_status_line(&mut out, &context).unwrap();
+        assert_eq!(
+            super::strip_ansi(&out),
+            " --  🦀 gemini://exampl…      :go foo "
+        );
+
+        // The link the current line is on takes the page's place
+        let link = Url::parse("gemini://a.org/").unwrap();
+        let with_link = StatusLineContext {
+            link: Some(&link),
+            pending_keys: String::new(),
+            ..context
+        };
+        let mut out = Vec::new();
+        terminal.draw_status_line(&mut out, &with_link).unwrap();
+        assert!(super::strip_ansi(&out).starts_with(" --  🦀 → gemini://a.or…"));
+
+        let segments = [Segment {
+            kind: SegmentKind::Lang,
+            colors: None,
+        }];
+        let context = StatusLineContext {
+            segments: &segments,
+            lang: Some("de, fr"),
+            ..context
+        };
+        let mut out = Vec::new();
+        terminal.draw_status_line(&mut out, &context).unwrap();
+        assert!(super::strip_ansi(&out).starts_with(" de,fr "));
+
+        // The lock is two columns wide, the rest of the line makes room for it
+        let without_trust = StatusLineContext {
+            segments: &[],
+            ..context
+        };
+        let mut out = Vec::new();
+        terminal.draw_status_line(&mut out, &without_trust).unwrap();
+        let width = textwrap::core::display_width(&super::strip_ansi(&out));
+        let context = StatusLineContext {
+            trust: Some(Trust::Pinned),
+            ..without_trust
+        };
+        let mut out = Vec::new();
+        terminal.draw_status_line(&mut out, &context).unwrap();
+        let drawn = super::strip_ansi(&out);
+        assert!(drawn.starts_with(" 🔒 -- "));
+        assert_eq!(textwrap::core::display_width(&drawn), width);
+
+        let segments = [Segment {
+            kind: SegmentKind::Trust,
+            colors: None,
+        }];
+        let context = StatusLineContext {
+            segments: &segments,
+            trust: Some(Trust::Accepted),
+            ..context
+        };
+        let mut out = Vec::new();
+        terminal.draw_status_line(&mut out, &context).unwrap();
+        assert!(super::strip_ansi(&out).starts_with(" 🔓 "));
+        // Changed or expired, the certificate was accepted once so the lock is a warning
+        let colors = ColorScheme::default();
+        let warning = format!(
+            "{}{} 🔓",
+            super::Fg(colors.error_fg),
+            super::Bg(colors.error_bg)
+        );
+        assert!(String::from_utf8_lossy(&out).contains(&warning));
+
+        let segments = [Segment {
+            kind: SegmentKind::Clock,
+            colors: None,
+        }];
+        let context = StatusLineContext {
+            segments: &segments,
+            clock: Some((9, 5)),
+            ..context
+        };
+        let mut out = Vec::new();
+        terminal.draw_status_line(&mut out, &context).unwrap();
+        assert!(super::strip_ansi(&out).starts_with(" 09:05 "));
+
+        // Only worth mentioning when something is waiting on something else
+        let context = StatusLineContext {
+            loading: true,
+            pending_requests: 1,
+            ..context
+        };
+        let mut out = Vec::new();
+        terminal.draw_status_line(&mut out, &context).unwrap();
+        assert!(super::strip_ansi(&out).starts_with(" Loading... "));
+        let context = StatusLineContext {
+            pending_requests: 3,
+            ..context
+        };
+        let mut out = Vec::new();
+        terminal.draw_status_line(&mut out, &context).unwrap();
+        assert!(super::strip_ansi(&out).starts_with(" Loading... (3 pending) "));
+    }
+
+    #[test]
+    fn frames_are_written_at_once() {
+        let terminal = super::Terminal::new(40, 5);
+        let content = vec![Line::parse("# Title"), Line::parse("=> /a A link")];
+
+        let mut out = BufWriter::new(CountingWriter::new(Vec::new()));
+        terminal
+            .render_content(&mut out, 0, &Highlights::default(), content, 0, &|_| false)
+            .unwrap();
+        terminal.render_separator(&mut out).unwrap();
+        out.flush().unwrap();
+
+        let counter = out.get_ref();
+        assert_eq!(counter.writes, 1);
+        assert_eq!(counter.bytes, counter.inner.len());
+    }
+
+    #[test]
+    fn control_characters_are_sanitized() {
+        let terminal = super::Terminal::new(60, 5).show_urls(true);
+        let content = vec![
+            Line::parse("\x1b]0;Retitled\x07\x1b[2JCleared\u{9b}31m"),
+            Line::parse("=> /\x1b[5m Blink\x1b[5m"),
+        ];
+        let url = Url::parse("gemini://example.org/").unwrap();
+        let context = StatusLineContext {
+            status_code: None,
+            url: Some(&url),
+            error_message: Some("51 \x1b[H\x1b[2K"),
+            message: None,
+            mode: Mode::Normal,
+            input: "",
+            loading: false,
+            confirmation: Some("Open \x1b[8m?"),
+            segments: &[],
+            line_index: 0,
+            line_count: 0,
+            pending_keys: String::new(),
+            auto_reload: None,
+            offline: false,
+            retrying: None,
+            deadline: None,
+            pending_requests: 0,
+            favicon: None,
+            search: None,
+            lang: None,
+            column: None,
+            detected_charset: None,
+            trust: None,
+            link: None,
+            clock: None,
+        };
+
+        let mut out = Vec::new();
+        terminal
+            .render_content(&mut out, 0, &Highlights::default(), content, 0, &|_| false)
+            .unwrap();
+        terminal.draw_status_line(&mut out, &context).unwrap();
+
+        // Only the colors and cursor movement drawn by diosk itself are left
+        let out = String::from_utf8(out).unwrap();
+        for sequence in out.split('\x1b').skip(1) {
+            let end = sequence.find(|c: char| c.is_ascii_alphabetic()).unwrap();
+            assert!(sequence.starts_with('['), "{:?}", sequence);
+            assert!(
+                sequence[1..end]
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || c == ';'),
+                "{:?}",
+                sequence
+            );
+            assert!(matches!(&sequence[end..=end], "m" | "H"), "{:?}", sequence);
+        }
+        assert!(!out.contains('\x07') && !out.contains('\u{9b}'));
+
+        let plain = super::strip_ansi(out.as_bytes());
+        assert!(plain.contains("␛]0;Retitled␇␛[2JCleared\u{fffd}31m"));
+        assert!(plain.contains("=> Blink␛[5m /␛[5m"));
+        assert!(plain.contains("51 ␛[H␛[2K"));
+        assert!(plain.contains("Open ␛[8m?"));
+    }
+
+    #[test]
+    fn tabs_are_expanded() {
+        let hardcopy = |terminal: super::Terminal, line: &str| {
+            let content = vec![Line::parse(line)];
+            let hardcopy = terminal
+                .hardcopy(0, &Highlights::default(), content, 0, &|_| false, None)
+                .unwrap();
+            super::strip_ansi(&hardcopy)
+        };
+
+        assert_eq!(
+            hardcopy(super::Terminal::new(40, 3), "a\tbc\tdef\t日\tx"),
+            "a       bc      def     日      x\n"
+        );
+        assert_eq!(
+            hardcopy(
+                super::Terminal::new(40, 3).tab_width(4),
+                "=> /\tab\tTab\tstops"
+            ),
+            "=> ab   Tab stops\n"
+        );
+        assert_eq!(
+            hardcopy(
+                super::Terminal::new(40, 3).tab_width(4),
+                "=> https://a.org/\tab\tTab"
+            ),
+            "=> [https] ab   Tab\n"
+        );
+
+        // Wrapped at the terminal width, the rows drawn are the rows counted
+        let terminal = super::Terminal::new(20, 10).tab_width(4);
+        let line = "\tone\ttwo\tthree\tfour\tfive";
+        let rows = hardcopy(terminal, line);
+        assert!(
+            rows.lines().all(|row| row.chars().count() <= 20),
+            "{:?}",
+            rows
+        );
+        let terminal = super::Terminal::new(20, 10).tab_width(4);
+        assert_eq!(rows.lines().count(), terminal.line_rows(&Line::parse(line)));
+    }
+
+    #[test]
+    fn preformatted_blocks() {
+        let terminal = super::Terminal::new(20, 10);
+        let page = "```ASCII art\n+----------------------+\n```\nAfter";
+
+        let hardcopy = terminal
+            .hardcopy(
+                0,
+                &Highlights::default(),
+                gemtext::parse(page),
+                0,
+                &|_| false,
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            super::strip_ansi(&hardcopy),
+            "▾ ASCII art\n+------------------…\n \nAfter\n"
+        );
+
+        let mut content = gemtext::parse(page);
+        gemtext::collapse(&mut content, 0);
+        let hardcopy = terminal
+            .hardcopy(0, &Highlights::default(), content, 0, &|_| false, None)
+            .unwrap();
+        assert_eq!(
+            super::strip_ansi(&hardcopy),
+            "▸ ASCII art (1 line…\nAfter\n"
+        );
+    }
+
+    #[test]
+    fn headings() {
+        let terminal = super::Terminal::new(10, 10);
+        let colors = ColorScheme::default();
+        let content = gemtext::parse("# A long title\n### Small");
+
+        let rows = terminal
+            .render_line(&content[0], false, false, None, &[], &[])
+            .unwrap();
+        assert_eq!(rows.len(), 2, "wrapped like text");
+        for row in &rows {
+            let row = String::from_utf8_lossy(row);
+            assert!(row.contains(&format!("{}", super::Fg(colors.heading_1))));
+            assert!(row.contains(&format!("{}", super::SetAttribute(super::Attribute::Bold))));
+        }
+
+        let rows = terminal
+            .render_line(&content[1], false, false, None, &[], &[])
+            .unwrap();
+        let row = String::from_utf8_lossy(&rows[0]);
+        assert!(row.contains(&format!("{}", super::Fg(colors.heading_3))));
+        assert_eq!(super::strip_ansi(&rows[0]), "Small     ");
+    }
+
+    #[test]
+    fn wide_characters() {
+        let terminal = super::Terminal::new(10, 10);
+        let line = Line::parse("日本語のテキスト and 🦀 crabs");
+
+        let rows = terminal
+            .render_line(&line, true, false, None, &[], &[])
+            .unwrap();
+        assert_eq!(rows.len(), terminal.line_rows(&line));
+        for row in &rows {
+            let row = super::strip_ansi(row);
+            assert!(textwrap::core::display_width(&row) <= 10, "{:?}", row);
+        }
+
+        // Cut short by columns, a character that would straddle the edge is left out
+        assert_eq!(super::truncate("日本語のテキスト", 6), "日本…");
+        assert_eq!(super::truncate("日本語のテキスト", 7), "日本語…");
+        assert_eq!(super::truncate("日本語", 6), "日本語");
+    }
+
+    #[test]
+    fn list_items() {
+        let terminal = super::Terminal::new(12, 10);
+        let content = gemtext::parse("* One two three four\n*");
+        let line_rows = terminal.line_rows(&content[0]);
+
+        let hardcopy = terminal
+            .hardcopy(0, &Highlights::default(), content, 0, &|_| false, None)
+            .unwrap();
+        assert_eq!(super::strip_ansi(&hardcopy), "• One two\n  three four\n*\n");
+        assert_eq!(line_rows, 2);
+    }
+
+    #[test]
+    fn quotes() {
+        let terminal = super::Terminal::new(12, 10);
+        let content = gemtext::parse("> One two three four\n>");
+        let line_rows = terminal.line_rows(&content[0]);
+
+        let hardcopy = terminal
+            .hardcopy(0, &Highlights::default(), content, 0, &|_| false, None)
+            .unwrap();
+        assert_eq!(
+            super::strip_ansi(&hardcopy),
+            "┃ One two\n┃ three four\n┃  \n"
+        );
+        assert_eq!(line_rows, 2);
+    }
+
+    #[test]
+    fn search_matches() {
+        let terminal = super::Terminal::new(8, 12);
+        let content = gemtext::parse("A cat, a cat\n```\ncat\n```\n=> /a links to cats");
+        let mut search = Search::new(
+            Pattern::parse(
+                "cat",
+                false,
+                Case {
+                    ignore: true,
+                    smart: true,
+                },
+            )
+            .unwrap(),
+            &content,
+            |_, text| Cow::Borrowed(text),
+        );
+        search.next(0);
+        let highlights = Highlights {
+            selected_url: 0,
+            search: Some(&search),
+            syntax: None,
+        };
+
+        let hardcopy = terminal
+            .hardcopy(0, &highlights, content, 0, &|_| false, None)
+            .unwrap();
+        let hardcopy = String::from_utf8(hardcopy).unwrap();
+        let colors = ColorScheme::default();
+        let current = format!("{}cat", Bg(colors.current_match_bg));
+        let other = format!("{}cat", Bg(colors.search_match_bg));
+        assert_eq!(hardcopy.matches(&current).count(), 1);
+        // The second match is on a wrapped row, the third in preformatted text, and the last in
+        // the name of a link
+        assert_eq!(hardcopy.matches(&other).count(), 3);
+
+        assert_eq!(super::search_indicator((2, 3)), "match 2/3");
+        assert_eq!(super::search_indicator((0, 3)), "3 matches");
+        assert_eq!(super::search_indicator((0, 0)), "no matches");
+    }
+
+    #[test]
+    fn split_columns() {
+        assert_eq!(super::split_columns(80), (39, 40));
+        assert_eq!(super::split_columns(81), (40, 40));
+        assert_eq!(super::split_columns(0), (0, 0));
+    }
+}
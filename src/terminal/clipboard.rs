@@ -0,0 +1,188 @@
+//! Reading the system clipboard, to paste URLs
+//!
+//! The terminal is asked for it first with OSC 52, which works over SSH, then the paste command
+//! is run. The input reader has to be paused while the terminal is asked as its reply arrives as
+//! input, and drops a reply that comes too late for that.
+
+use std::io::{self, stdout, Write};
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
+use thiserror::Error;
+
+/// How long to wait for the terminal's reply, those that don't allow reading the clipboard never
+/// send one
+const REPLY_TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Error, Debug)]
+pub enum ClipboardError {
+    #[error("the terminal didn't share the clipboard, set paste_command to read it")]
+    Unavailable,
+    #[error("unable to ask the terminal: {0}")]
+    Terminal(#[from] crossterm::ErrorKind),
+    #[error("unable to run {0}: {1}")]
+    Command(String, io::Error),
+    #[error("{0} exited with {1}")]
+    Failed(String, ExitStatus),
+}
+
+/// Picks a reply to the query out of what the input reader reads after it's stopped waiting,
+/// over SSH the reply can take longer than that
+#[derive(Debug, Default)]
+pub struct LateReply {
+    started: bool,
+}
+
+impl LateReply {
+    /// Whether the key is part of a reply and should be dropped rather than handled
+    ///
+    /// A key that couldn't be part of one ends it, so Alt-] pressed by itself only loses that.
+    pub fn swallow(&mut self, key: &KeyEvent) -> bool {
+        match (key.code, key.modifiers, self.started) {
+            (KeyCode::Char(']'), KeyModifiers::ALT, _) => self.started = true,
+            (KeyCode::Char('g'), KeyModifiers::CONTROL, true)
+            | (KeyCode::Char('\\'), KeyModifiers::ALT, true) => self.started = false,
+            (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT, true)
+                if is_reply_char(c) => {}
+            _ => {
+                self.started = false;
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The characters between the introducer and terminator of `52;c;<base64>`
+fn is_reply_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, ';' | '+' | '/' | '=' | '?')
+}
+
+/// The text on the clipboard, from the terminal or failing that the paste command
+pub fn read_text(paste_command: Option<&str>) -> Result<String, ClipboardError> {
+    if let Some(text) = ask_terminal()? {
+        return Ok(text);
+    }
+
+    match paste_command {
+        Some(command) => run(command),
+        None => Err(ClipboardError::Unavailable),
+    }
+}
+
+/// Ask the terminal for the clipboard, None when it doesn't reply in time
+///
+/// Crossterm reads the reply as keys: Alt-] then the characters of `52;c;<base64>` and either
+/// Ctrl-G or Alt-\ for the terminator. Any keys pressed while waiting are dropped.
+fn ask_terminal() -> Result<Option<String>, ClipboardError> {
+    let mut out = stdout();
+    out.write_all(b"\x1b]52;c;?\x07")
+        .and_then(|_| out.flush())
+        .map_err(crossterm::ErrorKind::IoError)?;
+
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+    let mut reply: Option<String> = None;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || !poll(remaining)? {
+            return Ok(None);
+        }
+
+        let key = match read()? {
+            Event::Key(key) => key,
+            _ => continue,
+        };
+        match (key.code, key.modifiers, reply.as_mut()) {
+            (KeyCode::Char(']'), KeyModifiers::ALT, _) => reply = Some(String::new()),
+            (KeyCode::Char('g'), KeyModifiers::CONTROL, Some(_))
+            | (KeyCode::Char('\\'), KeyModifiers::ALT, Some(_)) => break,
+            (KeyCode::Char(c), _, Some(reply)) => reply.push(c),
+            _ => {}
+        }
+    }
+
+    Ok(reply.as_deref().and_then(decode_reply))
+}
+
+/// The text in a reply to an OSC 52 query, without its introducer or terminator
+fn decode_reply(reply: &str) -> Option<String> {
+    let (_, data) = reply.strip_prefix("52;")?.split_once(';')?;
+    let bytes = base64::decode(data).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Run the paste command, e.g. wl-paste or xclip -o, for what it writes to stdout
+fn run(command: &str) -> Result<String, ClipboardError> {
+    let mut words = command.split_whitespace();
+    let program = words.next().ok_or(ClipboardError::Unavailable)?;
+
+    let output = Command::new(program)
+        .args(words)
+        .output()
+        .map_err(|e| ClipboardError::Command(command.to_string(), e))?;
+    if !output.status.success() {
+        return Err(ClipboardError::Failed(command.to_string(), output.status));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_reply() {
+        assert_eq!(
+            super::decode_reply("52;c;Z2VtaW5pOi8vZXhhbXBsZS5vcmcv").as_deref(),
+            Some("gemini://example.org/")
+        );
+        // Some terminals leave out which selection it is
+        assert_eq!(super::decode_reply("52;;YQ==").as_deref(), Some("a"));
+        assert_eq!(super::decode_reply("52;c;").as_deref(), Some(""));
+        assert_eq!(super::decode_reply("52;c;?"), None);
+        assert_eq!(super::decode_reply("11;rgb:0000/0000/0000"), None);
+    }
+
+    #[test]
+    fn late_reply() {
+        let key = |code, modifiers| KeyEvent::new(code, modifiers);
+        let mut late_reply = LateReply::default();
+
+        let reply = vec![key(KeyCode::Char(']'), KeyModifiers::ALT)]
+            .into_iter()
+            .chain(
+                "52;c;YQ=="
+                    .chars()
+                    .map(|c| key(KeyCode::Char(c), KeyModifiers::NONE)),
+            )
+            .chain(vec![key(KeyCode::Char('g'), KeyModifiers::CONTROL)]);
+        for key in reply {
+            assert!(late_reply.swallow(&key));
+        }
+        assert!(!late_reply.swallow(&key(KeyCode::Char('q'), KeyModifiers::NONE)));
+
+        // Alt-] pressed by itself
+        assert!(late_reply.swallow(&key(KeyCode::Char(']'), KeyModifiers::ALT)));
+        assert!(!late_reply.swallow(&key(KeyCode::Esc, KeyModifiers::NONE)));
+        assert!(!late_reply.swallow(&key(KeyCode::Char('j'), KeyModifiers::NONE)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn run() {
+        assert_eq!(
+            super::run("echo gemini://example.org/").unwrap(),
+            "gemini://example.org/\n"
+        );
+        assert!(matches!(
+            super::run("false"),
+            Err(ClipboardError::Failed(..))
+        ));
+        assert!(matches!(
+            super::run("diosk-no-such-command"),
+            Err(ClipboardError::Command(..))
+        ));
+    }
+}
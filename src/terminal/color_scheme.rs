@@ -0,0 +1,71 @@
+use crossterm::style::Color;
+
+use crate::terminal::colors;
+
+/// The colors used when rendering a page and the status line.
+#[derive(Debug, Clone, Copy)]
+pub struct ColorScheme {
+    pub foreground: Color,
+    pub background: Color,
+    /// Headings from # to ###, which are also bold
+    pub heading_1: Color,
+    pub heading_2: Color,
+    pub heading_3: Color,
+    pub list_bullet: Color,
+    /// Quoted text and the gutter beside it
+    pub quote: Color,
+    pub quote_gutter: Color,
+    /// Background of the current line when it isn't actionable
+    pub active_line_bg: Color,
+    /// Background and foreground of the current line when it's a link, Enter will navigate
+    pub active_link_bg: Color,
+    pub active_link_fg: Color,
+    pub link_prefix: Color,
+    pub link_url: Color,
+    /// Name color for links that point at an already visited URL
+    pub visited_link: Color,
+    pub invalid_link: Color,
+    /// The tag on links to schemes other than gemini, which are handed to other programs
+    pub foreign_link: Color,
+    /// The alt text shown above a preformatted block
+    pub caption: Color,
+    /// Background of search matches, and the colors of the one n and N last moved to
+    pub search_match_bg: Color,
+    pub current_match_fg: Color,
+    pub current_match_bg: Color,
+    pub status_fg: Color,
+    pub status_bg: Color,
+    pub error_fg: Color,
+    pub error_bg: Color,
+}
+
+impl Default for ColorScheme {
+    fn default() -> Self {
+        Self {
+            foreground: colors::FOREGROUND,
+            background: colors::BACKGROUND,
+            heading_1: colors::GOLDENROD,
+            heading_2: colors::MORNING_GLORY,
+            heading_3: colors::BILOBA_FLOWER,
+            list_bullet: colors::SHIP_COVE,
+            quote: colors::GREY_CHATEAU,
+            quote_gutter: colors::SHUTTLE_GREY,
+            active_line_bg: colors::REGENT_GREY,
+            active_link_bg: colors::MANTIS,
+            active_link_fg: colors::COD_GREY,
+            link_prefix: colors::MANTIS,
+            link_url: colors::REGENT_GREY,
+            visited_link: colors::BOULDER,
+            invalid_link: colors::OLD_BRICK,
+            foreign_link: colors::RAW_SIENNA,
+            caption: colors::REGENT_GREY,
+            search_match_bg: colors::SHUTTLE_GREY,
+            current_match_fg: colors::COD_GREY,
+            current_match_bg: colors::GOLDENROD,
+            status_fg: colors::GREEN_SMOKE,
+            status_bg: colors::COSTA_DEL_SOL,
+            error_fg: colors::TEMPTRESS,
+            error_bg: colors::OLD_BRICK,
+        }
+    }
+}
@@ -57,6 +57,14 @@ pub const TEA_GREEN: Color = rgb(210, 235, 190);
 pub const DELL: Color = rgb(67, 112, 25);
 pub const CALYPSO: Color = rgb(43, 91, 119);
 
+/// Look up a color by its name, ignoring case
+pub fn by_name(name: &str) -> Option<Color> {
+    all()
+        .into_iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, color)| color)
+}
+
 #[rustfmt::skip]
 pub fn all() -> Vec<(&'static str, Color)> {
     vec![
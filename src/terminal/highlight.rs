@@ -0,0 +1,115 @@
+//! Syntax highlighting for preformatted blocks whose alt text names a language, e.g. ```rust
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+use crossterm::style::Color;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+
+use crate::gemini::gemtext::Line;
+
+const THEME_NAME: &str = "base16-ocean.dark";
+
+/// The colors of a line's text as byte ranges of it
+pub type Spans = Vec<(Range<usize>, Color)>;
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    THEME.get_or_init(|| {
+        ThemeSet::load_defaults()
+            .themes
+            .remove(THEME_NAME)
+            .expect("bundled theme")
+    })
+}
+
+/// The colors of the lines in preformatted blocks whose alt text starts with a language syntect
+/// knows, keyed by line
+///
+/// The syntaxes aren't loaded until a page has such a block. Lines are highlighted as they're
+/// displayed so the ranges line up with what's drawn.
+pub fn spans<'t>(
+    lines: &'t [Line],
+    display: impl Fn(&'t str) -> Cow<'t, str>,
+) -> HashMap<usize, Spans> {
+    let mut spans = HashMap::new();
+    let mut highlighter: Option<HighlightLines> = None;
+
+    for (i, line) in lines.iter().enumerate() {
+        match line {
+            Line::PreformatStart { alt, .. } => {
+                highlighter = alt
+                    .as_deref()
+                    .and_then(|alt| alt.split_whitespace().next())
+                    .and_then(|token| syntaxes().find_syntax_by_token(token))
+                    .map(|syntax| HighlightLines::new(syntax, theme()));
+            }
+            Line::Preformatted { text, .. } => {
+                if let Some(h) = highlighter.as_mut() {
+                    let text = display(text);
+                    // The bundled syntaxes expect every line to end with a newline
+                    match h.highlight_line(&format!("{}\n", text), syntaxes()) {
+                        Ok(styles) => {
+                            let mut offset = 0;
+                            let line_spans = styles
+                                .into_iter()
+                                .map(|(style, piece)| {
+                                    let start = offset;
+                                    offset += piece.len();
+                                    let color = style.foreground;
+                                    let color = Color::Rgb {
+                                        r: color.r,
+                                        g: color.g,
+                                        b: color.b,
+                                    };
+                                    (start..offset.min(text.len()), color)
+                                })
+                                .filter(|(range, _)| !range.is_empty())
+                                .collect();
+                            spans.insert(i, line_spans);
+                        }
+                        Err(_) => highlighter = None,
+                    }
+                }
+            }
+            Line::PreformatEnd { .. } => highlighter = None,
+            _ => {}
+        }
+    }
+
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans() {
+        let lines = crate::gemini::gemtext::parse(
+            "```rust\nfn main() {}\n```\n```\nfn main() {}\n```\n```ascii art\n/\\\n```",
+        );
+        let spans = super::spans(&lines, Cow::from);
+
+        let keys: Vec<_> = spans.keys().copied().collect();
+        assert_eq!(keys, vec![1]);
+        let colors = &spans[&1];
+        assert_eq!(colors.first().map(|(r, _)| r.start), Some(0));
+        assert_eq!(
+            colors.last().map(|(r, _)| r.end),
+            Some("fn main() {}".len())
+        );
+        // The keyword is colored differently to the name
+        let color_at = |i| colors.iter().find(|(r, _)| r.contains(&i)).map(|(_, c)| *c);
+        assert_ne!(color_at(0), color_at(3));
+    }
+}
@@ -0,0 +1,174 @@
+//! Inline image previews using the kitty graphics protocol or sixel
+
+use std::env;
+use std::io::Write;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageError};
+
+/// Assumed size of a terminal cell in pixels, there's no portable way to ask
+const CELL_WIDTH: u32 = 10;
+const CELL_HEIGHT: u32 = 20;
+
+/// Bytes of base64 image data per kitty graphics escape sequence
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Kitty,
+    Sixel,
+}
+
+impl Protocol {
+    /// Guess which graphics protocol the terminal supports from the environment
+    pub fn detect() -> Option<Self> {
+        let term = env::var("TERM").unwrap_or_default();
+        let term_program = env::var("TERM_PROGRAM").unwrap_or_default();
+
+        if env::var_os("KITTY_WINDOW_ID").is_some()
+            || term.contains("kitty")
+            || term_program == "WezTerm"
+        {
+            Some(Protocol::Kitty)
+        } else if term.contains("sixel") || term == "mlterm" || term_program == "foot" {
+            Some(Protocol::Sixel)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<DynamicImage, ImageError> {
+    image::load_from_memory(data)
+}
+
+/// The number of columns and rows the image takes up when scaled to fit, keeping its aspect ratio
+fn fit(image: &DynamicImage, columns: u16, rows: u16) -> (u16, u16) {
+    let (width, height) = image.dimensions();
+    let (width, height) = (width.max(1) as f64, height.max(1) as f64);
+    let (max_width, max_height) = (
+        (columns as u32 * CELL_WIDTH) as f64,
+        (rows as u32 * CELL_HEIGHT) as f64,
+    );
+
+    let scale = (max_width / width).min(max_height / height).min(1.0);
+    let columns = ((width * scale) / CELL_WIDTH as f64).ceil().max(1.0) as u16;
+    let rows = ((height * scale) / CELL_HEIGHT as f64).ceil().max(1.0) as u16;
+
+    (columns, rows)
+}
+
+/// Write the escape sequences that draw the image at the cursor position, scaled to fit within
+/// the given number of columns and rows
+pub fn write<W: Write>(
+    w: &mut W,
+    protocol: Protocol,
+    image: &DynamicImage,
+    columns: u16,
+    rows: u16,
+) -> std::io::Result<()> {
+    let (columns, rows) = fit(image, columns, rows);
+
+    match protocol {
+        Protocol::Kitty => write_kitty(w, image, columns, rows),
+        Protocol::Sixel => {
+            let image = image.resize(
+                columns as u32 * CELL_WIDTH,
+                rows as u32 * CELL_HEIGHT,
+                FilterType::Triangle,
+            );
+            write_sixel(w, &image)
+        }
+    }
+}
+
+/// Remove any images drawn with the kitty graphics protocol
+pub fn clear<W: Write>(w: &mut W, protocol: Protocol) -> std::io::Result<()> {
+    match protocol {
+        Protocol::Kitty => write!(w, "\x1b_Ga=d\x1b\\"),
+        // Sixel images are part of the screen contents so clearing the screen removes them
+        Protocol::Sixel => Ok(()),
+    }
+}
+
+// https://sw.kovidgoyal.net/kitty/graphics-protocol/
+fn write_kitty<W: Write>(
+    w: &mut W,
+    image: &DynamicImage,
+    columns: u16,
+    rows: u16,
+) -> std::io::Result<()> {
+    let rgba = image.to_rgba8();
+    let data = base64::encode(rgba.as_raw());
+    let mut chunks = data.as_bytes().chunks(KITTY_CHUNK_SIZE).peekable();
+
+    let mut first = true;
+    while let Some(chunk) = chunks.next() {
+        let more = if chunks.peek().is_some() { 1 } else { 0 };
+        if first {
+            write!(
+                w,
+                "\x1b_Ga=T,f=32,s={},v={},c={},r={},q=2,m={};",
+                rgba.width(),
+                rgba.height(),
+                columns,
+                rows,
+                more
+            )?;
+            first = false;
+        } else {
+            write!(w, "\x1b_Gm={};", more)?;
+        }
+        w.write_all(chunk)?;
+        write!(w, "\x1b\\")?;
+    }
+
+    Ok(())
+}
+
+/// Encode the image as sixels using a fixed 6×6×6 color cube palette
+fn write_sixel<W: Write>(w: &mut W, image: &DynamicImage) -> std::io::Result<()> {
+    let rgb = image.to_rgb8();
+    let (width, height) = rgb.dimensions();
+
+    let level = |c: u8| (c as u16 * 5 + 127) / 255;
+    let index = |p: &image::Rgb<u8>| level(p[0]) * 36 + level(p[1]) * 6 + level(p[2]);
+
+    write!(w, "\x1bPq\"1;1;{};{}", width, height)?;
+    for i in 0..216u16 {
+        let (r, g, b) = (i / 36, (i / 6) % 6, i % 6);
+        write!(w, "#{};2;{};{};{}", i, r * 20, g * 20, b * 20)?;
+    }
+
+    for band in (0..height).step_by(6) {
+        let band_height = (height - band).min(6);
+
+        for color in 0..216u16 {
+            let mut row = Vec::with_capacity(width as usize);
+            let mut used = false;
+
+            for x in 0..width {
+                let mut sixel = 0u8;
+                for dy in 0..band_height {
+                    if index(rgb.get_pixel(x, band + dy)) == color {
+                        sixel |= 1 << dy;
+                    }
+                }
+                used |= sixel != 0;
+                row.push(b'?' + sixel);
+            }
+
+            if used {
+                write!(w, "#{}", color)?;
+                w.write_all(&row)?;
+                // Return to the start of the band for the next color
+                write!(w, "$")?;
+            }
+        }
+
+        // Move to the next band
+        write!(w, "-")?;
+    }
+
+    write!(w, "\x1b\\")
+}
@@ -1,36 +1,196 @@
-use std::sync::{mpsc, Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
 use std::thread;
 
 use log::info;
 
+use crate::input::{self, Pauser};
 use crate::state::{Event, State};
+use crate::terminal;
 
+/// Owns State, every change to it is an event handled here one at a time
 pub struct Worker;
 
 impl Worker {
-    pub fn spawn(state: Arc<Mutex<State>>, rx: mpsc::Receiver<Event>) -> thread::JoinHandle<()> {
-        thread::spawn(move || {
-            handle_event_loop(state, rx);
-        })
+    /// Hand State to the worker, it's given back once the worker is told to terminate
+    pub fn spawn(
+        mut state: State,
+        rx: mpsc::Receiver<Event>,
+        input: Pauser,
+    ) -> thread::JoinHandle<State> {
+        thread::Builder::new()
+            .name("worker".to_string())
+            .spawn(move || {
+                handle_event_loop(&mut state, rx, &input);
+                state
+            })
+            .expect("unable to spawn the worker thread")
     }
 }
 
-fn handle_event_loop(state: Arc<Mutex<State>>, rx: mpsc::Receiver<Event>) {
+fn handle_event_loop(state: &mut State, rx: mpsc::Receiver<Event>, input: &Pauser) {
     loop {
-        let event = rx.recv().unwrap();
+        let event = match rx.recv() {
+            Ok(event) => event,
+            Err(_) => {
+                state.shutdown("the worker channel closed".to_string());
+                break;
+            }
+        };
 
-        info!("event recv: {:?}", &event);
+        // Ticks arrive every poll interval, logging them would drown everything else out
+        if !matches!(event, Event::Tick) {
+            info!("event recv: {:?}", &event);
+        }
 
-        match event {
-            Event::TransactionComplete(response, url) => {
-                let mut state = state.lock().expect("poisoned");
-                state.transaction_complete(*response, url);
-            }
-            Event::TransactionError(e) => {
-                let mut state = state.lock().expect("poisoned");
-                state.transaction_error(e);
-            }
-            Event::TerminateWorker => break,
+        if let Event::TerminateWorker = event {
+            break;
+        }
+
+        // The panic has already been logged and State is almost always fine, so the crash is
+        // reported in the status line instead of taking the whole UI down
+        let handled = panic::catch_unwind(AssertUnwindSafe(|| handle_event(state, input, event)));
+        if handled.is_err() {
+            info!("worker recovered from a panic");
+            state
+                .set_error_message("A background task crashed, details are in the log".to_string());
         }
+        // Put back for the panic, wherever it was
+        let recovered = terminal::recover().unwrap_or_else(|e| {
+            info!("unable to take the terminal back: {}", e);
+            false
+        });
+        if handled.is_err() || recovered {
+            state.clear_screen_and_render_page();
+        }
+    }
+}
+
+fn handle_event(state: &mut State, input: &Pauser, event: Event) {
+    if state.terminated() {
+        return;
+    }
+
+    let from_input = matches!(event, Event::Key(_) | Event::Resize(..) | Event::Tick);
+    match event {
+        Event::TransactionComplete(request_id, response, url) => {
+            state.load_complete(request_id, *response, url);
+        }
+        Event::TransactionError(request_id, e) => {
+            state.in_request_pane(request_id, |state| state.transaction_error(e));
+        }
+        Event::PrefetchComplete(response, url, generation) => {
+            state.prefetch_complete(*response, url, generation);
+        }
+        Event::Favicon(url, icon) => {
+            state.favicon_received(url, icon);
+        }
+        Event::DownloadPrompt {
+            mime_type,
+            received,
+            decision,
+        } => {
+            state.download_prompt(mime_type, received, decision);
+        }
+        Event::DownloadProgress {
+            request_id,
+            url,
+            mime_type,
+            received,
+        } => {
+            state.download_progress(request_id, &url, &mime_type, received);
+        }
+        Event::Retrying {
+            request_id,
+            attempt,
+            attempts,
+        } => {
+            state.in_request_pane(request_id, |state| state.retrying(attempt, attempts));
+        }
+        Event::Open(url) => {
+            state.request(url.as_str());
+            state.clear_screen_and_render_page();
+        }
+        Event::Key(event) => input::handle_key_event(state, event),
+        Event::Resize(width, height) => state.new_size(width, height),
+        Event::Tick => {}
+        // Quit as if the user had so teardown happens normally
+        Event::InputClosed | Event::Signal(_) => state.quit(),
+        Event::TerminateWorker => {}
+    }
+
+    if from_input && !state.terminated() {
+        input::tick(state);
+    }
+
+    if state.has_pending_open() && !state.terminated() {
+        // Handlers need the terminal to themselves, the reader stops reading it until they exit
+        let _paused = input.pause();
+        state.open_pending();
+    }
+
+    // The terminal's reply to asking for the clipboard arrives as input, which the reader
+    // mustn't take
+    if state.has_pending_paste() && !state.terminated() {
+        let _paused = input.pause();
+        state.paste_pending();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::gemini::fetcher::Fixture;
+    use crate::gemini::TransactionError;
+    use crate::input::Reader;
+
+    #[test]
+    fn dropping_the_sender_stops_the_worker() {
+        let (state, _rx) = State::with_fetcher(Arc::new(Fixture::new("Foo.")));
+        let (tx, rx) = mpsc::channel();
+        let (reader, pauser) = Reader::new(tx.clone());
+
+        let worker = Worker::spawn(state, rx, pauser);
+        drop(tx);
+        drop(reader);
+
+        let state = worker.join().expect("worker thread panicked");
+        assert!(state.terminated());
+        assert_eq!(state.shutdown_reason(), Some("the worker channel closed"));
+    }
+
+    #[test]
+    fn events_are_handled_in_order() {
+        let (state, _rx) = State::with_fetcher(Arc::new(Fixture::new("Foo.")));
+        let (tx, rx) = mpsc::channel();
+        let (_reader, pauser) = Reader::new(tx.clone());
+
+        let worker = Worker::spawn(state, rx, pauser);
+        tx.send(Event::TransactionError(0, TransactionError::Cancelled))
+            .unwrap();
+        tx.send(Event::Tick).unwrap();
+        tx.send(Event::TerminateWorker).unwrap();
+        // Never handled, the worker has already stopped
+        tx.send(Event::InputClosed).unwrap();
+
+        let state = worker.join().expect("worker thread panicked");
+        assert!(!state.terminated());
+    }
+
+    #[test]
+    fn signals_quit() {
+        let (state, rx) = State::with_fetcher(Arc::new(Fixture::new("Foo.")));
+        let tx = state.sender();
+        let (_reader, pauser) = Reader::new(tx.clone());
+
+        let worker = Worker::spawn(state, rx, pauser);
+        tx.send(Event::Signal("SIGTERM")).unwrap();
+
+        // Quitting stops the worker itself, it isn't shut down for want of events
+        let state = worker.join().expect("worker thread panicked");
+        assert!(state.terminated());
+        assert_eq!(state.shutdown_reason(), None);
     }
 }